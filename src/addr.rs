@@ -3,7 +3,7 @@
 use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
-use crate::structures::paging::{PageOffset, PageTableIndex};
+use crate::structures::paging::{PageOffset, PageTableIndex, PageTableLevel};
 use bit_field::BitField;
 
 /// A canonical 64-bit virtual memory address.
@@ -16,6 +16,12 @@ use bit_field::BitField;
 /// On `x86_64`, only the 48 lower bits of a virtual address can be used. The top 16 bits need
 /// to be copies of bit 47, i.e. the most significant bit. Addresses that fulfil this criterium
 /// are called “canonical”. This type guarantees that it always represents a canonical address.
+///
+/// With 5-level paging (LA57), the 57 lower bits of a virtual address can be used and the top 7
+/// bits need to be copies of bit 56. Every address that is canonical for 4-level paging is also
+/// canonical for 5-level paging. The `new`, `try_new`, and `new_truncate` functions check for
+/// the 4-level canonical form, the `_la57` variants of these functions check for the 5-level
+/// canonical form.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct VirtAddr(u64);
@@ -85,11 +91,53 @@ impl VirtAddr {
         VirtAddr(((addr << 16) as i64 >> 16) as u64)
     }
 
+    /// Creates a new canonical virtual address for 5-level paging.
+    ///
+    /// This function performs sign extension of bit 56 to make the address canonical.
+    ///
+    /// ## Panics
+    ///
+    /// This function panics if the bits in the range 57 to 64 contain data (i.e. are not null and no sign extension).
+    #[inline]
+    pub fn new_la57(addr: u64) -> VirtAddr {
+        Self::try_new_la57(addr).expect(
+            "address passed to VirtAddr::new_la57 must not contain any data \
+             in bits 57 to 64",
+        )
+    }
+
+    /// Tries to create a new canonical virtual address for 5-level paging.
+    ///
+    /// This function tries to performs sign extension of bit 56 to make the address canonical.
+    /// It succeeds if bits 57 to 64 are either a correct sign extension (i.e. copies of bit 56)
+    /// or all null. Else, an error is returned.
+    #[inline]
+    pub fn try_new_la57(addr: u64) -> Result<VirtAddr, VirtAddrNotValid> {
+        match addr.get_bits(56..64) {
+            0 | 0xff => Ok(VirtAddr(addr)),             // address is canonical
+            1 => Ok(VirtAddr::new_truncate_la57(addr)), // address needs sign extension
+            other => Err(VirtAddrNotValid(other)),
+        }
+    }
+
+    /// Creates a new canonical virtual address for 5-level paging, throwing out bits 57..64.
+    ///
+    /// This function performs sign extension of bit 56 to make the address canonical, so
+    /// bits 57 to 64 are overwritten. If you want to check that these bits contain no data,
+    /// use `new_la57` or `try_new_la57`.
+    #[inline]
+    pub const fn new_truncate_la57(addr: u64) -> VirtAddr {
+        // By doing the right shift as a signed operation (on a i64), it will
+        // sign extend the value, repeating the leftmost bit.
+        VirtAddr(((addr << 7) as i64 >> 7) as u64)
+    }
+
     /// Creates a new virtual address, without any checks.
     ///
     /// ## Safety
     ///
-    /// You must make sure bits 48..64 are equal to bit 47. This is not checked.
+    /// You must make sure bits 48..64 are equal to bit 47 (or bits 57..64 are equal to bit 56
+    /// for 5-level paging). This is not checked.
     #[inline]
     pub const unsafe fn new_unsafe(addr: u64) -> VirtAddr {
         VirtAddr(addr)
@@ -198,6 +246,42 @@ impl VirtAddr {
     pub const fn p4_index(self) -> PageTableIndex {
         PageTableIndex::new_truncate((self.0 >> 12 >> 9 >> 9 >> 9) as u16)
     }
+
+    /// Returns the 9-bit page table index for the given level.
+    #[inline]
+    pub const fn page_table_index(self, level: PageTableLevel) -> PageTableIndex {
+        PageTableIndex::new_truncate((self.0 >> 12 >> ((level as u8 - 1) * 9)) as u16)
+    }
+
+    /// Returns the 9-bit level 5 page table index.
+    ///
+    /// This index is only used with 5-level paging.
+    #[inline]
+    pub const fn p5_index(self) -> PageTableIndex {
+        PageTableIndex::new_truncate((self.0 >> 12 >> 9 >> 9 >> 9 >> 9) as u16)
+    }
+
+    /// Returns whether this address is only canonical for 5-level paging.
+    #[inline]
+    fn is_la57_only(self) -> bool {
+        !matches!(self.0.get_bits(47..64), 0 | 0x1ffff)
+    }
+
+    /// Creates the result of an address computation on `self`.
+    ///
+    /// Results of computations on lower half addresses are sign extended from bit 56, so
+    /// that they don't end up in the 4-level upper half when they cross bit 47, which is
+    /// still part of the lower half for 5-level paging. Results of computations on upper half
+    /// addresses are checked for the canonical form of `self`, so that computations on
+    /// 4-level addresses don't silently leave the 4-level upper half.
+    #[inline]
+    fn new_like(self, addr: u64) -> VirtAddr {
+        if self.0.get_bit(63) && !self.is_la57_only() {
+            VirtAddr::new(addr)
+        } else {
+            VirtAddr::new_la57(addr)
+        }
+    }
 }
 
 impl fmt::Debug for VirtAddr {
@@ -247,7 +331,7 @@ impl Add<u64> for VirtAddr {
     type Output = Self;
     #[inline]
     fn add(self, rhs: u64) -> Self::Output {
-        VirtAddr::new(self.0 + rhs)
+        self.new_like(self.0 + rhs)
     }
 }
 
@@ -279,7 +363,7 @@ impl Sub<u64> for VirtAddr {
     type Output = Self;
     #[inline]
     fn sub(self, rhs: u64) -> Self::Output {
-        VirtAddr::new(self.0.checked_sub(rhs).unwrap())
+        self.new_like(self.0.checked_sub(rhs).unwrap())
     }
 }
 
@@ -561,6 +645,7 @@ pub const fn align_up(addr: u64, align: u64) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::structures::paging::{Page, Size4KiB};
 
     #[test]
     pub fn virtaddr_new_truncate() {
@@ -586,4 +671,42 @@ mod tests {
         assert_eq!(align_up(0, 2), 0);
         assert_eq!(align_up(0, 0x8000_0000_0000_0000), 0);
     }
+
+    #[test]
+    pub fn virtaddr_la57() {
+        assert_eq!(VirtAddr::new_truncate_la57(1 << 56), VirtAddr(0xff << 56));
+        assert_eq!(VirtAddr::new_la57(1 << 55), VirtAddr(1 << 55));
+        assert!(VirtAddr::try_new(1 << 55).is_err());
+        assert!(VirtAddr::try_new_la57(1 << 57).is_err());
+
+        let addr = VirtAddr::new_la57(0x00ab_cdef_0123_4000);
+        assert_eq!(u16::from(addr.p5_index()), 0xab);
+        assert_eq!(addr + 0x1000u64, VirtAddr(0x00ab_cdef_0123_5000));
+        assert_eq!(addr - 0x1000u64, VirtAddr(0x00ab_cdef_0123_3000));
+    }
+
+    #[test]
+    pub fn virtaddr_arithmetic_across_bit_47() {
+        // bit 47 is part of the lower half for 5-level paging
+        let last = VirtAddr::new(0x7fff_ffff_f000);
+        assert_eq!(last + 0x1000u64, VirtAddr(0x8000_0000_0000));
+        assert_eq!(VirtAddr::new_la57(0x8000_0000_0000) - 0x1000u64, last);
+        let page = Page::<Size4KiB>::containing_address(last);
+        assert_eq!(
+            Page::range(page, page + 2).last().unwrap().start_address(),
+            VirtAddr(0x8000_0000_0000)
+        );
+
+        // upper half addresses keep their canonical form
+        let upper = VirtAddr::new(0xffff_8000_0000_0000);
+        assert_eq!(upper + 0x1000u64, VirtAddr(0xffff_8000_0000_1000));
+        let la57_upper = VirtAddr::new_la57(0xff00_0000_0000_0000);
+        assert_eq!(la57_upper + 0x1000u64, VirtAddr(0xff00_0000_0000_1000));
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn virtaddr_arithmetic_leaves_4_level_upper_half() {
+        let _ = VirtAddr::new(0xffff_8000_0000_0000) - 0x1000u64;
+    }
 }
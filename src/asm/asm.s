@@ -104,6 +104,20 @@ _x86_64_asm_invpcid:
     invpcid (%rsi), %rdi
     retq
 
+.global _x86_64_asm_invlpgb
+.p2align 4
+_x86_64_asm_invlpgb:
+    mov %rdi, %rax
+    mov %esi, %ecx
+    .byte 0x0f, 0x01, 0xfe # invlpgb
+    retq
+
+.global _x86_64_asm_tlbsync
+.p2align 4
+_x86_64_asm_tlbsync:
+    .byte 0x0f, 0x01, 0xff # tlbsync
+    retq
+
 .global _x86_64_asm_ltr
 .p2align 4
 _x86_64_asm_ltr:
@@ -328,3 +342,19 @@ _x86_64_asm_xsetbv:
     movl  %esi, %eax    # Second param is the low 32-bits
     xsetbv              # Third param (high 32-bits) is already in %edx
     retq
+
+.global _x86_64_asm_rdpkru
+.p2align 4
+_x86_64_asm_rdpkru:
+    xorl   %ecx, %ecx
+    rdpkru
+    retq
+
+.global _x86_64_asm_wrpkru
+.p2align 4
+_x86_64_asm_wrpkru:
+    movl   %edi, %eax    # First param is the new PKRU value
+    xorl   %ecx, %ecx
+    xorl   %edx, %edx
+    wrpkru
+    retq
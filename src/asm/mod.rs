@@ -192,6 +192,18 @@ extern "sysv64" {
     )]
     pub(crate) fn x86_64_asm_invpcid(kind: u64, desc: u64);
 
+    #[cfg_attr(
+        any(target_env = "gnu", target_env = "musl"),
+        link_name = "_x86_64_asm_invlpgb"
+    )]
+    pub(crate) fn x86_64_asm_invlpgb(rax: u64, ecx: u32, edx: u32);
+
+    #[cfg_attr(
+        any(target_env = "gnu", target_env = "musl"),
+        link_name = "_x86_64_asm_tlbsync"
+    )]
+    pub(crate) fn x86_64_asm_tlbsync();
+
     #[cfg_attr(
         any(target_env = "gnu", target_env = "musl"),
         link_name = "_x86_64_asm_read_cr0"
@@ -293,4 +305,16 @@ extern "sysv64" {
         link_name = "_x86_64_asm_xsetbv"
     )]
     pub(crate) fn x86_64_asm_xsetbv(xcr: u32, low: u32, high: u32);
+
+    #[cfg_attr(
+        any(target_env = "gnu", target_env = "musl"),
+        link_name = "_x86_64_asm_rdpkru"
+    )]
+    pub(crate) fn x86_64_asm_rdpkru() -> u32;
+
+    #[cfg_attr(
+        any(target_env = "gnu", target_env = "musl"),
+        link_name = "_x86_64_asm_wrpkru"
+    )]
+    pub(crate) fn x86_64_asm_wrpkru(val: u32);
 }
@@ -0,0 +1,418 @@
+//! Functions to flush the translation lookaside buffer (TLB).
+
+use crate::structures::paging::{page::PageRange, PageSize, Size1GiB, Size2MiB};
+use crate::VirtAddr;
+use bitflags::bitflags;
+
+pub mod pcid;
+pub mod shootdown;
+
+/// Invalidate the given address in the TLB using the `invlpg` instruction.
+#[inline]
+pub fn flush(addr: VirtAddr) {
+    unsafe {
+        #[cfg(feature = "inline_asm")]
+        asm!("invlpg [{}]", in(reg) addr.as_u64(), options(nostack, preserves_flags));
+
+        #[cfg(not(feature = "inline_asm"))]
+        crate::asm::x86_64_asm_invlpg(addr.as_u64());
+    }
+}
+
+/// Invalidate the TLB completely by reloading the CR3 register.
+#[inline]
+pub fn flush_all() {
+    use crate::registers::control::Cr3;
+    let (frame, flags) = Cr3::read();
+    unsafe { Cr3::write(frame, flags) }
+}
+
+/// Invalidate the TLB completely, including pages that have the `GLOBAL` flag set.
+///
+/// If global pages are enabled, this toggles the `PAGE_GLOBAL` bit of the CR4 register, which
+/// invalidates the TLB entries of all PCIDs. Otherwise, the CR3 register is reloaded like in
+/// [`flush_all`].
+#[inline]
+pub fn flush_all_including_global() {
+    use crate::instructions::interrupts;
+    use crate::registers::control::{Cr4, Cr4Flags};
+
+    interrupts::without_interrupts(|| {
+        let flags = Cr4::read();
+        if flags.contains(Cr4Flags::PAGE_GLOBAL) {
+            // Safety: the bit is restored immediately, which only invalidates the TLB
+            unsafe {
+                Cr4::write(flags - Cr4Flags::PAGE_GLOBAL);
+                Cr4::write(flags);
+            }
+        } else {
+            flush_all();
+        }
+    })
+}
+
+/// The Invalidate PCID Command to execute.
+#[derive(Debug)]
+pub enum InvPicdCommand {
+    /// The logical processor invalidates mappings—except global translations—for the linear address and PCID specified.
+    Address(VirtAddr, Pcid),
+
+    /// The logical processor invalidates all mappings—except global translations—associated with the PCID.
+    Single(Pcid),
+
+    /// The logical processor invalidates all mappings—including global translations—associated with any PCID.
+    All,
+
+    /// The logical processor invalidates all mappings—except global translations—associated with any PCID.
+    AllExceptGlobal,
+}
+
+/// The INVPCID descriptor comprises 128 bits and consists of a PCID and a linear address.
+/// For INVPCID type 0, the processor uses the full 64 bits of the linear address even outside 64-bit mode; the linear address is not used for other INVPCID types.
+#[repr(C)]
+#[derive(Debug)]
+struct InvpcidDescriptor {
+    address: u64,
+    pcid: u64,
+}
+
+/// Structure of a PCID. A PCID has to be <= 4096 for x86_64.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcid(u16);
+
+impl Pcid {
+    /// Create a new PCID. Will result in a failure if the value of
+    /// PCID is out of expected bounds.
+    pub const fn new(pcid: u16) -> Result<Pcid, &'static str> {
+        if pcid >= 4096 {
+            Err("PCID should be < 4096.")
+        } else {
+            Ok(Pcid(pcid))
+        }
+    }
+
+    /// Get the value of the current PCID.
+    pub const fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+/// Invalidate the given address in the TLB using the `invpcid` instruction.
+///
+/// ## Safety
+/// This function is unsafe as it requires CPUID.(EAX=07H, ECX=0H):EBX.INVPCID to be 1.
+#[inline]
+pub unsafe fn flush_pcid(command: InvPicdCommand) {
+    let mut desc = InvpcidDescriptor {
+        address: 0,
+        pcid: 0,
+    };
+
+    let kind: u64;
+    match command {
+        InvPicdCommand::Address(addr, pcid) => {
+            kind = 0;
+            desc.pcid = pcid.value().into();
+            desc.address = addr.as_u64()
+        }
+        InvPicdCommand::Single(pcid) => {
+            kind = 1;
+            desc.pcid = pcid.0.into()
+        }
+        InvPicdCommand::All => kind = 2,
+        InvPicdCommand::AllExceptGlobal => kind = 3,
+    }
+
+    #[cfg(feature = "inline_asm")]
+    asm!("invpcid {0}, [{1}]", in(reg) kind, in(reg) &desc, options(nostack, preserves_flags));
+
+    #[cfg(not(feature = "inline_asm"))]
+    crate::asm::x86_64_asm_invpcid(kind, &desc as *const _ as u64);
+}
+
+/// Used to invalidate TLB entries on all CPUs using AMD's `invlpgb` and `tlbsync` instructions.
+///
+/// The `invlpgb` instruction broadcasts the invalidation to all CPUs of the system without
+/// interrupting them. It is asynchronous: The invalidation is only guaranteed to be complete
+/// after the same CPU executed `tlbsync`.
+///
+/// ```ignore
+/// let (frame, flush) = mapper.unmap(page)?;
+/// flush.ignore();
+/// invlpgb.flush(InvlpgbDescriptor::new().address(page.start_address()));
+/// invlpgb.tlbsync();
+/// // no CPU has the old mapping cached anymore, so the frame can be reused
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Invlpgb {
+    max_pages: u32,
+}
+
+impl Invlpgb {
+    /// Creates `Some(Invlpgb)` if the `invlpgb` and `tlbsync` instructions are supported,
+    /// `None` otherwise.
+    #[inline]
+    pub fn new() -> Option<Self> {
+        // INVLPGB support is indicated by CPUID page 8000_0008h, ebx bit 3, the maximum page
+        // count minus one by edx bits 0..16
+        let max_leaf = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+        if max_leaf < 0x8000_0008 {
+            return None;
+        }
+        let cpuid = unsafe { core::arch::x86_64::__cpuid(0x8000_0008) };
+        if cpuid.ebx & (1 << 3) != 0 {
+            Some(Invlpgb {
+                max_pages: (cpuid.edx & 0xffff) + 1,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the maximum number of pages that a single `invlpgb` can invalidate.
+    #[inline]
+    pub fn max_pages(self) -> u32 {
+        self.max_pages
+    }
+
+    /// Invalidates the TLB entries that are selected by the given descriptor on all CPUs
+    /// using the `invlpgb` instruction.
+    ///
+    /// The invalidation is only complete after [`tlbsync`](Self::tlbsync) returns.
+    ///
+    /// Panics if the descriptor selects more than [`max_pages`](Self::max_pages) pages.
+    #[inline]
+    pub fn flush(self, descriptor: InvlpgbDescriptor) {
+        assert!(
+            u32::from(descriptor.count) < self.max_pages,
+            "too many pages for invlpgb"
+        );
+        let rax = descriptor.rax();
+        let ecx = descriptor.ecx();
+        let edx = descriptor.edx();
+        unsafe {
+            #[cfg(feature = "inline_asm")]
+            asm!(".byte 0x0f, 0x01, 0xfe", in("rax") rax, in("ecx") ecx, in("edx") edx, options(nostack, preserves_flags));
+
+            #[cfg(not(feature = "inline_asm"))]
+            crate::asm::x86_64_asm_invlpgb(rax, ecx, edx);
+        }
+    }
+
+    /// Invalidates the given pages for all PCIDs on all CPUs.
+    ///
+    /// The pages are split into as few `invlpgb` instructions as possible. The cached entries
+    /// of the higher level page tables are invalidated too, so the page tables of the pages can
+    /// be freed after [`tlbsync`](Self::tlbsync) returns.
+    pub fn flush_pages<S: PageSize>(self, pages: PageRange<S>) {
+        let stride = match S::SIZE {
+            Size1GiB::SIZE => None,
+            Size2MiB::SIZE => Some(InvlpgbStride::Size2MiB),
+            _ => Some(InvlpgbStride::Size4KiB),
+        };
+        let mut start = pages.start;
+        while start < pages.end {
+            let descriptor = InvlpgbDescriptor::new().address(start.start_address());
+            let count = match stride {
+                Some(stride) => {
+                    let count = (pages.end - start)
+                        .min(u64::from(self.max_pages))
+                        .min(u64::from(u16::MAX));
+                    self.flush(descriptor.pages(count as u16, stride));
+                    count
+                }
+                // there is no 1GiB stride, but a single address invalidates the whole page
+                None => {
+                    self.flush(descriptor);
+                    1
+                }
+            };
+            start += count;
+        }
+    }
+
+    /// Waits until all `invlpgb` instructions that were executed by the current CPU are
+    /// complete on all CPUs using the `tlbsync` instruction.
+    #[inline]
+    pub fn tlbsync(self) {
+        unsafe {
+            #[cfg(feature = "inline_asm")]
+            asm!(".byte 0x0f, 0x01, 0xff", options(nostack, preserves_flags));
+
+            #[cfg(not(feature = "inline_asm"))]
+            crate::asm::x86_64_asm_tlbsync();
+        }
+    }
+}
+
+/// Selects the TLB entries that an `invlpgb` instruction invalidates.
+///
+/// Without any options, all non-global TLB entries of all PCIDs and ASIDs are invalidated.
+/// Each option restricts or extends the invalidated entries:
+///
+/// ```
+/// use x86_64::instructions::tlb::{InvlpgbDescriptor, InvlpgbFlags, InvlpgbStride, Pcid};
+/// use x86_64::VirtAddr;
+///
+/// let descriptor = InvlpgbDescriptor::new()
+///     .address(VirtAddr::new(0x1000_0000))
+///     .pages(16, InvlpgbStride::Size4KiB)
+///     .pcid(Pcid::new(1).unwrap())
+///     .flags(InvlpgbFlags::FINAL_ONLY);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvlpgbDescriptor {
+    address: Option<VirtAddr>,
+    /// The number of additional pages.
+    count: u16,
+    stride: InvlpgbStride,
+    pcid: Option<Pcid>,
+    asid: Option<u16>,
+    flags: InvlpgbFlags,
+}
+
+impl InvlpgbDescriptor {
+    /// Creates a descriptor that selects all non-global TLB entries.
+    #[inline]
+    pub const fn new() -> Self {
+        InvlpgbDescriptor {
+            address: None,
+            count: 0,
+            stride: InvlpgbStride::Size4KiB,
+            pcid: None,
+            asid: None,
+            flags: InvlpgbFlags::empty(),
+        }
+    }
+
+    /// Only selects the TLB entries of the page at the given address.
+    ///
+    /// Panics if the address is not aligned to 4KiB.
+    #[inline]
+    pub fn address(mut self, address: VirtAddr) -> Self {
+        assert!(address.is_aligned(4096u64), "address must be page aligned");
+        self.address = Some(address);
+        self
+    }
+
+    /// Selects `count` consecutive pages starting at the [`address`](Self::address), with the
+    /// given distance between them.
+    ///
+    /// Panics if `count` is 0 or if no address was set.
+    #[inline]
+    pub fn pages(mut self, count: u16, stride: InvlpgbStride) -> Self {
+        assert!(count > 0, "count must be greater than 0");
+        assert!(self.address.is_some(), "pages require an address");
+        self.count = count - 1;
+        self.stride = stride;
+        self
+    }
+
+    /// Only selects the TLB entries of the given PCID.
+    #[inline]
+    pub fn pcid(mut self, pcid: Pcid) -> Self {
+        self.pcid = Some(pcid);
+        self
+    }
+
+    /// Only selects the TLB entries of the given address space identifier (ASID) of a guest.
+    ///
+    /// The ASID of the host is 0.
+    #[inline]
+    pub fn asid(mut self, asid: u16) -> Self {
+        self.asid = Some(asid);
+        self
+    }
+
+    /// Sets the given options.
+    #[inline]
+    pub fn flags(mut self, flags: InvlpgbFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// The value of the rax register, i.e. the address and the flags.
+    fn rax(&self) -> u64 {
+        let mut rax = self.flags.bits();
+        if let Some(address) = self.address {
+            rax |= address.as_u64() | 1;
+        }
+        if self.pcid.is_some() {
+            rax |= 1 << 1;
+        }
+        if self.asid.is_some() {
+            rax |= 1 << 2;
+        }
+        rax
+    }
+
+    /// The value of the ecx register, i.e. the number of additional pages and the stride.
+    fn ecx(&self) -> u32 {
+        let stride = match self.stride {
+            InvlpgbStride::Size4KiB => 0,
+            InvlpgbStride::Size2MiB => 1 << 31,
+        };
+        stride | u32::from(self.count)
+    }
+
+    /// The value of the edx register, i.e. the PCID and the ASID.
+    fn edx(&self) -> u32 {
+        let pcid = self.pcid.map_or(0, |pcid| u32::from(pcid.value()));
+        let asid = self.asid.map_or(0, u32::from);
+        pcid << 16 | asid
+    }
+}
+
+impl Default for InvlpgbDescriptor {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The distance between the pages that an `invlpgb` instruction invalidates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvlpgbStride {
+    /// The pages are 4KiB apart.
+    Size4KiB,
+    /// The pages are 2MiB apart, which also selects the TLB entries of 2MiB pages.
+    Size2MiB,
+}
+
+bitflags! {
+    /// Options of the `invlpgb` instruction.
+    pub struct InvlpgbFlags: u64 {
+        /// Also invalidate global TLB entries.
+        const INCLUDE_GLOBAL = 1 << 3;
+        /// Only invalidate the final translations, but not the cached entries of the higher
+        /// level page tables.
+        const FINAL_ONLY = 1 << 4;
+        /// Also invalidate the nested translations of guests.
+        const INCLUDE_NESTED = 1 << 5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invlpgb_descriptor_encoding() {
+        let descriptor = InvlpgbDescriptor::new();
+        assert_eq!(
+            (descriptor.rax(), descriptor.ecx(), descriptor.edx()),
+            (0, 0, 0)
+        );
+
+        let descriptor = InvlpgbDescriptor::new()
+            .address(VirtAddr::new(0x1234_5000))
+            .pages(3, InvlpgbStride::Size2MiB)
+            .pcid(Pcid::new(0x123).unwrap())
+            .asid(7)
+            .flags(InvlpgbFlags::INCLUDE_GLOBAL | InvlpgbFlags::FINAL_ONLY);
+        assert_eq!(descriptor.rax(), 0x1234_5000 | 0b1_1111);
+        assert_eq!(descriptor.ecx(), 1 << 31 | 2);
+        assert_eq!(descriptor.edx(), 0x123 << 16 | 7);
+    }
+}
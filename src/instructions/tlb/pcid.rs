@@ -0,0 +1,389 @@
+//! Assigning process-context identifiers (PCIDs) to address spaces.
+//!
+//! With PCIDs enabled, the TLB keeps the translations of multiple address spaces, tagged with
+//! the PCID that was active when they were cached. Switching to an address space can then
+//! keep the cached translations by setting the no-flush bit of CR3, see
+//! [`Cr3::write_pcid_no_flush`]. This is only correct if the entries of the PCID belong to
+//! the same address space and if they are not stale, i.e. if no mapping of the address space
+//! was changed since they were cached.
+//!
+//! There are only 4096 PCIDs, so a [`PcidManager`] on each CPU assigns a small number of them
+//! to the most recently used address spaces. Address spaces are identified by a
+//! [`TlbContext`], which has a unique ID and a generation counter. The generation is
+//! increased whenever a mapping of the address space is changed, so a CPU that switches back
+//! to the address space later can detect that the entries of its PCID are stale.
+//!
+//! CPUs on which the address space is active don't switch to it again, so they need a TLB
+//! shootdown instead. For this, the kernel tracks the CPUs that use each address space, and
+//! the order of the accesses matters: A switching CPU must publish that it uses the address
+//! space before [`PcidManager::switch_to`] reads the generation, and an invalidating CPU must
+//! read the CPUs after [`TlbContext::invalidate`] increased it. Both functions execute a
+//! sequentially consistent fence, so either the invalidating CPU sees the switching CPU and
+//! sends it a shootdown, or the switching CPU sees the new generation and flushes its PCID:
+//!
+//! ```ignore
+//! // when switching to `space` on the current CPU
+//! space.active_cpus.insert(current_cpu); // an atomic store
+//! unsafe { pcid_manager.activate(space.root_frame(), &space.tlb_context) };
+//!
+//! // after unmapping a page of `space`
+//! let generation = space.tlb_context.invalidate();
+//! flush.flush();
+//! pcid_manager.mark_flushed(&space.tlb_context, generation);
+//! // CPUs on which `space` is active need a shootdown, all other CPUs flush the PCID of
+//! // `space` lazily when they switch to it again
+//! SHOOTDOWN.shootdown(batch, &space.active_cpus.load(), current_cpu, &mut ipi_sender);
+//! ```
+
+use crate::instructions::tlb::Pcid;
+use crate::registers::control::Cr3;
+use crate::structures::paging::PhysFrame;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// The TLB state of an address space that is shared by all CPUs.
+///
+/// It is usually stored together with the page tables of the address space.
+#[derive(Debug)]
+pub struct TlbContext {
+    id: u64,
+    generation: AtomicU64,
+}
+
+impl TlbContext {
+    /// Creates a new context with a unique ID.
+    #[inline]
+    pub fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        TlbContext {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the unique ID of the context.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the current generation of the context.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Increases the generation of the context after a mapping of the address space was
+    /// changed, so that the TLB entries that CPUs cached before are considered stale.
+    ///
+    /// Returns the new generation. This must be called before the TLB of the current CPU is
+    /// flushed, the flush can then be recorded through [`PcidManager::mark_flushed`].
+    ///
+    /// The CPUs that use the address space and need a TLB shootdown must be read after this
+    /// call. It is followed by a sequentially consistent fence, which pairs with the fence in
+    /// [`PcidManager::switch_to`].
+    #[inline]
+    pub fn invalidate(&self) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        fence(Ordering::SeqCst);
+        generation
+    }
+}
+
+impl Default for TlbContext {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A PCID that a [`PcidManager`] assigns to an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    /// The ID of the context, or 0 if the slot is unused.
+    context: u64,
+    /// The generation of the context when the TLB entries of the PCID were last flushed.
+    generation: u64,
+}
+
+/// How the CR3 register must be written to switch to an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcidSwitch {
+    /// The TLB entries of the PCID are up to date, so the no-flush bit can be set.
+    Keep(Pcid),
+    /// The TLB entries of the PCID belong to a different address space or are stale, so they
+    /// must be invalidated, either by writing CR3 without the no-flush bit or through
+    /// [`flush_pcid`](super::flush_pcid).
+    Flush(Pcid),
+}
+
+impl PcidSwitch {
+    /// Returns the PCID to switch to.
+    #[inline]
+    pub fn pcid(self) -> Pcid {
+        match self {
+            PcidSwitch::Keep(pcid) | PcidSwitch::Flush(pcid) => pcid,
+        }
+    }
+
+    /// Loads the given level 4 table with the PCID into the CR3 register.
+    ///
+    /// For [`Flush`](Self::Flush), the no-flush bit is cleared, so the CPU invalidates all
+    /// non-global TLB entries of the PCID.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Cr3::write_pcid`] apply. The switch must have been
+    /// returned by [`PcidManager::switch_to`] for the address space of `frame` on the current
+    /// CPU.
+    #[inline]
+    pub unsafe fn activate(self, frame: PhysFrame) {
+        match self {
+            PcidSwitch::Keep(pcid) => Cr3::write_pcid_no_flush(frame, pcid),
+            PcidSwitch::Flush(pcid) => Cr3::write_pcid(frame, pcid),
+        }
+    }
+}
+
+/// Assigns PCIDs to address spaces on a single CPU.
+///
+/// The manager uses the PCIDs `1..=SLOTS`, PCID 0 is left for code that doesn't use the
+/// manager, e.g. the boot page table. The PCIDs are assigned to the most recently used address
+/// spaces, and when all of them are in use, the least recently used one is recycled.
+///
+/// For each PCID, the manager remembers the generation of the [`TlbContext`] at which its TLB
+/// entries were last flushed. Switching to an address space keeps the TLB entries only if the
+/// PCID still belongs to the address space and if the generation is unchanged.
+///
+/// Each CPU needs its own manager, since the TLB is not shared between CPUs.
+#[derive(Debug, Clone)]
+pub struct PcidManager {
+    slots: [Slot; PcidManager::SLOTS],
+    /// The last time each slot was used, in switches.
+    last_used: [u64; PcidManager::SLOTS],
+    switches: u64,
+    /// The slot of the active address space.
+    active: Option<usize>,
+}
+
+impl PcidManager {
+    /// The number of PCIDs that the manager assigns.
+    pub const SLOTS: usize = 8;
+
+    const UNUSED: Slot = Slot {
+        context: 0,
+        generation: 0,
+    };
+
+    /// Creates a manager without any assigned PCIDs.
+    #[inline]
+    pub const fn new() -> Self {
+        PcidManager {
+            slots: [Self::UNUSED; Self::SLOTS],
+            last_used: [0; Self::SLOTS],
+            switches: 0,
+            active: None,
+        }
+    }
+
+    /// Assigns a PCID to the given address space and returns how CR3 must be written to
+    /// switch to it.
+    ///
+    /// The returned switch must be applied to CR3 before any other switch is requested from
+    /// this manager, e.g. through [`PcidSwitch::activate`].
+    ///
+    /// The caller must publish that the current CPU uses the address space before calling this
+    /// function, e.g. by adding the CPU to the set of CPUs that [`TlbContext::invalidate`]
+    /// callers send TLB shootdowns to. Otherwise, a concurrent invalidation might neither
+    /// interrupt this CPU nor be visible in the generation, so stale entries would be kept.
+    /// The generation is read after a sequentially consistent fence, which pairs with the
+    /// fence in [`TlbContext::invalidate`].
+    pub fn switch_to(&mut self, context: &TlbContext) -> PcidSwitch {
+        // order the read of the generation after the store that published this CPU
+        fence(Ordering::SeqCst);
+        // the generation must be read before the TLB is flushed, so that a concurrent
+        // invalidation makes the entries stale again
+        let generation = context.generation();
+        self.switches += 1;
+
+        let slot = match self
+            .slots
+            .iter()
+            .position(|slot| slot.context == context.id)
+        {
+            Some(slot) => slot,
+            None => {
+                // recycle the least recently used slot
+                let slot = (0..Self::SLOTS)
+                    .min_by_key(|&slot| self.last_used[slot])
+                    .unwrap();
+                self.slots[slot] = Slot {
+                    context: context.id,
+                    generation,
+                };
+                self.last_used[slot] = self.switches;
+                self.active = Some(slot);
+                return PcidSwitch::Flush(Self::pcid(slot));
+            }
+        };
+
+        self.last_used[slot] = self.switches;
+        self.active = Some(slot);
+        if self.slots[slot].generation == generation {
+            PcidSwitch::Keep(Self::pcid(slot))
+        } else {
+            self.slots[slot].generation = generation;
+            PcidSwitch::Flush(Self::pcid(slot))
+        }
+    }
+
+    /// Switches to the given level 4 table of the address space of `context`.
+    ///
+    /// This is a combination of [`switch_to`](Self::switch_to) and
+    /// [`PcidSwitch::activate`].
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Cr3::write_pcid`] apply. `frame` must be the level 4
+    /// table of the address space of `context` and all changes of its mappings must be
+    /// recorded through [`TlbContext::invalidate`]. The current CPU must already be published
+    /// as a user of the address space, see [`switch_to`](Self::switch_to).
+    #[inline]
+    pub unsafe fn activate(&mut self, frame: PhysFrame, context: &TlbContext) {
+        self.switch_to(context).activate(frame)
+    }
+
+    /// Records that the TLB entries of the given address space were flushed on this CPU up
+    /// to the given generation, e.g. after the flush that followed a call to
+    /// [`TlbContext::invalidate`] or a TLB shootdown.
+    ///
+    /// This only has an effect if the address space is the active address space of this CPU,
+    /// because only the entries of the active PCID are flushed by `invlpg`.
+    #[inline]
+    pub fn mark_flushed(&mut self, context: &TlbContext, generation: u64) {
+        if let Some(slot) = self.active {
+            let slot = &mut self.slots[slot];
+            if slot.context == context.id && slot.generation < generation {
+                slot.generation = generation;
+            }
+        }
+    }
+
+    /// Returns the PCID of the active address space, if it was activated through this
+    /// manager.
+    #[inline]
+    pub fn active_pcid(&self) -> Option<Pcid> {
+        self.active.map(Self::pcid)
+    }
+
+    /// Forgets the PCID of the given address space, e.g. when it is destroyed.
+    ///
+    /// This is not required for correctness, since the IDs of contexts are never reused, but
+    /// it makes the PCID available for other address spaces. The address space must not be
+    /// active on this CPU.
+    #[inline]
+    pub fn remove(&mut self, context: &TlbContext) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.context == context.id {
+                debug_assert_ne!(self.active, Some(index), "address space is still active");
+                *slot = Self::UNUSED;
+                self.last_used[index] = 0;
+            }
+        }
+    }
+
+    #[inline]
+    fn pcid(slot: usize) -> Pcid {
+        Pcid::new(slot as u16 + 1).unwrap()
+    }
+}
+
+impl Default for PcidManager {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcid(value: u16) -> Pcid {
+        Pcid::new(value).unwrap()
+    }
+
+    #[test]
+    fn keep_and_flush() {
+        let mut manager = PcidManager::new();
+        let context = TlbContext::new();
+        assert_eq!(manager.active_pcid(), None);
+
+        // a new PCID might contain entries of a previous address space
+        assert_eq!(manager.switch_to(&context), PcidSwitch::Flush(pcid(1)));
+        assert_eq!(manager.active_pcid(), Some(pcid(1)));
+        assert_eq!(manager.switch_to(&context), PcidSwitch::Keep(pcid(1)));
+
+        // the entries are stale after an invalidation
+        context.invalidate();
+        let switch = manager.switch_to(&context);
+        assert_eq!(switch, PcidSwitch::Flush(pcid(1)));
+        assert_eq!(switch.pcid(), pcid(1));
+        assert_eq!(manager.switch_to(&context), PcidSwitch::Keep(pcid(1)));
+
+        let other = TlbContext::new();
+        assert_ne!(other.id(), context.id());
+        assert_eq!(manager.switch_to(&other), PcidSwitch::Flush(pcid(2)));
+        assert_eq!(manager.switch_to(&context), PcidSwitch::Keep(pcid(1)));
+    }
+
+    #[test]
+    fn mark_flushed() {
+        let mut manager = PcidManager::new();
+        let (context, other) = (TlbContext::new(), TlbContext::new());
+        manager.switch_to(&other);
+        manager.switch_to(&context);
+
+        // the flush of the active address space is recorded
+        let generation = context.invalidate();
+        manager.mark_flushed(&context, generation);
+        assert_eq!(manager.switch_to(&context), PcidSwitch::Keep(pcid(2)));
+
+        // flushes of inactive address spaces are ignored, since `invlpg` only affects the
+        // active PCID
+        let generation = other.invalidate();
+        manager.mark_flushed(&other, generation);
+        assert_eq!(manager.switch_to(&other), PcidSwitch::Flush(pcid(1)));
+
+        // an older generation doesn't undo a newer one
+        let old = other.invalidate();
+        let new = other.invalidate();
+        manager.mark_flushed(&other, new);
+        manager.mark_flushed(&other, old);
+        assert_eq!(manager.switch_to(&other), PcidSwitch::Keep(pcid(1)));
+    }
+
+    #[test]
+    fn recycles_least_recently_used() {
+        let mut manager = PcidManager::new();
+        let contexts: Vec<_> = (0..=PcidManager::SLOTS)
+            .map(|_| TlbContext::new())
+            .collect();
+        for (index, context) in contexts[..PcidManager::SLOTS].iter().enumerate() {
+            let switch = manager.switch_to(context);
+            assert_eq!(switch, PcidSwitch::Flush(pcid(index as u16 + 1)));
+        }
+        // use the first address space again, so that the second one is the least recently used
+        assert_eq!(manager.switch_to(&contexts[0]), PcidSwitch::Keep(pcid(1)));
+
+        let last = &contexts[PcidManager::SLOTS];
+        assert_eq!(manager.switch_to(last), PcidSwitch::Flush(pcid(2)));
+        // the second address space lost its PCID
+        assert_eq!(manager.switch_to(&contexts[1]), PcidSwitch::Flush(pcid(3)));
+        assert_eq!(manager.switch_to(&contexts[0]), PcidSwitch::Keep(pcid(1)));
+
+        // removed address spaces free their PCID first
+        manager.remove(&contexts[5]);
+        let context = TlbContext::new();
+        assert_eq!(manager.switch_to(&context), PcidSwitch::Flush(pcid(6)));
+    }
+}
@@ -0,0 +1,448 @@
+//! Flushing the TLBs of other CPUs through inter-processor interrupts.
+//!
+//! The functions of the [`tlb`](super) module only flush the TLB of the current CPU. When a
+//! mapping that other CPUs might have cached is changed, their TLBs need to be flushed too
+//! before the old frame can be reused. This is called a TLB shootdown: The initiating CPU
+//! records the invalidations in a shared [`Shootdown`], sends an inter-processor interrupt
+//! (IPI) to the other CPUs, and waits until all of them acknowledged that they flushed their
+//! TLBs.
+//!
+//! Sending IPIs depends on the interrupt controller, so it is abstracted through the
+//! [`IpiSender`] trait. The interrupt handler of the IPI must call [`Shootdown::handle`].
+//!
+//! ```ignore
+//! static SHOOTDOWN: Shootdown = Shootdown::new();
+//!
+//! // on the initiating CPU
+//! let mut batch = FlushBatch::default();
+//! let (frame, flush) = mapper.unmap(page)?;
+//! batch.add(flush);
+//! SHOOTDOWN.shootdown(batch, &cpus_using_address_space, current_cpu, &mut ipi_sender);
+//! // no CPU has the old mapping cached anymore, so the frame can be reused
+//!
+//! // in the IPI handler of the other CPUs
+//! SHOOTDOWN.handle(current_cpu);
+//! ```
+
+use crate::structures::paging::mapper::FlushBatch;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// The number of 64-bit words of a [`CpuSet`].
+const WORDS: usize = CpuSet::MAX_CPUS / 64;
+
+/// A set of CPUs, identified by indices below [`CpuSet::MAX_CPUS`].
+///
+/// The indices are chosen by the kernel, e.g. the APIC IDs of the CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct CpuSet {
+    bits: [u64; WORDS],
+}
+
+impl CpuSet {
+    /// The maximum number of CPUs that a set can contain.
+    pub const MAX_CPUS: usize = 256;
+
+    /// Creates an empty set.
+    #[inline]
+    pub const fn new() -> Self {
+        CpuSet { bits: [0; WORDS] }
+    }
+
+    /// Creates a set that contains the CPUs `0..count`.
+    ///
+    /// Panics if `count` is greater than [`MAX_CPUS`](Self::MAX_CPUS).
+    #[inline]
+    pub fn first(count: usize) -> Self {
+        assert!(count <= Self::MAX_CPUS, "count must be <= MAX_CPUS");
+        let mut set = Self::new();
+        for cpu in 0..count {
+            set.insert(cpu);
+        }
+        set
+    }
+
+    /// Adds the given CPU to the set.
+    ///
+    /// Panics if `cpu` is not below [`MAX_CPUS`](Self::MAX_CPUS).
+    #[inline]
+    pub fn insert(&mut self, cpu: usize) {
+        let (word, bit) = Self::position(cpu);
+        self.bits[word] |= bit;
+    }
+
+    /// Removes the given CPU from the set.
+    ///
+    /// Panics if `cpu` is not below [`MAX_CPUS`](Self::MAX_CPUS).
+    #[inline]
+    pub fn remove(&mut self, cpu: usize) {
+        let (word, bit) = Self::position(cpu);
+        self.bits[word] &= !bit;
+    }
+
+    /// Returns whether the set contains the given CPU.
+    ///
+    /// Returns `false` if `cpu` is not below [`MAX_CPUS`](Self::MAX_CPUS).
+    #[inline]
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < Self::MAX_CPUS && {
+            let (word, bit) = Self::position(cpu);
+            self.bits[word] & bit != 0
+        }
+    }
+
+    /// Returns whether the set contains no CPUs.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&word| word == 0)
+    }
+
+    /// Returns an iterator over the CPUs of the set in ascending order.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..Self::MAX_CPUS).filter(move |&cpu| self.contains(cpu))
+    }
+
+    #[inline]
+    fn position(cpu: usize) -> (usize, u64) {
+        assert!(cpu < Self::MAX_CPUS, "cpu index must be < MAX_CPUS");
+        (cpu / 64, 1 << (cpu % 64))
+    }
+}
+
+/// Sends the inter-processor interrupts of a TLB shootdown.
+///
+/// This trait is implemented by the kernel, typically through the local APIC. The interrupt
+/// handler of the sent interrupt must call [`Shootdown::handle`].
+pub trait IpiSender {
+    /// Sends the shootdown IPI to all CPUs of the given set.
+    ///
+    /// The set never contains the CPU that initiated the shootdown.
+    fn send_ipi(&mut self, targets: &CpuSet);
+}
+
+/// The shared state of TLB shootdowns, which is usually stored in a `static`.
+///
+/// Only one shootdown is active at a time. CPUs that start a shootdown while another one is
+/// active wait until it is completed, but still handle requests of the active shootdown, so
+/// that two CPUs that start a shootdown at the same time don't wait for each other forever.
+#[derive(Debug)]
+pub struct Shootdown {
+    /// Whether a shootdown is active.
+    locked: AtomicBool,
+    /// The CPUs that haven't acknowledged the active shootdown yet.
+    pending: [AtomicU64; WORDS],
+    /// The invalidations of the active shootdown.
+    ///
+    /// This is only written while `locked` is set and `pending` is empty.
+    batch: UnsafeCell<FlushBatch>,
+}
+
+// `batch` is only read by CPUs that are in `pending` and only written while `pending` is empty
+unsafe impl Sync for Shootdown {}
+
+impl Shootdown {
+    /// Creates the state for TLB shootdowns without an active shootdown.
+    #[inline]
+    pub const fn new() -> Self {
+        Shootdown {
+            locked: AtomicBool::new(false),
+            pending: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            batch: UnsafeCell::new(FlushBatch::new(0)),
+        }
+    }
+
+    /// Flushes the given batch on all CPUs of `targets` and waits until they are done.
+    ///
+    /// If `targets` contains the current CPU `current`, the batch is flushed on the current CPU
+    /// directly. All other CPUs of `targets` are interrupted through `ipi_sender`. This
+    /// function returns after all of them called [`handle`](Self::handle).
+    ///
+    /// CPUs that have interrupts disabled delay the shootdown until they enable them again.
+    /// Since the current CPU is not interrupted, this function can be called with interrupts
+    /// disabled.
+    pub fn shootdown<I>(
+        &self,
+        batch: FlushBatch,
+        targets: &CpuSet,
+        current: usize,
+        ipi_sender: &mut I,
+    ) where
+        I: IpiSender + ?Sized,
+    {
+        self.shootdown_with(batch, targets, current, ipi_sender, FlushBatch::flush_local)
+    }
+
+    /// Implements [`shootdown`](Self::shootdown), with `flush` executing the invalidations
+    /// of a batch on the current CPU.
+    fn shootdown_with<I, F>(
+        &self,
+        batch: FlushBatch,
+        targets: &CpuSet,
+        current: usize,
+        ipi_sender: &mut I,
+        flush: F,
+    ) where
+        I: IpiSender + ?Sized,
+        F: Fn(&FlushBatch),
+    {
+        let mut remote = *targets;
+        if current < CpuSet::MAX_CPUS {
+            remote.remove(current);
+        }
+        if targets.contains(current) {
+            flush(&batch);
+        }
+        if remote.is_empty() || batch.is_empty() {
+            batch.ignore();
+            return;
+        }
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // the active shootdown might wait for us
+            self.handle_with(current, &flush);
+            core::hint::spin_loop();
+        }
+
+        // Safety: we hold the lock and `pending` is empty, so no other CPU reads the batch
+        unsafe { *self.batch.get() = batch };
+        for (pending, &bits) in self.pending.iter().zip(remote.bits.iter()) {
+            pending.store(bits, Ordering::Release);
+        }
+
+        ipi_sender.send_ipi(&remote);
+
+        while self
+            .pending
+            .iter()
+            .any(|pending| pending.load(Ordering::Acquire) != 0)
+        {
+            core::hint::spin_loop();
+        }
+        self.locked.store(false, Ordering::Release);
+    }
+
+    /// Handles the shootdown IPI on the given CPU.
+    ///
+    /// This function must be called from the interrupt handler of the IPI. If the active
+    /// shootdown targets the CPU, the TLB of the CPU is flushed and the shootdown is
+    /// acknowledged. Returns whether the TLB was flushed.
+    ///
+    /// Calling this function when no shootdown targets the CPU, e.g. for spurious interrupts,
+    /// does nothing.
+    pub fn handle(&self, cpu: usize) -> bool {
+        self.handle_with(cpu, FlushBatch::flush_local)
+    }
+
+    /// Implements [`handle`](Self::handle), with `flush` executing the invalidations of the
+    /// active batch.
+    fn handle_with<F>(&self, cpu: usize, flush: F) -> bool
+    where
+        F: Fn(&FlushBatch),
+    {
+        if cpu >= CpuSet::MAX_CPUS {
+            return false;
+        }
+        let (word, bit) = CpuSet::position(cpu);
+        if self.pending[word].load(Ordering::Acquire) & bit == 0 {
+            return false;
+        }
+
+        // Safety: the batch isn't modified while our bit in `pending` is set
+        flush(unsafe { &*self.batch.get() });
+        self.pending[word].fetch_and(!bit, Ordering::Release);
+        true
+    }
+}
+
+impl Default for Shootdown {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{mapper::MapperFlush, Page, Size4KiB};
+    use crate::VirtAddr;
+    use core::sync::atomic::AtomicUsize;
+
+    /// Handles the IPI synchronously on every target, like CPUs that have interrupts enabled.
+    struct SyncIpis<'a> {
+        shootdown: &'a Shootdown,
+        sent: Vec<CpuSet>,
+        flushed: &'a AtomicUsize,
+    }
+
+    impl IpiSender for SyncIpis<'_> {
+        fn send_ipi(&mut self, targets: &CpuSet) {
+            self.sent.push(*targets);
+            for cpu in targets.iter() {
+                assert!(self.shootdown.handle_with(cpu, |batch| {
+                    assert_eq!(batch.pages(), 1);
+                    self.flushed.fetch_add(1, Ordering::Relaxed);
+                }));
+                // acknowledged already
+                assert!(!self.shootdown.handle_with(cpu, |_| panic!()));
+            }
+        }
+    }
+
+    fn batch() -> FlushBatch {
+        let mut batch = FlushBatch::default();
+        batch.add(MapperFlush::<Size4KiB>::new(Page::containing_address(
+            VirtAddr::new(0x1000),
+        )));
+        batch
+    }
+
+    #[test]
+    fn cpu_set() {
+        let mut set = CpuSet::new();
+        assert!(set.is_empty());
+        for &cpu in &[0, 63, 64, 127, 128, 255] {
+            set.insert(cpu);
+            assert!(set.contains(cpu));
+        }
+        assert_eq!(set.bits, [(1 << 63) | 1, (1 << 63) | 1, 1, 1 << 63]);
+        assert!(!set.contains(1));
+        assert!(!set.contains(CpuSet::MAX_CPUS));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 63, 64, 127, 128, 255]);
+
+        set.remove(63);
+        set.remove(64);
+        // removing a CPU that isn't part of the set does nothing
+        set.remove(65);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 127, 128, 255]);
+        for &cpu in &[0, 127, 128, 255] {
+            set.remove(cpu);
+        }
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn cpu_set_first() {
+        assert!(CpuSet::first(0).is_empty());
+        assert_eq!(CpuSet::first(65).bits, [u64::MAX, 1, 0, 0]);
+        assert_eq!(CpuSet::first(CpuSet::MAX_CPUS).bits, [u64::MAX; WORDS]);
+        assert_eq!(CpuSet::first(3).iter().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn cpu_set_out_of_range() {
+        CpuSet::new().insert(CpuSet::MAX_CPUS);
+    }
+
+    #[test]
+    fn shootdown_excludes_current_cpu() {
+        let shootdown = Shootdown::new();
+        let flushed = AtomicUsize::new(0);
+        let mut ipis = SyncIpis {
+            shootdown: &shootdown,
+            sent: Vec::new(),
+            flushed: &flushed,
+        };
+        let mut targets = CpuSet::first(3);
+        targets.insert(200);
+        let local = AtomicUsize::new(0);
+        shootdown.shootdown_with(batch(), &targets, 1, &mut ipis, |_| {
+            local.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let mut remote = targets;
+        remote.remove(1);
+        assert_eq!(ipis.sent, [remote]);
+        assert_eq!(local.load(Ordering::Relaxed), 1);
+        assert_eq!(flushed.load(Ordering::Relaxed), 3);
+        assert!(!shootdown.locked.load(Ordering::Relaxed));
+        assert!(shootdown
+            .pending
+            .iter()
+            .all(|pending| pending.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn shootdown_without_remote_cpus() {
+        let shootdown = Shootdown::new();
+        let flushed = AtomicUsize::new(0);
+        let mut ipis = SyncIpis {
+            shootdown: &shootdown,
+            sent: Vec::new(),
+            flushed: &flushed,
+        };
+        let local = AtomicUsize::new(0);
+        let count = |_: &FlushBatch| {
+            local.fetch_add(1, Ordering::Relaxed);
+        };
+
+        // only the current CPU
+        let mut targets = CpuSet::new();
+        targets.insert(5);
+        shootdown.shootdown_with(batch(), &targets, 5, &mut ipis, count);
+        assert_eq!(local.load(Ordering::Relaxed), 1);
+        // nothing to flush
+        shootdown.shootdown_with(
+            FlushBatch::default(),
+            &CpuSet::first(4),
+            5,
+            &mut ipis,
+            count,
+        );
+        // the current CPU is not a target
+        shootdown.shootdown_with(batch(), &CpuSet::new(), 5, &mut ipis, count);
+        assert_eq!(local.load(Ordering::Relaxed), 1);
+        assert!(ipis.sent.is_empty());
+    }
+
+    #[test]
+    fn handle_without_shootdown() {
+        let shootdown = Shootdown::new();
+        assert!(!shootdown.handle_with(0, |_| panic!()));
+        assert!(!shootdown.handle_with(CpuSet::MAX_CPUS, |_| panic!()));
+    }
+
+    #[test]
+    fn shootdown_waits_for_acknowledgement() {
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+
+        struct Signal<'a>(&'a AtomicBool);
+
+        impl IpiSender for Signal<'_> {
+            fn send_ipi(&mut self, targets: &CpuSet) {
+                assert_eq!(targets.iter().collect::<Vec<_>>(), [1]);
+                self.0.store(true, Ordering::Release);
+            }
+        }
+
+        static SHOOTDOWN: Shootdown = Shootdown::new();
+        static SENT: AtomicBool = AtomicBool::new(false);
+        static FLUSHED: AtomicBool = AtomicBool::new(false);
+
+        let remote = thread::spawn(|| {
+            while !SENT.load(Ordering::Acquire) {
+                std::hint::spin_loop();
+            }
+            assert!(SHOOTDOWN.handle_with(1, |batch| {
+                assert_eq!(batch.pages(), 1);
+                FLUSHED.store(true, Ordering::Relaxed);
+            }));
+        });
+        SHOOTDOWN.shootdown_with(batch(), &CpuSet::first(2), 0, &mut Signal(&SENT), |_| {});
+        // the shootdown returns only after the remote CPU flushed its TLB
+        assert!(FLUSHED.load(Ordering::Relaxed));
+        remote.join().unwrap();
+    }
+}
@@ -12,6 +12,9 @@
 #![warn(missing_docs)]
 #![deny(missing_debug_implementations)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub use crate::addr::{align_down, align_up, PhysAddr, VirtAddr};
 
 /// Makes a function const only when `feature = "const_fn"` is enabled.
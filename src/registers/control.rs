@@ -122,6 +122,9 @@ bitflags! {
         const SUPERVISOR_MODE_ACCESS_PREVENTION = 1 << 21;
         /// Enables 4-level paging to associate each linear address with a protection key.
         const PROTECTION_KEY = 1 << 22;
+        /// Enables protection keys for supervisor-mode pages, which are controlled through the
+        /// PKRS register.
+        const PROTECTION_KEY_SUPERVISOR = 1 << 24;
     }
 }
 
@@ -284,6 +287,23 @@ mod x86_64 {
             Cr3::write_raw(frame, pcid.value());
         }
 
+        /// Write a new P4 table address into the CR3 register along with a PCID, without
+        /// invalidating the TLB entries of the PCID.
+        ///
+        /// This sets bit 63 of the written value, so the CPU keeps the cached translations of
+        /// the PCID. This is only correct if the TLB entries of the PCID still belong to the
+        /// page table hierarchy of `frame`, see
+        /// [`PcidManager`](crate::instructions::tlb::pcid::PcidManager).
+        ///
+        /// ## Safety
+        /// Changing the level 4 page table is unsafe, because it's possible to violate memory safety by
+        /// changing the page mapping. Stale TLB entries of the PCID can violate memory safety too.
+        /// [`Cr4Flags::PCID`] must be set before calling this method.
+        #[inline]
+        pub unsafe fn write_pcid_no_flush(frame: PhysFrame, pcid: Pcid) {
+            Cr3::write_value(frame.start_address().as_u64() | u64::from(pcid.value()) | 1 << 63);
+        }
+
         /// Write a new P4 table address into the CR3 register.
         ///
         /// ## Safety
@@ -292,8 +312,12 @@ mod x86_64 {
         #[inline]
         unsafe fn write_raw(frame: PhysFrame, val: u16) {
             let addr = frame.start_address();
-            let value = addr.as_u64() | val as u64;
+            Cr3::write_value(addr.as_u64() | val as u64);
+        }
 
+        /// Write the given value into the CR3 register.
+        #[inline]
+        unsafe fn write_value(value: u64) {
             #[cfg(feature = "inline_asm")]
             asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
 
@@ -2,6 +2,8 @@
 
 pub mod control;
 pub mod model_specific;
+pub mod mtrr;
+pub mod protection_key;
 pub mod rflags;
 pub mod xcontrol;
 
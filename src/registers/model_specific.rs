@@ -52,6 +52,14 @@ pub struct LStar;
 #[derive(Debug)]
 pub struct SFMask;
 
+/// IA32_PAT Model Specific Register, which contains the memory types of the page attribute
+/// table (PAT).
+///
+/// Page table entries select one of the 8 PAT entries through their PAT, PCD, and PWT bits, see
+/// [`PageTableEntry::pat_index`](crate::structures::paging::page_table::PageTableEntry::pat_index).
+#[derive(Debug)]
+pub struct Pat;
+
 impl Efer {
     /// The underlying model specific register.
     pub const MSR: Msr = Msr(0xC000_0080);
@@ -87,6 +95,69 @@ impl SFMask {
     pub const MSR: Msr = Msr(0xC000_0084);
 }
 
+impl Pat {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0x277);
+
+    /// The memory types of the PAT entries after power-up or reset.
+    pub const DEFAULT: [MemoryType; 8] = [
+        MemoryType::WriteBack,
+        MemoryType::WriteThrough,
+        MemoryType::UncacheableMinus,
+        MemoryType::Uncacheable,
+        MemoryType::WriteBack,
+        MemoryType::WriteThrough,
+        MemoryType::UncacheableMinus,
+        MemoryType::Uncacheable,
+    ];
+}
+
+/// A memory type, which controls how the processor caches accesses to a memory region.
+///
+/// The memory type of a page is determined by the PAT entry that its page table entry selects
+/// in combination with the memory type ranges (MTRRs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MemoryType {
+    /// Uncacheable (UC): Accesses are not cached and not reordered.
+    Uncacheable = 0,
+    /// Write combining (WC): Accesses are not cached, but writes may be combined in a buffer.
+    ///
+    /// This memory type is typically used for framebuffers.
+    WriteCombining = 1,
+    /// Write through (WT): Reads are cached, writes are cached and written to memory.
+    WriteThrough = 4,
+    /// Write protected (WP): Reads are cached, writes are not cached.
+    WriteProtected = 5,
+    /// Write back (WB): Reads and writes are cached.
+    WriteBack = 6,
+    /// Uncacheable minus (UC-): Like `Uncacheable`, but can be overridden by a write combining
+    /// MTRR. Only available through the PAT.
+    UncacheableMinus = 7,
+}
+
+impl MemoryType {
+    /// Creates a memory type from its encoding, returning `None` for reserved values.
+    #[inline]
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MemoryType::Uncacheable),
+            1 => Some(MemoryType::WriteCombining),
+            4 => Some(MemoryType::WriteThrough),
+            5 => Some(MemoryType::WriteProtected),
+            6 => Some(MemoryType::WriteBack),
+            7 => Some(MemoryType::UncacheableMinus),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoding of this memory type.
+    #[inline]
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
 bitflags! {
     /// Flags of the Extended Feature Enable Register.
     pub struct EferFlags: u64 {
@@ -407,6 +478,55 @@ mod x86_64 {
         }
     }
 
+    impl Pat {
+        /// Read the memory types of the 8 PAT entries.
+        #[inline]
+        pub fn read() -> [MemoryType; 8] {
+            let value = Self::read_raw();
+            let mut entries = [MemoryType::Uncacheable; 8];
+            for (i, entry) in entries.iter_mut().enumerate() {
+                let bits = value.get_bits(i * 8..i * 8 + 3) as u8;
+                *entry = MemoryType::from_u8(bits).expect("invalid memory type in PAT");
+            }
+            entries
+        }
+
+        /// Read the current raw PAT value.
+        #[inline]
+        pub fn read_raw() -> u64 {
+            unsafe { Self::MSR.read() }
+        }
+
+        /// Write the memory types of the 8 PAT entries.
+        ///
+        /// ## Safety
+        ///
+        /// Unsafe because changing the memory type of memory that is in use can break memory
+        /// safety, e.g. for memory mapped devices. The caller must also ensure that the PAT is
+        /// the same on all processors and that the caches and TLBs are flushed as described in
+        /// the Intel SDM.
+        #[inline]
+        pub unsafe fn write(entries: [MemoryType; 8]) {
+            let mut value = 0;
+            for (i, entry) in entries.iter().enumerate() {
+                value.set_bits(i * 8..i * 8 + 8, u64::from(entry.as_u8()));
+            }
+            Self::write_raw(value);
+        }
+
+        /// Write a raw value to the PAT register.
+        ///
+        /// ## Safety
+        ///
+        /// Unsafe for the same reasons as [`Pat::write`]. Also, writing reserved memory types
+        /// causes a general protection fault.
+        #[inline]
+        pub unsafe fn write_raw(value: u64) {
+            let mut msr = Self::MSR;
+            msr.write(value);
+        }
+    }
+
     impl SFMask {
         /// Read to the SFMask register.
         /// The SFMASK register is used to specify which RFLAGS bits
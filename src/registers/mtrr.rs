@@ -0,0 +1,653 @@
+//! Access to the memory type range registers (MTRRs).
+//!
+//! The MTRRs assign memory types to ranges of physical memory. Together with the memory type
+//! that a page table entry selects through the PAT, they determine the effective memory type of
+//! a memory access, see [`MtrrConfig::effective_memory_type`].
+
+use crate::registers::model_specific::{MemoryType, Msr};
+use crate::PhysAddr;
+use bitflags::bitflags;
+
+/// IA32_MTRRCAP Model Specific Register, which describes the supported MTRR features.
+#[derive(Debug)]
+pub struct MtrrCap;
+
+/// IA32_MTRR_DEF_TYPE Model Specific Register, which enables the MTRRs and contains the
+/// memory type of physical memory that is not covered by any MTRR.
+#[derive(Debug)]
+pub struct MtrrDefType;
+
+impl MtrrCap {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr::new(0xFE);
+}
+
+impl MtrrDefType {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr::new(0x2FF);
+}
+
+bitflags! {
+    /// Flags of the IA32_MTRRCAP register.
+    pub struct MtrrCapFlags: u64 {
+        /// The fixed range MTRRs are supported.
+        const FIXED_RANGE = 1 << 8;
+        /// The write combining memory type is supported.
+        const WRITE_COMBINING = 1 << 10;
+        /// The system management range registers are supported.
+        const SMRR = 1 << 11;
+    }
+}
+
+bitflags! {
+    /// Flags of the IA32_MTRR_DEF_TYPE register.
+    pub struct MtrrDefTypeFlags: u64 {
+        /// Enables the fixed range MTRRs. Has no effect if `ENABLE` is not set.
+        const FIXED_RANGE_ENABLE = 1 << 10;
+        /// Enables the MTRRs. If not set, all physical memory is uncacheable.
+        const ENABLE = 1 << 11;
+    }
+}
+
+/// One of the 11 fixed range MTRRs, which cover the first MiB of physical memory.
+///
+/// Each fixed range MTRR contains the memory types of 8 consecutive ranges of the same size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FixedMtrr {
+    msr: u32,
+    start: u64,
+    range_size: u64,
+}
+
+impl FixedMtrr {
+    /// The number of ranges in each fixed range MTRR.
+    pub const RANGES: usize = 8;
+
+    /// The end of the memory that is covered by the fixed range MTRRs.
+    pub const END: u64 = 0x10_0000;
+
+    /// All fixed range MTRRs, ordered by their start address.
+    pub const ALL: [FixedMtrr; 11] = [
+        FixedMtrr::new(0x250, 0x0_0000, 0x1_0000),
+        FixedMtrr::new(0x258, 0x8_0000, 0x4000),
+        FixedMtrr::new(0x259, 0xA_0000, 0x4000),
+        FixedMtrr::new(0x268, 0xC_0000, 0x1000),
+        FixedMtrr::new(0x269, 0xC_8000, 0x1000),
+        FixedMtrr::new(0x26A, 0xD_0000, 0x1000),
+        FixedMtrr::new(0x26B, 0xD_8000, 0x1000),
+        FixedMtrr::new(0x26C, 0xE_0000, 0x1000),
+        FixedMtrr::new(0x26D, 0xE_8000, 0x1000),
+        FixedMtrr::new(0x26E, 0xF_0000, 0x1000),
+        FixedMtrr::new(0x26F, 0xF_8000, 0x1000),
+    ];
+
+    const fn new(msr: u32, start: u64, range_size: u64) -> Self {
+        FixedMtrr {
+            msr,
+            start,
+            range_size,
+        }
+    }
+
+    /// Returns the fixed range MTRR that covers the given address, together with the index of
+    /// the range that contains it.
+    ///
+    /// Returns `None` if the address is not in the first MiB of physical memory.
+    #[inline]
+    pub fn containing(addr: PhysAddr) -> Option<(FixedMtrr, usize)> {
+        let addr = addr.as_u64();
+        Self::ALL
+            .iter()
+            .rev()
+            .find(|mtrr| mtrr.start <= addr && addr < Self::END)
+            .map(|&mtrr| (mtrr, ((addr - mtrr.start) / mtrr.range_size) as usize))
+    }
+
+    /// Returns the underlying model specific register.
+    #[inline]
+    pub const fn msr(&self) -> Msr {
+        Msr::new(self.msr)
+    }
+
+    /// Returns the start address of the first range.
+    #[inline]
+    pub fn start_address(&self) -> PhysAddr {
+        PhysAddr::new(self.start)
+    }
+
+    /// Returns the size of each of the 8 ranges.
+    #[inline]
+    pub const fn range_size(&self) -> u64 {
+        self.range_size
+    }
+
+    /// Decodes the memory types of the 8 ranges from a raw register value.
+    ///
+    /// Reserved encodings are treated as `Uncacheable`.
+    #[inline]
+    pub fn decode(value: u64) -> [MemoryType; 8] {
+        let mut types = [MemoryType::Uncacheable; 8];
+        for (i, memory_type) in types.iter_mut().enumerate() {
+            *memory_type = memory_type_from_bits(value >> (i * 8));
+        }
+        types
+    }
+
+    /// Encodes the memory types of the 8 ranges into a raw register value.
+    #[inline]
+    pub fn encode(types: [MemoryType; 8]) -> u64 {
+        types.iter().enumerate().fold(0, |value, (i, memory_type)| {
+            value | u64::from(memory_type.as_u8()) << (i * 8)
+        })
+    }
+}
+
+/// A pair of IA32_MTRR_PHYSBASEn and IA32_MTRR_PHYSMASKn Model Specific Registers, which
+/// describe a variable memory range.
+///
+/// The number of supported variable range MTRRs can be read from [`MtrrCap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VariableMtrr(u8);
+
+impl VariableMtrr {
+    /// Creates the variable range MTRR pair with the given index.
+    #[inline]
+    pub const fn new(index: u8) -> Self {
+        VariableMtrr(index)
+    }
+
+    /// Returns the index of this variable range MTRR pair.
+    #[inline]
+    pub const fn index(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns the IA32_MTRR_PHYSBASEn register.
+    #[inline]
+    pub const fn base_msr(&self) -> Msr {
+        Msr::new(0x200 + 2 * self.0 as u32)
+    }
+
+    /// Returns the IA32_MTRR_PHYSMASKn register.
+    #[inline]
+    pub const fn mask_msr(&self) -> Msr {
+        Msr::new(0x201 + 2 * self.0 as u32)
+    }
+}
+
+/// The contents of a variable range MTRR pair.
+///
+/// An address is in the range if `addr & mask == base & mask`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariableMtrrRange {
+    /// The base address of the range.
+    pub base: PhysAddr,
+    /// The mask that selects the address bits that must match the base address.
+    pub mask: u64,
+    /// The memory type of the range.
+    pub memory_type: MemoryType,
+    /// Whether the range is enabled.
+    pub valid: bool,
+}
+
+impl VariableMtrrRange {
+    /// The bits of the PHYSBASE and PHYSMASK registers that contain an address.
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+    /// The valid bit of the PHYSMASK register.
+    const VALID: u64 = 1 << 11;
+
+    /// Decodes the raw values of the PHYSBASE and PHYSMASK registers.
+    ///
+    /// Reserved memory type encodings are treated as `Uncacheable`.
+    #[inline]
+    pub fn decode(base: u64, mask: u64) -> Self {
+        VariableMtrrRange {
+            base: PhysAddr::new(base & Self::ADDRESS_MASK),
+            mask: mask & Self::ADDRESS_MASK,
+            memory_type: memory_type_from_bits(base),
+            valid: mask & Self::VALID != 0,
+        }
+    }
+
+    /// Encodes this range into raw values for the PHYSBASE and PHYSMASK registers.
+    #[inline]
+    pub fn encode(&self) -> (u64, u64) {
+        let base = (self.base.as_u64() & Self::ADDRESS_MASK) | u64::from(self.memory_type.as_u8());
+        let mut mask = self.mask & Self::ADDRESS_MASK;
+        if self.valid {
+            mask |= Self::VALID;
+        }
+        (base, mask)
+    }
+
+    /// Returns whether the range is enabled and contains the given address.
+    #[inline]
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        self.valid && addr.as_u64() & self.mask == self.base.as_u64() & self.mask
+    }
+}
+
+/// Decodes the memory type in the lowest byte of `value`, treating reserved encodings and the
+/// PAT only `UncacheableMinus` type as `Uncacheable`.
+fn memory_type_from_bits(value: u64) -> MemoryType {
+    match MemoryType::from_u8(value as u8) {
+        Some(MemoryType::UncacheableMinus) | None => MemoryType::Uncacheable,
+        Some(memory_type) => memory_type,
+    }
+}
+
+/// A snapshot of the MTRR configuration, used for determining the memory type of physical
+/// addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct MtrrConfig<'a> {
+    /// The flags of the IA32_MTRR_DEF_TYPE register.
+    pub flags: MtrrDefTypeFlags,
+    /// The memory type of physical memory that is not covered by any MTRR.
+    pub default_type: MemoryType,
+    /// The memory types of the fixed ranges, in the order of [`FixedMtrr::ALL`].
+    ///
+    /// Ignored if the `FIXED_RANGE_ENABLE` flag is not set.
+    pub fixed: [[MemoryType; 8]; 11],
+    /// The variable ranges.
+    pub variable: &'a [VariableMtrrRange],
+}
+
+impl<'a> MtrrConfig<'a> {
+    /// Returns the memory type that the MTRRs assign to the given physical address.
+    ///
+    /// If the address is covered by multiple variable ranges, uncacheable takes precedence over
+    /// all other memory types and write through takes precedence over write back. Returns
+    /// `None` for all other combinations of different memory types, for which the behavior of
+    /// the processor is undefined.
+    pub fn memory_type(&self, addr: PhysAddr) -> Option<MemoryType> {
+        if !self.flags.contains(MtrrDefTypeFlags::ENABLE) {
+            return Some(MemoryType::Uncacheable);
+        }
+
+        if self.flags.contains(MtrrDefTypeFlags::FIXED_RANGE_ENABLE) {
+            if let Some((mtrr, range)) = FixedMtrr::containing(addr) {
+                let index = FixedMtrr::ALL.iter().position(|m| *m == mtrr).unwrap();
+                return Some(self.fixed[index][range]);
+            }
+        }
+
+        // a later uncacheable range still takes precedence over an earlier conflict
+        let mut result: Option<MemoryType> = None;
+        let mut conflict = false;
+        for range in self.variable.iter().filter(|range| range.contains(addr)) {
+            match (result, range.memory_type) {
+                (_, MemoryType::Uncacheable) => return Some(MemoryType::Uncacheable),
+                (None, memory_type) => result = Some(memory_type),
+                (Some(a), b) if a == b => {}
+                (Some(MemoryType::WriteThrough), MemoryType::WriteBack)
+                | (Some(MemoryType::WriteBack), MemoryType::WriteThrough) => {
+                    result = Some(MemoryType::WriteThrough)
+                }
+                _ => conflict = true,
+            }
+        }
+        if conflict {
+            return None;
+        }
+        Some(result.unwrap_or(self.default_type))
+    }
+
+    /// Returns the effective memory type of an access to the given physical address through a
+    /// page whose page table entry selects the given PAT memory type.
+    ///
+    /// The PAT memory type of a page can be determined through
+    /// [`PageTableEntry::pat_index`](crate::structures::paging::page_table::PageTableEntry::pat_index)
+    /// and [`Pat::read`](crate::registers::model_specific::Pat).
+    ///
+    /// Returns `None` if the MTRR memory type is undefined, see [`memory_type`](Self::memory_type).
+    #[inline]
+    pub fn effective_memory_type(
+        &self,
+        addr: PhysAddr,
+        pat_type: MemoryType,
+    ) -> Option<MemoryType> {
+        self.memory_type(addr)
+            .map(|mtrr_type| effective_memory_type(mtrr_type, pat_type))
+    }
+}
+
+/// Combines the memory type of the MTRRs with the memory type of the PAT to the effective
+/// memory type, as described in the "Effective Page-Level Memory Types" table of the Intel SDM.
+///
+/// An `UncacheableMinus` MTRR type is invalid and treated as `Uncacheable`.
+pub fn effective_memory_type(mtrr_type: MemoryType, pat_type: MemoryType) -> MemoryType {
+    use MemoryType::*;
+
+    match (mtrr_type, pat_type) {
+        (_, Uncacheable) => Uncacheable,
+        (_, WriteCombining) => WriteCombining,
+        (Uncacheable, _) | (UncacheableMinus, _) => Uncacheable,
+        (WriteCombining, UncacheableMinus) | (WriteCombining, WriteBack) => WriteCombining,
+        (WriteCombining, _) => Uncacheable,
+        (WriteProtected, UncacheableMinus) => WriteCombining,
+        (_, UncacheableMinus) => Uncacheable,
+        (WriteProtected, WriteBack) => WriteProtected,
+        (_, WriteBack) => mtrr_type,
+        (_, WriteThrough) => WriteThrough,
+        (_, WriteProtected) => WriteProtected,
+    }
+}
+
+#[cfg(feature = "instructions")]
+mod x86_64 {
+    use super::*;
+
+    impl MtrrCap {
+        /// Read the supported MTRR features.
+        #[inline]
+        pub fn read() -> MtrrCapFlags {
+            MtrrCapFlags::from_bits_truncate(Self::read_raw())
+        }
+
+        /// Read the number of supported variable range MTRRs.
+        #[inline]
+        pub fn variable_count() -> u8 {
+            Self::read_raw() as u8
+        }
+
+        /// Read the raw IA32_MTRRCAP value.
+        #[inline]
+        pub fn read_raw() -> u64 {
+            unsafe { Self::MSR.read() }
+        }
+    }
+
+    impl MtrrDefType {
+        /// Read the flags and the default memory type.
+        #[inline]
+        pub fn read() -> (MtrrDefTypeFlags, MemoryType) {
+            let value = Self::read_raw();
+            (
+                MtrrDefTypeFlags::from_bits_truncate(value),
+                memory_type_from_bits(value),
+            )
+        }
+
+        /// Read the raw IA32_MTRR_DEF_TYPE value.
+        #[inline]
+        pub fn read_raw() -> u64 {
+            unsafe { Self::MSR.read() }
+        }
+
+        /// Write the flags and the default memory type.
+        ///
+        /// ## Safety
+        ///
+        /// Unsafe because changing the memory type of memory that is in use can break memory
+        /// safety. The caller must follow the MTRR update procedure of the Intel SDM, which
+        /// includes disabling the caches and flushing the TLBs on all processors.
+        #[inline]
+        pub unsafe fn write(flags: MtrrDefTypeFlags, default_type: MemoryType) {
+            Self::write_raw(flags.bits() | u64::from(default_type.as_u8()));
+        }
+
+        /// Write a raw IA32_MTRR_DEF_TYPE value.
+        ///
+        /// ## Safety
+        ///
+        /// Unsafe for the same reasons as [`MtrrDefType::write`].
+        #[inline]
+        pub unsafe fn write_raw(value: u64) {
+            let mut msr = Self::MSR;
+            msr.write(value);
+        }
+    }
+
+    impl FixedMtrr {
+        /// Read the memory types of the 8 ranges.
+        #[inline]
+        pub fn read(&self) -> [MemoryType; 8] {
+            Self::decode(unsafe { self.msr().read() })
+        }
+
+        /// Write the memory types of the 8 ranges.
+        ///
+        /// ## Safety
+        ///
+        /// Unsafe for the same reasons as [`MtrrDefType::write`].
+        #[inline]
+        pub unsafe fn write(&self, types: [MemoryType; 8]) {
+            self.msr().write(Self::encode(types));
+        }
+    }
+
+    impl VariableMtrr {
+        /// Read the range of this MTRR pair.
+        #[inline]
+        pub fn read(&self) -> VariableMtrrRange {
+            unsafe { VariableMtrrRange::decode(self.base_msr().read(), self.mask_msr().read()) }
+        }
+
+        /// Write the range of this MTRR pair.
+        ///
+        /// ## Safety
+        ///
+        /// Unsafe for the same reasons as [`MtrrDefType::write`].
+        #[inline]
+        pub unsafe fn write(&self, range: VariableMtrrRange) {
+            let (base, mask) = range.encode();
+            self.base_msr().write(base);
+            self.mask_msr().write(mask);
+        }
+    }
+
+    impl<'a> MtrrConfig<'a> {
+        /// Reads the current MTRR configuration.
+        ///
+        /// The variable ranges are read into the given buffer, which must be large enough for
+        /// all variable range MTRRs reported by [`MtrrCap::variable_count`].
+        ///
+        /// ## Panics
+        ///
+        /// Panics if the buffer is too small.
+        pub fn read(buffer: &'a mut [VariableMtrrRange]) -> Self {
+            let cap = MtrrCap::read();
+            let (mut flags, default_type) = MtrrDefType::read();
+            if !cap.contains(MtrrCapFlags::FIXED_RANGE) {
+                flags.remove(MtrrDefTypeFlags::FIXED_RANGE_ENABLE);
+            }
+
+            let mut fixed = [[MemoryType::Uncacheable; 8]; 11];
+            if flags.contains(MtrrDefTypeFlags::FIXED_RANGE_ENABLE) {
+                for (types, mtrr) in fixed.iter_mut().zip(FixedMtrr::ALL.iter()) {
+                    *types = mtrr.read();
+                }
+            }
+
+            let count = usize::from(MtrrCap::variable_count());
+            assert!(
+                buffer.len() >= count,
+                "buffer too small for {} variable range MTRRs",
+                count
+            );
+            let variable = &mut buffer[..count];
+            for (i, range) in variable.iter_mut().enumerate() {
+                *range = VariableMtrr::new(i as u8).read();
+            }
+
+            MtrrConfig {
+                flags,
+                default_type,
+                fixed,
+                variable,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MemoryType::*;
+
+    fn range(base: u64, size: u64, memory_type: MemoryType) -> VariableMtrrRange {
+        VariableMtrrRange {
+            base: PhysAddr::new(base),
+            mask: !(size - 1) & 0x000f_ffff_ffff_f000,
+            memory_type,
+            valid: true,
+        }
+    }
+
+    #[test]
+    fn test_effective_memory_type() {
+        let pat = [
+            Uncacheable,
+            UncacheableMinus,
+            WriteCombining,
+            WriteThrough,
+            WriteBack,
+            WriteProtected,
+        ];
+        let table = [
+            (
+                Uncacheable,
+                [
+                    Uncacheable,
+                    Uncacheable,
+                    WriteCombining,
+                    Uncacheable,
+                    Uncacheable,
+                    Uncacheable,
+                ],
+            ),
+            (
+                WriteCombining,
+                [
+                    Uncacheable,
+                    WriteCombining,
+                    WriteCombining,
+                    Uncacheable,
+                    WriteCombining,
+                    Uncacheable,
+                ],
+            ),
+            (
+                WriteThrough,
+                [
+                    Uncacheable,
+                    Uncacheable,
+                    WriteCombining,
+                    WriteThrough,
+                    WriteThrough,
+                    WriteProtected,
+                ],
+            ),
+            (
+                WriteBack,
+                [
+                    Uncacheable,
+                    Uncacheable,
+                    WriteCombining,
+                    WriteThrough,
+                    WriteBack,
+                    WriteProtected,
+                ],
+            ),
+            (
+                WriteProtected,
+                [
+                    Uncacheable,
+                    WriteCombining,
+                    WriteCombining,
+                    WriteThrough,
+                    WriteProtected,
+                    WriteProtected,
+                ],
+            ),
+        ];
+        for &(mtrr_type, expected) in table.iter() {
+            for (&pat_type, &expected) in pat.iter().zip(expected.iter()) {
+                assert_eq!(
+                    effective_memory_type(mtrr_type, pat_type),
+                    expected,
+                    "MTRR {:?}, PAT {:?}",
+                    mtrr_type,
+                    pat_type
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_mtrr_memory_type() {
+        let variable = [
+            range(0, 0x8000_0000, WriteBack),
+            range(0x4000_0000, 0x1000_0000, WriteThrough),
+            range(0x6000_0000, 0x1000_0000, Uncacheable),
+            range(0x7000_0000, 0x1000_0000, WriteCombining),
+        ];
+        let mut fixed = [[WriteBack; 8]; 11];
+        fixed[2] = [Uncacheable; 8];
+        let mut config = MtrrConfig {
+            flags: MtrrDefTypeFlags::ENABLE | MtrrDefTypeFlags::FIXED_RANGE_ENABLE,
+            default_type: Uncacheable,
+            fixed,
+            variable: &variable,
+        };
+
+        let memory_type = |config: &MtrrConfig, addr| config.memory_type(PhysAddr::new(addr));
+        assert_eq!(memory_type(&config, 0x1000), Some(WriteBack));
+        assert_eq!(memory_type(&config, 0xB_8000), Some(Uncacheable));
+        assert_eq!(memory_type(&config, 0x1000_0000), Some(WriteBack));
+        assert_eq!(memory_type(&config, 0x4000_0000), Some(WriteThrough));
+        assert_eq!(memory_type(&config, 0x6000_0000), Some(Uncacheable));
+        assert_eq!(memory_type(&config, 0x7000_0000), None);
+        assert_eq!(memory_type(&config, 0x8000_0000), Some(Uncacheable));
+
+        config.flags = MtrrDefTypeFlags::ENABLE;
+        assert_eq!(memory_type(&config, 0xB_8000), Some(WriteBack));
+
+        // uncacheable wins over an earlier conflict, write through wins over write back
+        let variable = [
+            range(0, 0x1000_0000, WriteBack),
+            range(0, 0x1000_0000, WriteCombining),
+            range(0x800_0000, 0x800_0000, WriteThrough),
+            range(0x800_0000, 0x800_0000, WriteBack),
+            range(0, 0x400_0000, Uncacheable),
+        ];
+        config.variable = &variable;
+        assert_eq!(memory_type(&config, 0x1000), Some(Uncacheable));
+        assert_eq!(memory_type(&config, 0x400_0000), None);
+        config.variable = &variable[2..4];
+        assert_eq!(memory_type(&config, 0x800_0000), Some(WriteThrough));
+        assert_eq!(memory_type(&config, 0x1000), Some(Uncacheable));
+
+        config.flags = MtrrDefTypeFlags::empty();
+        assert_eq!(memory_type(&config, 0x1000), Some(Uncacheable));
+    }
+
+    #[test]
+    fn test_fixed_mtrr() {
+        let (mtrr, index) = FixedMtrr::containing(PhysAddr::new(0xB_8000)).unwrap();
+        assert_eq!(mtrr.msr, 0x259);
+        assert_eq!(index, 6);
+        let (mtrr, index) = FixedMtrr::containing(PhysAddr::new(0xF_FFFF)).unwrap();
+        assert_eq!(mtrr.msr, 0x26F);
+        assert_eq!(index, 7);
+        assert!(FixedMtrr::containing(PhysAddr::new(0x10_0000)).is_none());
+
+        let types = [
+            WriteBack,
+            Uncacheable,
+            WriteCombining,
+            WriteThrough,
+            WriteProtected,
+            WriteBack,
+            WriteBack,
+            Uncacheable,
+        ];
+        assert_eq!(FixedMtrr::decode(FixedMtrr::encode(types)), types);
+
+        let variable = range(0x4000_0000, 0x1000_0000, WriteCombining);
+        let (base, mask) = variable.encode();
+        assert_eq!(base, 0x4000_0001);
+        assert_eq!(mask, 0x000f_ffff_f000_0800);
+        assert_eq!(VariableMtrrRange::decode(base, mask), variable);
+    }
+}
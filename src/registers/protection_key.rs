@@ -0,0 +1,291 @@
+//! Access to the protection key rights registers PKRU and PKRS.
+//!
+//! Each page has a [`ProtectionKey`] in its page table entry. The access rights for the 16
+//! protection keys are stored in the PKRU register for user-mode pages and in the PKRS register
+//! for supervisor-mode pages. These rights restrict data accesses in addition to the access
+//! rights of the page table entries; instruction fetches are not affected.
+
+use crate::registers::model_specific::Msr;
+use crate::structures::paging::ProtectionKey;
+use core::fmt;
+
+/// The PKRU register, which contains the access rights for user-mode pages.
+///
+/// Protection keys for user-mode pages are enabled through the
+/// [`PROTECTION_KEY`](crate::registers::control::Cr4Flags::PROTECTION_KEY) flag of the CR4
+/// register.
+#[derive(Debug)]
+pub struct Pkru;
+
+/// The IA32_PKRS model specific register, which contains the access rights for
+/// supervisor-mode pages.
+///
+/// Protection keys for supervisor-mode pages are enabled through the
+/// [`PROTECTION_KEY_SUPERVISOR`](crate::registers::control::Cr4Flags::PROTECTION_KEY_SUPERVISOR)
+/// flag of the CR4 register.
+#[derive(Debug)]
+pub struct Pkrs;
+
+impl Pkrs {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr::new(0x6E1);
+}
+
+/// The access rights for all 16 protection keys, as stored in the PKRU and PKRS registers.
+///
+/// Each key has an access-disable (AD) bit and a write-disable (WD) bit. If the AD bit of a key
+/// is set, all data accesses to pages with that key are forbidden. If the WD bit of a key is
+/// set, writes to pages with that key are forbidden.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(transparent)]
+pub struct ProtectionKeyRights(u32);
+
+impl ProtectionKeyRights {
+    /// Creates a new value that allows all accesses for all keys.
+    #[inline]
+    pub const fn new() -> Self {
+        ProtectionKeyRights(0)
+    }
+
+    /// Creates a new value from the raw register bits.
+    #[inline]
+    pub const fn from_bits(bits: u32) -> Self {
+        ProtectionKeyRights(bits)
+    }
+
+    /// Returns the raw register bits.
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether all data accesses to pages with the given key are disabled.
+    #[inline]
+    pub fn access_disabled(self, key: ProtectionKey) -> bool {
+        self.0 & Self::access_disable_bit(key) != 0
+    }
+
+    /// Returns whether writes to pages with the given key are disabled.
+    #[inline]
+    pub fn write_disabled(self, key: ProtectionKey) -> bool {
+        self.0 & Self::write_disable_bit(key) != 0
+    }
+
+    /// Enables or disables all data accesses to pages with the given key.
+    #[inline]
+    pub fn set_access_disabled(&mut self, key: ProtectionKey, disabled: bool) {
+        self.set_bit(Self::access_disable_bit(key), disabled);
+    }
+
+    /// Enables or disables writes to pages with the given key.
+    #[inline]
+    pub fn set_write_disabled(&mut self, key: ProtectionKey, disabled: bool) {
+        self.set_bit(Self::write_disable_bit(key), disabled);
+    }
+
+    #[inline]
+    fn access_disable_bit(key: ProtectionKey) -> u32 {
+        1 << (2 * u32::from(key))
+    }
+
+    #[inline]
+    fn write_disable_bit(key: ProtectionKey) -> u32 {
+        1 << (2 * u32::from(key) + 1)
+    }
+
+    #[inline]
+    fn set_bit(&mut self, bit: u32, value: bool) {
+        if value {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
+}
+
+impl fmt::Debug for ProtectionKeyRights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_map();
+        for key in 0..ProtectionKey::COUNT {
+            let key = ProtectionKey::new(key);
+            let rights = match (self.access_disabled(key), self.write_disabled(key)) {
+                (false, false) => "RW",
+                (false, true) => "R",
+                (true, _) => "-",
+            };
+            f.entry(&u8::from(key), &rights);
+        }
+        f.finish()
+    }
+}
+
+#[cfg(feature = "instructions")]
+mod x86_64 {
+    use super::*;
+
+    impl Pkru {
+        /// Read the current access rights from the PKRU register.
+        #[inline]
+        pub fn read() -> ProtectionKeyRights {
+            ProtectionKeyRights::from_bits(Self::read_raw())
+        }
+
+        /// Read the current raw PKRU value using the `rdpkru` instruction.
+        #[inline]
+        pub fn read_raw() -> u32 {
+            #[cfg(feature = "inline_asm")]
+            unsafe {
+                let value: u32;
+                asm!(
+                    "rdpkru",
+                    in("ecx") 0,
+                    out("eax") value, out("edx") _,
+                    options(nomem, nostack, preserves_flags),
+                );
+                value
+            }
+
+            #[cfg(not(feature = "inline_asm"))]
+            unsafe {
+                crate::asm::x86_64_asm_rdpkru()
+            }
+        }
+
+        /// Write the given access rights to the PKRU register.
+        ///
+        /// ## Safety
+        ///
+        /// This function is unsafe because the caller must ensure that no memory that is still
+        /// accessed is made inaccessible.
+        #[inline]
+        pub unsafe fn write(rights: ProtectionKeyRights) {
+            Self::write_raw(rights.bits());
+        }
+
+        /// Write a raw value to the PKRU register using the `wrpkru` instruction.
+        ///
+        /// ## Safety
+        ///
+        /// This function is unsafe because the caller must ensure that no memory that is still
+        /// accessed is made inaccessible.
+        #[inline]
+        pub unsafe fn write_raw(value: u32) {
+            #[cfg(feature = "inline_asm")]
+            asm!(
+                "wrpkru",
+                in("eax") value, in("ecx") 0, in("edx") 0,
+                options(nostack, preserves_flags),
+            );
+
+            #[cfg(not(feature = "inline_asm"))]
+            crate::asm::x86_64_asm_wrpkru(value);
+        }
+    }
+
+    impl Pkrs {
+        /// Read the current access rights from the PKRS register.
+        #[inline]
+        pub fn read() -> ProtectionKeyRights {
+            ProtectionKeyRights::from_bits(Self::read_raw())
+        }
+
+        /// Read the current raw PKRS value.
+        ///
+        /// The upper 32 bits of the register are reserved.
+        #[inline]
+        pub fn read_raw() -> u32 {
+            unsafe { Self::MSR.read() as u32 }
+        }
+
+        /// Write the given access rights to the PKRS register.
+        ///
+        /// ## Safety
+        ///
+        /// This function is unsafe because the caller must ensure that no memory that is still
+        /// accessed is made inaccessible.
+        #[inline]
+        pub unsafe fn write(rights: ProtectionKeyRights) {
+            Self::write_raw(rights.bits());
+        }
+
+        /// Write a raw value to the PKRS register.
+        ///
+        /// ## Safety
+        ///
+        /// This function is unsafe because the caller must ensure that no memory that is still
+        /// accessed is made inaccessible.
+        #[inline]
+        pub unsafe fn write_raw(value: u32) {
+            let mut msr = Self::MSR;
+            msr.write(u64::from(value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{page_table::PageTableEntry, PageTableFlags};
+    use crate::PhysAddr;
+
+    #[test]
+    fn rights_bit_layout() {
+        let mut rights = ProtectionKeyRights::new();
+        rights.set_access_disabled(ProtectionKey::new(0), true);
+        assert_eq!(rights.bits(), 0b01);
+        rights.set_write_disabled(ProtectionKey::new(0), true);
+        assert_eq!(rights.bits(), 0b11);
+        rights.set_access_disabled(ProtectionKey::new(0), false);
+        assert_eq!(rights.bits(), 0b10);
+
+        rights.set_access_disabled(ProtectionKey::new(15), true);
+        rights.set_write_disabled(ProtectionKey::new(15), true);
+        assert_eq!(rights.bits(), 0xc000_0002);
+        rights.set_write_disabled(ProtectionKey::new(15), false);
+        assert_eq!(rights.bits(), 0x4000_0002);
+
+        let rights = ProtectionKeyRights::from_bits(0b1001 << 10);
+        assert!(rights.access_disabled(ProtectionKey::new(5)));
+        assert!(!rights.write_disabled(ProtectionKey::new(5)));
+        assert!(!rights.access_disabled(ProtectionKey::new(6)));
+        assert!(rights.write_disabled(ProtectionKey::new(6)));
+    }
+
+    #[test]
+    fn page_table_flags_key() {
+        let mut flags =
+            PageTableFlags::PRESENT | PageTableFlags::BIT_58 | PageTableFlags::NO_EXECUTE;
+        assert_eq!(flags.protection_key(), ProtectionKey::new(0));
+        flags.set_protection_key(ProtectionKey::new(0b1010));
+        assert_eq!(
+            flags,
+            PageTableFlags::PRESENT
+                | PageTableFlags::BIT_58
+                | PageTableFlags::BIT_60
+                | PageTableFlags::BIT_62
+                | PageTableFlags::NO_EXECUTE
+        );
+        assert_eq!(flags.protection_key(), ProtectionKey::new(0b1010));
+        flags.set_protection_key(ProtectionKey::new(15));
+        assert_eq!(flags.bits() >> 59, 0b1_1111);
+        flags.set_protection_key(ProtectionKey::new(0));
+        assert_eq!(
+            flags,
+            PageTableFlags::PRESENT | PageTableFlags::BIT_58 | PageTableFlags::NO_EXECUTE
+        );
+
+        // the address of an entry is not affected
+        let mut entry = PageTableEntry::new();
+        entry.set_addr(
+            PhysAddr::new(0x000f_ffff_ffff_f000),
+            PageTableFlags::PRESENT,
+        );
+        entry.set_protection_key(ProtectionKey::new(7));
+        assert_eq!(entry.protection_key(), ProtectionKey::new(7));
+        assert_eq!(entry.addr(), PhysAddr::new(0x000f_ffff_ffff_f000));
+        assert_eq!(
+            entry.flags() - PageTableFlags::PRESENT,
+            PageTableFlags::BIT_59 | PageTableFlags::BIT_60 | PageTableFlags::BIT_61
+        );
+    }
+}
@@ -915,6 +915,11 @@ bitflags! {
         /// If this flag is set, it indicates that the access that caused the page fault was an
         /// instruction fetch.
         const INSTRUCTION_FETCH = 1 << 4;
+
+        /// If this flag is set, the page fault was caused by a protection key violation, i.e.
+        /// the access rights of the page's protection key in the PKRU register (for user-mode
+        /// pages) or the PKRS register (for supervisor-mode pages) don't allow the access.
+        const PROTECTION_KEY = 1 << 5;
     }
 }
 
@@ -0,0 +1,367 @@
+use crate::structures::paging::{
+    frame::PhysFrameRange,
+    frame_alloc::{
+        ContiguousFrameAllocator, ContiguousFrameDeallocator, FrameAllocator, FrameDeallocator,
+        FrameStats,
+    },
+    PageSize, PhysFrame, Size4KiB,
+};
+use crate::{align_up, PhysAddr};
+
+/// A frame allocator that stores the state of each frame in a bitmap.
+///
+/// The allocator manages a contiguous region of physical memory, which may contain holes. The
+/// bitmap contains two bits per frame of the region: one that is set if the frame is used and
+/// one that is set if the frame was added, so that holes are never deallocated. It is stored in
+/// a buffer that is passed by the caller, see [`bitmap_words`](Self::bitmap_words) for the
+/// required size.
+#[derive(Debug)]
+pub struct BitmapFrameAllocator<'a> {
+    start: PhysFrame,
+    frame_count: u64,
+    /// The used bits of all frames, followed by the added bits of all frames.
+    bitmap: &'a mut [u64],
+    /// The number of frames that were added through `add_range`.
+    added: u64,
+    free: u64,
+    /// The index of the bitmap word at which the next search starts.
+    next: usize,
+}
+
+impl<'a> BitmapFrameAllocator<'a> {
+    /// Returns the number of `u64` words that the bitmap needs for the given number of frames.
+    #[inline]
+    pub const fn bitmap_words(frame_count: u64) -> usize {
+        2 * Self::words(frame_count)
+    }
+
+    /// Returns the number of `u64` words that are needed for one bit per frame.
+    const fn words(frame_count: u64) -> usize {
+        (frame_count / 64) as usize + (frame_count & 63 != 0) as usize
+    }
+
+    /// Creates a new allocator for the `frame_count` frames starting at `start`.
+    ///
+    /// All frames are marked as used initially, use [`add_range`](Self::add_range) to add the
+    /// usable memory. The previous content of `bitmap` is ignored. Panics if `bitmap` has less
+    /// than [`bitmap_words(frame_count)`](Self::bitmap_words) words.
+    pub fn new(start: PhysFrame, frame_count: u64, bitmap: &'a mut [u64]) -> Self {
+        assert!(
+            bitmap.len() >= Self::bitmap_words(frame_count),
+            "bitmap is too small for the frame count"
+        );
+        let words = Self::words(frame_count);
+        for word in bitmap[..words].iter_mut() {
+            *word = !0;
+        }
+        for word in bitmap[words..2 * words].iter_mut() {
+            *word = 0;
+        }
+        BitmapFrameAllocator {
+            start,
+            frame_count,
+            bitmap,
+            added: 0,
+            free: 0,
+            next: 0,
+        }
+    }
+
+    /// Marks all frames of the given range as free.
+    ///
+    /// Frames that were added before are skipped. Panics if the range is not contained in the
+    /// region of the allocator.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the frames are unused.
+    pub unsafe fn add_range(&mut self, range: PhysFrameRange) {
+        let words = Self::words(self.frame_count);
+        for frame in range {
+            let (word, bit) = self.position(frame);
+            if self.bitmap[words + word] & bit == 0 {
+                self.bitmap[words + word] |= bit;
+                self.bitmap[word] &= !bit;
+                self.added += 1;
+                self.free += 1;
+            }
+        }
+    }
+
+    /// Returns the number of frames of the region of the allocator, including holes.
+    #[inline]
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Returns the number of free and used frames.
+    ///
+    /// Holes in the region of the allocator are not counted as used frames.
+    #[inline]
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            free: self.free,
+            used: self.added - self.free,
+        }
+    }
+
+    /// Returns whether the frame with the given index is used.
+    fn is_used(&self, index: u64) -> bool {
+        self.bitmap[(index / 64) as usize] & (1 << (index % 64)) != 0
+    }
+
+    /// Returns the bitmap word and the bit of the given frame.
+    fn position(&self, frame: PhysFrame) -> (usize, u64) {
+        assert!(
+            frame >= self.start && frame - self.start < self.frame_count,
+            "frame is outside of the region of the allocator"
+        );
+        let index = frame - self.start;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+}
+
+unsafe impl<'a> FrameAllocator<Size4KiB> for BitmapFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = Self::words(self.frame_count);
+        for offset in 0..words {
+            let word = (self.next + offset) % words;
+            let bits = self.bitmap[word];
+            if bits != !0 {
+                let bit = (!bits).trailing_zeros();
+                self.bitmap[word] |= 1 << bit;
+                self.free -= 1;
+                self.next = word;
+                return Some(self.start + (word as u64 * 64 + u64::from(bit)));
+            }
+        }
+        None
+    }
+}
+
+impl<'a> FrameDeallocator<Size4KiB> for BitmapFrameAllocator<'a> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let (word, bit) = self.position(frame);
+        let words = Self::words(self.frame_count);
+        assert!(
+            self.bitmap[words + word] & bit != 0,
+            "frame was not added to the allocator"
+        );
+        assert!(self.bitmap[word] & bit != 0, "frame is not allocated");
+        self.bitmap[word] &= !bit;
+        self.free += 1;
+    }
+}
+
+unsafe impl<'a> ContiguousFrameAllocator<Size4KiB> for BitmapFrameAllocator<'a> {
+    fn allocate_contiguous(
+        &mut self,
+        count: u64,
+        align: u64,
+        limit: Option<PhysAddr>,
+    ) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 || count > self.free {
+            return None;
+        }
+        let start_addr = self.start.start_address().as_u64();
+        let align = align.max(Size4KiB::SIZE);
+        let end = match limit {
+            Some(limit) if limit.as_u64() <= start_addr => return None,
+            Some(limit) => ((limit.as_u64() - start_addr) / Size4KiB::SIZE).min(self.frame_count),
+            None => self.frame_count,
+        };
+        // the index of the first frame at or after `index` that is aligned
+        let aligned = |index: u64| {
+            let addr = align_up(start_addr + index * Size4KiB::SIZE, align);
+            (addr - start_addr) / Size4KiB::SIZE
+        };
+
+        let mut first = aligned(0);
+        while first + count <= end {
+            match (first..first + count).find(|&index| self.is_used(index)) {
+                Some(used) => first = aligned(used + 1),
+                None => {
+                    for index in first..first + count {
+                        self.bitmap[(index / 64) as usize] |= 1 << (index % 64);
+                    }
+                    self.free -= count;
+                    return Some(PhysFrame::range(
+                        self.start + first,
+                        self.start + (first + count),
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<'a> ContiguousFrameDeallocator<Size4KiB> for BitmapFrameAllocator<'a> {
+    unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(index: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(0x10_0000 + index * Size4KiB::SIZE))
+    }
+
+    fn allocator(frame_count: u64, bitmap: &mut [u64]) -> BitmapFrameAllocator<'_> {
+        BitmapFrameAllocator::new(frame(0), frame_count, bitmap)
+    }
+
+    #[test]
+    fn holes_are_skipped() {
+        let mut bitmap = [0; 2];
+        let mut allocator = allocator(64, &mut bitmap);
+        unsafe {
+            allocator.add_range(PhysFrame::range(frame(0), frame(2)));
+            allocator.add_range(PhysFrame::range(frame(4), frame(5)));
+            // adding frames twice doesn't count them twice
+            allocator.add_range(PhysFrame::range(frame(1), frame(3)));
+        }
+        assert_eq!(allocator.stats(), FrameStats { free: 4, used: 0 });
+
+        let frames: Vec<_> = (0..4)
+            .map(|_| allocator.allocate_frame().unwrap())
+            .collect();
+        assert_eq!(frames, [frame(0), frame(1), frame(2), frame(4)]);
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.stats(), FrameStats { free: 0, used: 4 });
+
+        unsafe { allocator.deallocate_frame(frame(1)) };
+        assert_eq!(allocator.stats(), FrameStats { free: 1, used: 3 });
+        assert_eq!(allocator.allocate_frame(), Some(frame(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "frame was not added to the allocator")]
+    fn deallocate_hole() {
+        let mut bitmap = [0; 2];
+        let mut allocator = allocator(64, &mut bitmap);
+        unsafe {
+            allocator.add_range(PhysFrame::range(frame(0), frame(2)));
+            allocator.deallocate_frame(frame(3));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "frame is not allocated")]
+    fn deallocate_free_frame() {
+        let mut bitmap = [0; 2];
+        let mut allocator = allocator(64, &mut bitmap);
+        unsafe {
+            allocator.add_range(PhysFrame::range(frame(0), frame(2)));
+            allocator.deallocate_frame(frame(1));
+        }
+    }
+
+    #[test]
+    fn partial_last_word() {
+        let mut bitmap = [0; 4];
+        assert_eq!(BitmapFrameAllocator::bitmap_words(70), 4);
+        let mut allocator = allocator(70, &mut bitmap);
+        unsafe { allocator.add_range(PhysFrame::range(frame(0), frame(70))) };
+        assert_eq!(allocator.stats(), FrameStats { free: 70, used: 0 });
+        for index in 0..70 {
+            assert_eq!(allocator.allocate_frame(), Some(frame(index)));
+        }
+        // the bits after the last frame are never allocated
+        assert_eq!(allocator.allocate_frame(), None);
+    }
+
+    #[test]
+    fn search_wraps_around() {
+        let mut bitmap = [0; 6];
+        let mut allocator = allocator(192, &mut bitmap);
+        unsafe { allocator.add_range(PhysFrame::range(frame(0), frame(192))) };
+        for index in 0..130 {
+            assert_eq!(allocator.allocate_frame(), Some(frame(index)));
+        }
+        assert_eq!(allocator.next, 2);
+
+        // the search continues in the last word before wrapping around
+        unsafe { allocator.deallocate_frame(frame(3)) };
+        assert_eq!(allocator.allocate_frame(), Some(frame(130)));
+        for index in 131..192 {
+            assert_eq!(allocator.allocate_frame(), Some(frame(index)));
+        }
+        assert_eq!(allocator.allocate_frame(), Some(frame(3)));
+        assert_eq!(allocator.next, 0);
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.stats(), FrameStats { free: 0, used: 192 });
+    }
+
+    #[test]
+    fn contiguous_alignment() {
+        let mut bitmap = [0; 4];
+        let mut allocator = allocator(128, &mut bitmap);
+        unsafe {
+            allocator.add_range(PhysFrame::range(frame(0), frame(9)));
+            allocator.add_range(PhysFrame::range(frame(10), frame(128)));
+        }
+        assert_eq!(allocator.allocate_frame(), Some(frame(0)));
+
+        // frames 1..4 are free, but not aligned to 16KiB
+        let range = allocator.allocate_contiguous(4, 0x4000, None).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(4), frame(8)));
+        // alignments below 4KiB are rounded up
+        let range = allocator.allocate_contiguous(2, 1, None).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(1), frame(3)));
+        // the search skips the hole and continues at the next aligned frame
+        let range = allocator.allocate_contiguous(8, 0x8000, None).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(16), frame(24)));
+        assert_eq!(
+            allocator.stats(),
+            FrameStats {
+                free: 112,
+                used: 15
+            }
+        );
+
+        unsafe { allocator.deallocate_contiguous(range) };
+        assert_eq!(allocator.stats(), FrameStats { free: 120, used: 7 });
+        assert_eq!(allocator.allocate_contiguous(0, 1, None), None);
+        assert_eq!(allocator.allocate_contiguous(121, 1, None), None);
+    }
+
+    #[test]
+    fn contiguous_limit() {
+        let mut bitmap = [0; 4];
+        let mut allocator = allocator(128, &mut bitmap);
+        unsafe {
+            allocator.add_range(PhysFrame::range(frame(0), frame(4)));
+            allocator.add_range(PhysFrame::range(frame(8), frame(128)));
+        }
+
+        // the range must end at or below the limit
+        let limit = frame(12).start_address();
+        assert_eq!(allocator.allocate_contiguous(5, 1, Some(limit)), None);
+        let range = allocator.allocate_contiguous(4, 1, Some(limit)).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(0), frame(4)));
+        let range = allocator.allocate_contiguous(4, 1, Some(limit)).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(8), frame(12)));
+        assert_eq!(allocator.allocate_contiguous(1, 1, Some(limit)), None);
+
+        // limits at or below the start of the region
+        assert_eq!(
+            allocator.allocate_contiguous(1, 1, Some(frame(0).start_address())),
+            None
+        );
+        // limits above the end of the region
+        let range = allocator
+            .allocate_contiguous(116, 1, Some(PhysAddr::new(1 << 40)))
+            .unwrap();
+        assert_eq!(range, PhysFrame::range(frame(12), frame(128)));
+    }
+}
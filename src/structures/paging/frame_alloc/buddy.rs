@@ -0,0 +1,498 @@
+use crate::structures::paging::{
+    frame::PhysFrameRange,
+    frame_alloc::{
+        ContiguousFrameAllocator, ContiguousFrameDeallocator, FrameAllocator, FrameDeallocator,
+        FrameStats,
+    },
+    PageSize, PhysFrame, Size1GiB, Size4KiB,
+};
+use crate::PhysAddr;
+
+/// The order of the largest blocks, which are 1GiB frames.
+const MAX_ORDER: usize = 18;
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// A buddy allocator for frames of all sizes.
+///
+/// The allocator manages blocks of 2^n 4KiB frames for n from 0 (4KiB) to 18 (1GiB). To
+/// allocate a block of a certain size, a larger free block is split in halves if necessary.
+/// When a block is deallocated, it is merged with its buddy, i.e. the other half of the block
+/// it was split from, if the buddy is free too.
+///
+/// The free blocks of each size are stored in a bitmap. The bitmaps are stored in a buffer that
+/// is passed by the caller, see [`storage_words`](Self::storage_words) for the required size.
+/// All blocks are aligned relative to the 1GiB aligned start of the region of the allocator,
+/// so they can be used as huge pages.
+#[derive(Debug)]
+pub struct BuddyFrameAllocator<'a> {
+    start: PhysAddr,
+    frame_count: u64,
+    storage: &'a mut [u64],
+    /// The index of the first word of the bitmap of each order in `storage`.
+    offsets: [usize; ORDERS],
+    /// The number of free blocks of each order.
+    free: [u64; ORDERS],
+    /// The number of allocated blocks of each order.
+    used: [u64; ORDERS],
+}
+
+impl<'a> BuddyFrameAllocator<'a> {
+    /// Returns the number of `u64` words that the bitmaps need for the given number of 4KiB
+    /// frames.
+    #[inline]
+    pub fn storage_words(frame_count: u64) -> usize {
+        (0..ORDERS)
+            .map(|order| Self::bitmap_words(frame_count >> order))
+            .sum()
+    }
+
+    /// Creates a new allocator for the `frame_count` 4KiB frames starting at `start`.
+    ///
+    /// All frames are marked as used initially, use [`add_range`](Self::add_range) to add the
+    /// usable memory. The previous content of `storage` is ignored. Panics if `storage` has less
+    /// than [`storage_words(frame_count)`](Self::storage_words) words.
+    pub fn new(start: PhysFrame<Size1GiB>, frame_count: u64, storage: &'a mut [u64]) -> Self {
+        assert!(
+            storage.len() >= Self::storage_words(frame_count),
+            "storage is too small for the frame count"
+        );
+        for word in storage.iter_mut() {
+            *word = 0;
+        }
+        let mut offsets = [0; ORDERS];
+        for order in 1..ORDERS {
+            offsets[order] = offsets[order - 1] + Self::bitmap_words(frame_count >> (order - 1));
+        }
+        BuddyFrameAllocator {
+            start: start.start_address(),
+            frame_count,
+            storage,
+            offsets,
+            free: [0; ORDERS],
+            used: [0; ORDERS],
+        }
+    }
+
+    /// Adds all frames of the given range to the free frames.
+    ///
+    /// Panics if the range is not contained in the region of the allocator.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the frames are unused and that they weren't added before.
+    pub unsafe fn add_range(&mut self, range: PhysFrameRange) {
+        if range.is_empty() {
+            return;
+        }
+        let index = self.frame_index(range.start.start_address());
+        let end = index + (range.end - range.start);
+        assert!(
+            end <= self.frame_count,
+            "range is outside of the region of the allocator"
+        );
+
+        self.free_range(index, end);
+    }
+
+    /// Returns the number of 4KiB frames of the region of the allocator, including holes.
+    #[inline]
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Returns the number of free and used frames of the given size.
+    ///
+    /// The free frames are the frames of size `S` that can be allocated, including the ones
+    /// that are contained in larger free blocks. The used frames are the frames that were
+    /// allocated through `FrameAllocator<S>` and not deallocated yet.
+    pub fn stats<S: PageSize>(&self) -> FrameStats {
+        let order = Self::order::<S>();
+        let free = (order..ORDERS)
+            .map(|larger| self.free[larger] << (larger - order))
+            .sum();
+        FrameStats {
+            free,
+            used: self.used[order],
+        }
+    }
+
+    /// Returns the order of the blocks for frames of size `S`.
+    fn order<S: PageSize>() -> usize {
+        (S::SIZE / Size4KiB::SIZE).trailing_zeros() as usize
+    }
+
+    fn bitmap_words(blocks: u64) -> usize {
+        (blocks / 64) as usize + (blocks & 63 != 0) as usize
+    }
+
+    /// Returns the index of the 4KiB frame at the given address, relative to the region start.
+    fn frame_index(&self, addr: PhysAddr) -> u64 {
+        assert!(
+            addr >= self.start,
+            "frame is outside of the region of the allocator"
+        );
+        (addr - self.start) / Size4KiB::SIZE
+    }
+
+    /// Returns the bitmap word and the bit of the given block.
+    fn position(&self, block: u64, order: usize) -> (usize, u64) {
+        (
+            self.offsets[order] + (block / 64) as usize,
+            1 << (block % 64),
+        )
+    }
+
+    fn is_free(&self, block: u64, order: usize) -> bool {
+        let (word, bit) = self.position(block, order);
+        self.storage[word] & bit != 0
+    }
+
+    fn set_free(&mut self, block: u64, order: usize, free: bool) {
+        let (word, bit) = self.position(block, order);
+        if free {
+            self.storage[word] |= bit;
+            self.free[order] += 1;
+        } else {
+            self.storage[word] &= !bit;
+            self.free[order] -= 1;
+        }
+    }
+
+    /// Allocates a block of the given order, splitting larger blocks if necessary.
+    ///
+    /// The block ends at or below the frame with index `limit`.
+    fn allocate_block(&mut self, order: usize, limit: u64) -> Option<u64> {
+        let (larger, mut block) = (order..ORDERS)
+            .filter(|&larger| self.free[larger] > 0)
+            .filter_map(|larger| Some((larger, self.first_free_block(larger)?)))
+            .find(|&(larger, block)| ((block << larger) + (1 << order)) <= limit)?;
+        self.set_free(block, larger, false);
+
+        // put the second halves of the split blocks back
+        for split_order in (order..larger).rev() {
+            block *= 2;
+            self.set_free(block + 1, split_order, true);
+        }
+        Some(block)
+    }
+
+    /// Returns the free block of the given order with the lowest address.
+    fn first_free_block(&self, order: usize) -> Option<u64> {
+        let words = Self::bitmap_words(self.frame_count >> order);
+        let offset = self.offsets[order];
+        let word = (offset..offset + words).find(|&word| self.storage[word] != 0)?;
+        Some((word - offset) as u64 * 64 + u64::from(self.storage[word].trailing_zeros()))
+    }
+
+    /// Frees the frames with the indices `index..end` as the largest aligned blocks.
+    fn free_range(&mut self, mut index: u64, end: u64) {
+        while index < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| index & ((1 << order) - 1) == 0 && index + (1 << order) <= end)
+                .unwrap();
+            self.free_block(index >> order, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Frees a block of the given order and merges it with its buddies.
+    fn free_block(&mut self, mut block: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = block ^ 1;
+            if buddy >= self.frame_count >> order || !self.is_free(buddy, order) {
+                break;
+            }
+            self.set_free(buddy, order, false);
+            block /= 2;
+            order += 1;
+        }
+        assert!(!self.is_free(block, order), "block is already free");
+        self.set_free(block, order, true);
+    }
+}
+
+unsafe impl<'a, S: PageSize> FrameAllocator<S> for BuddyFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let order = Self::order::<S>();
+        let block = self.allocate_block(order, self.frame_count)?;
+        self.used[order] += 1;
+        let addr = self.start + (block << order) * Size4KiB::SIZE;
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl<'a, S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator<'a> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let order = Self::order::<S>();
+        let index = self.frame_index(frame.start_address());
+        assert!(
+            index + (1 << order) <= self.frame_count,
+            "frame is outside of the region of the allocator"
+        );
+        self.used[order] = self.used[order].saturating_sub(1);
+        self.free_block(index >> order, order);
+    }
+}
+
+unsafe impl<'a, S: PageSize> ContiguousFrameAllocator<S> for BuddyFrameAllocator<'a> {
+    /// Allocates the frames from a single block, so the size of the range and the alignment
+    /// are limited to 1GiB. The unused end of the block is freed again.
+    fn allocate_contiguous(
+        &mut self,
+        count: u64,
+        align: u64,
+        limit: Option<PhysAddr>,
+    ) -> Option<PhysFrameRange<S>> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 {
+            return None;
+        }
+        let order = Self::order::<S>();
+        let frames = count.checked_mul(1 << order)?;
+        let align_order = (align.max(Size4KiB::SIZE) / Size4KiB::SIZE).trailing_zeros() as usize;
+        let block_order = (64 - (frames - 1).leading_zeros() as usize).max(align_order);
+        if block_order > MAX_ORDER {
+            return None;
+        }
+        let limit = match limit {
+            Some(limit) if limit <= self.start => return None,
+            Some(limit) => ((limit - self.start) / Size4KiB::SIZE).min(self.frame_count),
+            None => self.frame_count,
+        };
+
+        let index = self.allocate_block(block_order, limit)? << block_order;
+        self.free_range(index + frames, index + (1 << block_order));
+        self.used[order] += count;
+        let start = PhysFrame::containing_address(self.start + index * Size4KiB::SIZE);
+        Some(PhysFrame::range(start, start + count))
+    }
+}
+
+impl<'a, S: PageSize> ContiguousFrameDeallocator<S> for BuddyFrameAllocator<'a> {
+    unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange<S>) {
+        if range.is_empty() {
+            return;
+        }
+        let order = Self::order::<S>();
+        let index = self.frame_index(range.start.start_address());
+        let end = index + ((range.end - range.start) << order);
+        assert!(
+            end <= self.frame_count,
+            "range is outside of the region of the allocator"
+        );
+        self.used[order] = self.used[order].saturating_sub(range.end - range.start);
+        self.free_range(index, end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::Size2MiB;
+
+    fn allocator(frame_count: u64, storage: &mut Vec<u64>) -> BuddyFrameAllocator<'_> {
+        storage.resize(BuddyFrameAllocator::storage_words(frame_count), 0);
+        let start = PhysFrame::containing_address(PhysAddr::new(Size1GiB::SIZE));
+        let mut allocator = BuddyFrameAllocator::new(start, frame_count, storage);
+        let start = PhysFrame::containing_address(allocator.start);
+        unsafe { allocator.add_range(PhysFrame::range(start, start + frame_count)) };
+        allocator
+    }
+
+    fn frame<S: PageSize>(index: u64) -> PhysFrame<S> {
+        PhysFrame::containing_address(PhysAddr::new(Size1GiB::SIZE + index * Size4KiB::SIZE))
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut storage = Vec::new();
+        let mut allocator = allocator(1024, &mut storage);
+        assert_eq!(allocator.free[10], 1);
+
+        let first: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(first, frame(0));
+        // the 4MiB block was split into one block of each smaller order
+        assert_eq!(allocator.free[..11], [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+        let second: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(second, frame(1));
+        assert_eq!(allocator.free[0], 0);
+
+        unsafe { allocator.deallocate_frame(first) };
+        assert_eq!(allocator.free[0], 1);
+        unsafe { allocator.deallocate_frame(second) };
+        // the buddies were merged back into the 4MiB block
+        assert_eq!(allocator.free[..11], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn non_power_of_two_frame_count() {
+        let mut storage = Vec::new();
+        let mut allocator = allocator(515, &mut storage);
+        assert_eq!(allocator.free[9], 1);
+        assert_eq!(allocator.free[1], 1);
+        assert_eq!(allocator.free[0], 1);
+        assert_eq!(allocator.stats::<Size4KiB>().free, 515);
+
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge, frame(0));
+        assert_eq!(
+            FrameAllocator::<Size2MiB>::allocate_frame(&mut allocator),
+            None
+        );
+        let tail: Vec<PhysFrame> = (0..3)
+            .map(|_| allocator.allocate_frame().unwrap())
+            .collect();
+        assert_eq!(tail, [frame(514), frame(512), frame(513)]);
+        assert_eq!(
+            FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator),
+            None
+        );
+
+        unsafe {
+            allocator.deallocate_frame(tail[0]);
+            allocator.deallocate_frame(tail[1]);
+            allocator.deallocate_frame(tail[2]);
+        }
+        // the last frame has no buddy, so it isn't merged
+        assert_eq!(allocator.free[0], 1);
+        assert_eq!(allocator.free[1], 1);
+        assert_eq!(allocator.free[2], 0);
+    }
+
+    #[test]
+    fn stats_per_size() {
+        let mut storage = Vec::new();
+        let mut allocator = allocator(1 << 19, &mut storage);
+        let stats = |allocator: &BuddyFrameAllocator<'_>| {
+            [
+                allocator.stats::<Size4KiB>(),
+                allocator.stats::<Size2MiB>(),
+                allocator.stats::<Size1GiB>(),
+            ]
+        };
+        assert_eq!(
+            stats(&allocator),
+            [
+                FrameStats {
+                    free: 1 << 19,
+                    used: 0
+                },
+                FrameStats {
+                    free: 1024,
+                    used: 0
+                },
+                FrameStats { free: 2, used: 0 },
+            ]
+        );
+
+        let gigantic: PhysFrame<Size1GiB> = allocator.allocate_frame().unwrap();
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        let small: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(
+            stats(&allocator),
+            [
+                FrameStats {
+                    free: (1 << 18) - 513,
+                    used: 1
+                },
+                FrameStats { free: 510, used: 1 },
+                FrameStats { free: 0, used: 1 },
+            ]
+        );
+
+        unsafe {
+            allocator.deallocate_frame(small);
+            allocator.deallocate_frame(huge);
+            allocator.deallocate_frame(gigantic);
+        }
+        assert_eq!(stats(&allocator)[2], FrameStats { free: 2, used: 0 });
+        assert_eq!(stats(&allocator)[0].used, 0);
+    }
+
+    #[test]
+    fn contiguous_frees_unused_tail() {
+        let mut storage = Vec::new();
+        let mut allocator = allocator(1024, &mut storage);
+
+        // three frames are allocated from a block of four frames
+        let range: PhysFrameRange = allocator.allocate_contiguous(3, 1, None).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(0), frame(3)));
+        assert_eq!(
+            allocator.stats::<Size4KiB>(),
+            FrameStats {
+                free: 1021,
+                used: 3
+            }
+        );
+        // the fourth frame of the block is free
+        assert_eq!(allocator.free[0], 1);
+        let single: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(single, frame(3));
+
+        unsafe {
+            allocator.deallocate_contiguous(range);
+            allocator.deallocate_frame(single);
+        }
+        assert_eq!(
+            allocator.stats::<Size4KiB>(),
+            FrameStats {
+                free: 1024,
+                used: 0
+            }
+        );
+        assert_eq!(allocator.free[10], 1);
+    }
+
+    #[test]
+    fn contiguous_alignment() {
+        let mut storage = Vec::new();
+        let mut allocator = allocator(1024, &mut storage);
+        let first: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(first, frame(0));
+
+        let range: PhysFrameRange = allocator.allocate_contiguous(3, 0x4000, None).unwrap();
+        assert_eq!(range, PhysFrame::range(frame(4), frame(7)));
+        let range: PhysFrameRange = allocator
+            .allocate_contiguous(2, Size2MiB::SIZE, None)
+            .unwrap();
+        assert_eq!(range, PhysFrame::range(frame(512), frame(514)));
+        // the alignment is limited to 1GiB
+        assert_eq!(
+            allocator.allocate_contiguous(1, 2 * Size1GiB::SIZE, None),
+            None::<PhysFrameRange>
+        );
+
+        // both 2MiB blocks are partially used
+        assert_eq!(
+            allocator.allocate_contiguous(1, 1, None),
+            None::<PhysFrameRange<Size2MiB>>
+        );
+        unsafe { allocator.deallocate_contiguous(range) };
+        let huge: PhysFrameRange<Size2MiB> = allocator.allocate_contiguous(1, 1, None).unwrap();
+        assert_eq!(huge, PhysFrame::range(frame(512), frame(1024)));
+    }
+
+    #[test]
+    fn contiguous_limit() {
+        // a region from 1GiB to 4GiB
+        let mut storage = Vec::new();
+        let mut allocator = allocator(3 << 18, &mut storage);
+        let limit = Some(PhysAddr::new(2 * Size1GiB::SIZE));
+
+        let range: PhysFrameRange<Size1GiB> = allocator.allocate_contiguous(1, 1, limit).unwrap();
+        assert_eq!(range.start, frame(0));
+        assert_eq!(
+            allocator.allocate_contiguous(1, 1, limit),
+            None::<PhysFrameRange>
+        );
+        // without the limit, the frames above it are used
+        let range: PhysFrameRange = allocator.allocate_contiguous(1, 1, None).unwrap();
+        assert_eq!(range.start, frame(1 << 18));
+        // limits at or below the start of the region
+        assert_eq!(
+            allocator.allocate_contiguous(1, 1, Some(PhysAddr::new(Size1GiB::SIZE))),
+            None::<PhysFrameRange>
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use crate::structures::paging::{
+    frame::PhysFrameRange, frame_alloc::FrameAllocator, PhysFrame, Size4KiB,
+};
+
+/// A frame allocator that returns the frames of a list of ranges one after another.
+///
+/// This is the simplest possible frame allocator, e.g. for allocating the initial page tables
+/// from the usable regions of the memory map of the bootloader. It can't deallocate frames.
+#[derive(Debug, Clone)]
+pub struct BumpFrameAllocator<I> {
+    ranges: I,
+    current: Option<PhysFrameRange>,
+    allocated: u64,
+}
+
+impl<I> BumpFrameAllocator<I>
+where
+    I: Iterator<Item = PhysFrameRange>,
+{
+    /// Creates a new allocator that returns the frames of the given ranges.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the ranges only contain unused frames and that they don't
+    /// overlap, since every frame of the ranges is returned as an unused frame.
+    #[inline]
+    pub unsafe fn new(ranges: I) -> Self {
+        BumpFrameAllocator {
+            ranges,
+            current: None,
+            allocated: 0,
+        }
+    }
+
+    /// Returns the number of frames that were allocated.
+    #[inline]
+    pub fn allocated_frames(&self) -> u64 {
+        self.allocated
+    }
+}
+
+unsafe impl<I> FrameAllocator<Size4KiB> for BumpFrameAllocator<I>
+where
+    I: Iterator<Item = PhysFrameRange>,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        loop {
+            if let Some(frame) = self.current.as_mut().and_then(Iterator::next) {
+                self.allocated += 1;
+                return Some(frame);
+            }
+            self.current = Some(self.ranges.next()?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PhysAddr;
+
+    fn frame(index: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(0x10_0000 + index * 0x1000))
+    }
+
+    #[test]
+    fn ranges_are_used_in_order() {
+        let ranges = [
+            PhysFrame::range(frame(4), frame(6)),
+            PhysFrame::range(frame(8), frame(8)),
+            PhysFrame::range(frame(0), frame(1)),
+        ];
+        let mut allocator = unsafe { BumpFrameAllocator::new(ranges.iter().copied()) };
+        assert_eq!(allocator.allocated_frames(), 0);
+        assert_eq!(allocator.allocate_frame(), Some(frame(4)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(5)));
+        // the empty range is skipped
+        assert_eq!(allocator.allocate_frame(), Some(frame(0)));
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.allocated_frames(), 3);
+    }
+
+    #[test]
+    fn no_ranges() {
+        let mut allocator = unsafe { BumpFrameAllocator::new(core::iter::empty()) };
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.allocated_frames(), 0);
+    }
+}
@@ -0,0 +1,141 @@
+//! Traits for abstracting away frame allocation and deallocation, and implementations of them.
+//!
+//! The allocators of this module don't need a heap. Instead, the bitmap and buddy allocators
+//! store their bookkeeping data in a buffer that is passed by the caller.
+
+pub use self::bitmap::BitmapFrameAllocator;
+pub use self::buddy::BuddyFrameAllocator;
+pub use self::bump::BumpFrameAllocator;
+
+use crate::structures::paging::{
+    frame::PhysFrameRange, mapper::MappedFrame, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use crate::PhysAddr;
+
+mod bitmap;
+mod buddy;
+mod bump;
+
+/// A trait for types that can allocate a frame of memory.
+///
+/// This trait is unsafe to implement because the implementer must guarantee that
+/// the `allocate_frame` method returns only unique unused frames.
+pub unsafe trait FrameAllocator<S: PageSize> {
+    /// Allocate a frame of the appropriate size and return it if possible.
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>>;
+}
+
+/// A trait for types that can deallocate a frame of memory.
+pub trait FrameDeallocator<S: PageSize> {
+    /// Deallocate the given unused frame.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the passed frame is unused.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>);
+}
+
+/// A trait for types that can allocate physically contiguous frames, e.g. for DMA buffers.
+///
+/// # Safety
+///
+/// This trait is unsafe to implement because the implementer must guarantee that
+/// the `allocate_contiguous` method returns only unique unused frames.
+pub unsafe trait ContiguousFrameAllocator<S: PageSize> {
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// The start address of the returned range is aligned to `align`, which must be a power of
+    /// two. Alignments below the frame size are rounded up to the frame size. If `limit` is
+    /// given, all frames of the range end at or below this address, e.g. `0x1_0000_0000` for
+    /// devices that only support 32-bit DMA addresses.
+    ///
+    /// Returns `None` if `count` is zero or if no suitable range of frames is free.
+    fn allocate_contiguous(
+        &mut self,
+        count: u64,
+        align: u64,
+        limit: Option<PhysAddr>,
+    ) -> Option<PhysFrameRange<S>>;
+}
+
+/// A trait for types that can deallocate physically contiguous frames.
+pub trait ContiguousFrameDeallocator<S: PageSize> {
+    /// Deallocate the given range of unused frames.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the frames of the range are unused and that the range was
+    /// allocated through [`ContiguousFrameAllocator::allocate_contiguous`].
+    unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange<S>);
+}
+
+/// Allocates the largest available frame that is not larger than `max_size`.
+///
+/// This tries to allocate a `Size1GiB` frame first, then a `Size2MiB` frame, and then a
+/// `Size4KiB` frame, skipping all sizes that are larger than `max_size`. This is useful for
+/// mapping memory with huge pages where possible and falling back to smaller pages otherwise.
+pub fn allocate_largest_frame<A>(frame_allocator: &mut A, max_size: u64) -> Option<MappedFrame>
+where
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB> + ?Sized,
+{
+    if max_size >= Size1GiB::SIZE {
+        if let Some(frame) = FrameAllocator::<Size1GiB>::allocate_frame(frame_allocator) {
+            return Some(MappedFrame::Size1GiB(frame));
+        }
+    }
+    if max_size >= Size2MiB::SIZE {
+        if let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator) {
+            return Some(MappedFrame::Size2MiB(frame));
+        }
+    }
+    if max_size >= Size4KiB::SIZE {
+        if let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator) {
+            return Some(MappedFrame::Size4KiB(frame));
+        }
+    }
+    None
+}
+
+/// The number of free and used frames of a frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameStats {
+    /// The number of frames that can be allocated.
+    pub free: u64,
+    /// The number of frames that are allocated.
+    pub used: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped_frame_size(frame: Option<MappedFrame>) -> Option<u64> {
+        frame.map(|frame| frame.size())
+    }
+
+    #[test]
+    fn largest_frame_fallback() {
+        // 1GiB, 2MiB and 4KiB of memory
+        let frame_count = (1 << 18) + 512 + 1;
+        let mut storage = vec![0; BuddyFrameAllocator::storage_words(frame_count)];
+        let start = PhysFrame::containing_address(PhysAddr::new(0));
+        let mut allocator = BuddyFrameAllocator::new(start, frame_count, &mut storage);
+        unsafe {
+            allocator.add_range(PhysFrame::range(
+                PhysFrame::containing_address(PhysAddr::new(0)),
+                PhysFrame::containing_address(PhysAddr::new(frame_count * Size4KiB::SIZE)),
+            ))
+        };
+
+        // sizes larger than `max_size` are skipped
+        let frame = allocate_largest_frame(&mut allocator, Size2MiB::SIZE - 1);
+        assert_eq!(mapped_frame_size(frame), Some(Size4KiB::SIZE));
+        assert_eq!(allocator.stats::<Size1GiB>().free, 1);
+
+        // the largest size is tried first
+        let sizes: Vec<_> = (0..3)
+            .map(|_| mapped_frame_size(allocate_largest_frame(&mut allocator, u64::MAX)))
+            .collect();
+        assert_eq!(sizes, [Some(Size1GiB::SIZE), Some(Size2MiB::SIZE), None]);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::structures::paging::{
+    mapper::{advance, MappedPage, MapperFlushRange, WalkMut},
+    page::PageRange,
+    PageSize, PageTableFlags, Size4KiB,
+};
+
+/// Provides methods for reading and clearing the `ACCESSED` and `DIRTY` flags of all mappings
+/// in a page range.
+///
+/// The CPU sets the `ACCESSED` flag of a page table entry when the page is accessed and the
+/// `DIRTY` flag when the page is written to. By periodically reading and clearing these flags,
+/// the working set of a process can be determined, e.g. for page reclaim or for tracking the
+/// dirty pages during live migration.
+///
+/// The CPU caches the flags in the TLB, so it only sets them again after the TLB entry of the
+/// page was flushed. For this reason, all methods return a [`MapperFlushRange`] that contains
+/// the pages whose flags were cleared.
+///
+/// This trait is automatically implemented for all types that implement [`WalkMut`].
+pub trait AccessTracker: WalkMut {
+    /// Calls `f` for every mapping in the given page range and clears the given flags of its
+    /// entry.
+    ///
+    /// The second argument of `f` are the flags of the entry that maps the page, before any
+    /// flags were cleared. Huge pages that only partially overlap with the range are included.
+    /// Only the `ACCESSED` and `DIRTY` flags of `clear` are used, all other flags are ignored.
+    /// Pass an empty set of flags to only read the flags.
+    ///
+    /// The flags are cleared atomically, so that flags that the CPU sets concurrently on other
+    /// cores are not lost.
+    fn harvest_access_flags<F>(
+        &mut self,
+        pages: PageRange,
+        clear: PageTableFlags,
+        mut f: F,
+    ) -> MapperFlushRange
+    where
+        Self: Sized,
+        F: FnMut(MappedPage, PageTableFlags),
+    {
+        let clear = clear & (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+        let start = pages.start.start_address();
+        let end = pages.end.start_address();
+
+        let mut flush = MapperFlushRange::new();
+        let mut next = Some(start);
+        while let Some(addr) = next.filter(|&addr| addr < end) {
+            // Safety: only the `ACCESSED` and `DIRTY` flags are modified, which doesn't affect
+            // memory safety
+            let (flags, level) = unsafe {
+                self.walk_mut(addr, |entry, level| {
+                    if entry.flags().contains(PageTableFlags::PRESENT) {
+                        (entry.clear_flags(clear), level)
+                    } else {
+                        (entry.flags(), level)
+                    }
+                })
+            };
+
+            let size = level.entry_address_space_alignment();
+            if flags.contains(PageTableFlags::PRESENT) {
+                let page = MappedPage::containing_address(addr, level);
+                if flags.intersects(clear) {
+                    flush.add(page.start_address(), size);
+                }
+                f(page, flags);
+            }
+            next = advance(self.paging_mode(), addr, size);
+        }
+
+        flush
+    }
+
+    /// Records in a bitmap which 4KiB pages of the given range have the given flags set and
+    /// optionally clears the flags.
+    ///
+    /// Bit `i % 64` of `bitmap[i / 64]` corresponds to the `i`th 4KiB page of the range. The
+    /// bit is set if the page is mapped and the entry that maps it has any of the given flags
+    /// set, which are usually `ACCESSED` or `DIRTY`. For huge pages, the bits of all contained
+    /// 4KiB pages are set. All other bits of the bitmap are cleared. If `clear` is true, the
+    /// given flags are cleared like in [`harvest_access_flags`](Self::harvest_access_flags).
+    ///
+    /// Panics if the bitmap has less bits than the range has pages.
+    fn harvest_access_bitmap(
+        &mut self,
+        pages: PageRange,
+        flags: PageTableFlags,
+        clear: bool,
+        bitmap: &mut [u64],
+    ) -> MapperFlushRange
+    where
+        Self: Sized,
+    {
+        let page_count = pages.end - pages.start;
+        assert!(
+            (bitmap.len() as u64) * 64 >= page_count,
+            "bitmap is too small for the page range"
+        );
+        for word in bitmap.iter_mut() {
+            *word = 0;
+        }
+
+        let start = pages.start.start_address();
+        let end = pages.end.start_address();
+        let clear = if clear {
+            flags
+        } else {
+            PageTableFlags::empty()
+        };
+        self.harvest_access_flags(pages, clear, |page, page_flags| {
+            if !page_flags.intersects(flags) {
+                return;
+            }
+            let page_start = page.start_address().max(start);
+            let page_end = (page.start_address() + (page.size() - 1)).min(end - 1u64);
+            let first = (page_start - start) / Size4KiB::SIZE;
+            let last = (page_end - start) / Size4KiB::SIZE;
+            for i in first..=last {
+                bitmap[(i / 64) as usize] |= 1 << (i % 64);
+            }
+        })
+    }
+}
+
+impl<T> AccessTracker for T where T: WalkMut + ?Sized {}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{
+        mapper::{SimulatedMemory, TranslateResult},
+        Page, PhysFrame, Size2MiB,
+    };
+    use crate::{PhysAddr, VirtAddr};
+
+    const ACCESSED: PageTableFlags = PageTableFlags::ACCESSED;
+    const DIRTY: PageTableFlags = PageTableFlags::DIRTY;
+
+    /// Maps four 4KiB pages at 0x1000 with different access flags and a 2MiB page at
+    /// 0x20_0000 that was accessed.
+    fn simulated_memory() -> SimulatedMemory {
+        let mut memory = SimulatedMemory::new(8);
+        let flags = [
+            PageTableFlags::empty(),
+            ACCESSED,
+            ACCESSED | DIRTY,
+            PageTableFlags::empty(),
+        ];
+        for (i, &extra) in flags.iter().enumerate() {
+            memory.map(
+                Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000 * (i as u64 + 1))),
+                PhysFrame::containing_address(PhysAddr::new(0x1_0000)),
+                PageTableFlags::PRESENT | extra,
+            );
+        }
+        memory.map(
+            Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000)),
+            PhysFrame::containing_address(PhysAddr::new(0x4000_0000)),
+            PageTableFlags::PRESENT | ACCESSED,
+        );
+        memory
+    }
+
+    fn flags(memory: &mut SimulatedMemory, addr: u64) -> PageTableFlags {
+        match memory.translate(VirtAddr::new(addr)) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            result => panic!("{:#x} is not mapped: {:?}", addr, result),
+        }
+    }
+
+    #[test]
+    fn read_flags() {
+        let mut memory = simulated_memory();
+        let mut seen = Vec::new();
+        {
+            let (mut mapper, _) = memory.mapped_page_table();
+            let flush = mapper.harvest_access_flags(
+                SimulatedMemory::pages(0, 0x30_0000),
+                PageTableFlags::empty(),
+                |page, flags| {
+                    seen.push((page.start_address().as_u64(), flags & (ACCESSED | DIRTY)))
+                },
+            );
+            assert!(flush.is_empty());
+            flush.ignore();
+        }
+        assert_eq!(
+            seen,
+            [
+                (0x1000, PageTableFlags::empty()),
+                (0x2000, ACCESSED),
+                (0x3000, ACCESSED | DIRTY),
+                (0x4000, PageTableFlags::empty()),
+                (0x20_0000, ACCESSED),
+            ]
+        );
+        assert!(flags(&mut memory, 0x3000).contains(ACCESSED | DIRTY));
+    }
+
+    #[test]
+    fn clear_flags() {
+        let mut memory = simulated_memory();
+        let mut count = 0;
+        {
+            let (mut mapper, _) = memory.mapped_page_table();
+            // the range only contains the first 4KiB of the huge page
+            let flush = mapper.harvest_access_flags(
+                SimulatedMemory::pages(0x2000, 0x20_1000),
+                ACCESSED | PageTableFlags::WRITABLE,
+                |_, _| count += 1,
+            );
+            // the pages at 0x2000, 0x3000, and the huge page
+            assert_eq!(flush.pages(), 3);
+            flush.ignore();
+        }
+        assert_eq!(count, 4);
+        assert_eq!(
+            flags(&mut memory, 0x1000) & ACCESSED,
+            PageTableFlags::empty()
+        );
+        assert_eq!(
+            flags(&mut memory, 0x2000) & ACCESSED,
+            PageTableFlags::empty()
+        );
+        assert_eq!(flags(&mut memory, 0x3000) & (ACCESSED | DIRTY), DIRTY);
+        assert_eq!(
+            flags(&mut memory, 0x20_0000) & ACCESSED,
+            PageTableFlags::empty()
+        );
+    }
+
+    #[test]
+    fn bitmap() {
+        let mut memory = simulated_memory();
+        let (mut mapper, _) = memory.mapped_page_table();
+        // 0x20_1000 - 0x1000 = 512 pages
+        let pages = SimulatedMemory::pages(0x1000, 0x20_1000);
+        let mut bitmap = [u64::MAX; 9];
+
+        mapper
+            .harvest_access_bitmap(pages, ACCESSED, true, &mut bitmap)
+            .ignore();
+        let mut expected = [0; 9];
+        // 0x2000 and 0x3000
+        expected[0] = 0b110;
+        // the first 4KiB of the huge page is the 511th page of the range
+        expected[7] = 1 << 63;
+        assert_eq!(bitmap, expected);
+
+        // the flags were cleared
+        mapper
+            .harvest_access_bitmap(pages, ACCESSED, false, &mut bitmap)
+            .ignore();
+        assert_eq!(bitmap, [0; 9]);
+
+        mapper
+            .harvest_access_bitmap(
+                SimulatedMemory::pages(0x1000, 0x5000),
+                DIRTY,
+                false,
+                &mut bitmap[..1],
+            )
+            .ignore();
+        assert_eq!(bitmap[0], 0b100);
+    }
+
+    #[test]
+    #[should_panic(expected = "bitmap is too small")]
+    fn bitmap_too_small() {
+        let mut memory = simulated_memory();
+        let (mut mapper, _) = memory.mapped_page_table();
+        let mut bitmap = [0; 1];
+        mapper
+            .harvest_access_bitmap(
+                SimulatedMemory::pages(0, 0x41_000),
+                ACCESSED,
+                false,
+                &mut bitmap,
+            )
+            .ignore();
+    }
+}
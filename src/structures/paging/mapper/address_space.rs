@@ -0,0 +1,458 @@
+#[cfg(feature = "instructions")]
+use crate::instructions::tlb::Pcid;
+#[cfg(feature = "instructions")]
+use crate::registers::control::{Cr3, Cr3Flags};
+use crate::structures::paging::{
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::{
+        walk_tables, FlagUpdateError, MapToError, MappedFrame, MappedPageTable, Mapper,
+        MapperFlush, PageTableFrameMapping, Translate, TranslateResult, UnmapError, Walk,
+        WalkResult,
+    },
+    Page, PageSize, PageTable, PageTableFlags, PageTableLevel, PhysFrame, Size4KiB,
+};
+use crate::VirtAddr;
+use core::ops::Range;
+
+/// The index of the first level 4 entry of the upper (kernel) half.
+pub(super) const KERNEL_HALF_START: usize = 256;
+
+/// An address space with its own level 4 table, which shares the upper half with the kernel.
+///
+/// The entries of the upper (kernel) half of the level 4 table are copied from a template,
+/// usually the level 4 table of the kernel, so all address spaces share the same kernel level
+/// 3 tables. The lower (user) half belongs to the address space: The mapping methods of this
+/// type only accept pages of the lower half, and all page tables of the lower half are
+/// deallocated through the `frame_deallocator` of the address space when it is dropped,
+/// together with the level 4 table. The frames that are mapped by the page tables are not
+/// deallocated.
+///
+/// The page tables are accessed through a [`PageTableFrameMapping`], like for the
+/// [`MappedPageTable`]. Only 4-level paging is supported.
+#[derive(Debug)]
+pub struct AddressSpace<P: PageTableFrameMapping, D: FrameDeallocator<Size4KiB>> {
+    root: PhysFrame,
+    mapping: P,
+    frame_deallocator: D,
+}
+
+impl<P, D> AddressSpace<P, D>
+where
+    P: PageTableFrameMapping,
+    D: FrameDeallocator<Size4KiB>,
+{
+    /// Creates a new address space that uses `root` as its level 4 table.
+    ///
+    /// The lower half of the new level 4 table is empty and the entries of the upper half are
+    /// copied from `template`. Note that a recursive entry of `template` would still point to
+    /// `template` afterwards.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that `root` is an unused frame that can be deallocated through
+    /// `frame_deallocator` and that `mapping` is correct. The kernel half tables of `template`
+    /// must stay valid as long as the address space is used. The address space must not be
+    /// active on any CPU when it is dropped.
+    pub unsafe fn new(
+        root: PhysFrame,
+        template: &PageTable,
+        mapping: P,
+        frame_deallocator: D,
+    ) -> Self {
+        let level_4_table = &mut *mapping.frame_to_pointer(root);
+        level_4_table.zero();
+        for index in KERNEL_HALF_START..512 {
+            level_4_table[index] = template[index].clone();
+        }
+        AddressSpace {
+            root,
+            mapping,
+            frame_deallocator,
+        }
+    }
+
+    /// Returns the frame of the level 4 table, which can be loaded into the CR3 register.
+    #[inline]
+    pub fn root_frame(&self) -> PhysFrame {
+        self.root
+    }
+
+    /// Returns a reference to the level 4 table.
+    #[inline]
+    pub fn level_4_table(&self) -> &PageTable {
+        // SAFETY: the caller of `new` guaranteed that `root` is a frame that only this address
+        // space uses and that `mapping` maps it to a valid pointer
+        unsafe { &*self.mapping.frame_to_pointer(self.root) }
+    }
+
+    /// Returns a mapper for the complete address space, including the kernel half.
+    ///
+    /// ## Safety
+    ///
+    /// The kernel half is shared with other address spaces, so the caller must ensure that
+    /// modifying it doesn't break memory safety in any of them. Page tables of the kernel half
+    /// are not freed when the address space is dropped, so they must not be created through
+    /// this mapper.
+    #[inline]
+    pub unsafe fn mapper(&mut self) -> MappedPageTable<'_, &P> {
+        MappedPageTable::new(
+            &mut *self.mapping.frame_to_pointer(self.root),
+            &self.mapping,
+        )
+    }
+
+    /// Maps the given page of the lower half to the given frame, like [`Mapper::map_to`].
+    ///
+    /// Returns [`AddressSpaceError::KernelHalf`] if the page is in the upper half.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Mapper::map_to`] apply.
+    pub unsafe fn map_to<'a, S, A>(
+        &'a mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<S>, AddressSpaceError<MapToError<S>>>
+    where
+        S: PageSize,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+        MappedPageTable<'a, &'a P>: Mapper<S>,
+    {
+        check_user_page(page)?;
+        self.mapper()
+            .map_to(page, frame, flags, frame_allocator)
+            .map_err(AddressSpaceError::Mapper)
+    }
+
+    /// Removes the mapping of the given page of the lower half, like [`Mapper::unmap`].
+    ///
+    /// Returns [`AddressSpaceError::KernelHalf`] if the page is in the upper half.
+    pub fn unmap<'a, S>(
+        &'a mut self,
+        page: Page<S>,
+    ) -> Result<(PhysFrame<S>, MapperFlush<S>), AddressSpaceError<UnmapError>>
+    where
+        S: PageSize,
+        MappedPageTable<'a, &'a P>: Mapper<S>,
+    {
+        check_user_page(page)?;
+        // SAFETY: the page is in the lower half, which belongs to this address space
+        unsafe { self.mapper() }
+            .unmap(page)
+            .map_err(AddressSpaceError::Mapper)
+    }
+
+    /// Updates the flags of the given page of the lower half, like [`Mapper::update_flags`].
+    ///
+    /// Returns [`AddressSpaceError::KernelHalf`] if the page is in the upper half.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Mapper::update_flags`] apply.
+    pub unsafe fn update_flags<'a, S>(
+        &'a mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<S>, AddressSpaceError<FlagUpdateError>>
+    where
+        S: PageSize,
+        MappedPageTable<'a, &'a P>: Mapper<S>,
+    {
+        check_user_page(page)?;
+        self.mapper()
+            .update_flags(page, flags)
+            .map_err(AddressSpaceError::Mapper)
+    }
+
+    /// Loads the level 4 table of the address space into the CR3 register.
+    ///
+    /// ## Safety
+    ///
+    /// Changing the active address space can break memory safety, e.g. if the kernel half
+    /// doesn't map the currently executed code. See [`Cr3::write`].
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn activate(&self, flags: Cr3Flags) {
+        Cr3::write(self.root, flags);
+    }
+
+    /// Loads the level 4 table of the address space into the CR3 register together with the
+    /// given PCID.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`activate`](Self::activate) apply. The `PCID` flag of
+    /// the CR4 register must be set and the TLB entries of the PCID must not belong to a
+    /// different address space. See [`Cr3::write_pcid`].
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn activate_pcid(&self, pcid: Pcid) {
+        Cr3::write_pcid(self.root, pcid);
+    }
+
+    /// Returns the mapping that is used for accessing the page tables.
+    #[inline]
+    pub(super) fn mapping(&self) -> &P {
+        &self.mapping
+    }
+
+    /// Deallocates the page tables below the given entries of the given table.
+    ///
+    /// The page tables must belong to the address space.
+    pub(super) unsafe fn free_tables(
+        &mut self,
+        table: PhysFrame,
+        level: PageTableLevel,
+        entries: Range<usize>,
+    ) {
+        let next_level = match level.next_lower_level() {
+            Some(next_level) => next_level,
+            None => return,
+        };
+        let page_table = &*self.mapping.frame_to_pointer(table);
+        for index in entries {
+            let entry = &page_table[index];
+            let flags = entry.flags();
+            // skip huge pages and recursive entries
+            if !flags.contains(PageTableFlags::PRESENT)
+                || flags.contains(PageTableFlags::HUGE_PAGE)
+                || entry.addr() == self.root.start_address()
+            {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            self.free_tables(frame, next_level, 0..512);
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Deallocates the given frame through the frame deallocator of the address space.
+    #[inline]
+    pub(super) unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.frame_deallocator.deallocate_frame(frame);
+    }
+}
+
+impl<P, D> Walk for AddressSpace<P, D>
+where
+    P: PageTableFrameMapping,
+    D: FrameDeallocator<Size4KiB>,
+{
+    #[inline]
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        walk_tables(
+            self.level_4_table(),
+            PageTableLevel::Four,
+            addr,
+            Some(self.root.start_address()),
+            |page_table, index| {
+                let frame = PhysFrame::containing_address(page_table[index].addr());
+                unsafe { &*self.mapping.frame_to_pointer(frame) }
+            },
+        )
+    }
+}
+
+impl<P, D> Translate for AddressSpace<P, D>
+where
+    P: PageTableFrameMapping,
+    D: FrameDeallocator<Size4KiB>,
+{
+    fn translate(&self, addr: VirtAddr) -> TranslateResult {
+        let (entry, level) = match self.walk(addr) {
+            WalkResult::Mapped { entry, level, .. } => (entry, level),
+            WalkResult::NotMapped { .. } => return TranslateResult::NotMapped,
+        };
+        let frame_addr = entry.leaf_addr(level);
+        let frame = match level {
+            PageTableLevel::One => {
+                PhysFrame::from_start_address(frame_addr).map(MappedFrame::Size4KiB)
+            }
+            PageTableLevel::Two => {
+                PhysFrame::from_start_address(frame_addr).map(MappedFrame::Size2MiB)
+            }
+            _ => PhysFrame::from_start_address(frame_addr).map(MappedFrame::Size1GiB),
+        };
+        match frame {
+            Ok(frame) => TranslateResult::Mapped {
+                offset: addr.as_u64() & (frame.size() - 1),
+                frame,
+                flags: entry.flags(),
+            },
+            Err(_) => TranslateResult::InvalidFrameAddress(entry.addr()),
+        }
+    }
+}
+
+impl<P, D> Drop for AddressSpace<P, D>
+where
+    P: PageTableFrameMapping,
+    D: FrameDeallocator<Size4KiB>,
+{
+    fn drop(&mut self) {
+        // SAFETY: the lower half tables and the level 4 table belong to the address space, which
+        // is not active anymore
+        unsafe {
+            self.free_tables(self.root, PageTableLevel::Four, 0..KERNEL_HALF_START);
+            self.deallocate_frame(self.root);
+        }
+    }
+}
+
+/// An error indicating that an operation of an [`AddressSpace`] failed.
+#[derive(Debug)]
+pub enum AddressSpaceError<E> {
+    /// The given page is in the upper half, which is shared with the kernel.
+    KernelHalf(VirtAddr),
+    /// The operation failed in the mapper.
+    Mapper(E),
+}
+
+/// Checks that the given page is in the lower half of the address space.
+pub(super) fn check_user_page<S: PageSize, E>(page: Page<S>) -> Result<(), AddressSpaceError<E>> {
+    if usize::from(page.p4_index()) < KERNEL_HALF_START {
+        Ok(())
+    } else {
+        Err(AddressSpaceError::KernelHalf(page.start_address()))
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{mapper::SimulatedMemory, Size2MiB};
+    use crate::PhysAddr;
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::USER_ACCESSIBLE);
+    const KERNEL_ADDR: u64 = 0xffff_8000_0000_0000;
+
+    /// Returns a memory whose level 4 table maps a kernel page.
+    fn kernel_memory() -> SimulatedMemory {
+        let mut memory = SimulatedMemory::new(32);
+        memory.map(
+            SimulatedMemory::page(KERNEL_ADDR),
+            SimulatedMemory::frame(0x10_0000),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+        memory
+    }
+
+    #[test]
+    fn kernel_half_is_rejected() {
+        let memory = kernel_memory();
+        let mut frame_allocator = memory.frame_allocator();
+        let root = frame_allocator.allocate_frame().unwrap();
+        let template = unsafe { &*memory.frame_to_pointer(memory.root_frame()) };
+        let mut space = unsafe { AddressSpace::new(root, template, &memory, frame_allocator) };
+
+        let addr = VirtAddr::new(KERNEL_ADDR + 0x1000);
+        let page = Page::containing_address(addr);
+        let frame = SimulatedMemory::frame(0x1000);
+        assert!(matches!(
+            unsafe { space.map_to(page, frame, FLAGS, &mut frame_allocator) },
+            Err(AddressSpaceError::KernelHalf(a)) if a == addr
+        ));
+        assert!(matches!(
+            space.unmap(SimulatedMemory::page(KERNEL_ADDR)),
+            Err(AddressSpaceError::KernelHalf(a)) if a.as_u64() == KERNEL_ADDR
+        ));
+        assert!(matches!(
+            unsafe { space.update_flags(SimulatedMemory::page(KERNEL_ADDR), FLAGS) },
+            Err(AddressSpaceError::KernelHalf(_))
+        ));
+        // the last page of the lower half is accepted
+        unsafe {
+            space.map_to(
+                SimulatedMemory::page(0x7fff_ffff_f000),
+                SimulatedMemory::frame(0x1000),
+                FLAGS,
+                &mut frame_allocator,
+            )
+        }
+        .unwrap()
+        .ignore();
+
+        // the kernel half is shared with the template
+        assert!(matches!(
+            space.translate(VirtAddr::new(KERNEL_ADDR)),
+            TranslateResult::Mapped { .. }
+        ));
+        assert_eq!(
+            space.level_4_table()[KERNEL_HALF_START].addr(),
+            template[KERNEL_HALF_START].addr()
+        );
+    }
+
+    #[test]
+    fn drop_frees_user_tables() {
+        let mut memory = kernel_memory();
+        let allocated = memory.allocated_frames();
+        {
+            let mut frame_allocator = memory.frame_allocator();
+            let root = frame_allocator.allocate_frame().unwrap();
+            let template = unsafe { &*memory.frame_to_pointer(memory.root_frame()) };
+            let mut space = unsafe { AddressSpace::new(root, template, &memory, frame_allocator) };
+            unsafe {
+                space
+                    .map_to(
+                        SimulatedMemory::page(0x1000),
+                        SimulatedMemory::frame(0x1000),
+                        FLAGS,
+                        &mut frame_allocator,
+                    )
+                    .unwrap()
+                    .ignore();
+                space
+                    .map_to(
+                        Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000)),
+                        PhysFrame::containing_address(PhysAddr::new(0x20_0000)),
+                        FLAGS,
+                        &mut frame_allocator,
+                    )
+                    .unwrap()
+                    .ignore();
+                space
+                    .map_to(
+                        SimulatedMemory::page(0x80_0000_0000),
+                        SimulatedMemory::frame(0x2000),
+                        FLAGS,
+                        &mut frame_allocator,
+                    )
+                    .unwrap()
+                    .ignore();
+                // a page in the shared kernel level 3 table, created through the unrestricted
+                // mapper
+                space
+                    .mapper()
+                    .map_to(
+                        SimulatedMemory::page(KERNEL_ADDR + 0x1000),
+                        SimulatedMemory::frame(0x3000),
+                        PageTableFlags::PRESENT,
+                        &mut frame_allocator,
+                    )
+                    .unwrap()
+                    .ignore();
+            }
+            // the level 4 table and three user tables for each level 4 entry
+            assert_eq!(memory.allocated_frames(), allocated + 7);
+        }
+
+        // all user tables and the level 4 table were freed, the kernel tables are kept
+        assert_eq!(memory.allocated_frames(), allocated);
+        memory.assert_mapped(
+            VirtAddr::new(KERNEL_ADDR),
+            PhysAddr::new(0x10_0000),
+            PageTableFlags::PRESENT,
+        );
+        memory.assert_mapped(
+            VirtAddr::new(KERNEL_ADDR + 0x1000),
+            PhysAddr::new(0x3000),
+            PageTableFlags::PRESENT,
+        );
+        memory.assert_not_mapped(VirtAddr::new(0x1000));
+    }
+}
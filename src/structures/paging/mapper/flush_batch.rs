@@ -0,0 +1,329 @@
+use crate::instructions::tlb::{self, InvPicdCommand, Pcid};
+use crate::structures::paging::{
+    mapper::{MapperFlush, MapperFlushAll, MapperFlushRange},
+    PageSize,
+};
+use crate::VirtAddr;
+
+/// The maximum number of non-contiguous ranges that a [`FlushBatch`] can store.
+const CAPACITY: usize = 16;
+
+/// Collects the TLB flushes of many page table changes and executes them at once.
+///
+/// The flush tokens that the mapper methods return, i.e. [`MapperFlush`], [`MapperFlushRange`],
+/// and [`MapperFlushAll`], can be added to a batch instead of flushing them one by one. When
+/// the batch is flushed, it either invalidates the changed pages individually or flushes the
+/// complete TLB. Invalidating single pages is faster for a few pages, but a complete flush is
+/// cheaper than thousands of `invlpg` instructions. The complete TLB is flushed if more than
+/// `threshold` pages were added, if a [`MapperFlushAll`] was added, or if more than 16
+/// non-contiguous ranges of pages were added.
+///
+/// A batch that is created through [`new`](Self::new) flushes the TLB entries of the active
+/// address space, using `invlpg` for single pages and a reload of the CR3 register for complete
+/// flushes. A batch that is created through [`for_pcid`](Self::for_pcid) uses `invpcid` to
+/// flush the TLB entries of the given PCID instead, which also works for address spaces that
+/// are not active.
+///
+/// Like for [`MapperFlushAll`], complete flushes don't invalidate pages that have the `GLOBAL`
+/// flag set. If such pages were changed, [`include_global`](Self::include_global) must be called,
+/// so that complete flushes toggle the `PAGE_GLOBAL` bit of the CR4 register or use an
+/// all-context `invpcid` command instead. Batches for a PCID then always use an all-context
+/// command, since individual-address commands don't invalidate global pages either.
+#[derive(Debug)]
+#[must_use = "Page Table changes must be flushed or ignored."]
+pub struct FlushBatch {
+    ranges: [FlushRange; CAPACITY],
+    len: usize,
+    /// The number of pages that were added to the batch.
+    pages: u64,
+    flush_all: bool,
+    /// Whether pages with the `GLOBAL` flag set were added.
+    global: bool,
+    threshold: u64,
+    pcid: Option<Pcid>,
+}
+
+/// A range of pages of the same size.
+#[derive(Debug, Clone, Copy)]
+struct FlushRange {
+    first: VirtAddr,
+    last: VirtAddr,
+    step: u64,
+}
+
+impl FlushBatch {
+    /// The default threshold, which is used by the `Default` implementation.
+    pub const DEFAULT_THRESHOLD: u64 = 32;
+
+    /// Creates an empty batch for the active address space.
+    ///
+    /// If more than `threshold` pages are added to the batch, the complete TLB is flushed
+    /// instead of the single pages.
+    #[inline]
+    pub const fn new(threshold: u64) -> Self {
+        FlushBatch {
+            ranges: [FlushRange {
+                first: VirtAddr::zero(),
+                last: VirtAddr::zero(),
+                step: 0,
+            }; CAPACITY],
+            len: 0,
+            pages: 0,
+            flush_all: false,
+            global: false,
+            threshold,
+            pcid: None,
+        }
+    }
+
+    /// Creates an empty batch that flushes the TLB entries of the given PCID.
+    ///
+    /// Single pages are flushed using `invpcid` with an individual-address command and complete
+    /// flushes use a single-context command. See [`new`](Self::new) for the `threshold`.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe as flushing the batch requires
+    /// CPUID.(EAX=07H, ECX=0H):EBX.INVPCID to be 1.
+    #[inline]
+    pub const unsafe fn for_pcid(threshold: u64, pcid: Pcid) -> Self {
+        let mut batch = Self::new(threshold);
+        batch.pcid = Some(pcid);
+        batch
+    }
+
+    /// Adds the changed page of the given flush token to the batch.
+    #[inline]
+    pub fn add<S: PageSize>(&mut self, flush: MapperFlush<S>) {
+        let start = flush.0.start_address();
+        self.push(start, start + (S::SIZE - 1), S::SIZE);
+    }
+
+    /// Adds the changed pages of the given flush token to the batch.
+    ///
+    /// If the token would flush the complete TLB, the batch does the same. Since this includes
+    /// global pages for a [`MapperFlushRange`], [`include_global`](Self::include_global) is
+    /// implied in this case.
+    #[inline]
+    pub fn add_range(&mut self, flush: MapperFlushRange) {
+        if flush.flushes_all() {
+            self.pages = self.pages.saturating_add(flush.pages());
+            self.flush_all = true;
+            self.global = true;
+            return;
+        }
+        for (first, last, step) in flush.runs() {
+            self.push(first, last, step);
+        }
+    }
+
+    /// Adds the given flush token to the batch, so that the complete TLB is flushed.
+    #[inline]
+    pub fn add_all(&mut self, flush: MapperFlushAll) {
+        flush.ignore();
+        self.flush_all = true;
+    }
+
+    /// Records that some of the changed pages have the `GLOBAL` flag set, so that they are
+    /// invalidated by complete flushes too.
+    #[inline]
+    pub fn include_global(&mut self) {
+        self.global = true;
+    }
+
+    /// Returns whether no changes were added to the batch, so that there is nothing to flush.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0 && !self.flush_all
+    }
+
+    /// Returns the number of pages that were added to the batch.
+    ///
+    /// Pages that were added through [`add_all`](Self::add_all) are not counted.
+    #[inline]
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    /// Returns whether flushing the batch flushes the complete TLB instead of single pages.
+    #[inline]
+    pub fn flushes_all(&self) -> bool {
+        self.flush_all || self.pages > self.threshold
+    }
+
+    /// Flushes all changes of the batch from the TLB to ensure that the newest mappings are
+    /// used.
+    #[inline]
+    pub fn flush(self) {
+        self.flush_local();
+    }
+
+    /// Don't flush the TLB and silence the “must be used” warning.
+    #[inline]
+    pub fn ignore(self) {}
+
+    /// Flushes the changes of the batch on the current CPU without consuming the batch.
+    ///
+    /// This is used to execute the same batch on multiple CPUs in a TLB shootdown.
+    pub(crate) fn flush_local(&self) {
+        if self.flushes_all() || (self.global && self.pcid.is_some()) {
+            match (self.pcid, self.global) {
+                // Safety: the caller of `for_pcid` ensured that `invpcid` is supported
+                (Some(_), true) => unsafe { tlb::flush_pcid(InvPicdCommand::All) },
+                (Some(pcid), false) => unsafe { tlb::flush_pcid(InvPicdCommand::Single(pcid)) },
+                (None, true) => tlb::flush_all_including_global(),
+                (None, false) => tlb::flush_all(),
+            }
+            return;
+        }
+
+        for range in self.ranges[..self.len].iter() {
+            let mut addr = range.first;
+            loop {
+                match self.pcid {
+                    // Safety: the caller of `for_pcid` ensured that `invpcid` is supported
+                    Some(pcid) => unsafe { tlb::flush_pcid(InvPicdCommand::Address(addr, pcid)) },
+                    None => tlb::flush(addr),
+                }
+                if range.last - addr < range.step {
+                    break;
+                }
+                addr += range.step;
+            }
+        }
+    }
+
+    fn push(&mut self, first: VirtAddr, last: VirtAddr, step: u64) {
+        self.pages = self.pages.saturating_add((last - first) / step + 1);
+        if self.flushes_all() {
+            return;
+        }
+
+        if let Some(previous) = self.len.checked_sub(1).map(|i| &mut self.ranges[i]) {
+            if previous.step == step && previous.last.as_u64().wrapping_add(1) == first.as_u64() {
+                previous.last = last;
+                return;
+            }
+        }
+
+        if self.len == CAPACITY {
+            self.flush_all = true;
+        } else {
+            self.ranges[self.len] = FlushRange { first, last, step };
+            self.len += 1;
+        }
+    }
+}
+
+impl Default for FlushBatch {
+    #[inline]
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_THRESHOLD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{Page, Size2MiB, Size4KiB};
+
+    fn flush_4kib(addr: u64) -> MapperFlush<Size4KiB> {
+        MapperFlush::new(Page::containing_address(VirtAddr::new(addr)))
+    }
+
+    fn ranges(batch: &FlushBatch) -> Vec<(u64, u64, u64)> {
+        batch.ranges[..batch.len]
+            .iter()
+            .map(|range| (range.first.as_u64(), range.last.as_u64(), range.step))
+            .collect()
+    }
+
+    #[test]
+    fn merges_contiguous_pages() {
+        let mut batch = FlushBatch::default();
+        assert!(batch.is_empty());
+        batch.add(flush_4kib(0x1000));
+        batch.add(flush_4kib(0x2000));
+        // not contiguous
+        batch.add(flush_4kib(0x4000));
+        // contiguous, but a different page size
+        batch.add(MapperFlush::<Size2MiB>::new(Page::containing_address(
+            VirtAddr::new(0x20_0000),
+        )));
+        assert_eq!(
+            ranges(&batch),
+            [
+                (0x1000, 0x2fff, 0x1000),
+                (0x4000, 0x4fff, 0x1000),
+                (0x20_0000, 0x3f_ffff, 0x20_0000)
+            ]
+        );
+        assert_eq!(batch.pages(), 4);
+        assert!(!batch.flushes_all());
+        batch.ignore();
+    }
+
+    #[test]
+    fn threshold() {
+        let mut batch = FlushBatch::new(4);
+        for i in 0..4 {
+            batch.add(flush_4kib(i * 0x1000));
+        }
+        assert!(!batch.flushes_all());
+        batch.add(flush_4kib(0x10_0000));
+        assert!(batch.flushes_all());
+        assert_eq!(batch.pages(), 5);
+        batch.ignore();
+    }
+
+    #[test]
+    fn capacity_overflow() {
+        let mut batch = FlushBatch::new(u64::MAX);
+        for i in 0..CAPACITY as u64 {
+            batch.add(flush_4kib(i * 0x2000));
+        }
+        assert!(!batch.flushes_all());
+        assert_eq!(batch.len, CAPACITY);
+        // contiguous to the last range, so it still fits
+        batch.add(flush_4kib((CAPACITY as u64 - 1) * 0x2000 + 0x1000));
+        assert!(!batch.flushes_all());
+        batch.add(flush_4kib(0x100_0000));
+        assert!(batch.flushes_all());
+        batch.ignore();
+    }
+
+    #[test]
+    fn add_all_and_ranges() {
+        let mut batch = FlushBatch::default();
+        batch.add_all(MapperFlushAll::new());
+        assert!(!batch.is_empty());
+        assert!(batch.flushes_all());
+        assert_eq!(batch.pages(), 0);
+        assert!(!batch.global);
+        batch.ignore();
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let mut batch = FlushBatch::default();
+        batch.add_range(MapperFlushRange::for_page(page, Size2MiB::SIZE));
+        assert_eq!(ranges(&batch), [(0x20_0000, 0x3f_ffff, 0x20_0000)]);
+        assert!(!batch.global);
+        // a range that flushes everything includes the global pages
+        batch.add_range(MapperFlushRange::for_page(page, Size4KiB::SIZE));
+        assert!(batch.flushes_all());
+        assert!(batch.global);
+        assert_eq!(batch.pages(), 513);
+        batch.ignore();
+    }
+
+    #[test]
+    fn include_global() {
+        let mut batch = FlushBatch::default();
+        batch.add(flush_4kib(0x1000));
+        assert!(!batch.global);
+        batch.include_global();
+        assert!(batch.global);
+        // the pages are still flushed individually
+        assert!(!batch.flushes_all());
+        batch.ignore();
+    }
+}
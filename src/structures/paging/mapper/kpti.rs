@@ -0,0 +1,594 @@
+#[cfg(feature = "instructions")]
+use crate::instructions::tlb::{self, InvPicdCommand, Pcid};
+#[cfg(feature = "instructions")]
+use crate::registers::control::Cr3;
+use crate::structures::paging::{
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::{
+        address_space::{check_user_page, KERNEL_HALF_START},
+        AddressSpace, AddressSpaceError, FlagUpdateError, MapToError, MappedPageTable, Mapper,
+        MapperFlush, PageTableFrameMapping, UnmapError, Walk, WalkMut, WalkResult,
+    },
+    Page, PageSize, PageTable, PageTableFlags, PageTableLevel, PhysFrame, Size4KiB,
+};
+use crate::VirtAddr;
+
+/// An [`AddressSpace`] with a second level 4 table for user mode, as used for kernel page-table
+/// isolation (KPTI).
+///
+/// As a mitigation for Meltdown, the kernel half is not mapped while user code runs. Instead,
+/// the CPU switches to a user level 4 table on every exit to user mode, and back to the kernel
+/// level 4 table on every entry to the kernel. The lower half of the user table has the same
+/// entries as the kernel table, so both share the same user page tables. The upper half of the
+/// user table only maps the kernel pages that are needed for entering and leaving the kernel,
+/// usually the entry/exit trampoline, the GDT, the IDT and the TSS. These pages are added
+/// through [`map_kernel_range`](Self::map_kernel_range).
+///
+/// The level 4 entries of the user table are kept in sync by the mapping methods of this type.
+/// The kernel half page tables of the user table are deallocated together with the user table
+/// when the address space is dropped.
+///
+/// With PCIDs, the two tables use different PCIDs, see [`KptiPcids`].
+#[derive(Debug)]
+pub struct KptiAddressSpace<P: PageTableFrameMapping, D: FrameDeallocator<Size4KiB>> {
+    space: AddressSpace<P, D>,
+    user_root: PhysFrame,
+}
+
+impl<P, D> KptiAddressSpace<P, D>
+where
+    P: PageTableFrameMapping,
+    D: FrameDeallocator<Size4KiB>,
+{
+    /// Adds the user level 4 table `user_root` to the given address space.
+    ///
+    /// The lower half of the user table is copied from the level 4 table of `space` and the
+    /// upper half is empty.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that `user_root` is an unused frame that can be deallocated
+    /// through the frame deallocator of `space`.
+    pub unsafe fn new(space: AddressSpace<P, D>, user_root: PhysFrame) -> Self {
+        let user_table = &mut *space.mapping().frame_to_pointer(user_root);
+        user_table.zero();
+        let mut kpti = KptiAddressSpace { space, user_root };
+        kpti.sync_user_half();
+        kpti
+    }
+
+    /// Returns the underlying address space, whose level 4 table is the kernel table.
+    #[inline]
+    pub fn address_space(&self) -> &AddressSpace<P, D> {
+        &self.space
+    }
+
+    /// Returns the frame of the kernel level 4 table.
+    #[inline]
+    pub fn kernel_root_frame(&self) -> PhysFrame {
+        self.space.root_frame()
+    }
+
+    /// Returns the frame of the user level 4 table.
+    #[inline]
+    pub fn user_root_frame(&self) -> PhysFrame {
+        self.user_root
+    }
+
+    /// Returns a reference to the user level 4 table.
+    #[inline]
+    pub fn user_level_4_table(&self) -> &PageTable {
+        // SAFETY: `user_root` was zeroed in `new` and is only modified through `&mut self`, and
+        // the mapping of the underlying address space is correct
+        unsafe { &*self.space.mapping().frame_to_pointer(self.user_root) }
+    }
+
+    /// Maps the kernel pages that contain the given range into the user table.
+    ///
+    /// The pages are mapped to the same frames, with the same flags and with the same memory
+    /// type as in the kernel table, using 4KiB pages. Pages that are already mapped to the same frame in the user table are
+    /// skipped, so objects that share a page can be added separately. The page tables that are
+    /// needed for the mappings are allocated from `frame_allocator`.
+    ///
+    /// If an error occurs, the pages before the failed page stay mapped.
+    ///
+    /// ## Safety
+    ///
+    /// Every page of the range is accessible from user mode on CPUs that are vulnerable to
+    /// Meltdown, so the caller must ensure that the range doesn't contain any secrets. The
+    /// frames of `frame_allocator` must be deallocatable through the frame deallocator of the
+    /// address space.
+    pub unsafe fn map_kernel_range<A>(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        frame_allocator: &mut A,
+    ) -> Result<(), KptiError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (len - 1));
+        for page in Page::range_inclusive(first, last) {
+            if check_user_page::<_, ()>(page).is_ok() {
+                return Err(KptiError::UserHalf(page.start_address()));
+            }
+            let (frame, flags, pat_index) = match self.space.walk(page.start_address()) {
+                WalkResult::Mapped { entry, level, .. } => {
+                    let offset =
+                        page.start_address().as_u64() & (level.entry_address_space_alignment() - 1);
+                    (
+                        PhysFrame::containing_address(entry.leaf_addr(level) + offset),
+                        // the `HUGE_PAGE` flag is the PAT bit for 4KiB pages
+                        entry.flags() & !(PageTableFlags::HUGE_PAGE | PageTableFlags::ACCESSED),
+                        entry.pat_index(level),
+                    )
+                }
+                WalkResult::NotMapped { .. } => {
+                    return Err(KptiError::NotMapped(page.start_address()))
+                }
+            };
+
+            let mut mapper = self.user_mapper();
+            match mapper.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                frame_allocator,
+            ) {
+                // the user table is not active, so no flush is needed
+                Ok(flush) => {
+                    mapper.walk_mut(page.start_address(), |entry, level| {
+                        entry.set_pat_index(level, pat_index)
+                    });
+                    flush.ignore()
+                }
+                Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+                Err(err) => return Err(KptiError::Mapper(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the given page of the lower half to the given frame in both level 4 tables, like
+    /// [`AddressSpace::map_to`].
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Mapper::map_to`] apply.
+    pub unsafe fn map_to<S, A>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<S>, AddressSpaceError<MapToError<S>>>
+    where
+        S: PageSize,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+        for<'a> MappedPageTable<'a, &'a P>: Mapper<S>,
+    {
+        let flush = self.space.map_to(page, frame, flags, frame_allocator)?;
+        // a new level 3 table might have been created
+        self.sync_user_entry(usize::from(page.p4_index()));
+        Ok(flush)
+    }
+
+    /// Removes the mapping of the given page of the lower half, like [`AddressSpace::unmap`].
+    ///
+    /// The page must be flushed for both PCIDs, see [`KptiPcids::flush_user_page`].
+    #[inline]
+    pub fn unmap<S>(
+        &mut self,
+        page: Page<S>,
+    ) -> Result<(PhysFrame<S>, MapperFlush<S>), AddressSpaceError<UnmapError>>
+    where
+        S: PageSize,
+        for<'a> MappedPageTable<'a, &'a P>: Mapper<S>,
+    {
+        // the level 4 entries are not changed, since page tables are not freed
+        self.space.unmap(page)
+    }
+
+    /// Updates the flags of the given page of the lower half, like
+    /// [`AddressSpace::update_flags`].
+    ///
+    /// The page must be flushed for both PCIDs, see [`KptiPcids::flush_user_page`].
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Mapper::update_flags`] apply.
+    #[inline]
+    pub unsafe fn update_flags<S>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<S>, AddressSpaceError<FlagUpdateError>>
+    where
+        S: PageSize,
+        for<'a> MappedPageTable<'a, &'a P>: Mapper<S>,
+    {
+        self.space.update_flags(page, flags)
+    }
+
+    /// Loads the kernel level 4 table into the CR3 register together with the kernel PCID.
+    ///
+    /// The TLB entries of the PCID are kept, since this is called on every entry to the
+    /// kernel. Changed mappings must be invalidated explicitly through
+    /// [`KptiPcids::flush_user_page`] or [`KptiPcids::flush_all`].
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`AddressSpace::activate_pcid`] apply. In addition, the
+    /// TLB entries of the kernel PCID must belong to this address space and must not be
+    /// stale, e.g. because [`KptiPcids::flush_all`] was called when the PCIDs were assigned to
+    /// the address space. See [`Cr3::write_pcid_no_flush`].
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn activate_kernel(&self, pcids: KptiPcids) {
+        Cr3::write_pcid_no_flush(self.space.root_frame(), pcids.kernel());
+    }
+
+    /// Loads the user level 4 table into the CR3 register together with the user PCID.
+    ///
+    /// Like for [`activate_kernel`](Self::activate_kernel), the TLB entries of the PCID are
+    /// kept.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`activate_kernel`](Self::activate_kernel) apply to the
+    /// user PCID. In addition, the currently executed code and stack must be mapped by
+    /// [`map_kernel_range`](Self::map_kernel_range), since the rest of the kernel half is not
+    /// mapped afterwards.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub unsafe fn activate_user(&self, pcids: KptiPcids) {
+        Cr3::write_pcid_no_flush(self.user_root, pcids.user());
+    }
+
+    /// Copies all level 4 entries of the lower half from the kernel table to the user table.
+    fn sync_user_half(&mut self) {
+        for index in 0..KERNEL_HALF_START {
+            self.sync_user_entry(index);
+        }
+    }
+
+    /// Copies the given level 4 entry from the kernel table to the user table.
+    fn sync_user_entry(&mut self, index: usize) {
+        debug_assert!(index < KERNEL_HALF_START);
+        let entry = self.space.level_4_table()[index].clone();
+        // SAFETY: the user table is owned by the address space and the mapping is correct
+        let user_table = unsafe { &mut *self.space.mapping().frame_to_pointer(self.user_root) };
+        user_table[index] = entry;
+    }
+
+    fn user_mapper(&mut self) -> MappedPageTable<'_, &P> {
+        let mapping = self.space.mapping();
+        // SAFETY: the user table is owned by the address space and the mapping is correct
+        unsafe { MappedPageTable::new(&mut *mapping.frame_to_pointer(self.user_root), mapping) }
+    }
+}
+
+impl<P, D> Drop for KptiAddressSpace<P, D>
+where
+    P: PageTableFrameMapping,
+    D: FrameDeallocator<Size4KiB>,
+{
+    fn drop(&mut self) {
+        // SAFETY: the kernel half tables of the user table belong to the address space, the
+        // tables of the lower half are freed by the `AddressSpace`
+        unsafe {
+            self.space
+                .free_tables(self.user_root, PageTableLevel::Four, KERNEL_HALF_START..512);
+            self.space.deallocate_frame(self.user_root);
+        }
+    }
+}
+
+/// An error indicating that [`KptiAddressSpace::map_kernel_range`] failed.
+#[derive(Debug)]
+pub enum KptiError {
+    /// The given page is in the lower half, which is shared by both level 4 tables anyway.
+    UserHalf(VirtAddr),
+    /// The given page is not mapped in the kernel level 4 table.
+    NotMapped(VirtAddr),
+    /// Mapping the page in the user level 4 table failed.
+    Mapper(MapToError<Size4KiB>),
+}
+
+/// The pair of PCIDs of a [`KptiAddressSpace`].
+///
+/// The user PCID is the kernel PCID with bit 11 set, so the kernel PCID must be below 2048.
+/// Switching between the tables keeps the TLB entries of both PCIDs, so changes of user
+/// mappings must be flushed for both PCIDs through [`flush_user_page`](Self::flush_user_page),
+/// while `invlpg` only flushes the active one.
+#[cfg(feature = "instructions")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KptiPcids {
+    kernel: Pcid,
+}
+
+#[cfg(feature = "instructions")]
+impl KptiPcids {
+    /// The bit that distinguishes the user PCID from the kernel PCID.
+    pub const USER_BIT: u16 = 1 << 11;
+
+    /// Creates the PCID pair for the given kernel PCID.
+    ///
+    /// Will result in a failure if the kernel PCID has the [`USER_BIT`](Self::USER_BIT) set.
+    pub const fn new(kernel: Pcid) -> Result<Self, &'static str> {
+        if kernel.value() & Self::USER_BIT != 0 {
+            Err("kernel PCID should be < 2048.")
+        } else {
+            Ok(KptiPcids { kernel })
+        }
+    }
+
+    /// Returns the PCID of the kernel level 4 table.
+    #[inline]
+    pub const fn kernel(self) -> Pcid {
+        self.kernel
+    }
+
+    /// Returns the PCID of the user level 4 table.
+    #[inline]
+    pub fn user(self) -> Pcid {
+        Pcid::new(self.kernel.value() | Self::USER_BIT).unwrap()
+    }
+
+    /// Invalidates the TLB entries of the given address for both PCIDs.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe as it requires CPUID.(EAX=07H, ECX=0H):EBX.INVPCID to be 1.
+    #[inline]
+    pub unsafe fn flush_user_page(self, addr: VirtAddr) {
+        tlb::flush_pcid(InvPicdCommand::Address(addr, self.kernel));
+        tlb::flush_pcid(InvPicdCommand::Address(addr, self.user()));
+    }
+
+    /// Invalidates all non-global TLB entries of both PCIDs.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe as it requires CPUID.(EAX=07H, ECX=0H):EBX.INVPCID to be 1.
+    #[inline]
+    pub unsafe fn flush_all(self) {
+        tlb::flush_pcid(InvPicdCommand::Single(self.kernel));
+        tlb::flush_pcid(InvPicdCommand::Single(self.user()));
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{
+        mapper::{SimulatedFrameAllocator, SimulatedMemory},
+        Size2MiB,
+    };
+    use crate::PhysAddr;
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::USER_ACCESSIBLE);
+    const KERNEL_ADDR: u64 = 0xffff_8000_0000_0000;
+    const KERNEL_HUGE_ADDR: u64 = KERNEL_ADDR + 0x20_0000;
+
+    /// Returns a memory whose level 4 table maps a kernel page with PAT index 5 and a kernel
+    /// 2MiB page with PAT index 6.
+    fn kernel_memory() -> SimulatedMemory {
+        let mut memory = SimulatedMemory::new(32);
+        let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::GLOBAL | PageTableFlags::ACCESSED;
+        unsafe {
+            mapper
+                .map_to(
+                    SimulatedMemory::page(KERNEL_ADDR),
+                    SimulatedMemory::frame(0x10_0000),
+                    flags,
+                    &mut frame_allocator,
+                )
+                .unwrap()
+                .ignore();
+            mapper
+                .map_to(
+                    Page::<Size2MiB>::containing_address(VirtAddr::new(KERNEL_HUGE_ADDR)),
+                    PhysFrame::containing_address(PhysAddr::new(0x4000_0000)),
+                    flags,
+                    &mut frame_allocator,
+                )
+                .unwrap()
+                .ignore();
+            mapper.walk_mut(VirtAddr::new(KERNEL_ADDR), |entry, level| {
+                entry.set_pat_index(level, 5)
+            });
+            mapper.walk_mut(VirtAddr::new(KERNEL_HUGE_ADDR), |entry, level| {
+                entry.set_pat_index(level, 6)
+            });
+        }
+        memory
+    }
+
+    fn kpti(
+        memory: &SimulatedMemory,
+    ) -> KptiAddressSpace<&SimulatedMemory, SimulatedFrameAllocator<'_>> {
+        let mut frame_allocator = memory.frame_allocator();
+        let root = frame_allocator.allocate_frame().unwrap();
+        let user_root = frame_allocator.allocate_frame().unwrap();
+        let template = unsafe { &*memory.frame_to_pointer(memory.root_frame()) };
+        unsafe {
+            let space = AddressSpace::new(root, template, memory, frame_allocator);
+            KptiAddressSpace::new(space, user_root)
+        }
+    }
+
+    /// Walks the user level 4 table of the given address space.
+    fn user_walk(
+        memory: &SimulatedMemory,
+        kpti: &KptiAddressSpace<&SimulatedMemory, SimulatedFrameAllocator<'_>>,
+        addr: u64,
+    ) -> WalkResult {
+        let user_table = unsafe { &mut *memory.frame_to_pointer(kpti.user_root_frame()) };
+        let mapper = unsafe { MappedPageTable::new(user_table, memory) };
+        mapper.walk(VirtAddr::new(addr))
+    }
+
+    #[test]
+    fn map_kernel_range_copies_mappings() {
+        let memory = kernel_memory();
+        let mut kpti = kpti(&memory);
+        let mut frame_allocator = memory.frame_allocator();
+        assert!(matches!(
+            user_walk(&memory, &kpti, KERNEL_ADDR),
+            WalkResult::NotMapped { .. }
+        ));
+
+        unsafe {
+            kpti.map_kernel_range(
+                VirtAddr::new(KERNEL_ADDR + 0x800),
+                0x10,
+                &mut frame_allocator,
+            )
+            .unwrap();
+            // the pages of the huge page are mapped with 4KiB pages
+            kpti.map_kernel_range(
+                VirtAddr::new(KERNEL_HUGE_ADDR + 0x1800),
+                0x1000,
+                &mut frame_allocator,
+            )
+            .unwrap();
+            // pages that are already mapped are skipped
+            kpti.map_kernel_range(VirtAddr::new(KERNEL_ADDR), 0x1000, &mut frame_allocator)
+                .unwrap();
+        }
+
+        let expected = [
+            (KERNEL_ADDR, 0x10_0000, 5),
+            (KERNEL_HUGE_ADDR + 0x1000, 0x4000_1000, 6),
+            (KERNEL_HUGE_ADDR + 0x2000, 0x4000_2000, 6),
+        ];
+        for &(addr, frame, pat_index) in expected.iter() {
+            match user_walk(&memory, &kpti, addr) {
+                WalkResult::Mapped { entry, level, .. } => {
+                    assert_eq!(level, PageTableLevel::One);
+                    assert_eq!(entry.addr(), PhysAddr::new(frame));
+                    assert_eq!(entry.pat_index(level), pat_index);
+                    let flags = entry.flags();
+                    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::GLOBAL));
+                    assert!(!flags.contains(PageTableFlags::ACCESSED));
+                }
+                WalkResult::NotMapped { .. } => panic!("{:#x} is not mapped", addr),
+            }
+        }
+        assert!(matches!(
+            user_walk(&memory, &kpti, KERNEL_HUGE_ADDR),
+            WalkResult::NotMapped { .. }
+        ));
+    }
+
+    #[test]
+    fn map_kernel_range_errors() {
+        let memory = kernel_memory();
+        let mut kpti = kpti(&memory);
+        let mut frame_allocator = memory.frame_allocator();
+
+        assert!(matches!(
+            unsafe { kpti.map_kernel_range(VirtAddr::new(0x1000), 0x10, &mut frame_allocator) },
+            Err(KptiError::UserHalf(addr)) if addr.as_u64() == 0x1000
+        ));
+        // the first page is mapped before the error
+        assert!(matches!(
+            unsafe {
+                kpti.map_kernel_range(VirtAddr::new(KERNEL_ADDR), 0x2000, &mut frame_allocator)
+            },
+            Err(KptiError::NotMapped(addr)) if addr.as_u64() == KERNEL_ADDR + 0x1000
+        ));
+        assert!(matches!(
+            user_walk(&memory, &kpti, KERNEL_ADDR),
+            WalkResult::Mapped { .. }
+        ));
+    }
+
+    #[test]
+    fn map_to_syncs_user_table() {
+        let memory = kernel_memory();
+        let mut kpti = kpti(&memory);
+        let mut frame_allocator = memory.frame_allocator();
+
+        // the page needs a new level 3 table
+        let addr = 0x80_0000_0000;
+        let page = SimulatedMemory::page(addr);
+        unsafe {
+            kpti.map_to(
+                page,
+                SimulatedMemory::frame(0x1000),
+                FLAGS,
+                &mut frame_allocator,
+            )
+        }
+        .unwrap()
+        .ignore();
+
+        let index = usize::from(page.p4_index());
+        let kernel_entry = &kpti.address_space().level_4_table()[index];
+        let user_entry = &kpti.user_level_4_table()[index];
+        assert!(!user_entry.is_unused());
+        assert_eq!(user_entry.addr(), kernel_entry.addr());
+        assert_eq!(user_entry.flags(), kernel_entry.flags());
+        assert!(matches!(
+            user_walk(&memory, &kpti, addr),
+            WalkResult::Mapped { entry, .. } if entry.addr().as_u64() == 0x1000
+        ));
+        // the kernel half is not shared
+        assert!(kpti.user_level_4_table()[KERNEL_HALF_START].is_unused());
+    }
+
+    #[test]
+    fn drop_frees_user_tables() {
+        let mut memory = kernel_memory();
+        let allocated = memory.allocated_frames();
+        {
+            let mut kpti = kpti(&memory);
+            let mut frame_allocator = memory.frame_allocator();
+            unsafe {
+                kpti.map_kernel_range(VirtAddr::new(KERNEL_ADDR), 0x1000, &mut frame_allocator)
+                    .unwrap();
+                kpti.map_to(
+                    SimulatedMemory::page(0x1000),
+                    SimulatedMemory::frame(0x1000),
+                    FLAGS,
+                    &mut frame_allocator,
+                )
+                .unwrap()
+                .ignore();
+            }
+            // the two level 4 tables, three kernel half tables of the user table and three
+            // user tables
+            assert_eq!(memory.allocated_frames(), allocated + 8);
+        }
+
+        // all tables of both level 4 tables were freed, the kernel tables are kept
+        assert_eq!(memory.allocated_frames(), allocated);
+        memory.assert_mapped(
+            VirtAddr::new(KERNEL_ADDR),
+            PhysAddr::new(0x10_0000),
+            PageTableFlags::PRESENT | PageTableFlags::GLOBAL | PageTableFlags::ACCESSED,
+        );
+    }
+
+    #[cfg(feature = "instructions")]
+    #[test]
+    fn pcids() {
+        let pcids = KptiPcids::new(Pcid::new(5).unwrap()).unwrap();
+        assert_eq!(pcids.kernel().value(), 5);
+        assert_eq!(pcids.user().value(), 5 | KptiPcids::USER_BIT);
+
+        let pcids = KptiPcids::new(Pcid::new(2047).unwrap()).unwrap();
+        assert_eq!(pcids.user().value(), 4095);
+        assert!(KptiPcids::new(Pcid::new(2048).unwrap()).is_err());
+    }
+}
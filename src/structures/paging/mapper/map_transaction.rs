@@ -0,0 +1,609 @@
+use crate::structures::paging::{
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::{
+        leaf_level, MapToError, Mapper, MapperAllSizes, MapperFlushRange, WalkMut, WalkResult,
+    },
+    Page, PageSize, PageTableFlags, PageTableLevel, PagingMode, PhysFrame, Size1GiB, Size2MiB,
+    Size4KiB,
+};
+use crate::{PhysAddr, VirtAddr};
+
+/// The low bits of the log words, which are used for tagging the kind of the change.
+const TAG_MASK: u64 = 0xfff;
+/// The tag of log words that record a new page table.
+const TABLE_TAG: u64 = TAG_MASK;
+/// The tag of log words that record the flags that were added to an existing parent entry.
+///
+/// The level of the table that contains the entry is stored in bits 4 to 6 of the word and
+/// the added flags in bits 0 to 2.
+const PARENT_TAG: u64 = 0x800;
+
+/// Maps multiple pages as a single unit that can be reverted.
+///
+/// A transaction records every page that it maps, every page table that the mapper allocates
+/// for it, and the old flags of every existing parent entry to which the mapper adds the
+/// `WRITABLE` or `USER_ACCESSIBLE` flag. If a mapping fails, e.g. because the frame allocator
+/// is out of memory or because a page is already mapped, [`rollback`](Self::rollback) removes
+/// all mappings of the transaction again, deallocates the page tables that were created for
+/// them, and restores the flags of the parent entries. Otherwise, [`commit`](Self::commit)
+/// keeps the mappings. Both methods return a single flush for all pages of the transaction.
+///
+/// The changes are recorded in a log that is passed by the caller, so no heap is needed.
+/// Each mapped page, each new page table and each changed parent entry takes one word of the
+/// log, so a transaction of `n` pages needs at most `n * 5` words with 5-level paging and
+/// `n * 4` words with 4-level paging, but usually much less since the pages share their page
+/// tables.
+///
+/// ```ignore
+/// let mut log = [0; 64];
+/// let mut transaction = MapTransaction::new(&mut mapper, &mut frame_allocator, &mut log);
+/// for (page, frame) in pages.zip(frames) {
+///     if let Err(err) = unsafe { transaction.map_to(page, frame, flags) } {
+///         transaction.rollback().flush();
+///         return Err(err);
+///     }
+/// }
+/// transaction.commit().flush();
+/// ```
+#[derive(Debug)]
+#[must_use = "Transactions must be committed or rolled back."]
+pub struct MapTransaction<'a, M, A: ?Sized> {
+    mapper: &'a mut M,
+    frame_allocator: &'a mut A,
+    /// The start addresses of the mapped pages, the frames of the new page tables, and the
+    /// changed parent entries.
+    ///
+    /// The page size of a mapped page is stored in the low bits of its address, see
+    /// `size_tag`. The page tables that were created for a page are stored before it, in the
+    /// order in which they were allocated, and the parent entries that were changed for it
+    /// are stored before its page tables, see `PARENT_TAG`.
+    log: &'a mut [u64],
+    len: usize,
+    /// The pages of the transaction.
+    flush: MapperFlushRange,
+}
+
+impl<'a, M, A> MapTransaction<'a, M, A>
+where
+    M: MapperAllSizes + WalkMut,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+{
+    /// Starts a new transaction that records its changes in `log`.
+    ///
+    /// The previous content of `log` is ignored.
+    #[inline]
+    pub fn new(mapper: &'a mut M, frame_allocator: &'a mut A, log: &'a mut [u64]) -> Self {
+        MapTransaction {
+            mapper,
+            frame_allocator,
+            log,
+            len: 0,
+            flush: MapperFlushRange::new(),
+        }
+    }
+
+    /// Returns whether the transaction doesn't contain any changes yet.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of words of the log that are used.
+    #[inline]
+    pub fn log_len(&self) -> usize {
+        self.len
+    }
+
+    /// Maps the given page to the given frame as part of the transaction.
+    ///
+    /// The page tables that are missing for the mapping are allocated from the frame
+    /// allocator of the transaction, like for [`Mapper::map_to`]. If the mapping fails, the
+    /// page tables that were allocated for it are deallocated again, but the previous
+    /// mappings of the transaction are kept until it is rolled back.
+    ///
+    /// Returns [`MapTransactionError::LogFull`] if the log has no space for recording the
+    /// changes.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`Mapper::map_to`] apply. They only need to hold if the
+    /// transaction is committed, since the mappings can't be used before.
+    pub unsafe fn map_to<S>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapTransactionError<S>>
+    where
+        S: PageSize,
+        M: Mapper<S>,
+    {
+        if self.len >= self.log.len() {
+            return Err(MapTransactionError::LogFull);
+        }
+        let parents_start = self.len;
+        let parent_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE);
+        if !self.record_parents(page.start_address(), leaf_level(S::SIZE), parent_flags) {
+            self.len = parents_start;
+            return Err(MapTransactionError::LogFull);
+        }
+
+        let mut recorder = Recorder {
+            frame_allocator: &mut *self.frame_allocator,
+            log: &mut *self.log,
+            len: self.len,
+            log_full: false,
+        };
+        let result = self.mapper.map_to(page, frame, flags, &mut recorder);
+        let (tables_end, log_full) = (recorder.len, recorder.log_full);
+        let tables = tables_end - self.len;
+        if tables > 0 || result.is_ok() {
+            self.flush.add(page.start_address(), S::SIZE);
+        }
+
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                self.log[tables_end] = page.start_address().as_u64() | size_tag(S::SIZE);
+                self.len = tables_end + 1;
+                Ok(())
+            }
+            Err(err) => {
+                self.free_tables(page.start_address(), tables);
+                self.restore_parents(parents_start);
+                if log_full {
+                    Err(MapTransactionError::LogFull)
+                } else {
+                    Err(err.into())
+                }
+            }
+        }
+    }
+
+    /// Keeps all mappings of the transaction.
+    ///
+    /// The returned flush covers all pages of the transaction.
+    #[inline]
+    pub fn commit(self) -> MapperFlushRange {
+        self.flush
+    }
+
+    /// Removes all mappings of the transaction, deallocates the page tables that were
+    /// created for them, and restores the flags of the changed parent entries.
+    ///
+    /// The pages might have been accessed since they were mapped, so the returned flush
+    /// covers all of them. It also invalidates the paging-structure caches for the freed page
+    /// tables, so it must be flushed before the page table frames are reused. If the flags of
+    /// a parent entry were restored, the complete TLB is flushed, since other pages below the
+    /// entry might have been cached with the added flags.
+    pub fn rollback(mut self) -> MapperFlushRange {
+        while self.len > 0 {
+            self.len -= 1;
+            let word = self.log[self.len];
+            let addr = self.word_addr(word);
+            match word & TAG_MASK {
+                0 => unmap_page::<M, Size4KiB>(self.mapper, addr),
+                1 => unmap_page::<M, Size2MiB>(self.mapper, addr),
+                _ => unmap_page::<M, Size1GiB>(self.mapper, addr),
+            }
+
+            let tables = self.log[..self.len]
+                .iter()
+                .rev()
+                .take_while(|&&word| word & TAG_MASK == TABLE_TAG)
+                .count();
+            self.len -= tables;
+            self.free_tables(addr, tables);
+
+            let parents = self.log[..self.len]
+                .iter()
+                .rev()
+                .take_while(|&&word| is_parent_word(word))
+                .count();
+            self.restore_parents(self.len - parents);
+        }
+        self.flush
+    }
+
+    /// Returns the virtual address that is stored in the given log word.
+    ///
+    /// The address is sign extended according to the paging mode of the mapper, since bit 47
+    /// belongs to the lower half for 5-level paging.
+    fn word_addr(&self, word: u64) -> VirtAddr {
+        match self.mapper.paging_mode() {
+            PagingMode::Level4 => VirtAddr::new_truncate(word & !TAG_MASK),
+            PagingMode::Level5 => VirtAddr::new_truncate_la57(word & !TAG_MASK),
+        }
+    }
+
+    /// Records the existing parent entries of the page at `addr` to which `Mapper::map_to`
+    /// adds flags of `parent_flags`, starting at the highest level.
+    ///
+    /// Returns `false` if the log has no space left, keeping a word for the mapped page.
+    fn record_parents(
+        &mut self,
+        addr: VirtAddr,
+        leaf_level: PageTableLevel,
+        parent_flags: PageTableFlags,
+    ) -> bool {
+        let mut level = self.mapper.paging_mode().root_level();
+        while level > leaf_level {
+            // SAFETY: the entry is not modified
+            let (entry_level, flags) = unsafe {
+                self.mapper
+                    .walk_mut_to_level(addr, level, |entry, entry_level| {
+                        (entry_level, entry.flags())
+                    })
+            };
+            if entry_level == level {
+                // the mapper creates the tables below non-present entries and fails at huge
+                // pages, but it adds the flags to both present tables and huge pages
+                if !flags.contains(PageTableFlags::PRESENT) {
+                    break;
+                }
+                let added = parent_flags - flags;
+                if !added.is_empty() {
+                    if self.len + 1 >= self.log.len() {
+                        return false;
+                    }
+                    self.log[self.len] = (addr.as_u64() & !TAG_MASK)
+                        | PARENT_TAG
+                        | ((level as u64) << 4)
+                        | added.bits();
+                    self.len += 1;
+                }
+                if flags.contains(PageTableFlags::HUGE_PAGE) {
+                    break;
+                }
+            } else if entry_level > level {
+                break;
+            }
+            level = level.next_lower_level().unwrap();
+        }
+        true
+    }
+
+    /// Removes the flags that were added to the parent entries that are recorded at
+    /// `log[start..len]` and truncates the log to `start`.
+    fn restore_parents(&mut self, start: usize) {
+        while self.len > start {
+            self.len -= 1;
+            let word = self.log[self.len];
+            let addr = self.word_addr(word);
+            let level = match (word >> 4) & 0x7 {
+                2 => PageTableLevel::Two,
+                3 => PageTableLevel::Three,
+                4 => PageTableLevel::Four,
+                _ => PageTableLevel::Five,
+            };
+            let added = PageTableFlags::from_bits_truncate(word & 0x7);
+            // SAFETY: the flags were added by the transaction, so removing them again restores
+            // the previous mappings
+            unsafe {
+                self.mapper
+                    .walk_mut_to_level(addr, level, |entry, entry_level| {
+                        assert!(
+                            entry_level == level,
+                            "parent entry of the transaction was modified"
+                        );
+                        entry.set_flags(entry.flags() - added);
+                    });
+            }
+            self.flush.add_all();
+        }
+    }
+
+    /// Unlinks and deallocates the `tables` page tables that were allocated for the page at
+    /// `addr` and that are recorded at `log[len..len + tables]`.
+    ///
+    /// The tables must be empty.
+    fn free_tables(&mut self, addr: VirtAddr, tables: usize) {
+        if tables == 0 {
+            return;
+        }
+        // the new tables form a chain that ends at the first non-present entry
+        let mut level = match self.mapper.walk(addr) {
+            WalkResult::NotMapped { level, .. } => level,
+            WalkResult::Mapped { .. } => panic!("page of the transaction is still mapped"),
+        };
+        for _ in 0..tables {
+            level = level
+                .next_higher_level()
+                .expect("too many page tables for the page");
+        }
+
+        let first_table = PhysAddr::new(self.log[self.len] & !TAG_MASK);
+        // SAFETY: the entry points to a table that was created by the transaction, so it isn't
+        // used for any other mappings
+        unsafe {
+            self.mapper
+                .walk_mut_to_level(addr, level, |entry, entry_level| {
+                    assert!(
+                        entry_level == level && entry.addr() == first_table,
+                        "page table of the transaction was modified"
+                    );
+                    entry.set_unused();
+                });
+        }
+
+        for &word in &self.log[self.len..self.len + tables] {
+            let frame = PhysFrame::containing_address(PhysAddr::new(word & !TAG_MASK));
+            // SAFETY: the tables are unlinked and were allocated from this allocator
+            unsafe { self.frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// An error indicating that a `MapTransaction::map_to` call failed.
+///
+/// The page tables that were allocated for the failed mapping are already deallocated again.
+#[derive(Debug)]
+pub enum MapTransactionError<S: PageSize> {
+    /// An additional frame was needed for the mapping process, but the frame allocator
+    /// returned `None`.
+    FrameAllocationFailed,
+    /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
+    /// given page is part of an already mapped huge page.
+    ParentEntryHugePage,
+    /// The given page is already mapped to a physical frame.
+    PageAlreadyMapped(PhysFrame<S>),
+    /// The log of the transaction has no space for recording the changes.
+    LogFull,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapTransactionError<S> {
+    #[inline]
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MapTransactionError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapTransactionError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => MapTransactionError::PageAlreadyMapped(frame),
+        }
+    }
+}
+
+/// Returns whether the given log word records a changed parent entry.
+fn is_parent_word(word: u64) -> bool {
+    word & TAG_MASK != TABLE_TAG && word & PARENT_TAG != 0
+}
+
+/// Returns the tag for the low bits of the log word of a page of the given size.
+fn size_tag(size: u64) -> u64 {
+    match size {
+        Size4KiB::SIZE => 0,
+        Size2MiB::SIZE => 1,
+        _ => 2,
+    }
+}
+
+fn unmap_page<M, S>(mapper: &mut M, addr: VirtAddr)
+where
+    M: Mapper<S> + ?Sized,
+    S: PageSize,
+{
+    match mapper.unmap(Page::containing_address(addr)) {
+        Ok((_, flush)) => flush.ignore(),
+        Err(err) => panic!("failed to unmap page of the transaction: {:?}", err),
+    }
+}
+
+/// A frame allocator that records the allocated frames in the log of a transaction.
+struct Recorder<'b, A: ?Sized> {
+    frame_allocator: &'b mut A,
+    log: &'b mut [u64],
+    len: usize,
+    log_full: bool,
+}
+
+unsafe impl<'b, A> FrameAllocator<Size4KiB> for Recorder<'b, A>
+where
+    A: FrameAllocator<Size4KiB> + ?Sized,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // the last word is reserved for the mapped page
+        if self.len + 1 >= self.log.len() {
+            self.log_full = true;
+            return None;
+        }
+        let frame = self.frame_allocator.allocate_frame()?;
+        self.log[self.len] = frame.start_address().as_u64() | TABLE_TAG;
+        self.len += 1;
+        Some(frame)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{PageTableFrameMapping, SimulatedMemory, Translate};
+
+    const USER_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::USER_ACCESSIBLE);
+
+    /// Returns the entries of all page tables that are reachable from the root table of the
+    /// given level.
+    fn tables(memory: &SimulatedMemory, root_level: PageTableLevel) -> Vec<Vec<u64>> {
+        fn visit(
+            memory: &SimulatedMemory,
+            frame: PhysFrame,
+            level: PageTableLevel,
+            tables: &mut Vec<Vec<u64>>,
+        ) {
+            let table = unsafe { &*memory.frame_to_pointer(frame) };
+            tables.push(
+                table
+                    .iter()
+                    .map(|entry| entry.addr().as_u64() | entry.flags().bits())
+                    .collect(),
+            );
+            for entry in table.iter() {
+                if let (Ok(frame), Some(next_level)) = (entry.frame(), level.next_lower_level()) {
+                    visit(memory, frame, next_level, tables);
+                }
+            }
+        }
+
+        let mut tables = Vec::new();
+        visit(memory, memory.root_frame(), root_level, &mut tables);
+        tables
+    }
+
+    #[test]
+    fn rollback_restores_parent_flags() {
+        // the level 4 table and three tables for the existing page, one frame is left
+        let mut memory = SimulatedMemory::new(5);
+        memory.map(
+            SimulatedMemory::page(0x1000),
+            SimulatedMemory::frame(0x10_0000),
+            PageTableFlags::PRESENT,
+        );
+        let before = tables(&memory, PageTableLevel::Four);
+        let allocated = memory.allocated_frames();
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let mut log = [0; 16];
+            let mut transaction = MapTransaction::new(&mut mapper, &mut frame_allocator, &mut log);
+            unsafe {
+                transaction
+                    .map_to(
+                        SimulatedMemory::page(0x2000),
+                        SimulatedMemory::frame(0x20_0000),
+                        USER_FLAGS,
+                    )
+                    .unwrap();
+                // the three parent entries and the page
+                assert_eq!(transaction.log_len(), 4);
+                // a level 2 and a level 1 table are needed, so the second allocation fails
+                assert!(matches!(
+                    transaction.map_to(
+                        SimulatedMemory::page(0x4000_0000),
+                        SimulatedMemory::frame(0x30_0000),
+                        USER_FLAGS
+                    ),
+                    Err(MapTransactionError::FrameAllocationFailed)
+                ));
+            }
+            assert_eq!(transaction.log_len(), 4);
+            let flush = transaction.rollback();
+            assert!(flush.flushes_all());
+            flush.ignore();
+        }
+
+        assert_eq!(tables(&memory, PageTableLevel::Four), before);
+        assert_eq!(memory.allocated_frames(), allocated);
+        memory.assert_not_mapped(VirtAddr::new(0x2000));
+    }
+
+    #[test]
+    fn rollback_level_5() {
+        let mut memory = SimulatedMemory::new(16);
+        // bit 47 of the addresses is part of the lower half
+        let existing = Page::containing_address(VirtAddr::new_la57(0x8000_0000_1000));
+        let page = Page::containing_address(VirtAddr::new_la57(0x8000_0000_2000));
+        let huge_page =
+            Page::<Size2MiB>::containing_address(VirtAddr::new_la57(0x00ab_cdef_0000_0000));
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_level_5_page_table();
+            unsafe {
+                mapper.map_to(
+                    existing,
+                    SimulatedMemory::frame(0x10_0000),
+                    PageTableFlags::PRESENT,
+                    &mut frame_allocator,
+                )
+            }
+            .unwrap()
+            .ignore();
+        }
+        let before = tables(&memory, PageTableLevel::Five);
+        let allocated = memory.allocated_frames();
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_level_5_page_table();
+            let mut log = [0; 16];
+            let mut transaction = MapTransaction::new(&mut mapper, &mut frame_allocator, &mut log);
+            unsafe {
+                transaction
+                    .map_to(page, SimulatedMemory::frame(0x20_0000), USER_FLAGS)
+                    .unwrap();
+                let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+                transaction.map_to(huge_page, frame, USER_FLAGS).unwrap();
+            }
+            transaction.rollback().ignore();
+            assert_eq!(mapper.translate_addr(page.start_address()), None);
+            assert_eq!(mapper.translate_addr(huge_page.start_address()), None);
+        }
+
+        assert_eq!(tables(&memory, PageTableLevel::Five), before);
+        assert_eq!(memory.allocated_frames(), allocated);
+    }
+
+    #[test]
+    fn failed_mapping_restores_parent_flags() {
+        let mut memory = SimulatedMemory::new(4);
+        memory.map(
+            Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000)),
+            PhysFrame::containing_address(PhysAddr::new(0x20_0000)),
+            PageTableFlags::PRESENT,
+        );
+        let before = tables(&memory, PageTableLevel::Four);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let mut log = [0; 16];
+            let mut transaction = MapTransaction::new(&mut mapper, &mut frame_allocator, &mut log);
+            // the flags are added to the huge page entry before the mapping fails
+            assert!(matches!(
+                unsafe {
+                    transaction.map_to(
+                        SimulatedMemory::page(0x20_1000),
+                        SimulatedMemory::frame(0x1000),
+                        USER_FLAGS,
+                    )
+                },
+                Err(MapTransactionError::ParentEntryHugePage)
+            ));
+            assert!(transaction.is_empty());
+            let flush = transaction.rollback();
+            assert!(flush.flushes_all());
+            flush.ignore();
+        }
+
+        assert_eq!(tables(&memory, PageTableLevel::Four), before);
+        assert_eq!(memory.allocated_frames(), 3);
+    }
+
+    #[test]
+    fn commit_keeps_parent_flags() {
+        let mut memory = SimulatedMemory::new(8);
+        memory.map(
+            SimulatedMemory::page(0x1000),
+            SimulatedMemory::frame(0x10_0000),
+            PageTableFlags::PRESENT,
+        );
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let mut log = [0; 16];
+            let mut transaction = MapTransaction::new(&mut mapper, &mut frame_allocator, &mut log);
+            unsafe {
+                transaction.map_to(
+                    SimulatedMemory::page(0x2000),
+                    SimulatedMemory::frame(0x20_0000),
+                    USER_FLAGS,
+                )
+            }
+            .unwrap();
+            let flush = transaction.commit();
+            assert!(!flush.flushes_all());
+            flush.ignore();
+        }
+
+        memory.assert_mapped(VirtAddr::new(0x2000), PhysAddr::new(0x20_0000), USER_FLAGS);
+    }
+}
@@ -1,9 +1,9 @@
 use crate::structures::paging::{
     frame::PhysFrame,
-    frame_alloc::FrameAllocator,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
     mapper::*,
-    page::{AddressNotAligned, Page, Size1GiB, Size2MiB, Size4KiB},
-    page_table::{FrameError, PageTable, PageTableEntry, PageTableFlags},
+    page::{AddressNotAligned, Page, PageRangeInclusive, Size1GiB, Size2MiB, Size4KiB},
+    page_table::{FrameError, PageTable, PageTableEntry, PageTableFlags, PageTableLevel},
 };
 
 /// A Mapper implementation that relies on a PhysAddr to VirtAddr conversion function.
@@ -42,6 +42,49 @@ impl<'a, P: PageTableFrameMapping> MappedPageTable<'a, P> {
         &mut self.level_4_table
     }
 
+    /// Creates a copy of this page table hierarchy, e.g. for implementing `fork`.
+    ///
+    /// The entries of the upper (kernel) half of the level 4 table are copied, so that both
+    /// hierarchies share the same level 3 tables. All page tables of the lower (user) half are
+    /// copied, but both hierarchies map the same frames afterwards. An entry of the level 4
+    /// table that points to the table itself (i.e. a recursive entry) is updated to point to
+    /// the new table.
+    ///
+    /// If a `cow_flag` is given, all present leaf entries of the lower half that have both the
+    /// `WRITABLE` and the `USER_ACCESSIBLE` flag set are made read-only in both hierarchies and
+    /// marked with the given flag, which should be one of the bits that are available for
+    /// software use (e.g. `BIT_9`). A write to such a page then causes a page fault, which can
+    /// copy the frame and restore the `WRITABLE` flag. Since the entries of this page table are
+    /// changed too, the returned `MapperFlushAll` must be flushed if this page table is active.
+    ///
+    /// The new page tables are allocated from the given frame allocator. If an allocation fails,
+    /// all new page tables are deallocated again and this page table is left unchanged.
+    ///
+    /// Returns the frame of the new level 4 table, which can be loaded through `Cr3::write`.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the frames that are mapped by both hierarchies are not
+    /// deallocated while they are still in use by one of them. The shared kernel half tables
+    /// must stay valid as long as any of the hierarchies is used. Also, the page table must not
+    /// be modified concurrently.
+    pub unsafe fn clone_address_space<A>(
+        &mut self,
+        cow_flag: Option<PageTableFlags>,
+        frame_allocator: &mut A,
+    ) -> Result<(PhysFrame, MapperFlushAll), CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let frame = self.page_table_walker.clone_hierarchy(
+            self.level_4_table,
+            PageTableLevel::Four,
+            cow_flag,
+            frame_allocator,
+        )?;
+        Ok((frame, MapperFlushAll::new()))
+    }
+
     /// Helper function for implementing Mapper. Safe to limit the scope of unsafe, see
     /// https://github.com/rust-lang/rfcs/pull/2585.
     fn map_to_1gib<A>(
@@ -177,7 +220,7 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size1GiB> for MappedPageTable<'a, P> {
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p3_entry.addr())
+        let frame = PhysFrame::from_start_address(p3_entry.leaf_addr(PageTableLevel::Three))
             .map_err(|AddressNotAligned| UnmapError::InvalidFrameAddress(p3_entry.addr()))?;
 
         p3_entry.set_unused();
@@ -236,17 +279,8 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size1GiB> for MappedPageTable<'a, P> {
     }
 
     fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
-        let p4 = &self.level_4_table;
-        let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
-
-        let p3_entry = &p3[page.p3_index()];
-
-        if p3_entry.is_unused() {
-            return Err(TranslateError::PageNotMapped);
-        }
-
-        PhysFrame::from_start_address(p3_entry.addr())
-            .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p3_entry.addr()))
+        self.page_table_walker
+            .translate_page_1gib(self.level_4_table, page)
     }
 }
 
@@ -288,7 +322,7 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p2_entry.addr())
+        let frame = PhysFrame::from_start_address(p2_entry.leaf_addr(PageTableLevel::Two))
             .map_err(|AddressNotAligned| UnmapError::InvalidFrameAddress(p2_entry.addr()))?;
 
         p2_entry.set_unused();
@@ -363,18 +397,8 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
     }
 
     fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
-        let p4 = &self.level_4_table;
-        let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
-        let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
-
-        let p2_entry = &p2[page.p2_index()];
-
-        if p2_entry.is_unused() {
-            return Err(TranslateError::PageNotMapped);
-        }
-
-        PhysFrame::from_start_address(p2_entry.addr())
-            .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p2_entry.addr()))
+        self.page_table_walker
+            .translate_page_2mib(self.level_4_table, page)
     }
 }
 
@@ -411,10 +435,11 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for MappedPageTable<'a, P> {
 
         let p1_entry = &mut p1[page.p1_index()];
 
-        let frame = p1_entry.frame().map_err(|err| match err {
-            FrameError::FrameNotPresent => UnmapError::PageNotMapped,
-            FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
-        })?;
+        if !p1_entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        // the `HUGE_PAGE` bit is the PAT bit in level 1 entries
+        let frame = PhysFrame::containing_address(p1_entry.addr());
 
         p1_entry.set_unused();
         Ok((frame, MapperFlush::new(page)))
@@ -506,203 +531,1190 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for MappedPageTable<'a, P> {
     }
 
     fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
-        let p4 = &self.level_4_table;
-        let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
-        let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
-        let p1 = self.page_table_walker.next_table(&p2[page.p2_index()])?;
-
-        let p1_entry = &p1[page.p1_index()];
-
-        if p1_entry.is_unused() {
-            return Err(TranslateError::PageNotMapped);
-        }
-
-        PhysFrame::from_start_address(p1_entry.addr())
-            .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p1_entry.addr()))
+        self.page_table_walker
+            .translate_page_4kib(self.level_4_table, page)
     }
 }
 
 impl<'a, P: PageTableFrameMapping> Translate for MappedPageTable<'a, P> {
-    #[allow(clippy::inconsistent_digit_grouping)]
+    #[inline]
     fn translate(&self, addr: VirtAddr) -> TranslateResult {
-        let p4 = &self.level_4_table;
-        let p3 = match self.page_table_walker.next_table(&p4[addr.p4_index()]) {
-            Ok(page_table) => page_table,
-            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
-            Err(PageTableWalkError::MappedToHugePage) => {
-                panic!("level 4 entry has huge page bit set")
-            }
-        };
-        let p2 = match self.page_table_walker.next_table(&p3[addr.p3_index()]) {
-            Ok(page_table) => page_table,
-            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
-            Err(PageTableWalkError::MappedToHugePage) => {
-                let entry = &p3[addr.p3_index()];
-                let frame = PhysFrame::containing_address(entry.addr());
-                let offset = addr.as_u64() & 0o_777_777_7777;
-                let flags = entry.flags();
-                return TranslateResult::Mapped {
-                    frame: MappedFrame::Size1GiB(frame),
-                    offset,
-                    flags,
-                };
-            }
-        };
-        let p1 = match self.page_table_walker.next_table(&p2[addr.p2_index()]) {
-            Ok(page_table) => page_table,
-            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
-            Err(PageTableWalkError::MappedToHugePage) => {
-                let entry = &p2[addr.p2_index()];
-                let frame = PhysFrame::containing_address(entry.addr());
-                let offset = addr.as_u64() & 0o_777_7777;
-                let flags = entry.flags();
-                return TranslateResult::Mapped {
-                    frame: MappedFrame::Size2MiB(frame),
-                    offset,
-                    flags,
-                };
-            }
-        };
-
-        let p1_entry = &p1[addr.p1_index()];
-
-        if p1_entry.is_unused() {
-            return TranslateResult::NotMapped;
-        }
+        self.page_table_walker.translate(self.level_4_table, addr)
+    }
+}
 
-        let frame = match PhysFrame::from_start_address(p1_entry.addr()) {
-            Ok(frame) => frame,
-            Err(AddressNotAligned) => return TranslateResult::InvalidFrameAddress(p1_entry.addr()),
-        };
-        let offset = u64::from(addr.page_offset());
-        let flags = p1_entry.flags();
-        TranslateResult::Mapped {
-            frame: MappedFrame::Size4KiB(frame),
-            offset,
-            flags,
-        }
+impl<'a, P: PageTableFrameMapping> Walk for MappedPageTable<'a, P> {
+    #[inline]
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        self.page_table_walker
+            .walk(self.level_4_table, PageTableLevel::Four, addr)
     }
 }
 
-#[derive(Debug)]
-struct PageTableWalker<P: PageTableFrameMapping> {
-    page_table_frame_mapping: P,
+impl<'a, P: PageTableFrameMapping> WalkMut for MappedPageTable<'a, P> {
+    #[inline]
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        self.page_table_walker
+            .walk_mut(self.level_4_table, PageTableLevel::Four, addr, level, f)
+    }
 }
 
-impl<P: PageTableFrameMapping> PageTableWalker<P> {
+impl<'a, P: PageTableFrameMapping> CleanUp for MappedPageTable<'a, P> {
     #[inline]
-    pub unsafe fn new(page_table_frame_mapping: P) -> Self {
-        Self {
-            page_table_frame_mapping,
-        }
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.page_table_walker.clean_up(
+            self.level_4_table,
+            PageTableLevel::Four,
+            Some(range.start.start_address()),
+            Some(range.end.start_address()),
+            frame_deallocator,
+        );
+        MapperFlushAll::new()
     }
+}
 
-    /// Internal helper function to get a reference to the page table of the next level.
-    ///
-    /// Returns `PageTableWalkError::NotMapped` if the entry is unused. Returns
-    /// `PageTableWalkError::MappedToHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
+impl<'a, P: PageTableFrameMapping> HugePageMapper<Size1GiB> for MappedPageTable<'a, P> {
     #[inline]
-    fn next_table<'b>(
-        &self,
-        entry: &'b PageTableEntry,
-    ) -> Result<&'b PageTable, PageTableWalkError> {
-        let page_table_ptr = self
-            .page_table_frame_mapping
-            .frame_to_pointer(entry.frame()?);
-        let page_table: &PageTable = unsafe { &*page_table_ptr };
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.level_4_table;
+        let p3 = self
+            .page_table_walker
+            .next_table_mut(&mut p4[page.p4_index()])?;
 
-        Ok(page_table)
+        self.page_table_walker.split_huge_entry(
+            &mut p3[page.p3_index()],
+            PageTableLevel::Three,
+            frame_allocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size1GiB::SIZE))
     }
 
-    /// Internal helper function to get a mutable reference to the page table of the next level.
-    ///
-    /// Returns `PageTableWalkError::NotMapped` if the entry is unused. Returns
-    /// `PageTableWalkError::MappedToHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
     #[inline]
-    fn next_table_mut<'b>(
-        &self,
-        entry: &'b mut PageTableEntry,
-    ) -> Result<&'b mut PageTable, PageTableWalkError> {
-        let page_table_ptr = self
-            .page_table_frame_mapping
-            .frame_to_pointer(entry.frame()?);
-        let page_table: &mut PageTable = unsafe { &mut *page_table_ptr };
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.level_4_table;
+        let p3 = self
+            .page_table_walker
+            .next_table_mut(&mut p4[page.p4_index()])?;
 
-        Ok(page_table)
+        self.page_table_walker.promote_table(
+            &mut p3[page.p3_index()],
+            PageTableLevel::Three,
+            frame_deallocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size2MiB::SIZE))
     }
+}
 
-    /// Internal helper function to create the page table of the next level if needed.
-    ///
-    /// If the passed entry is unused, a new frame is allocated from the given allocator, zeroed,
-    /// and the entry is updated to that address. If the passed entry is already mapped, the next
-    /// table is returned directly.
-    ///
-    /// Returns `MapToError::FrameAllocationFailed` if the entry is unused and the allocator
-    /// returned `None`. Returns `MapToError::ParentEntryHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
-    fn create_next_table<'b, A>(
-        &self,
-        entry: &'b mut PageTableEntry,
-        insert_flags: PageTableFlags,
-        allocator: &mut A,
-    ) -> Result<&'b mut PageTable, PageTableCreateError>
+impl<'a, P: PageTableFrameMapping> HugePageMapper<Size2MiB> for MappedPageTable<'a, P> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
     where
         A: FrameAllocator<Size4KiB> + ?Sized,
     {
-        let created;
+        let p4 = &mut self.level_4_table;
+        let p3 = self
+            .page_table_walker
+            .next_table_mut(&mut p4[page.p4_index()])?;
+        let p2 = self
+            .page_table_walker
+            .next_table_mut(&mut p3[page.p3_index()])?;
 
-        if entry.is_unused() {
-            if let Some(frame) = allocator.allocate_frame() {
-                entry.set_frame(frame, insert_flags);
-                created = true;
-            } else {
-                return Err(PageTableCreateError::FrameAllocationFailed);
-            }
-        } else {
-            if !insert_flags.is_empty() && !entry.flags().contains(insert_flags) {
-                entry.set_flags(entry.flags() | insert_flags);
-            }
-            created = false;
-        }
+        self.page_table_walker.split_huge_entry(
+            &mut p2[page.p2_index()],
+            PageTableLevel::Two,
+            frame_allocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size2MiB::SIZE))
+    }
 
-        let page_table = match self.next_table_mut(entry) {
-            Err(PageTableWalkError::MappedToHugePage) => {
-                return Err(PageTableCreateError::MappedToHugePage);
-            }
-            Err(PageTableWalkError::NotMapped) => panic!("entry should be mapped at this point"),
-            Ok(page_table) => page_table,
-        };
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.level_4_table;
+        let p3 = self
+            .page_table_walker
+            .next_table_mut(&mut p4[page.p4_index()])?;
+        let p2 = self
+            .page_table_walker
+            .next_table_mut(&mut p3[page.p3_index()])?;
 
-        if created {
-            page_table.zero();
-        }
-        Ok(page_table)
+        self.page_table_walker.promote_table(
+            &mut p2[page.p2_index()],
+            PageTableLevel::Two,
+            frame_deallocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size4KiB::SIZE))
     }
 }
 
+/// A Mapper implementation for 5-level paging that relies on a PhysAddr to VirtAddr conversion
+/// function.
+///
+/// This type works like [`MappedPageTable`], but the root of its page table hierarchy is a
+/// level 5 table. It must only be used when 5-level paging (LA57) is enabled, i.e. when the
+/// `L5_PAGING` flag of the CR4 register is set. Virtual addresses passed to this mapper are
+/// interpreted as 57-bit addresses, so they should be created through the `_la57` constructors
+/// of [`VirtAddr`].
 #[derive(Debug)]
-enum PageTableWalkError {
-    NotMapped,
-    MappedToHugePage,
-}
-
-#[derive(Debug)]
-enum PageTableCreateError {
-    MappedToHugePage,
-    FrameAllocationFailed,
+pub struct MappedLevel5PageTable<'a, P: PageTableFrameMapping> {
+    page_table_walker: PageTableWalker<P>,
+    level_5_table: &'a mut PageTable,
 }
 
-impl From<PageTableCreateError> for MapToError<Size4KiB> {
+impl<'a, P: PageTableFrameMapping> MappedLevel5PageTable<'a, P> {
+    /// Creates a new `MappedLevel5PageTable` that uses the passed closure for converting virtual
+    /// to physical addresses.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed `page_table_frame_mapping`
+    /// closure is correct. Also, the passed `level_5_table` must point to the level 5 page table
+    /// of a valid page table hierarchy. Otherwise this function might break memory safety, e.g.
+    /// by writing to an illegal memory location.
     #[inline]
-    fn from(err: PageTableCreateError) -> Self {
-        match err {
-            PageTableCreateError::MappedToHugePage => MapToError::ParentEntryHugePage,
-            PageTableCreateError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+    pub unsafe fn new(level_5_table: &'a mut PageTable, page_table_frame_mapping: P) -> Self {
+        Self {
+            level_5_table,
+            page_table_walker: PageTableWalker::new(page_table_frame_mapping),
         }
     }
-}
+
+    /// Returns a mutable reference to the wrapped level 5 `PageTable` instance.
+    pub fn level_5_table(&mut self) -> &mut PageTable {
+        self.level_5_table
+    }
+
+    /// Creates a copy of this page table hierarchy, e.g. for implementing `fork`.
+    ///
+    /// The entries of the upper (kernel) half of the level 5 table are copied, so that both
+    /// hierarchies share the same level 4 tables. All page tables of the lower (user) half are
+    /// copied, but both hierarchies map the same frames afterwards. An entry of the level 5
+    /// table that points to the table itself (i.e. a recursive entry) is updated to point to
+    /// the new table.
+    ///
+    /// If a `cow_flag` is given, all present leaf entries of the lower half that have both the
+    /// `WRITABLE` and the `USER_ACCESSIBLE` flag set are made read-only in both hierarchies and
+    /// marked with the given flag, which should be one of the bits that are available for
+    /// software use (e.g. `BIT_9`). A write to such a page then causes a page fault, which can
+    /// copy the frame and restore the `WRITABLE` flag. Since the entries of this page table are
+    /// changed too, the returned `MapperFlushAll` must be flushed if this page table is active.
+    ///
+    /// The new page tables are allocated from the given frame allocator. If an allocation fails,
+    /// all new page tables are deallocated again and this page table is left unchanged.
+    ///
+    /// Returns the frame of the new level 5 table, which can be loaded through `Cr3::write`.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that the frames that are mapped by both hierarchies are not
+    /// deallocated while they are still in use by one of them. The shared kernel half tables
+    /// must stay valid as long as any of the hierarchies is used. Also, the page table must not
+    /// be modified concurrently.
+    pub unsafe fn clone_address_space<A>(
+        &mut self,
+        cow_flag: Option<PageTableFlags>,
+        frame_allocator: &mut A,
+    ) -> Result<(PhysFrame, MapperFlushAll), CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let frame = self.page_table_walker.clone_hierarchy(
+            self.level_5_table,
+            PageTableLevel::Five,
+            cow_flag,
+            frame_allocator,
+        )?;
+        Ok((frame, MapperFlushAll::new()))
+    }
+
+    /// Set the flags of an existing page level 5 table entry
+    ///
+    /// ## Safety
+    ///
+    /// This method is unsafe because changing the flags of a mapping
+    /// might result in undefined behavior. For example, setting the
+    /// `GLOBAL` and `WRITABLE` flags for a page might result in the corruption
+    /// of values stored in that page from processes running in other address
+    /// spaces.
+    pub unsafe fn set_flags_p5_entry<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        let p5_entry = &mut self.level_5_table[page.p5_index()];
+
+        if p5_entry.is_unused() {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+
+        p5_entry.set_flags(flags);
+
+        Ok(MapperFlushAll::new())
+    }
+
+    /// Internal helper function that returns a 4-level mapper for the level 4 table
+    /// responsible for the given address.
+    fn level_4_mapper(
+        &mut self,
+        addr: VirtAddr,
+    ) -> Result<MappedPageTable<'_, &P>, PageTableWalkError> {
+        let p4 = self
+            .page_table_walker
+            .next_table_mut(&mut self.level_5_table[addr.p5_index()])?;
+        Ok(MappedPageTable {
+            page_table_walker: PageTableWalker {
+                page_table_frame_mapping: &self.page_table_walker.page_table_frame_mapping,
+            },
+            level_4_table: p4,
+        })
+    }
+
+    /// Internal helper function that returns a 4-level mapper for the level 4 table
+    /// responsible for the given address, creating the level 4 table if needed.
+    fn create_level_4_mapper<A>(
+        &mut self,
+        addr: VirtAddr,
+        insert_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MappedPageTable<'_, &P>, PageTableCreateError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let p4 = self.page_table_walker.create_next_table(
+            &mut self.level_5_table[addr.p5_index()],
+            insert_flags,
+            allocator,
+        )?;
+        Ok(MappedPageTable {
+            page_table_walker: PageTableWalker {
+                page_table_frame_mapping: &self.page_table_walker.page_table_frame_mapping,
+            },
+            level_4_table: p4,
+        })
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Mapper<Size1GiB> for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError<Size1GiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.create_level_4_mapper(page.start_address(), parent_table_flags, allocator)?
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), UnmapError> {
+        self.level_4_mapper(page.start_address())?.unmap(page)
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .update_flags(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p4_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p3_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p2_entry(page, flags)
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
+        let p5 = &self.level_5_table;
+        let p4 = self.page_table_walker.next_table(&p5[page.p5_index()])?;
+        self.page_table_walker.translate_page_1gib(p4, page)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError<Size2MiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.create_level_4_mapper(page.start_address(), parent_table_flags, allocator)?
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
+        self.level_4_mapper(page.start_address())?.unmap(page)
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .update_flags(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p4_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p3_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p2_entry(page, flags)
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
+        let p5 = &self.level_5_table;
+        let p4 = self.page_table_walker.next_table(&p5[page.p5_index()])?;
+        self.page_table_walker.translate_page_2mib(p4, page)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.create_level_4_mapper(page.start_address(), parent_table_flags, allocator)?
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
+        self.level_4_mapper(page.start_address())?.unmap(page)
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .update_flags(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p4_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p3_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.level_4_mapper(page.start_address())?
+            .set_flags_p2_entry(page, flags)
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+        let p5 = &self.level_5_table;
+        let p4 = self.page_table_walker.next_table(&p5[page.p5_index()])?;
+        self.page_table_walker.translate_page_4kib(p4, page)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Translate for MappedLevel5PageTable<'a, P> {
+    fn translate(&self, addr: VirtAddr) -> TranslateResult {
+        let p5 = &self.level_5_table;
+        let p4 = match self.page_table_walker.next_table(&p5[addr.p5_index()]) {
+            Ok(page_table) => page_table,
+            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
+            Err(PageTableWalkError::MappedToHugePage) => {
+                panic!("level 5 entry has huge page bit set")
+            }
+        };
+        self.page_table_walker.translate(p4, addr)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Walk for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        self.page_table_walker
+            .walk(self.level_5_table, PageTableLevel::Five, addr)
+    }
+
+    #[inline]
+    fn paging_mode(&self) -> PagingMode {
+        PagingMode::Level5
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> WalkMut for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        self.page_table_walker
+            .walk_mut(self.level_5_table, PageTableLevel::Five, addr, level, f)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> CleanUp for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.page_table_walker.clean_up(
+            self.level_5_table,
+            PageTableLevel::Five,
+            Some(range.start.start_address()),
+            Some(range.end.start_address()),
+            frame_deallocator,
+        );
+        MapperFlushAll::new()
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> HugePageMapper<Size1GiB> for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.level_4_mapper(page.start_address())?
+            .split_huge_page(page, frame_allocator)
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.level_4_mapper(page.start_address())?
+            .promote_to_huge_page(page, frame_deallocator)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> HugePageMapper<Size2MiB> for MappedLevel5PageTable<'a, P> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.level_4_mapper(page.start_address())?
+            .split_huge_page(page, frame_allocator)
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.level_4_mapper(page.start_address())?
+            .promote_to_huge_page(page, frame_deallocator)
+    }
+}
+
+#[derive(Debug)]
+struct PageTableWalker<P: PageTableFrameMapping> {
+    page_table_frame_mapping: P,
+}
+
+impl<P: PageTableFrameMapping> PageTableWalker<P> {
+    #[inline]
+    pub unsafe fn new(page_table_frame_mapping: P) -> Self {
+        Self {
+            page_table_frame_mapping,
+        }
+    }
+
+    /// Internal helper function to get a reference to the page table of the next level.
+    ///
+    /// Returns `PageTableWalkError::NotMapped` if the entry is unused. Returns
+    /// `PageTableWalkError::MappedToHugePage` if the `HUGE_PAGE` flag is set
+    /// in the passed entry.
+    #[inline]
+    fn next_table<'b>(
+        &self,
+        entry: &'b PageTableEntry,
+    ) -> Result<&'b PageTable, PageTableWalkError> {
+        let page_table_ptr = self
+            .page_table_frame_mapping
+            .frame_to_pointer(entry.frame()?);
+        let page_table: &PageTable = unsafe { &*page_table_ptr };
+
+        Ok(page_table)
+    }
+
+    /// Internal helper function to get a mutable reference to the page table of the next level.
+    ///
+    /// Returns `PageTableWalkError::NotMapped` if the entry is unused. Returns
+    /// `PageTableWalkError::MappedToHugePage` if the `HUGE_PAGE` flag is set
+    /// in the passed entry.
+    #[inline]
+    fn next_table_mut<'b>(
+        &self,
+        entry: &'b mut PageTableEntry,
+    ) -> Result<&'b mut PageTable, PageTableWalkError> {
+        let page_table_ptr = self
+            .page_table_frame_mapping
+            .frame_to_pointer(entry.frame()?);
+        let page_table: &mut PageTable = unsafe { &mut *page_table_ptr };
+
+        Ok(page_table)
+    }
+
+    /// Internal helper function to create the page table of the next level if needed.
+    ///
+    /// If the passed entry is unused, a new frame is allocated from the given allocator, zeroed,
+    /// and the entry is updated to that address. If the passed entry is already mapped, the next
+    /// table is returned directly.
+    ///
+    /// Returns `MapToError::FrameAllocationFailed` if the entry is unused and the allocator
+    /// returned `None`. Returns `MapToError::ParentEntryHugePage` if the `HUGE_PAGE` flag is set
+    /// in the passed entry.
+    fn create_next_table<'b, A>(
+        &self,
+        entry: &'b mut PageTableEntry,
+        insert_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<&'b mut PageTable, PageTableCreateError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let created;
+
+        if entry.is_unused() {
+            if let Some(frame) = allocator.allocate_frame() {
+                entry.set_frame(frame, insert_flags);
+                created = true;
+            } else {
+                return Err(PageTableCreateError::FrameAllocationFailed);
+            }
+        } else {
+            if !insert_flags.is_empty() && !entry.flags().contains(insert_flags) {
+                entry.set_flags(entry.flags() | insert_flags);
+            }
+            created = false;
+        }
+
+        let page_table = match self.next_table_mut(entry) {
+            Err(PageTableWalkError::MappedToHugePage) => {
+                return Err(PageTableCreateError::MappedToHugePage);
+            }
+            Err(PageTableWalkError::NotMapped) => panic!("entry should be mapped at this point"),
+            Ok(page_table) => page_table,
+        };
+
+        if created {
+            page_table.zero();
+        }
+        Ok(page_table)
+    }
+
+    /// Internal helper function to free the empty page tables below `page_table`.
+    ///
+    /// Only the entries of `page_table` that overlap the range from `start` to `end` (both
+    /// inclusive) are considered. A bound of `None` means that the range covers the complete
+    /// table on that side. Empty child tables are unlinked and passed to the deallocator,
+    /// starting at the lowest level.
+    ///
+    /// Returns whether `page_table` is empty after the clean up.
+    unsafe fn clean_up<D>(
+        &self,
+        page_table: &mut PageTable,
+        level: PageTableLevel,
+        start: Option<VirtAddr>,
+        end: Option<VirtAddr>,
+        frame_deallocator: &mut D,
+    ) -> bool
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        if page_table.is_empty() {
+            return true;
+        }
+
+        let next_level = match level.next_lower_level() {
+            Some(next_level) => next_level,
+            None => return false,
+        };
+
+        let start_index = start.map_or(0, |addr| usize::from(addr.page_table_index(level)));
+        let end_index = end.map_or(511, |addr| usize::from(addr.page_table_index(level)));
+
+        for index in start_index..=end_index {
+            let entry = &mut page_table[index];
+            let frame = match entry.frame() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            let next_table = &mut *self.page_table_frame_mapping.frame_to_pointer(frame);
+
+            let next_start = if index == start_index { start } else { None };
+            let next_end = if index == end_index { end } else { None };
+            if self.clean_up(
+                next_table,
+                next_level,
+                next_start,
+                next_end,
+                frame_deallocator,
+            ) {
+                entry.set_unused();
+                frame_deallocator.deallocate_frame(frame);
+            }
+        }
+
+        page_table.is_empty()
+    }
+
+    /// Internal helper function to translate a 1GiB page using the given level 4 table.
+    fn translate_page_1gib(
+        &self,
+        p4: &PageTable,
+        page: Page<Size1GiB>,
+    ) -> Result<PhysFrame<Size1GiB>, TranslateError> {
+        let p3 = self.next_table(&p4[page.p4_index()])?;
+
+        let p3_entry = &p3[page.p3_index()];
+
+        if p3_entry.is_unused() {
+            return Err(TranslateError::PageNotMapped);
+        }
+
+        PhysFrame::from_start_address(p3_entry.leaf_addr(PageTableLevel::Three))
+            .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p3_entry.addr()))
+    }
+
+    /// Internal helper function to translate a 2MiB page using the given level 4 table.
+    fn translate_page_2mib(
+        &self,
+        p4: &PageTable,
+        page: Page<Size2MiB>,
+    ) -> Result<PhysFrame<Size2MiB>, TranslateError> {
+        let p3 = self.next_table(&p4[page.p4_index()])?;
+        let p2 = self.next_table(&p3[page.p3_index()])?;
+
+        let p2_entry = &p2[page.p2_index()];
+
+        if p2_entry.is_unused() {
+            return Err(TranslateError::PageNotMapped);
+        }
+
+        PhysFrame::from_start_address(p2_entry.leaf_addr(PageTableLevel::Two))
+            .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p2_entry.addr()))
+    }
+
+    /// Internal helper function to translate a 4KiB page using the given level 4 table.
+    fn translate_page_4kib(
+        &self,
+        p4: &PageTable,
+        page: Page<Size4KiB>,
+    ) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+        let p3 = self.next_table(&p4[page.p4_index()])?;
+        let p2 = self.next_table(&p3[page.p3_index()])?;
+        let p1 = self.next_table(&p2[page.p2_index()])?;
+
+        let p1_entry = &p1[page.p1_index()];
+
+        if p1_entry.is_unused() {
+            return Err(TranslateError::PageNotMapped);
+        }
+
+        PhysFrame::from_start_address(p1_entry.addr())
+            .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p1_entry.addr()))
+    }
+
+    /// Internal helper function to translate a virtual address using the given level 4 table.
+    #[allow(clippy::inconsistent_digit_grouping)]
+    fn translate(&self, p4: &PageTable, addr: VirtAddr) -> TranslateResult {
+        let p3 = match self.next_table(&p4[addr.p4_index()]) {
+            Ok(page_table) => page_table,
+            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
+            Err(PageTableWalkError::MappedToHugePage) => {
+                panic!("level 4 entry has huge page bit set")
+            }
+        };
+        let p2 = match self.next_table(&p3[addr.p3_index()]) {
+            Ok(page_table) => page_table,
+            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
+            Err(PageTableWalkError::MappedToHugePage) => {
+                let entry = &p3[addr.p3_index()];
+                let frame = PhysFrame::containing_address(entry.addr());
+                let offset = addr.as_u64() & 0o_777_777_7777;
+                let flags = entry.flags();
+                return TranslateResult::Mapped {
+                    frame: MappedFrame::Size1GiB(frame),
+                    offset,
+                    flags,
+                };
+            }
+        };
+        let p1 = match self.next_table(&p2[addr.p2_index()]) {
+            Ok(page_table) => page_table,
+            Err(PageTableWalkError::NotMapped) => return TranslateResult::NotMapped,
+            Err(PageTableWalkError::MappedToHugePage) => {
+                let entry = &p2[addr.p2_index()];
+                let frame = PhysFrame::containing_address(entry.addr());
+                let offset = addr.as_u64() & 0o_777_7777;
+                let flags = entry.flags();
+                return TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(frame),
+                    offset,
+                    flags,
+                };
+            }
+        };
+
+        let p1_entry = &p1[addr.p1_index()];
+
+        if p1_entry.is_unused() {
+            return TranslateResult::NotMapped;
+        }
+
+        let frame = match PhysFrame::from_start_address(p1_entry.addr()) {
+            Ok(frame) => frame,
+            Err(AddressNotAligned) => return TranslateResult::InvalidFrameAddress(p1_entry.addr()),
+        };
+        let offset = u64::from(addr.page_offset());
+        let flags = p1_entry.flags();
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            offset,
+            flags,
+        }
+    }
+
+    /// Internal helper function to walk the page tables below `page_table` of the given level.
+    fn walk(&self, page_table: &PageTable, level: PageTableLevel, addr: VirtAddr) -> WalkResult {
+        let root = self
+            .page_table_frame_mapping
+            .pointer_to_frame(page_table)
+            .map(|frame| frame.start_address());
+        walk_tables(page_table, level, addr, root, |page_table, index| {
+            let frame = PhysFrame::containing_address(page_table[index].addr());
+            unsafe { &*self.page_table_frame_mapping.frame_to_pointer(frame) }
+        })
+    }
+
+    /// Internal helper function to walk the page tables for `addr`, starting at `page_table`
+    /// of the given level, and to call `f` with the entry at which the walk stops.
+    ///
+    /// The walk doesn't descend below the table of `stop_level`.
+    fn walk_mut<F, R>(
+        &self,
+        page_table: &mut PageTable,
+        level: PageTableLevel,
+        addr: VirtAddr,
+        stop_level: PageTableLevel,
+        f: F,
+    ) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        walk_tables_mut(
+            page_table,
+            level,
+            addr,
+            stop_level,
+            |page_table, index| {
+                let frame = PhysFrame::containing_address(page_table[index].addr());
+                unsafe { &mut *self.page_table_frame_mapping.frame_to_pointer(frame) }
+            },
+            f,
+        )
+    }
+
+    /// Internal helper function to split the huge page `entry` of a table of the given level
+    /// into a new page table.
+    ///
+    /// The new table is completely filled before `entry` is updated to point to it.
+    fn split_huge_entry<A>(
+        &self,
+        entry: &mut PageTableEntry,
+        level: PageTableLevel,
+        allocator: &mut A,
+    ) -> Result<(), SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(SplitError::PageNotMapped);
+        }
+        if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Err(SplitError::NotHugePage);
+        }
+
+        let frame = allocator
+            .allocate_frame()
+            .ok_or(SplitError::FrameAllocationFailed)?;
+        let page_table = unsafe { &mut *self.page_table_frame_mapping.frame_to_pointer(frame) };
+        fill_split_table(entry, level, page_table);
+        entry.set_frame(frame, split_table_entry_flags(flags));
+        Ok(())
+    }
+
+    /// Internal helper function to replace the table referenced by `entry` of a table of the
+    /// given level with a huge page.
+    ///
+    /// The frame of the replaced table is passed to the deallocator.
+    unsafe fn promote_table<D>(
+        &self,
+        entry: &mut PageTableEntry,
+        level: PageTableLevel,
+        frame_deallocator: &mut D,
+    ) -> Result<(), PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let frame = entry.frame().map_err(|err| match err {
+            FrameError::FrameNotPresent => PromoteError::PageNotMapped,
+            FrameError::HugeFrame => PromoteError::AlreadyHugePage,
+        })?;
+        let page_table = &*self.page_table_frame_mapping.frame_to_pointer(frame);
+        let (addr, flags) = promoted_entry(entry, level, page_table)?;
+
+        entry.set_addr(addr, flags);
+        frame_deallocator.deallocate_frame(frame);
+        Ok(())
+    }
+
+    /// Internal helper function to create a copy of the page table hierarchy with the given
+    /// root table of the given level.
+    ///
+    /// The upper half entries of the root table are shared, all page tables of the lower half
+    /// are copied. Entries that point to the root table itself are updated to point to the copy.
+    /// If `cow_flag` is given, writable user accessible leaf entries are marked as copy-on-write
+    /// in both hierarchies.
+    ///
+    /// Returns the frame of the new root table.
+    unsafe fn clone_hierarchy<A>(
+        &self,
+        root: &mut PageTable,
+        level: PageTableLevel,
+        cow_flag: Option<PageTableFlags>,
+        allocator: &mut A,
+    ) -> Result<PhysFrame, CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let frame = allocator
+            .allocate_frame()
+            .ok_or(CloneError::FrameAllocationFailed)?;
+        let new_root = &mut *self.page_table_frame_mapping.frame_to_pointer(frame);
+        new_root.zero();
+
+        let root_ptr: *const PageTable = root;
+        for index in 0..512 {
+            let entry = &mut root[index];
+            let is_recursive = match entry.frame() {
+                Ok(entry_frame) => core::ptr::eq(
+                    self.page_table_frame_mapping.frame_to_pointer(entry_frame),
+                    root_ptr,
+                ),
+                Err(_) => false,
+            };
+
+            if is_recursive {
+                new_root[index].set_frame(frame, entry.flags());
+            } else if index >= 256 {
+                new_root[index] = entry.clone();
+            } else if let Err(err) = self.copy_entry(entry, &mut new_root[index], level, allocator)
+            {
+                for new_entry in new_root.iter_mut().take(index) {
+                    if new_entry.frame().ok() != Some(frame) {
+                        self.free_copied_tables(new_entry, level, allocator);
+                    }
+                }
+                allocator.deallocate_frame(frame);
+                return Err(err);
+            }
+        }
+
+        // all allocations succeeded, so the marking can't leave a partial copy behind
+        if let Some(cow_flag) = cow_flag {
+            for index in 0..256 {
+                let new_entry = &mut new_root[index];
+                if new_entry.frame().ok() != Some(frame) {
+                    self.mark_copy_on_write(&mut root[index], new_entry, level, cow_flag);
+                }
+            }
+        }
+
+        Ok(frame)
+    }
+
+    /// Internal helper function to copy `entry` of a table of the given level to `new_entry`,
+    /// including all page tables below it.
+    ///
+    /// The source hierarchy is not modified.
+    unsafe fn copy_entry<A>(
+        &self,
+        entry: &mut PageTableEntry,
+        new_entry: &mut PageTableEntry,
+        level: PageTableLevel,
+        allocator: &mut A,
+    ) -> Result<(), CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let flags = entry.flags();
+        let (frame, next_level) = match (entry.frame(), level.next_lower_level()) {
+            (Ok(frame), Some(next_level)) => (frame, next_level),
+            _ => {
+                // unused or a leaf entry
+                *new_entry = entry.clone();
+                return Ok(());
+            }
+        };
+
+        let new_frame = allocator
+            .allocate_frame()
+            .ok_or(CloneError::FrameAllocationFailed)?;
+        let table = &mut *self.page_table_frame_mapping.frame_to_pointer(frame);
+        let new_table = &mut *self.page_table_frame_mapping.frame_to_pointer(new_frame);
+        new_table.zero();
+
+        for index in 0..512 {
+            let new_entry = &mut new_table[index];
+            if let Err(err) = self.copy_entry(&mut table[index], new_entry, next_level, allocator) {
+                for new_entry in new_table.iter_mut() {
+                    self.free_copied_tables(new_entry, next_level, allocator);
+                }
+                allocator.deallocate_frame(new_frame);
+                return Err(err);
+            }
+        }
+
+        new_entry.set_frame(new_frame, flags);
+        Ok(())
+    }
+
+    /// Internal helper function to mark the writable user accessible leaf entries below `entry`
+    /// and below its copy `new_entry` as copy-on-write.
+    ///
+    /// Both hierarchies must have the same structure, i.e. `new_entry` must have been created
+    /// by `copy_entry`.
+    unsafe fn mark_copy_on_write(
+        &self,
+        entry: &mut PageTableEntry,
+        new_entry: &mut PageTableEntry,
+        level: PageTableLevel,
+        cow_flag: PageTableFlags,
+    ) {
+        match (entry.frame(), new_entry.frame(), level.next_lower_level()) {
+            (Ok(frame), Ok(new_frame), Some(next_level)) => {
+                let table = &mut *self.page_table_frame_mapping.frame_to_pointer(frame);
+                let new_table = &mut *self.page_table_frame_mapping.frame_to_pointer(new_frame);
+                for (entry, new_entry) in table.iter_mut().zip(new_table.iter_mut()) {
+                    self.mark_copy_on_write(entry, new_entry, next_level, cow_flag);
+                }
+            }
+            _ => {
+                // unused or a leaf entry
+                let writable_user = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE;
+                let flags = entry.flags();
+                if flags.contains(writable_user) {
+                    let cow_flags = (flags - PageTableFlags::WRITABLE) | cow_flag;
+                    entry.set_flags(cow_flags);
+                    new_entry.set_flags(cow_flags);
+                }
+            }
+        }
+    }
+
+    /// Internal helper function to deallocate all page tables below `entry` that were created
+    /// by `copy_entry`.
+    unsafe fn free_copied_tables<D>(
+        &self,
+        entry: &mut PageTableEntry,
+        level: PageTableLevel,
+        frame_deallocator: &mut D,
+    ) where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        if let (Ok(frame), Some(next_level)) = (entry.frame(), level.next_lower_level()) {
+            let table = &mut *self.page_table_frame_mapping.frame_to_pointer(frame);
+            for entry in table.iter_mut() {
+                self.free_copied_tables(entry, next_level, frame_deallocator);
+            }
+            entry.set_unused();
+            frame_deallocator.deallocate_frame(frame);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PageTableWalkError {
+    NotMapped,
+    MappedToHugePage,
+}
+
+#[derive(Debug)]
+enum PageTableCreateError {
+    MappedToHugePage,
+    FrameAllocationFailed,
+}
+
+impl From<PageTableCreateError> for MapToError<Size4KiB> {
+    #[inline]
+    fn from(err: PageTableCreateError) -> Self {
+        match err {
+            PageTableCreateError::MappedToHugePage => MapToError::ParentEntryHugePage,
+            PageTableCreateError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        }
+    }
+}
 
 impl From<PageTableCreateError> for MapToError<Size2MiB> {
     #[inline]
@@ -754,6 +1766,26 @@ impl From<PageTableWalkError> for FlagUpdateError {
     }
 }
 
+impl From<PageTableWalkError> for SplitError {
+    #[inline]
+    fn from(err: PageTableWalkError) -> Self {
+        match err {
+            PageTableWalkError::MappedToHugePage => SplitError::ParentEntryHugePage,
+            PageTableWalkError::NotMapped => SplitError::PageNotMapped,
+        }
+    }
+}
+
+impl From<PageTableWalkError> for PromoteError {
+    #[inline]
+    fn from(err: PageTableWalkError) -> Self {
+        match err {
+            PageTableWalkError::MappedToHugePage => PromoteError::ParentEntryHugePage,
+            PageTableWalkError::NotMapped => PromoteError::PageNotMapped,
+        }
+    }
+}
+
 impl From<PageTableWalkError> for TranslateError {
     #[inline]
     fn from(err: PageTableWalkError) -> Self {
@@ -776,4 +1808,518 @@ impl From<PageTableWalkError> for TranslateError {
 pub unsafe trait PageTableFrameMapping {
     /// Translate the given physical frame to a virtual page table pointer.
     fn frame_to_pointer(&self, frame: PhysFrame) -> *mut PageTable;
+
+    /// Translate the given virtual page table pointer back to its physical frame.
+    ///
+    /// This is used for reporting the physical address of the root table in
+    /// [`VisitedTables`]. The default implementation returns `None`, which means that the
+    /// physical address is unknown.
+    #[inline]
+    fn pointer_to_frame(&self, page_table: *const PageTable) -> Option<PhysFrame> {
+        let _ = page_table;
+        None
+    }
+}
+
+unsafe impl<P: PageTableFrameMapping + ?Sized> PageTableFrameMapping for &P {
+    #[inline]
+    fn frame_to_pointer(&self, frame: PhysFrame) -> *mut PageTable {
+        (**self).frame_to_pointer(frame)
+    }
+
+    #[inline]
+    fn pointer_to_frame(&self, page_table: *const PageTable) -> Option<PhysFrame> {
+        (**self).pointer_to_frame(page_table)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::SimulatedMemory;
+    use crate::{PhysAddr, VirtAddr};
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::USER_ACCESSIBLE);
+
+    /// Returns the leaf entry, its level, and the effective flags for the given address.
+    fn walk(
+        memory: &mut SimulatedMemory,
+        addr: u64,
+    ) -> (PageTableEntry, PageTableLevel, PageTableFlags) {
+        match memory.mapped_page_table().0.walk(VirtAddr::new(addr)) {
+            WalkResult::Mapped {
+                entry,
+                level,
+                flags,
+                ..
+            } => (entry, level, flags),
+            WalkResult::NotMapped { .. } => panic!("{:#x} is not mapped", addr),
+        }
+    }
+
+    /// Returns the effective flags of the given address in the hierarchy with the given root.
+    fn flags_in(memory: &SimulatedMemory, root: PhysFrame, addr: u64) -> PageTableFlags {
+        let level_4_table = unsafe { &mut *memory.frame_to_pointer(root) };
+        let mapper = unsafe { MappedPageTable::new(level_4_table, memory) };
+        match mapper.walk(VirtAddr::new(addr)) {
+            WalkResult::Mapped { flags, .. } => flags,
+            WalkResult::NotMapped { .. } => panic!("{:#x} is not mapped", addr),
+        }
+    }
+
+    /// Maps two user pages with separate level 3 tables and one kernel page.
+    fn clone_source(frame_count: usize) -> SimulatedMemory {
+        let mut memory = SimulatedMemory::new(frame_count);
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+        for &addr in [0x1000, 0x80_0000_0000].iter() {
+            memory.map(
+                Page::<Size4KiB>::containing_address(VirtAddr::new(addr)),
+                frame,
+                FLAGS,
+            );
+        }
+        memory.map(
+            Page::<Size4KiB>::containing_address(VirtAddr::new(0xffff_8000_0000_0000)),
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+        assert_eq!(memory.allocated_frames(), 10);
+        memory
+    }
+
+    #[test]
+    fn clone_address_space_marks_copy_on_write() {
+        let cow = PageTableFlags::BIT_9;
+        let mut memory = clone_source(32);
+        let root = memory.root_frame();
+        let new_root = {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let (frame, flush) =
+                unsafe { mapper.clone_address_space(Some(cow), &mut frame_allocator) }.unwrap();
+            flush.ignore();
+            frame
+        };
+        // a new root and three tables for each user page
+        assert_eq!(memory.allocated_frames(), 17);
+
+        for &r in [root, new_root].iter() {
+            for &addr in [0x1000, 0x80_0000_0000].iter() {
+                assert_eq!(
+                    flags_in(&memory, r, addr),
+                    (FLAGS - PageTableFlags::WRITABLE) | cow
+                );
+            }
+            assert_eq!(
+                flags_in(&memory, r, 0xffff_8000_0000_0000),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            );
+        }
+        let level_4_table = unsafe { &*memory.frame_to_pointer(root) };
+        let new_level_4_table = unsafe { &*memory.frame_to_pointer(new_root) };
+        assert_eq!(new_level_4_table[256].addr(), level_4_table[256].addr());
+        assert_ne!(new_level_4_table[0].addr(), level_4_table[0].addr());
+    }
+
+    #[test]
+    fn clone_address_space_failure_keeps_source() {
+        // enough frames for the new root and the tables of the first user page only
+        let mut memory = clone_source(14);
+        let root = memory.root_frame();
+        let before = unsafe { (*memory.frame_to_pointer(root)).clone() };
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let result = unsafe {
+                mapper.clone_address_space(Some(PageTableFlags::BIT_9), &mut frame_allocator)
+            };
+            assert!(matches!(result, Err(CloneError::FrameAllocationFailed)));
+        }
+        assert_eq!(memory.allocated_frames(), 10);
+        for &addr in [0x1000, 0x80_0000_0000].iter() {
+            assert_eq!(flags_in(&memory, root, addr), FLAGS);
+        }
+        let after = unsafe { &*memory.frame_to_pointer(root) };
+        for (old, new) in before.iter().zip(after.iter()) {
+            assert_eq!((old.addr(), old.flags()), (new.addr(), new.flags()));
+        }
+    }
+
+    #[test]
+    fn clean_up_reuses_frames() {
+        let mut memory = SimulatedMemory::new(8);
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+        memory.map(page, frame, FLAGS);
+        memory.assert_mapped(VirtAddr::new(0x4000_1000), PhysAddr::new(0x20_1000), FLAGS);
+        assert_eq!(memory.allocated_frames(), 3);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            mapper.unmap(page).unwrap().1.ignore();
+            unsafe { mapper.clean_up(&mut frame_allocator) }.ignore();
+        }
+        memory.assert_not_mapped(VirtAddr::new(0x4000_1000));
+        assert_eq!(memory.allocated_frames(), 1);
+
+        memory.map(page, frame, FLAGS);
+        assert_eq!(memory.allocated_frames(), 3);
+    }
+
+    #[test]
+    fn clean_up_keeps_used_tables() {
+        let mut memory = SimulatedMemory::new(8);
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        let second = Page::<Size4KiB>::containing_address(VirtAddr::new(0x20_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x5000_0000));
+        memory.map(first, frame, FLAGS);
+        memory.map(second, frame, FLAGS);
+        assert_eq!(memory.allocated_frames(), 5);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            mapper.unmap(first).unwrap().1.ignore();
+            unsafe { mapper.clean_up(&mut frame_allocator) }.ignore();
+        }
+        // only the level 1 table of `first` became empty
+        assert_eq!(memory.allocated_frames(), 4);
+        memory.assert_not_mapped(first.start_address());
+        memory.assert_mapped(second.start_address(), frame.start_address(), FLAGS);
+    }
+
+    #[test]
+    fn clean_up_addr_range() {
+        let mut memory = SimulatedMemory::new(8);
+        let low = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        let high = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x5000_0000));
+        memory.map(low, frame, FLAGS);
+        memory.map(high, frame, FLAGS);
+        assert_eq!(memory.allocated_frames(), 6);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            mapper.unmap(low).unwrap().1.ignore();
+            mapper.unmap(high).unwrap().1.ignore();
+            let range = Page::range_inclusive(
+                Page::containing_address(VirtAddr::new(0)),
+                Page::containing_address(VirtAddr::new(0x3fff_ffff)),
+            );
+            unsafe { mapper.clean_up_addr_range(range, &mut frame_allocator) }.ignore();
+        }
+        // the level 2 and level 1 tables of `high` are outside of the range
+        assert_eq!(memory.allocated_frames(), 4);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.clean_up(&mut frame_allocator) }.ignore();
+        }
+        assert_eq!(memory.allocated_frames(), 1);
+    }
+
+    #[test]
+    fn unmap_and_clean_up() {
+        let mut memory = SimulatedMemory::new(8);
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        let second = Page::<Size4KiB>::containing_address(VirtAddr::new(0x2000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x5000_0000));
+        memory.map(first, frame, FLAGS);
+        memory.map(second, frame, FLAGS);
+        assert_eq!(memory.allocated_frames(), 4);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let (unmapped, flush) =
+                unsafe { mapper.unmap_and_clean_up(first, &mut frame_allocator) }.unwrap();
+            flush.ignore();
+            assert_eq!(unmapped, frame);
+        }
+        // the tables are still used by `second`
+        assert_eq!(memory.allocated_frames(), 4);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let (unmapped, flush) =
+                unsafe { mapper.unmap_and_clean_up(second, &mut frame_allocator) }.unwrap();
+            flush.ignore();
+            assert_eq!(unmapped, frame);
+            assert!(matches!(
+                unsafe { mapper.unmap_and_clean_up(second, &mut frame_allocator) },
+                Err(UnmapError::PageNotMapped)
+            ));
+        }
+        assert_eq!(memory.allocated_frames(), 1);
+        memory.assert_not_mapped(second.start_address());
+    }
+
+    #[test]
+    fn split_and_promote_2mib() {
+        let mut memory = SimulatedMemory::new(8);
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+        memory.map(page, frame, FLAGS | PageTableFlags::NO_EXECUTE);
+        assert_eq!(memory.allocated_frames(), 3);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let flush = unsafe { mapper.split_huge_page(page, &mut frame_allocator) }.unwrap();
+            assert_eq!(flush.pages(), 1);
+            flush.ignore();
+        }
+        assert_eq!(memory.allocated_frames(), 4);
+        for offset in [0, 0x1000, 0x1f_f000].iter() {
+            let (entry, level, flags) = walk(&mut memory, 0x20_0000 + offset);
+            assert_eq!(level, PageTableLevel::One);
+            assert_eq!(entry.addr(), PhysAddr::new(0x4000_0000 + offset));
+            assert_eq!(flags, FLAGS | PageTableFlags::NO_EXECUTE);
+        }
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let flush = unsafe { mapper.promote_to_huge_page(page, &mut frame_allocator) }.unwrap();
+            assert_eq!(flush.pages(), 512);
+            flush.ignore();
+        }
+        assert_eq!(memory.allocated_frames(), 3);
+        let (entry, level, flags) = walk(&mut memory, 0x20_1000);
+        assert_eq!(level, PageTableLevel::Two);
+        assert_eq!(entry.addr(), PhysAddr::new(0x4000_0000));
+        assert_eq!(
+            flags,
+            FLAGS | PageTableFlags::NO_EXECUTE | PageTableFlags::HUGE_PAGE
+        );
+    }
+
+    #[test]
+    fn split_and_promote_1gib() {
+        let mut memory = SimulatedMemory::new(8);
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        memory.map(page, frame, FLAGS);
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.split_huge_page(page, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (entry, level, _) = walk(&mut memory, 0x4020_0000);
+        assert_eq!(level, PageTableLevel::Two);
+        assert_eq!(entry.addr(), PhysAddr::new(0x8020_0000));
+        assert!(entry.flags().contains(PageTableFlags::HUGE_PAGE));
+
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.promote_to_huge_page(page, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (entry, level, _) = walk(&mut memory, 0x4020_0000);
+        assert_eq!(level, PageTableLevel::Three);
+        assert_eq!(entry.addr(), PhysAddr::new(0x8000_0000));
+        assert_eq!(memory.allocated_frames(), 2);
+    }
+
+    #[test]
+    fn split_and_promote_relocate_pat_bit() {
+        let mut memory = SimulatedMemory::new(8);
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        memory.map(page, frame, FLAGS);
+        {
+            let (mut mapper, _) = memory.mapped_page_table();
+            unsafe {
+                mapper.walk_mut(page.start_address(), |entry, level| {
+                    entry.set_pat_index(level, 5)
+                })
+            };
+        }
+
+        // 1GiB -> 2MiB: the PAT bit stays bit 12
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.split_huge_page(page, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (entry, level, _) = walk(&mut memory, 0x4020_0000);
+        assert_eq!(level, PageTableLevel::Two);
+        assert_eq!(entry.pat_index(level), 5);
+
+        // 2MiB -> 4KiB: the PAT bit moves to bit 7
+        let huge = Page::<Size2MiB>::containing_address(VirtAddr::new(0x4020_0000));
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.split_huge_page(huge, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (entry, level, _) = walk(&mut memory, 0x4020_1000);
+        assert_eq!(level, PageTableLevel::One);
+        assert_eq!(entry.pat_index(level), 5);
+        assert_eq!(entry.addr(), PhysAddr::new(0x8020_1000));
+
+        // and back
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.promote_to_huge_page(huge, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+            unsafe { mapper.promote_to_huge_page(page, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (entry, level, _) = walk(&mut memory, 0x4020_1000);
+        assert_eq!(level, PageTableLevel::Three);
+        assert_eq!(entry.pat_index(level), 5);
+        assert_eq!(
+            entry.addr().as_u64() & !0x1000,
+            frame.start_address().as_u64()
+        );
+        assert_eq!(memory.allocated_frames(), 2);
+    }
+
+    #[test]
+    fn promote_keeps_parent_rights() {
+        let mut memory = SimulatedMemory::new(8);
+        let base = 0x20_0000;
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            for i in 0..512 {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + i * 0x1000));
+                let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000 + i * 0x1000));
+                unsafe {
+                    mapper.map_to_with_table_flags(
+                        page,
+                        frame,
+                        FLAGS,
+                        PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+                        &mut frame_allocator,
+                    )
+                }
+                .unwrap()
+                .ignore();
+            }
+        }
+        let (_, _, before) = walk(&mut memory, base + 0x1000);
+        assert_eq!(before, PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE);
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(base));
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.promote_to_huge_page(page, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (entry, level, after) = walk(&mut memory, base + 0x1000);
+        assert_eq!(level, PageTableLevel::Two);
+        assert_eq!(after, before | PageTableFlags::HUGE_PAGE);
+        assert!(!entry.flags().contains(PageTableFlags::USER_ACCESSIBLE));
+
+        // splitting keeps the effective flags too
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe { mapper.split_huge_page(page, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        let (_, level, split) = walk(&mut memory, base + 0x1000);
+        assert_eq!(level, PageTableLevel::One);
+        assert_eq!(split, before);
+    }
+
+    #[test]
+    fn promote_rejects_non_uniform_tables() {
+        let mut memory = SimulatedMemory::new(8);
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(0x20_0000));
+        memory.map(
+            first,
+            PhysFrame::containing_address(PhysAddr::new(0x4000_0000)),
+            FLAGS,
+        );
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+        assert!(matches!(
+            unsafe { mapper.promote_to_huge_page(page, &mut frame_allocator) },
+            Err(PromoteError::NotUniform)
+        ));
+        assert!(matches!(
+            unsafe {
+                mapper.split_huge_page(
+                    Page::<Size2MiB>::containing_address(VirtAddr::new(0x40_0000)),
+                    &mut frame_allocator,
+                )
+            },
+            Err(SplitError::PageNotMapped)
+        ));
+    }
+
+    #[test]
+    fn level_5_map_and_translate() {
+        let mut memory = SimulatedMemory::new(32);
+        let (mut mapper, mut frame_allocator) = memory.mapped_level_5_page_table();
+        // above the 4-level lower half, in the middle of the lower half and in the upper half
+        let small = Page::<Size4KiB>::containing_address(VirtAddr::new_la57(0x8000_0000_1000));
+        let huge = Page::<Size2MiB>::containing_address(VirtAddr::new_la57(0x00ab_cdef_0000_0000));
+        let upper = Page::<Size4KiB>::containing_address(VirtAddr::new_la57(0xff00_0000_0000_0000));
+        unsafe {
+            mapper
+                .map_to(
+                    small,
+                    SimulatedMemory::frame(0x10_0000),
+                    FLAGS,
+                    &mut frame_allocator,
+                )
+                .unwrap()
+                .ignore();
+            let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+            mapper
+                .map_to(huge, frame, FLAGS, &mut frame_allocator)
+                .unwrap()
+                .ignore();
+            mapper
+                .map_to(
+                    upper,
+                    SimulatedMemory::frame(0x20_0000),
+                    FLAGS,
+                    &mut frame_allocator,
+                )
+                .unwrap()
+                .ignore();
+        }
+
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0x8000_0000_1234)),
+            Some(PhysAddr::new(0x10_0234))
+        );
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0x00ab_cdef_0012_3456)),
+            Some(PhysAddr::new(0x4012_3456))
+        );
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0xff00_0000_0000_0042)),
+            Some(PhysAddr::new(0x20_0042))
+        );
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0x8000_0000_2000)),
+            None
+        );
+        // the level 5 index is taken from bits 48..57
+        let present: Vec<usize> = mapper
+            .level_5_table()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_unused())
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(present, [0, 0xab, 0x100]);
+
+        let (frame, flush) = mapper.unmap(small).unwrap();
+        flush.ignore();
+        assert_eq!(frame, SimulatedMemory::frame(0x10_0000));
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0x8000_0000_1000)),
+            None
+        );
+    }
 }
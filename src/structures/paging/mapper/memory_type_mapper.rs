@@ -0,0 +1,328 @@
+use crate::registers::model_specific::MemoryType;
+use crate::structures::paging::{
+    frame_alloc::FrameAllocator,
+    mapper::{Mapper, MapperFlush, MemoryTypeMapError, WalkMut},
+    Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+
+/// Provides methods for creating mappings with a specific memory type.
+///
+/// The memory type of a page is selected through the index of a PAT entry, which is stored in
+/// the `WRITE_THROUGH` and `NO_CACHE` flags and the PAT bit of the page table entry. The PAT
+/// bit has a different position for 4KiB pages and huge pages, so it can't be set through the
+/// flags that are passed to [`Mapper::map_to`].
+///
+/// This trait is automatically implemented for all types that implement both [`Mapper`] and
+/// [`WalkMut`].
+pub trait MemoryTypeMapper<S: PageSize>: Mapper<S> + WalkMut {
+    /// Creates a new mapping with the given memory type.
+    ///
+    /// The `pat` argument must contain the memory types of the PAT entries, as configured in
+    /// the [`Pat`](crate::registers::model_specific::Pat) register. The first PAT entry with
+    /// the given memory type is selected. The `WRITE_THROUGH` and `NO_CACHE` flags of `flags`
+    /// are ignored.
+    ///
+    /// The entry is only marked as present after the memory type is set, so that the page is
+    /// never accessible with a different memory type.
+    ///
+    /// Returns [`MemoryTypeMapError::MemoryTypeNotInPat`] if `pat` doesn't contain the memory
+    /// type.
+    ///
+    /// ## Safety
+    ///
+    /// This is a convencience function that invokes [`Mapper::map_to_with_table_flags`]
+    /// internally, so all safety requirements of it also apply for this function. In addition,
+    /// the caller must ensure that the frame is not mapped with a different memory type
+    /// elsewhere, since this can lead to undefined behavior.
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &[MemoryType; 8],
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<S>, MemoryTypeMapError<S>>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let pat_index = pat
+            .iter()
+            .position(|&entry| entry == memory_type)
+            .ok_or(MemoryTypeMapError::MemoryTypeNotInPat(memory_type))?;
+
+        let parent_table_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE);
+        let entry_flags = flags
+            - PageTableFlags::PRESENT
+            - PageTableFlags::WRITE_THROUGH
+            - PageTableFlags::NO_CACHE;
+        let flush = self.map_to_with_table_flags(
+            page,
+            frame,
+            entry_flags,
+            parent_table_flags,
+            frame_allocator,
+        )?;
+
+        let present = flags & PageTableFlags::PRESENT;
+        self.walk_mut(page.start_address(), |entry, level| {
+            entry.set_pat_index(level, pat_index as u8);
+            entry.set_flags(entry.flags() | present);
+        });
+        Ok(flush)
+    }
+}
+
+impl<T, S> MemoryTypeMapper<S> for T
+where
+    T: Mapper<S> + WalkMut + ?Sized,
+    S: PageSize,
+{
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{
+        mapper::{
+            AddressSpace, FlagUpdateError, MappedFrame, MappedPageTable, RangeMapper,
+            SimulatedMemory, Translate, TranslateResult, Walk, WalkResult,
+        },
+        PageTable, PageTableLevel, Size1GiB, Size2MiB,
+    };
+    use crate::{PhysAddr, VirtAddr};
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+    /// Returns the PAT index and the flags of the leaf entry that maps `addr`.
+    fn leaf(mapper: &impl Walk, addr: VirtAddr) -> Option<(u8, PageTableFlags)> {
+        match mapper.walk(addr) {
+            WalkResult::Mapped { entry, level, .. } => {
+                Some((entry.pat_index(level), entry.flags()))
+            }
+            WalkResult::NotMapped { .. } => None,
+        }
+    }
+
+    /// Maps, translates, protects, and unmaps a page of size `S` with every PAT index.
+    fn check_all_pat_indices<S: PageSize>(level: PageTableLevel)
+    where
+        for<'a> MappedPageTable<'a, &'a SimulatedMemory>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(VirtAddr::new(S::SIZE));
+        let frame = PhysFrame::<S>::containing_address(PhysAddr::new(4 * S::SIZE));
+        let pages = Page::range(
+            Page::<Size4KiB>::containing_address(page.start_address()),
+            Page::containing_address(page.start_address() + S::SIZE),
+        );
+        // the `HUGE_PAGE` bit is the PAT bit of level 1 entries
+        let memory_type_flags =
+            PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE | PageTableFlags::HUGE_PAGE;
+
+        for index in 0..8 {
+            let mut memory = SimulatedMemory::new(8);
+            memory.map(page, frame, FLAGS);
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            unsafe {
+                mapper.walk_mut(page.start_address(), |entry, entry_level| {
+                    assert_eq!(entry_level, level);
+                    entry.set_pat_index(level, index)
+                })
+            };
+
+            assert_eq!(mapper.translate_page(page).unwrap(), frame);
+            let addr = page.start_address() + 0x234u64;
+            match mapper.translate(addr) {
+                TranslateResult::Mapped {
+                    frame: f, offset, ..
+                } => {
+                    assert_eq!(f.start_address() + offset, frame.start_address() + 0x234u64)
+                }
+                result => panic!("{:?} is not mapped: {:?}", addr, result),
+            }
+
+            // the memory type flags of `flags` are ignored
+            unsafe {
+                mapper.update_protection_flags(page, PageTableFlags::PRESENT | memory_type_flags)
+            }
+            .unwrap()
+            .ignore();
+            let (pat_index, flags) = leaf(&mapper, addr).unwrap();
+            assert_eq!(pat_index, index);
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+            unsafe { mapper.protect_range(pages, FLAGS, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+            let (pat_index, flags) = leaf(&mapper, addr).unwrap();
+            assert_eq!(pat_index, index);
+            assert_eq!(flags - memory_type_flags, FLAGS);
+
+            if index % 2 == 0 {
+                assert_eq!(mapper.unmap(page).unwrap().0, frame);
+            } else {
+                unsafe { mapper.unmap_range(pages, &mut frame_allocator) }
+                    .unwrap()
+                    .ignore();
+            }
+            assert!(leaf(&mapper, addr).is_none());
+        }
+    }
+
+    #[test]
+    fn pat_index_4kib() {
+        check_all_pat_indices::<Size4KiB>(PageTableLevel::One);
+    }
+
+    #[test]
+    fn pat_index_2mib() {
+        check_all_pat_indices::<Size2MiB>(PageTableLevel::Two);
+    }
+
+    #[test]
+    fn pat_index_1gib() {
+        check_all_pat_indices::<Size1GiB>(PageTableLevel::Three);
+    }
+
+    #[test]
+    fn map_to_with_memory_type() {
+        use MemoryType::*;
+        let pat = [
+            WriteBack,
+            WriteCombining,
+            UncacheableMinus,
+            Uncacheable,
+            WriteBack,
+            WriteProtected,
+            UncacheableMinus,
+            WriteThrough,
+        ];
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+
+        let mut memory = SimulatedMemory::new(8);
+        let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+        unsafe {
+            mapper.map_to_with_memory_type(
+                page,
+                frame,
+                FLAGS,
+                WriteThrough,
+                &pat,
+                &mut frame_allocator,
+            )
+        }
+        .unwrap()
+        .ignore();
+        assert_eq!(
+            leaf(&mapper, page.start_address()),
+            Some((
+                7,
+                FLAGS
+                    | PageTableFlags::HUGE_PAGE
+                    | PageTableFlags::WRITE_THROUGH
+                    | PageTableFlags::NO_CACHE
+            ))
+        );
+        assert_eq!(mapper.translate_page(page).unwrap(), frame);
+        assert!(matches!(
+            unsafe {
+                mapper.map_to_with_memory_type(
+                    page,
+                    frame,
+                    FLAGS,
+                    MemoryType::Uncacheable,
+                    &[WriteBack; 8],
+                    &mut frame_allocator,
+                )
+            },
+            Err(MemoryTypeMapError::MemoryTypeNotInPat(
+                MemoryType::Uncacheable
+            ))
+        ));
+    }
+
+    #[test]
+    fn address_space_translate() {
+        let memory = SimulatedMemory::new(8);
+        let mut frame_allocator = memory.frame_allocator();
+        let root = frame_allocator.allocate_frame().unwrap();
+        let mut space =
+            unsafe { AddressSpace::new(root, &PageTable::new(), &memory, frame_allocator) };
+
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        unsafe { space.map_to(page, frame, FLAGS, &mut frame_allocator) }
+            .unwrap()
+            .ignore();
+        unsafe {
+            space
+                .mapper()
+                .walk_mut(page.start_address(), |entry, level| {
+                    entry.set_pat_index(level, 4)
+                })
+        };
+        match space.translate(VirtAddr::new(0x4000_1234)) {
+            TranslateResult::Mapped {
+                frame: f, offset, ..
+            } => {
+                assert!(matches!(f, MappedFrame::Size1GiB(f) if f == frame));
+                assert_eq!(offset, 0x1234);
+            }
+            result => panic!("not mapped: {:?}", result),
+        }
+        assert_eq!(space.unmap(page).unwrap().0, frame);
+    }
+
+    #[test]
+    fn update_flags_sets_memory_type_flags() {
+        let mut memory = SimulatedMemory::new(8);
+        let small = SimulatedMemory::page(0x1000);
+        let huge = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        memory.map(small, SimulatedMemory::frame(0x5000), FLAGS);
+        memory.map(
+            huge,
+            PhysFrame::containing_address(PhysAddr::new(0x4000_0000)),
+            FLAGS,
+        );
+        let (mut mapper, _) = memory.mapped_page_table();
+        for &addr in &[small.start_address(), huge.start_address()] {
+            unsafe { mapper.walk_mut(addr, |entry, level| entry.set_pat_index(level, 5)) };
+        }
+
+        // `update_flags` sets the flags as given, including the PAT bit of 4KiB pages
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_CACHE;
+        unsafe { mapper.update_flags(small, flags | PageTableFlags::HUGE_PAGE) }
+            .unwrap()
+            .ignore();
+        assert_eq!(
+            leaf(&mapper, small.start_address()),
+            Some((6, flags | PageTableFlags::HUGE_PAGE))
+        );
+        // the PAT bit of huge pages is part of the address bits
+        unsafe { mapper.update_flags(huge, flags) }
+            .unwrap()
+            .ignore();
+        assert_eq!(
+            leaf(&mapper, huge.start_address()),
+            Some((6, flags | PageTableFlags::HUGE_PAGE))
+        );
+
+        unsafe { mapper.update_protection_flags(small, FLAGS) }
+            .unwrap()
+            .ignore();
+        assert_eq!(leaf(&mapper, small.start_address()).unwrap().0, 6);
+        assert!(matches!(
+            unsafe { mapper.update_protection_flags(SimulatedMemory::page(0x2000), FLAGS) },
+            Err(FlagUpdateError::PageNotMapped)
+        ));
+        assert!(matches!(
+            unsafe { mapper.update_protection_flags(SimulatedMemory::page(0x20_1000), FLAGS) },
+            Err(FlagUpdateError::ParentEntryHugePage)
+        ));
+    }
+}
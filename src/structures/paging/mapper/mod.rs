@@ -1,21 +1,52 @@
 //! Abstractions for reading and modifying the mapping of pages.
 
-pub use self::mapped_page_table::{MappedPageTable, PageTableFrameMapping};
+pub use self::access_tracker::AccessTracker;
+pub use self::address_space::{AddressSpace, AddressSpaceError};
+#[cfg(feature = "instructions")]
+pub use self::flush_batch::FlushBatch;
+#[cfg(feature = "instructions")]
+pub use self::kpti::KptiPcids;
+pub use self::kpti::{KptiAddressSpace, KptiError};
+pub use self::map_transaction::{MapTransaction, MapTransactionError};
+pub use self::mapped_page_table::{MappedLevel5PageTable, MappedPageTable, PageTableFrameMapping};
+pub use self::memory_type_mapper::MemoryTypeMapper;
 #[cfg(target_pointer_width = "64")]
-pub use self::offset_page_table::OffsetPageTable;
+pub use self::offset_page_table::{AnyOffsetPageTable, OffsetLevel5PageTable, OffsetPageTable};
+pub use self::page_fault::{
+    handle_page_fault, FatalFault, FaultMemory, FaultOutcome, FaultRegion, FaultResolver, PageFault,
+};
+pub use self::page_table_dump::PageTableDump;
+pub use self::range_mapper::RangeMapper;
 #[cfg(feature = "instructions")]
 pub use self::recursive_page_table::{InvalidPageTable, RecursivePageTable};
+#[cfg(feature = "alloc")]
+pub use self::simulated_memory::{SimulatedFrameAllocator, SimulatedMemory};
 
+use crate::registers::model_specific::MemoryType;
 use crate::structures::paging::{
-    frame_alloc::FrameAllocator, page_table::PageTableFlags, Page, PageSize, PhysFrame, Size1GiB,
-    Size2MiB, Size4KiB,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    page::PageRangeInclusive,
+    page_table::{PageTable, PageTableEntry, PageTableFlags, PageTableIndex, PageTableLevel},
+    Page, PageSize, PagingMode, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
 };
 use crate::{PhysAddr, VirtAddr};
 
+mod access_tracker;
+mod address_space;
+#[cfg(feature = "instructions")]
+mod flush_batch;
+mod kpti;
+mod map_transaction;
 mod mapped_page_table;
+mod memory_type_mapper;
 mod offset_page_table;
+mod page_fault;
+mod page_table_dump;
+mod range_mapper;
 #[cfg(feature = "instructions")]
 mod recursive_page_table;
+#[cfg(feature = "alloc")]
+mod simulated_memory;
 
 /// An empty convencience trait that requires the `Mapper` trait for all page sizes.
 pub trait MapperAllSizes: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> {}
@@ -46,6 +77,82 @@ pub trait Translate {
             TranslateResult::Mapped { frame, offset, .. } => Some(frame.start_address() + offset),
         }
     }
+
+    /// Translates the given virtual address and returns details about the page table walk.
+    ///
+    /// In contrast to [`translate`](Translate::translate), the returned flags are the
+    /// effective flags that the CPU enforces for the address: The `WRITABLE` and
+    /// `USER_ACCESSIBLE` flags are only set if they are set in all entries on the path to the
+    /// page and the `NO_EXECUTE` flag is set if it is set in any of these entries. The result
+    /// also contains the level of the entry that maps the page and the physical addresses of
+    /// all visited page tables.
+    ///
+    /// For example, user mode code can write to the address if the returned flags contain
+    /// `PRESENT`, `WRITABLE`, and `USER_ACCESSIBLE`.
+    #[inline]
+    fn translate_detailed(&self, addr: VirtAddr) -> DetailedTranslateResult
+    where
+        Self: Walk + Sized,
+    {
+        match self.walk(addr) {
+            WalkResult::Mapped {
+                entry,
+                level,
+                flags,
+                tables,
+            } => {
+                let frame_addr = entry.addr();
+                let frame = match level {
+                    PageTableLevel::One => {
+                        MappedFrame::Size4KiB(PhysFrame::containing_address(frame_addr))
+                    }
+                    PageTableLevel::Two => {
+                        MappedFrame::Size2MiB(PhysFrame::containing_address(frame_addr))
+                    }
+                    PageTableLevel::Three => {
+                        MappedFrame::Size1GiB(PhysFrame::containing_address(frame_addr))
+                    }
+                    _ => panic!("level {} entry has huge page bit set", level as u8),
+                };
+                DetailedTranslateResult::Mapped {
+                    offset: addr.as_u64() & (frame.size() - 1),
+                    frame,
+                    flags,
+                    level,
+                    tables,
+                }
+            }
+            WalkResult::NotMapped { level, tables } => {
+                DetailedTranslateResult::NotMapped { level, tables }
+            }
+        }
+    }
+}
+
+/// The return value of the [`Translate::translate_detailed`] function.
+#[derive(Debug)]
+pub enum DetailedTranslateResult {
+    /// The virtual address is mapped to a physical frame.
+    Mapped {
+        /// The mapped frame.
+        frame: MappedFrame,
+        /// The offset whithin the mapped frame.
+        offset: u64,
+        /// The effective flags of the mapping, see [`Translate::translate_detailed`].
+        flags: PageTableFlags,
+        /// The level of the page table that contains the entry that maps the page.
+        level: PageTableLevel,
+        /// The physical addresses of the visited page tables.
+        tables: VisitedTables,
+    },
+    /// The virtual address is not mapped because the entry in the page table of the given
+    /// level doesn't have the `PRESENT` flag set.
+    NotMapped {
+        /// The level of the page table that contains the non-present entry.
+        level: PageTableLevel,
+        /// The physical addresses of the visited page tables.
+        tables: VisitedTables,
+    },
 }
 
 /// The return value of the [`Translate::translate`] function.
@@ -104,6 +211,305 @@ impl MappedFrame {
     }
 }
 
+/// Represents a page mapped in a page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPage {
+    /// The page is a normal 4KiB page.
+    Size4KiB(Page<Size4KiB>),
+    /// The page is a "large" 2MiB page.
+    Size2MiB(Page<Size2MiB>),
+    /// The page is a "huge" 1GiB page.
+    Size1GiB(Page<Size1GiB>),
+}
+
+impl MappedPage {
+    /// Returns the start address of the page.
+    pub fn start_address(&self) -> VirtAddr {
+        match self {
+            MappedPage::Size4KiB(page) => page.start_address(),
+            MappedPage::Size2MiB(page) => page.start_address(),
+            MappedPage::Size1GiB(page) => page.start_address(),
+        }
+    }
+
+    /// Returns the size the page (4KB, 2MB or 1GB).
+    pub const fn size(&self) -> u64 {
+        match self {
+            MappedPage::Size4KiB(_) => Size4KiB::SIZE,
+            MappedPage::Size2MiB(_) => Size2MiB::SIZE,
+            MappedPage::Size1GiB(_) => Size1GiB::SIZE,
+        }
+    }
+
+    /// Returns the page that contains `addr` and that is mapped by an entry of the given level.
+    fn containing_address(addr: VirtAddr, level: PageTableLevel) -> Self {
+        match level {
+            PageTableLevel::One => MappedPage::Size4KiB(Page::containing_address(addr)),
+            PageTableLevel::Two => MappedPage::Size2MiB(Page::containing_address(addr)),
+            PageTableLevel::Three => MappedPage::Size1GiB(Page::containing_address(addr)),
+            _ => panic!("level {} entry has huge page bit set", level as u8),
+        }
+    }
+}
+
+/// Provides methods for walking the page table hierarchy and for iterating over all mappings.
+pub trait Walk {
+    /// Walks the page table hierarchy for the given virtual address.
+    ///
+    /// The walk stops at the first entry that doesn't have the `PRESENT` flag set or that maps
+    /// a page, i.e. at a level 1 entry or at an entry with the `HUGE_PAGE` flag set.
+    fn walk(&self, addr: VirtAddr) -> WalkResult;
+
+    /// Returns the paging mode that the page table hierarchy is used with.
+    ///
+    /// This determines where the non-canonical hole of the virtual address space is.
+    #[inline]
+    fn paging_mode(&self) -> PagingMode {
+        PagingMode::Level4
+    }
+
+    /// Returns an iterator over all present mappings in the page table.
+    ///
+    /// Unmapped parts of the address space are skipped as a whole, so iterating over a sparse
+    /// page table is fast even if it spans the complete address space.
+    #[inline]
+    fn mappings(&self) -> Mappings<'_, Self>
+    where
+        Self: Sized,
+    {
+        Mappings {
+            walker: self,
+            mode: self.paging_mode(),
+            next: Some(VirtAddr::zero()),
+            last: VirtAddr::new_truncate(u64::MAX),
+        }
+    }
+
+    /// Returns an iterator over all present mappings that overlap with the given range.
+    ///
+    /// Huge pages that only partially overlap with the range are included.
+    #[inline]
+    fn mappings_in_range(&self, range: PageRangeInclusive) -> Mappings<'_, Self>
+    where
+        Self: Sized,
+    {
+        let next = if range.is_empty() {
+            None
+        } else {
+            Some(range.start.start_address())
+        };
+        Mappings {
+            walker: self,
+            mode: self.paging_mode(),
+            next,
+            last: range.end.start_address() + (range.end.size() - 1),
+        }
+    }
+
+    /// Returns a formatter that prints all present mappings as merged regions.
+    ///
+    /// See [`PageTableDump`] for details about the format.
+    #[inline]
+    fn dump(&self) -> PageTableDump<'_, Self>
+    where
+        Self: Sized,
+    {
+        PageTableDump::new(self.mappings())
+    }
+}
+
+/// Provides mutable access to the page table entries that a page table walk visits.
+pub trait WalkMut: Walk {
+    /// Walks the page table hierarchy for the given virtual address and calls `f` with the entry
+    /// at which the walk stops.
+    ///
+    /// Like for [`Walk::walk`], the walk stops at the first entry that doesn't have the
+    /// `PRESENT` flag set or that maps a page. The second argument of `f` is the level of the
+    /// page table that contains the entry.
+    ///
+    /// ## Safety
+    ///
+    /// Modifying page table entries can break memory safety in the same ways as
+    /// [`Mapper::map_to`] and [`Mapper::update_flags`]. The caller is also responsible for
+    /// flushing the TLB for modified entries.
+    #[inline]
+    unsafe fn walk_mut<F, R>(&mut self, addr: VirtAddr, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        self.walk_mut_to_level(addr, PageTableLevel::One, f)
+    }
+
+    /// Walks the page table hierarchy for the given virtual address like
+    /// [`walk_mut`](Self::walk_mut), but stops at the page table of the given level at the
+    /// latest.
+    ///
+    /// This makes it possible to modify entries that point to page tables. If the walk stops
+    /// at a higher level, the second argument of `f` is the level of that table.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`walk_mut`](Self::walk_mut) apply.
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R;
+}
+
+/// The return value of the [`Walk::walk`] function.
+#[derive(Debug, Clone)]
+pub enum WalkResult {
+    /// The virtual address is mapped by the given entry.
+    Mapped {
+        /// The entry that maps the page.
+        entry: PageTableEntry,
+        /// The level of the page table that contains the entry.
+        ///
+        /// This is `PageTableLevel::One` for 4KiB pages, `PageTableLevel::Two` for 2MiB pages
+        /// and `PageTableLevel::Three` for 1GiB pages.
+        level: PageTableLevel,
+        /// The effective flags of the mapping.
+        ///
+        /// The `WRITABLE` and `USER_ACCESSIBLE` flags are only set if they are set in all
+        /// entries on the path to the page. The `NO_EXECUTE` flag is set if it is set in any
+        /// of these entries. All other flags are the flags of `entry`.
+        flags: PageTableFlags,
+        /// The physical addresses of the page tables that were visited.
+        tables: VisitedTables,
+    },
+    /// The virtual address is not mapped because the entry in the page table of the given
+    /// level doesn't have the `PRESENT` flag set.
+    NotMapped {
+        /// The level of the page table that contains the non-present entry.
+        level: PageTableLevel,
+        /// The physical addresses of the page tables that were visited.
+        tables: VisitedTables,
+    },
+}
+
+/// The physical addresses of the page tables that were visited by a page table walk.
+///
+/// The [`MappedPageTable`] types only have a virtual reference to the root table, so they only
+/// know its physical address if their [`PageTableFrameMapping`] implements
+/// [`pointer_to_frame`](PageTableFrameMapping::pointer_to_frame). The address is always known
+/// for the [`RecursivePageTable`] and the [`OffsetPageTable`] types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VisitedTables {
+    tables: [Option<PhysAddr>; 5],
+}
+
+impl VisitedTables {
+    /// Returns the physical address of the visited page table of the given level.
+    ///
+    /// Returns `None` if no table of this level was visited or if its address is unknown.
+    #[inline]
+    pub fn get(&self, level: PageTableLevel) -> Option<PhysAddr> {
+        self.tables[usize::from(level as u8 - 1)]
+    }
+
+    /// Returns an iterator over the levels and physical addresses of the visited page tables,
+    /// starting at the highest level.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (PageTableLevel, PhysAddr)> + '_ {
+        let mut level = Some(PageTableLevel::Five);
+        core::iter::from_fn(move || {
+            let current = level?;
+            level = current.next_lower_level();
+            Some((current, self.get(current)))
+        })
+        .filter_map(|(level, addr)| addr.map(|addr| (level, addr)))
+    }
+
+    #[inline]
+    fn set(&mut self, level: PageTableLevel, addr: PhysAddr) {
+        self.tables[usize::from(level as u8 - 1)] = Some(addr);
+    }
+}
+
+/// An iterator over the present mappings of a page table.
+///
+/// The iterator yields the mapped page, the mapped frame, and the effective flags of each
+/// mapping, see [`WalkResult::Mapped`]. This struct is created by the [`Walk::mappings`] and
+/// [`Walk::mappings_in_range`] methods.
+#[derive(Debug)]
+pub struct Mappings<'a, W: Walk> {
+    walker: &'a W,
+    mode: PagingMode,
+    next: Option<VirtAddr>,
+    last: VirtAddr,
+}
+
+impl<'a, W: Walk> Mappings<'a, W> {
+    /// Returns the start address, the entry, the level, and the effective flags of the next
+    /// mapping.
+    fn next_entry(&mut self) -> Option<(VirtAddr, PageTableEntry, PageTableLevel, PageTableFlags)> {
+        loop {
+            let addr = self.next.filter(|&addr| addr <= self.last)?;
+            match self.walker.walk(addr) {
+                WalkResult::Mapped {
+                    entry,
+                    level,
+                    flags,
+                    ..
+                } => {
+                    let start = self.skip(addr, level);
+                    return Some((start, entry, level, flags));
+                }
+                WalkResult::NotMapped { level, .. } => {
+                    self.skip(addr, level);
+                }
+            }
+        }
+    }
+
+    /// Continues the iteration after the memory that is covered by an entry of the given level.
+    fn skip(&mut self, addr: VirtAddr, level: PageTableLevel) -> VirtAddr {
+        let size = level.entry_address_space_alignment();
+        let start = addr.align_down(size);
+        self.next = start.as_u64().checked_add(size).map(|next| {
+            self.mode
+                .try_new_virt_addr(next)
+                .unwrap_or_else(|_| self.mode.higher_half_start())
+        });
+        start
+    }
+}
+
+impl<'a, W: Walk> Clone for Mappings<'a, W> {
+    fn clone(&self) -> Self {
+        Mappings {
+            walker: self.walker,
+            mode: self.mode,
+            next: self.next,
+            last: self.last,
+        }
+    }
+}
+
+impl<'a, W: Walk> Iterator for Mappings<'a, W> {
+    type Item = (MappedPage, MappedFrame, PageTableFlags);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start, entry, level, flags) = self.next_entry()?;
+        let frame_addr = entry.addr();
+        let (page, frame) = match level {
+            PageTableLevel::One => (
+                MappedPage::Size4KiB(Page::containing_address(start)),
+                MappedFrame::Size4KiB(PhysFrame::containing_address(frame_addr)),
+            ),
+            PageTableLevel::Two => (
+                MappedPage::Size2MiB(Page::containing_address(start)),
+                MappedFrame::Size2MiB(PhysFrame::containing_address(frame_addr)),
+            ),
+            PageTableLevel::Three => (
+                MappedPage::Size1GiB(Page::containing_address(start)),
+                MappedFrame::Size1GiB(PhysFrame::containing_address(frame_addr)),
+            ),
+            _ => panic!("level {} entry has huge page bit set", level as u8),
+        };
+        Some((page, frame, flags))
+    }
+}
+
 /// A trait for common page table operations on pages of size `S`.
 pub trait Mapper<S: PageSize> {
     /// Creates a new mapping in the page table.
@@ -295,6 +701,48 @@ pub trait Mapper<S: PageSize> {
         flags: PageTableFlags,
     ) -> Result<MapperFlush<S>, FlagUpdateError>;
 
+    /// Updates the flags of an existing mapping like [`update_flags`](Self::update_flags), but
+    /// keeps the memory type of the mapping.
+    ///
+    /// The `WRITE_THROUGH` and `NO_CACHE` flags and the PAT bit of `flags` are ignored, so
+    /// that only the access rights and the other flags of the mapping change. Use
+    /// [`WalkMut::walk_mut`] together with [`PageTableEntry::set_pat_index`] for changing the
+    /// memory type.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`update_flags`](Self::update_flags) apply.
+    unsafe fn update_protection_flags(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<S>, FlagUpdateError>
+    where
+        Self: WalkMut + Sized,
+    {
+        let level = leaf_level(S::SIZE);
+        self.walk_mut_to_level(page.start_address(), level, |entry, entry_level| {
+            if entry_level != level {
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    return Err(FlagUpdateError::ParentEntryHugePage);
+                }
+                return Err(FlagUpdateError::PageNotMapped);
+            }
+            if entry.is_unused() {
+                return Err(FlagUpdateError::PageNotMapped);
+            }
+            if level == PageTableLevel::One {
+                entry.set_leaf_flags(level, flags);
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                entry.set_leaf_flags(level, flags | PageTableFlags::HUGE_PAGE);
+            } else {
+                // the entry points to a page table instead of a huge page
+                return Err(FlagUpdateError::PageNotMapped);
+            }
+            Ok(MapperFlush::new(page))
+        })
+    }
+
     /// Set the flags of an existing page level 4 table entry
     ///
     /// ## Safety
@@ -370,6 +818,143 @@ pub trait Mapper<S: PageSize> {
     }
 }
 
+/// Provides methods for cleaning up unused page tables.
+pub trait CleanUp {
+    /// Remove all empty P1-P3 tables
+    ///
+    /// The freed page tables might still be cached in the paging-structure caches of the CPU,
+    /// so the returned `MapperFlushAll` must be flushed before the freed frames are reused.
+    ///
+    /// ## Safety
+    ///
+    /// The caller has to guarantee that it's safe to free page table frames:
+    /// All page table frames must only be used once and only in this page table
+    /// (e.g. no reference counted page tables or reusing the same page tables for different
+    /// virtual addresses ranges in the same page table). Page tables that are shared with
+    /// other address spaces must not be freed this way.
+    #[inline]
+    unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let range = Page::range_inclusive(
+            Page::containing_address(VirtAddr::zero()),
+            Page::containing_address(VirtAddr::new_truncate(u64::MAX)),
+        );
+        self.clean_up_addr_range(range, frame_deallocator)
+    }
+
+    /// Remove all empty P1-P3 tables in a certain range
+    ///
+    /// The freed page tables might still be cached in the paging-structure caches of the CPU,
+    /// so the returned `MapperFlushAll` must be flushed before the freed frames are reused.
+    ///
+    /// ## Safety
+    ///
+    /// The caller has to guarantee that it's safe to free page table frames:
+    /// All page table frames must only be used once and only in this page table
+    /// (e.g. no reference counted page tables or reusing the same page tables for different
+    /// virtual addresses ranges in the same page table). Page tables that are shared with
+    /// other address spaces must not be freed this way.
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized;
+
+    /// Removes a mapping from the page table and frees the page tables that became empty
+    /// because of it.
+    ///
+    /// The frame that used to be mapped is returned, it is not deallocated. The returned
+    /// `MapperFlush` also invalidates the paging-structure caches that might still reference
+    /// the freed page tables, so no full TLB flush is needed.
+    ///
+    /// ## Safety
+    ///
+    /// The same requirements as for [`clean_up`](CleanUp::clean_up) apply.
+    unsafe fn unmap_and_clean_up<S, D>(
+        &mut self,
+        page: Page<S>,
+        frame_deallocator: &mut D,
+    ) -> Result<(PhysFrame<S>, MapperFlush<S>), UnmapError>
+    where
+        Self: Mapper<S> + Sized,
+        S: PageSize,
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let result = self.unmap(page)?;
+
+        // `invlpg` invalidates all paging-structure cache entries on the path to the page, so
+        // the flush returned by `unmap` covers the freed page tables as well.
+        let range = Page::range_inclusive(
+            Page::containing_address(page.start_address()),
+            Page::containing_address(page.start_address() + (page.size() - 1)),
+        );
+        self.clean_up_addr_range(range, frame_deallocator).ignore();
+
+        Ok(result)
+    }
+}
+
+/// Provides methods for splitting huge pages and for promoting page tables to huge pages.
+///
+/// This trait is implemented for `Size2MiB` and `Size1GiB` pages. A `Size2MiB` page is split into
+/// a level 1 table of `Size4KiB` pages and a `Size1GiB` page is split into a level 2 table of
+/// `Size2MiB` pages.
+pub trait HugePageMapper<S: PageSize> {
+    /// Splits the given huge page into a page table of pages of the next smaller size.
+    ///
+    /// The new page table maps the same frames with the same flags as the huge page, so the
+    /// translation of all addresses stays the same. Afterwards, the smaller pages can be
+    /// updated individually, e.g. through [`Mapper::update_flags`]. The frame for the new page
+    /// table is allocated from the `frame_allocator`.
+    ///
+    /// The parent entry of the new table gets the `PRESENT` and `WRITABLE` flags and keeps the
+    /// `USER_ACCESSIBLE` flag of the huge page. All other flags are set on the smaller pages only.
+    ///
+    /// ## Safety
+    ///
+    /// Some mapper implementations can't update the page table atomically. For example, the
+    /// `RecursivePageTable` can only fill the new page table after linking it, so the huge page
+    /// must not be accessed while it is split. See the documentation of the mapper types for
+    /// details.
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<S>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized;
+
+    /// Replaces the page table that maps the given page with a single huge page entry and
+    /// frees the table frame.
+    ///
+    /// This is only possible if all entries of the table map contiguous frames with the same
+    /// flags and if the first frame is aligned to the size of the huge page. The `ACCESSED` and
+    /// `DIRTY` flags are ignored for this check and set on the huge page if they are set on any
+    /// of the entries. The access rights of the parent entry are kept, so that the effective
+    /// flags of all addresses stay the same.
+    ///
+    /// The smaller pages might still be cached in the TLB, so the returned `MapperFlushRange`
+    /// covers all of them.
+    ///
+    /// ## Safety
+    ///
+    /// The caller has to guarantee that it's safe to free the page table frame, i.e. that it
+    /// is not used anywhere else, e.g. in other address spaces.
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<S>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        Self: Sized,
+        D: FrameDeallocator<Size4KiB> + ?Sized;
+}
+
 /// This type represents a page whose mapping has changed in the page table.
 ///
 /// The old mapping might be still cached in the translation lookaside buffer (TLB), so it needs
@@ -382,7 +967,7 @@ pub struct MapperFlush<S: PageSize>(Page<S>);
 impl<S: PageSize> MapperFlush<S> {
     /// Create a new flush promise
     #[inline]
-    fn new(page: Page<S>) -> Self {
+    pub(crate) fn new(page: Page<S>) -> Self {
         MapperFlush(page)
     }
 
@@ -426,6 +1011,164 @@ impl MapperFlushAll {
     pub fn ignore(self) {}
 }
 
+/// This type represents a range of pages whose mappings have changed in the page table.
+///
+/// It is returned from methods that might change the mappings of many pages at once, such as
+/// the methods of the [`RangeMapper`] and [`HugePageMapper`] traits. Like [`MapperFlush`], it
+/// ensures that the TLB flush is not forgotten.
+///
+/// The changed pages are stored as a few runs of contiguous pages of the same size, so that
+/// flushing them only invalidates the changed pages. If more than
+/// [`THRESHOLD`](Self::THRESHOLD) pages or too many separate runs of pages were changed, the
+/// complete TLB is flushed instead.
+#[derive(Debug)]
+#[must_use = "Page Table changes must be flushed or ignored."]
+pub struct MapperFlushRange {
+    runs: [FlushRun; FLUSH_RANGE_RUNS],
+    len: usize,
+    /// The number of changed pages, counting each huge page once.
+    pages: u64,
+    /// Whether the changed pages didn't fit into `runs`.
+    overflow: bool,
+}
+
+/// The maximum number of runs of pages that a [`MapperFlushRange`] can store.
+const FLUSH_RANGE_RUNS: usize = 4;
+
+/// A run of contiguous pages of the same size.
+#[derive(Debug, Clone, Copy)]
+struct FlushRun {
+    first: VirtAddr,
+    last: VirtAddr,
+    step: u64,
+}
+
+impl MapperFlushRange {
+    /// The number of changed pages above which [`flush`](Self::flush) flushes the complete TLB
+    /// instead of the single pages.
+    pub const THRESHOLD: u64 = 32;
+
+    /// Create a new flush promise that doesn't contain any pages yet
+    #[inline]
+    fn new() -> Self {
+        MapperFlushRange {
+            runs: [FlushRun {
+                first: VirtAddr::zero(),
+                last: VirtAddr::zero(),
+                step: 0,
+            }; FLUSH_RANGE_RUNS],
+            len: 0,
+            pages: 0,
+            overflow: false,
+        }
+    }
+
+    /// Create a new flush promise for all pages of size `step` in the given page
+    #[inline]
+    fn for_page<S: PageSize>(page: Page<S>, step: u64) -> Self {
+        let mut flush = Self::new();
+        flush.runs[0] = FlushRun {
+            first: page.start_address(),
+            last: page.start_address() + (page.size() - 1),
+            step,
+        };
+        flush.len = 1;
+        flush.pages = page.size() / step;
+        flush
+    }
+
+    /// Add a changed page to the flush promise
+    #[inline]
+    fn add(&mut self, start: VirtAddr, size: u64) {
+        self.pages = self.pages.saturating_add(1);
+        if self.flushes_all() {
+            return;
+        }
+
+        if let Some(previous) = self.len.checked_sub(1).map(|i| &mut self.runs[i]) {
+            if previous.step == size && previous.last.as_u64().wrapping_add(1) == start.as_u64() {
+                previous.last = start + (size - 1);
+                return;
+            }
+        }
+
+        if self.len == FLUSH_RANGE_RUNS {
+            self.overflow = true;
+        } else {
+            self.runs[self.len] = FlushRun {
+                first: start,
+                last: start + (size - 1),
+                step: size,
+            };
+            self.len += 1;
+        }
+    }
+
+    /// Marks the complete TLB as changed, e.g. because the flags of a parent entry changed
+    #[inline]
+    fn add_all(&mut self) {
+        self.pages = self.pages.saturating_add(1);
+        self.overflow = true;
+    }
+
+    /// Returns the runs of changed pages as `(first, last, step)` tuples.
+    ///
+    /// The result is only complete if [`flushes_all`](Self::flushes_all) returns `false`.
+    fn runs(&self) -> impl Iterator<Item = (VirtAddr, VirtAddr, u64)> + '_ {
+        self.runs[..self.len]
+            .iter()
+            .map(|run| (run.first, run.last, run.step))
+    }
+
+    /// Returns whether no mapping was changed, so that there is nothing to flush.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    /// Returns the number of changed pages, counting each huge page once.
+    #[inline]
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    /// Returns whether [`flush`](Self::flush) flushes the complete TLB instead of single pages.
+    #[inline]
+    pub fn flushes_all(&self) -> bool {
+        self.overflow || self.pages > Self::THRESHOLD
+    }
+
+    /// Flush all changed pages from the TLB to ensure that the newest mappings are used.
+    ///
+    /// This executes an `invlpg` instruction for every changed page. If
+    /// [`flushes_all`](Self::flushes_all) returns `true`, the complete TLB is flushed through
+    /// [`tlb::flush_all_including_global`](crate::instructions::tlb::flush_all_including_global)
+    /// instead. In both cases, pages that have the `GLOBAL` flag set are invalidated too.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn flush(self) {
+        if self.flushes_all() {
+            crate::instructions::tlb::flush_all_including_global();
+            return;
+        }
+
+        for (first, last, step) in self.runs() {
+            let mut addr = first;
+            loop {
+                crate::instructions::tlb::flush(addr);
+                if last - addr < step {
+                    break;
+                }
+                addr += step;
+            }
+        }
+    }
+
+    /// Don't flush the TLB and silence the “must be used” warning.
+    #[inline]
+    pub fn ignore(self) {}
+}
+
 /// This error is returned from `map_to` and similar methods.
 #[derive(Debug)]
 pub enum MapToError<S: PageSize> {
@@ -461,6 +1204,89 @@ pub enum FlagUpdateError {
     ParentEntryHugePage,
 }
 
+/// An error indicating that a `map_range` call failed.
+#[derive(Debug)]
+pub enum MapRangeError {
+    /// An additional frame was needed for the mapping process, but the frame allocator
+    /// returned `None`.
+    ///
+    /// The pages that were mapped before the allocation failed are not unmapped again.
+    FrameAllocationFailed,
+    /// The given address in the range is already mapped, either directly or as part of a
+    /// huge page.
+    ///
+    /// No mappings were created in this case.
+    PageAlreadyMapped(VirtAddr),
+}
+
+/// An error indicating that an `unmap_range` or `protect_range` call failed.
+///
+/// No mapping is changed when an error is returned, but huge pages at the boundaries of the
+/// range might already be split, which doesn't change the translation of any address.
+#[derive(Debug)]
+pub enum RangeUpdateError {
+    /// A frame was needed for splitting a huge page that only partially overlaps with the
+    /// range, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+    /// The page table entry for the given address points to an invalid physical address.
+    InvalidFrameAddress(PhysAddr),
+}
+
+/// An error indicating that a `split_huge_page` call failed.
+#[derive(Debug)]
+pub enum SplitError {
+    /// A frame was needed for the new page table, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+    /// The given page is not mapped to a physical frame.
+    PageNotMapped,
+    /// The given page is mapped through a page table instead of a huge page entry.
+    NotHugePage,
+    /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
+    /// given page is part of a larger huge page.
+    ParentEntryHugePage,
+}
+
+/// An error indicating that a `promote_to_huge_page` call failed.
+#[derive(Debug)]
+pub enum PromoteError {
+    /// There is no page table for the given page.
+    PageNotMapped,
+    /// The given page is already mapped as a huge page.
+    AlreadyHugePage,
+    /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
+    /// given page is part of a larger huge page.
+    ParentEntryHugePage,
+    /// The entries of the page table don't map contiguous and aligned frames with the same
+    /// flags, so they can't be replaced with a huge page.
+    NotUniform,
+}
+
+/// An error indicating that a `clone_address_space` call failed.
+#[derive(Debug)]
+pub enum CloneError {
+    /// A frame was needed for a new page table, but the frame allocator returned `None`.
+    ///
+    /// All page tables that were already created for the copy are deallocated again.
+    FrameAllocationFailed,
+}
+
+/// An error indicating that a `map_to_with_memory_type` call failed.
+#[derive(Debug)]
+pub enum MemoryTypeMapError<S: PageSize> {
+    /// The given memory type is not contained in the given PAT, so it can't be selected by a
+    /// page table entry. No mapping was created in this case.
+    MemoryTypeNotInPat(MemoryType),
+    /// Creating the mapping failed.
+    MapTo(MapToError<S>),
+}
+
+impl<S: PageSize> From<MapToError<S>> for MemoryTypeMapError<S> {
+    #[inline]
+    fn from(err: MapToError<S>) -> Self {
+        MemoryTypeMapError::MapTo(err)
+    }
+}
+
 /// An error indicating that an `translate` call failed.
 #[derive(Debug)]
 pub enum TranslateError {
@@ -473,4 +1299,338 @@ pub enum TranslateError {
     InvalidFrameAddress(PhysAddr),
 }
 
+/// Returns the level of the page table that contains the entries of pages of the given size.
+fn leaf_level(size: u64) -> PageTableLevel {
+    match size {
+        Size4KiB::SIZE => PageTableLevel::One,
+        Size2MiB::SIZE => PageTableLevel::Two,
+        _ => PageTableLevel::Three,
+    }
+}
+
+/// Returns the start of the `size` aligned block after the one that contains `addr`, or `None`
+/// if the end of the address space is reached.
+fn advance(mode: PagingMode, addr: VirtAddr, size: u64) -> Option<VirtAddr> {
+    let next = addr.align_down(size).as_u64().checked_add(size)?;
+    Some(
+        mode.try_new_virt_addr(next)
+            .unwrap_or_else(|_| mode.higher_half_start()),
+    )
+}
+
+/// Walks the page tables for `addr`, starting at `page_table` of the given level.
+///
+/// The `next_table` closure must return the page table that the present entry at the given
+/// index of the given table points to. The `root` argument is the physical address of
+/// `page_table`, if it is known.
+fn walk_tables<'a, F>(
+    page_table: &'a PageTable,
+    level: PageTableLevel,
+    addr: VirtAddr,
+    root: Option<PhysAddr>,
+    mut next_table: F,
+) -> WalkResult
+where
+    F: FnMut(&'a PageTable, PageTableIndex) -> &'a PageTable,
+{
+    let rights_mask = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut rights = rights_mask;
+    let mut no_execute = PageTableFlags::empty();
+    let mut tables = VisitedTables::default();
+    if let Some(root) = root {
+        tables.set(level, root);
+    }
+
+    let mut page_table = page_table;
+    let mut level = level;
+    loop {
+        let index = addr.page_table_index(level);
+        let entry = &page_table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return WalkResult::NotMapped { level, tables };
+        }
+
+        match level.next_lower_level() {
+            Some(next_level) if !flags.contains(PageTableFlags::HUGE_PAGE) => {
+                rights &= flags;
+                no_execute |= flags & PageTableFlags::NO_EXECUTE;
+                tables.set(next_level, entry.addr());
+                page_table = next_table(page_table, index);
+                level = next_level;
+            }
+            _ => {
+                return WalkResult::Mapped {
+                    entry: entry.clone(),
+                    level,
+                    flags: (flags - rights_mask) | (flags & rights) | no_execute,
+                    tables,
+                }
+            }
+        }
+    }
+}
+
+/// Walks the page tables for `addr` like [`walk_tables`] and calls `f` with the entry at which
+/// the walk stops.
+///
+/// The walk doesn't descend below the table of `stop_level`.
+/// The `next_table` closure must return the page table that the present entry at the given
+/// index of the given table points to.
+fn walk_tables_mut<'a, N, F, R>(
+    page_table: &'a mut PageTable,
+    level: PageTableLevel,
+    addr: VirtAddr,
+    stop_level: PageTableLevel,
+    mut next_table: N,
+    f: F,
+) -> R
+where
+    N: FnMut(&'a mut PageTable, PageTableIndex) -> &'a mut PageTable,
+    F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+{
+    let mut page_table = page_table;
+    let mut level = level;
+    loop {
+        let index = addr.page_table_index(level);
+        let flags = page_table[index].flags();
+        match level.next_lower_level() {
+            Some(next_level)
+                if level > stop_level
+                    && flags.contains(PageTableFlags::PRESENT)
+                    && !flags.contains(PageTableFlags::HUGE_PAGE) =>
+            {
+                page_table = next_table(page_table, index);
+                level = next_level;
+            }
+            _ => return f(&mut page_table[index], level),
+        }
+    }
+}
+
+/// In huge page entries, the PAT bit is bit 12 instead of bit 7, which is the `HUGE_PAGE` flag.
+const HUGE_PAGE_PAT_BIT: u64 = 1 << 12;
+
+/// Returns the flags for an entry that points to the table that a huge page with the given
+/// flags was split into.
+fn split_table_entry_flags(flags: PageTableFlags) -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Fills `table` with entries of the next lower level that map the same memory as the huge page
+/// `entry` in a table of the given level.
+fn fill_split_table(entry: &PageTableEntry, level: PageTableLevel, table: &mut PageTable) {
+    let child_level = level
+        .next_lower_level()
+        .expect("level 1 entries can't be split");
+    let child_size = child_level.entry_address_space_alignment();
+
+    let addr = entry.addr().as_u64();
+    let pat = addr & HUGE_PAGE_PAT_BIT != 0;
+    let base = addr & !(level.entry_address_space_alignment() - 1);
+
+    let mut flags = entry.flags();
+    let mut pat_bit = 0;
+    if child_level == PageTableLevel::One {
+        // the `HUGE_PAGE` bit is the PAT bit in level 1 entries
+        flags.set(PageTableFlags::HUGE_PAGE, pat);
+    } else if pat {
+        pat_bit = HUGE_PAGE_PAT_BIT;
+    }
+
+    for (index, child) in table.iter_mut().enumerate() {
+        let child_addr = base + index as u64 * child_size;
+        child.set_addr(PhysAddr::new(child_addr | pat_bit), flags);
+    }
+}
+
+/// Returns the address and flags of a huge page entry that maps the same memory as `table`,
+/// which is referenced by `entry` in a table of the given level.
+fn promoted_entry(
+    entry: &PageTableEntry,
+    level: PageTableLevel,
+    table: &PageTable,
+) -> Result<(PhysAddr, PageTableFlags), PromoteError> {
+    let child_level = level
+        .next_lower_level()
+        .expect("level 1 entries can't be promoted");
+    let child_size = child_level.entry_address_space_alignment();
+    let accessed_dirty = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+
+    let first_addr = table[0].addr().as_u64();
+    let flags = table[0].flags() - accessed_dirty;
+    if !flags.contains(PageTableFlags::PRESENT)
+        || (child_level != PageTableLevel::One && !flags.contains(PageTableFlags::HUGE_PAGE))
+    {
+        return Err(PromoteError::NotUniform);
+    }
+
+    let mut used_flags = PageTableFlags::empty();
+    for (index, child) in table.iter().enumerate() {
+        if child.flags() - accessed_dirty != flags
+            || child.addr().as_u64() != first_addr + index as u64 * child_size
+        {
+            return Err(PromoteError::NotUniform);
+        }
+        used_flags |= child.flags() & accessed_dirty;
+    }
+
+    let (base, pat) = if child_level == PageTableLevel::One {
+        (first_addr, flags.contains(PageTableFlags::HUGE_PAGE))
+    } else {
+        (
+            first_addr & !HUGE_PAGE_PAT_BIT,
+            first_addr & HUGE_PAGE_PAT_BIT != 0,
+        )
+    };
+    if base % level.entry_address_space_alignment() != 0 {
+        return Err(PromoteError::NotUniform);
+    }
+    let addr = if pat { base | HUGE_PAGE_PAT_BIT } else { base };
+
+    // keep the effective access rights that the parent entry applied to the table
+    let mut huge_flags = flags | used_flags | PageTableFlags::HUGE_PAGE;
+    let parent_flags = entry.flags();
+    huge_flags &= parent_flags | !(PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    huge_flags |= parent_flags & PageTableFlags::NO_EXECUTE;
+
+    Ok((PhysAddr::new(addr), huge_flags))
+}
+
 static _ASSERT_OBJECT_SAFE: Option<&(dyn Translate + Sync)> = None;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "alloc")]
+    use crate::structures::paging::mapper::SimulatedMemory;
+
+    fn runs(flush: &MapperFlushRange) -> Vec<(u64, u64, u64)> {
+        flush
+            .runs()
+            .map(|(first, last, step)| (first.as_u64(), last.as_u64(), step))
+            .collect()
+    }
+
+    #[test]
+    fn flush_range_keeps_page_sizes() {
+        let mut flush = MapperFlushRange::new();
+        assert!(flush.is_empty());
+        flush.add(VirtAddr::new(0x4000_0000), Size1GiB::SIZE);
+        flush.add(VirtAddr::new(0x8000_0000), Size1GiB::SIZE);
+        flush.add(VirtAddr::new(0xc000_0000), Size4KiB::SIZE);
+        assert_eq!(
+            runs(&flush),
+            [
+                (0x4000_0000, 0xbfff_ffff, Size1GiB::SIZE),
+                (0xc000_0000, 0xc000_0fff, Size4KiB::SIZE)
+            ]
+        );
+        assert_eq!(flush.pages(), 3);
+        assert!(!flush.flushes_all());
+        flush.ignore();
+    }
+
+    #[test]
+    fn flush_range_skips_gaps() {
+        let mut flush = MapperFlushRange::new();
+        flush.add(VirtAddr::new(0x1000), Size4KiB::SIZE);
+        flush.add(VirtAddr::new(0x4000_0000), Size4KiB::SIZE);
+        assert_eq!(
+            runs(&flush),
+            [
+                (0x1000, 0x1fff, Size4KiB::SIZE),
+                (0x4000_0000, 0x4000_0fff, Size4KiB::SIZE)
+            ]
+        );
+        flush.ignore();
+    }
+
+    #[test]
+    fn flush_range_threshold() {
+        let mut flush = MapperFlushRange::new();
+        for i in 0..MapperFlushRange::THRESHOLD {
+            flush.add(VirtAddr::new(i * Size4KiB::SIZE), Size4KiB::SIZE);
+        }
+        assert!(!flush.flushes_all());
+        flush.add(VirtAddr::new(0x10_0000), Size4KiB::SIZE);
+        assert!(flush.flushes_all());
+        flush.ignore();
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let flush = MapperFlushRange::for_page(page, Size4KiB::SIZE);
+        assert_eq!(flush.pages(), 512);
+        assert!(flush.flushes_all());
+        flush.ignore();
+    }
+
+    #[test]
+    fn flush_range_overflow() {
+        let mut flush = MapperFlushRange::new();
+        for i in 0..FLUSH_RANGE_RUNS as u64 {
+            flush.add(VirtAddr::new(i * 2 * Size4KiB::SIZE), Size4KiB::SIZE);
+        }
+        assert!(!flush.flushes_all());
+        flush.add(VirtAddr::new(0x10_0000), Size4KiB::SIZE);
+        assert!(flush.flushes_all());
+        flush.ignore();
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn mappings_skip_gaps_in_order() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mut memory = SimulatedMemory::new(16);
+        memory.map(
+            SimulatedMemory::page(0x1000),
+            SimulatedMemory::frame(0x10_0000),
+            flags,
+        );
+        // the effective flags don't contain the `WRITABLE` flag of the parent entries
+        memory.map(
+            SimulatedMemory::page(0x3000),
+            SimulatedMemory::frame(0x20_0000),
+            PageTableFlags::PRESENT,
+        );
+        memory.map(
+            Page::<Size2MiB>::containing_address(VirtAddr::new(0x40_0000)),
+            PhysFrame::containing_address(PhysAddr::new(0x4000_0000)),
+            flags,
+        );
+        memory.map(
+            Page::<Size1GiB>::containing_address(VirtAddr::new(0x8000_0000)),
+            PhysFrame::containing_address(PhysAddr::new(0xc000_0000)),
+            flags,
+        );
+        memory.map(
+            SimulatedMemory::page(0xffff_8000_0000_0000),
+            SimulatedMemory::frame(0x30_0000),
+            flags,
+        );
+
+        let (mapper, _) = memory.mapped_page_table();
+        let mappings: Vec<_> = mapper
+            .mappings()
+            .map(|(page, frame, flags)| {
+                assert_eq!(page.size(), frame.size());
+                (
+                    page.start_address().as_u64(),
+                    page.size(),
+                    frame.start_address().as_u64(),
+                    flags,
+                )
+            })
+            .collect();
+        let huge = flags | PageTableFlags::HUGE_PAGE;
+        assert_eq!(
+            mappings,
+            [
+                (0x1000, Size4KiB::SIZE, 0x10_0000, flags),
+                (0x3000, Size4KiB::SIZE, 0x20_0000, PageTableFlags::PRESENT),
+                (0x40_0000, Size2MiB::SIZE, 0x4000_0000, huge),
+                (0x8000_0000, Size1GiB::SIZE, 0xc000_0000, huge),
+                (0xffff_8000_0000_0000, Size4KiB::SIZE, 0x30_0000, flags),
+            ]
+        );
+    }
+}
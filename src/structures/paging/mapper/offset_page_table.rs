@@ -1,7 +1,12 @@
 #![cfg(target_pointer_width = "64")]
 
 use crate::structures::paging::{
-    frame::PhysFrame, mapper::*, page_table::PageTable, Page, PageTableFlags,
+    frame::PhysFrame,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::*,
+    page::PageRangeInclusive,
+    page_table::{PageTable, PageTableEntry, PageTableLevel},
+    Page, PageTableFlags, PagingMode,
 };
 
 /// A Mapper implementation that requires that the complete physically memory is mapped at some
@@ -41,6 +46,192 @@ impl<'a> OffsetPageTable<'a> {
     pub fn level_4_table(&mut self) -> &mut PageTable {
         self.inner.level_4_table()
     }
+
+    /// Creates a copy of this page table hierarchy, e.g. for implementing `fork`.
+    ///
+    /// See [`MappedPageTable::clone_address_space`] for details.
+    ///
+    /// ## Safety
+    ///
+    /// This is a convencience function that invokes [`MappedPageTable::clone_address_space`]
+    /// internally, so all safety requirements of it also apply for this function.
+    #[inline]
+    pub unsafe fn clone_address_space<A>(
+        &mut self,
+        cow_flag: Option<PageTableFlags>,
+        frame_allocator: &mut A,
+    ) -> Result<(PhysFrame, MapperFlushAll), CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.clone_address_space(cow_flag, frame_allocator)
+    }
+}
+
+/// A Mapper implementation for 5-level paging that requires that the complete physically memory
+/// is mapped at some offset in the virtual address space.
+///
+/// This is the 5-level equivalent of [`OffsetPageTable`]. It must only be used when 5-level
+/// paging (LA57) is enabled.
+#[derive(Debug)]
+pub struct OffsetLevel5PageTable<'a> {
+    inner: MappedLevel5PageTable<'a, PhysOffset>,
+}
+
+impl<'a> OffsetLevel5PageTable<'a> {
+    /// Creates a new `OffsetLevel5PageTable` that uses the given offset for converting virtual
+    /// to physical addresses.
+    ///
+    /// The complete physical memory must be mapped in the virtual address space starting at
+    /// address `phys_offset`. This means that for example physical address `0x5000` can be
+    /// accessed through virtual address `phys_offset + 0x5000`. This mapping is required because
+    /// the mapper needs to access page tables, which are not mapped into the virtual address
+    /// space by default.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed `phys_offset`
+    /// is correct. Also, the passed `level_5_table` must point to the level 5 page table
+    /// of a valid page table hierarchy. Otherwise this function might break memory safety, e.g.
+    /// by writing to an illegal memory location.
+    #[inline]
+    pub unsafe fn new(level_5_table: &'a mut PageTable, phys_offset: VirtAddr) -> Self {
+        let phys_offset = PhysOffset {
+            offset: phys_offset,
+        };
+        Self {
+            inner: MappedLevel5PageTable::new(level_5_table, phys_offset),
+        }
+    }
+
+    /// Returns a mutable reference to the wrapped level 5 `PageTable` instance.
+    pub fn level_5_table(&mut self) -> &mut PageTable {
+        self.inner.level_5_table()
+    }
+
+    /// Set the flags of an existing page level 5 table entry
+    ///
+    /// ## Safety
+    ///
+    /// This method is unsafe because changing the flags of a mapping
+    /// might result in undefined behavior. For example, setting the
+    /// `GLOBAL` and `WRITABLE` flags for a page might result in the corruption
+    /// of values stored in that page from processes running in other address
+    /// spaces.
+    #[inline]
+    pub unsafe fn set_flags_p5_entry<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p5_entry(page, flags)
+    }
+
+    /// Creates a copy of this page table hierarchy, e.g. for implementing `fork`.
+    ///
+    /// See [`MappedLevel5PageTable::clone_address_space`] for details.
+    ///
+    /// ## Safety
+    ///
+    /// This is a convencience function that invokes [`MappedLevel5PageTable::clone_address_space`]
+    /// internally, so all safety requirements of it also apply for this function.
+    #[inline]
+    pub unsafe fn clone_address_space<A>(
+        &mut self,
+        cow_flag: Option<PageTableFlags>,
+        frame_allocator: &mut A,
+    ) -> Result<(PhysFrame, MapperFlushAll), CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.clone_address_space(cow_flag, frame_allocator)
+    }
+}
+
+/// An offset page table for either 4-level or 5-level paging.
+///
+/// This type allows to pick the right mapper at runtime, e.g. based on
+/// [`PagingMode::current`], while still providing a single `Mapper` implementation.
+#[derive(Debug)]
+pub enum AnyOffsetPageTable<'a> {
+    /// A page table hierarchy for 4-level paging.
+    Level4(OffsetPageTable<'a>),
+    /// A page table hierarchy for 5-level paging.
+    Level5(OffsetLevel5PageTable<'a>),
+}
+
+impl<'a> AnyOffsetPageTable<'a> {
+    /// Creates a new offset page table for the given paging mode.
+    ///
+    /// The `root_table` is interpreted as a level 4 table for [`PagingMode::Level4`] and as a
+    /// level 5 table for [`PagingMode::Level5`].
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed `phys_offset`
+    /// is correct. Also, the passed `root_table` must point to the root page table of a valid
+    /// page table hierarchy for the given `mode`. Otherwise this function might break memory
+    /// safety, e.g. by writing to an illegal memory location.
+    #[inline]
+    pub unsafe fn new(
+        root_table: &'a mut PageTable,
+        phys_offset: VirtAddr,
+        mode: PagingMode,
+    ) -> Self {
+        match mode {
+            PagingMode::Level4 => {
+                AnyOffsetPageTable::Level4(OffsetPageTable::new(root_table, phys_offset))
+            }
+            PagingMode::Level5 => {
+                AnyOffsetPageTable::Level5(OffsetLevel5PageTable::new(root_table, phys_offset))
+            }
+        }
+    }
+
+    /// Returns the paging mode of the wrapped page table hierarchy.
+    #[inline]
+    pub fn paging_mode(&self) -> PagingMode {
+        match self {
+            AnyOffsetPageTable::Level4(_) => PagingMode::Level4,
+            AnyOffsetPageTable::Level5(_) => PagingMode::Level5,
+        }
+    }
+
+    /// Returns a mutable reference to the wrapped root `PageTable` instance.
+    pub fn root_table(&mut self) -> &mut PageTable {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.level_4_table(),
+            AnyOffsetPageTable::Level5(inner) => inner.level_5_table(),
+        }
+    }
+
+    /// Creates a copy of this page table hierarchy, e.g. for implementing `fork`.
+    ///
+    /// See [`MappedPageTable::clone_address_space`] for details. The upper half of the root
+    /// table is shared for both paging modes.
+    ///
+    /// ## Safety
+    ///
+    /// This is a convencience function that invokes [`MappedPageTable::clone_address_space`]
+    /// internally, so all safety requirements of it also apply for this function.
+    #[inline]
+    pub unsafe fn clone_address_space<A>(
+        &mut self,
+        cow_flag: Option<PageTableFlags>,
+        frame_allocator: &mut A,
+    ) -> Result<(PhysFrame, MapperFlushAll), CloneError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.clone_address_space(cow_flag, frame_allocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.clone_address_space(cow_flag, frame_allocator)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -53,6 +244,11 @@ unsafe impl PageTableFrameMapping for PhysOffset {
         let virt = self.offset + frame.start_address().as_u64();
         virt.as_mut_ptr()
     }
+
+    fn pointer_to_frame(&self, page_table: *const PageTable) -> Option<PhysFrame> {
+        let phys = (page_table as u64).checked_sub(self.offset.as_u64())?;
+        PhysFrame::from_start_address(PhysAddr::try_new(phys).ok()?).ok()
+    }
 }
 
 // delegate all trait implementations to inner
@@ -264,3 +460,889 @@ impl<'a> Translate for OffsetPageTable<'a> {
         self.inner.translate(addr)
     }
 }
+
+impl<'a> Walk for OffsetPageTable<'a> {
+    #[inline]
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        self.inner.walk(addr)
+    }
+}
+
+impl<'a> WalkMut for OffsetPageTable<'a> {
+    #[inline]
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        self.inner.walk_mut_to_level(addr, level, f)
+    }
+}
+
+impl<'a> CleanUp for OffsetPageTable<'a> {
+    #[inline]
+    unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.clean_up(frame_deallocator)
+    }
+
+    #[inline]
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.clean_up_addr_range(range, frame_deallocator)
+    }
+}
+
+impl<'a> HugePageMapper<Size1GiB> for OffsetPageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner.split_huge_page(page, frame_allocator)
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.promote_to_huge_page(page, frame_deallocator)
+    }
+}
+
+impl<'a> HugePageMapper<Size2MiB> for OffsetPageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner.split_huge_page(page, frame_allocator)
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.promote_to_huge_page(page, frame_deallocator)
+    }
+}
+
+impl<'a> Mapper<Size1GiB> for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError<Size1GiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), UnmapError> {
+        self.inner.unmap(page)
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p4_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p3_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p2_entry(page, flags)
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
+        self.inner.translate_page(page)
+    }
+}
+
+impl<'a> Mapper<Size2MiB> for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError<Size2MiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
+        self.inner.unmap(page)
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p4_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p3_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p2_entry(page, flags)
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
+        self.inner.translate_page(page)
+    }
+}
+
+impl<'a> Mapper<Size4KiB> for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
+        self.inner.unmap(page)
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p4_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p3_entry(page, flags)
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.set_flags_p2_entry(page, flags)
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+        self.inner.translate_page(page)
+    }
+}
+
+impl<'a> Translate for OffsetLevel5PageTable<'a> {
+    #[inline]
+    fn translate(&self, addr: VirtAddr) -> TranslateResult {
+        self.inner.translate(addr)
+    }
+}
+
+impl<'a> Walk for OffsetLevel5PageTable<'a> {
+    #[inline]
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        self.inner.walk(addr)
+    }
+
+    #[inline]
+    fn paging_mode(&self) -> PagingMode {
+        PagingMode::Level5
+    }
+}
+
+impl<'a> WalkMut for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        self.inner.walk_mut_to_level(addr, level, f)
+    }
+}
+
+impl<'a> CleanUp for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.clean_up(frame_deallocator)
+    }
+
+    #[inline]
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.clean_up_addr_range(range, frame_deallocator)
+    }
+}
+
+impl<'a> HugePageMapper<Size1GiB> for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner.split_huge_page(page, frame_allocator)
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.promote_to_huge_page(page, frame_deallocator)
+    }
+}
+
+impl<'a> HugePageMapper<Size2MiB> for OffsetLevel5PageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        self.inner.split_huge_page(page, frame_allocator)
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        self.inner.promote_to_huge_page(page, frame_deallocator)
+    }
+}
+
+impl<'a> Mapper<Size1GiB> for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError<Size1GiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+            }
+        }
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), UnmapError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.unmap(page),
+            AnyOffsetPageTable::Level5(inner) => inner.unmap(page),
+        }
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.update_flags(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.update_flags(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p4_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p4_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p3_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p3_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p2_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p2_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.translate_page(page),
+            AnyOffsetPageTable::Level5(inner) => inner.translate_page(page),
+        }
+    }
+}
+
+impl<'a> Mapper<Size2MiB> for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError<Size2MiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+            }
+        }
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.unmap(page),
+            AnyOffsetPageTable::Level5(inner) => inner.unmap(page),
+        }
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.update_flags(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.update_flags(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p4_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p4_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p3_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p3_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p2_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p2_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.translate_page(page),
+            AnyOffsetPageTable::Level5(inner) => inner.translate_page(page),
+        }
+    }
+}
+
+impl<'a> Mapper<Size4KiB> for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+            }
+        }
+    }
+
+    #[inline]
+    fn unmap(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.unmap(page),
+            AnyOffsetPageTable::Level5(inner) => inner.unmap(page),
+        }
+    }
+
+    #[inline]
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.update_flags(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.update_flags(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p4_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p4_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p3_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p3_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.set_flags_p2_entry(page, flags),
+            AnyOffsetPageTable::Level5(inner) => inner.set_flags_p2_entry(page, flags),
+        }
+    }
+
+    #[inline]
+    fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.translate_page(page),
+            AnyOffsetPageTable::Level5(inner) => inner.translate_page(page),
+        }
+    }
+}
+
+impl<'a> Translate for AnyOffsetPageTable<'a> {
+    #[inline]
+    fn translate(&self, addr: VirtAddr) -> TranslateResult {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.translate(addr),
+            AnyOffsetPageTable::Level5(inner) => inner.translate(addr),
+        }
+    }
+}
+
+impl<'a> Walk for AnyOffsetPageTable<'a> {
+    #[inline]
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.walk(addr),
+            AnyOffsetPageTable::Level5(inner) => inner.walk(addr),
+        }
+    }
+
+    #[inline]
+    fn paging_mode(&self) -> PagingMode {
+        AnyOffsetPageTable::paging_mode(self)
+    }
+}
+
+impl<'a> WalkMut for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.walk_mut_to_level(addr, level, f),
+            AnyOffsetPageTable::Level5(inner) => inner.walk_mut_to_level(addr, level, f),
+        }
+    }
+}
+
+impl<'a> CleanUp for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.clean_up(frame_deallocator),
+            AnyOffsetPageTable::Level5(inner) => inner.clean_up(frame_deallocator),
+        }
+    }
+
+    #[inline]
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.clean_up_addr_range(range, frame_deallocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.clean_up_addr_range(range, frame_deallocator)
+            }
+        }
+    }
+}
+
+impl<'a> HugePageMapper<Size1GiB> for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.split_huge_page(page, frame_allocator),
+            AnyOffsetPageTable::Level5(inner) => inner.split_huge_page(page, frame_allocator),
+        }
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.promote_to_huge_page(page, frame_deallocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.promote_to_huge_page(page, frame_deallocator)
+            }
+        }
+    }
+}
+
+impl<'a> HugePageMapper<Size2MiB> for AnyOffsetPageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => inner.split_huge_page(page, frame_allocator),
+            AnyOffsetPageTable::Level5(inner) => inner.split_huge_page(page, frame_allocator),
+        }
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        match self {
+            AnyOffsetPageTable::Level4(inner) => {
+                inner.promote_to_huge_page(page, frame_deallocator)
+            }
+            AnyOffsetPageTable::Level5(inner) => {
+                inner.promote_to_huge_page(page, frame_deallocator)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::SimulatedMemory;
+    use crate::{PhysAddr, VirtAddr};
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+    #[test]
+    fn level_5_map_and_translate() {
+        let mut memory = SimulatedMemory::new(16);
+        let (mut mapper, mut frame_allocator) = memory.offset_level_5_page_table();
+        let addr = VirtAddr::new_la57(0x0123_4567_8000_0000);
+        let page = Page::<Size1GiB>::containing_address(addr);
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        unsafe { mapper.map_to(page, frame, FLAGS, &mut frame_allocator) }
+            .unwrap()
+            .ignore();
+
+        assert_eq!(
+            mapper.translate_addr(addr + 0x1234_5678u64),
+            Some(PhysAddr::new(0x9234_5678))
+        );
+        // the address with the same 4-level indices is not mapped
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0x4567_8000_0000)),
+            None
+        );
+        assert!(!mapper.level_5_table()[0x123].is_unused());
+        assert_eq!(mapper.paging_mode(), PagingMode::Level5);
+    }
+
+    #[test]
+    fn any_offset_page_table() {
+        for &mode in &[PagingMode::Level4, PagingMode::Level5] {
+            let memory = SimulatedMemory::new(16);
+            let root = unsafe { &mut *memory.frame_to_pointer(memory.root_frame()) };
+            let mut mapper = unsafe { AnyOffsetPageTable::new(root, memory.phys_offset(), mode) };
+            let mut frame_allocator = memory.frame_allocator();
+            assert_eq!(mapper.paging_mode(), mode);
+            assert_eq!(Walk::paging_mode(&mapper), mode);
+
+            // the first lower half address above 128 TiB only exists for 5-level paging
+            let addr = match mode {
+                PagingMode::Level4 => VirtAddr::new(0x7fff_ffff_f000),
+                PagingMode::Level5 => VirtAddr::new_la57(0x8000_0000_0000),
+            };
+            let page = Page::<Size4KiB>::containing_address(addr);
+            unsafe {
+                mapper.map_to(
+                    page,
+                    SimulatedMemory::frame(0x5000),
+                    FLAGS,
+                    &mut frame_allocator,
+                )
+            }
+            .unwrap()
+            .ignore();
+            assert_eq!(
+                mapper.translate_addr(addr + 0x10u64),
+                Some(PhysAddr::new(0x5010))
+            );
+
+            let index = match mode {
+                PagingMode::Level4 => 0xff,
+                PagingMode::Level5 => 0,
+            };
+            let present = mapper
+                .root_table()
+                .iter()
+                .position(|entry| !entry.is_unused());
+            assert_eq!(present, Some(index));
+        }
+    }
+}
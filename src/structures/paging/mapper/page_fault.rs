@@ -0,0 +1,923 @@
+#[cfg(feature = "instructions")]
+use crate::registers::control::Cr2;
+use crate::structures::idt::PageFaultErrorCode;
+use crate::structures::paging::{
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::{MapToError, Mapper, MapperFlush, WalkMut, WalkResult},
+    page_table::PageTableEntry,
+    vma::{Vma, VmaSet},
+    Page, PageSize, PageTableFlags, PageTableLevel, PhysFrame, Size4KiB,
+};
+use crate::VirtAddr;
+
+/// A page fault, decoded from the CR2 register and the error code of the exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    /// The accessed virtual address.
+    pub addr: VirtAddr,
+    /// The error code of the page fault exception.
+    pub error_code: PageFaultErrorCode,
+}
+
+impl PageFault {
+    /// Reads the accessed address of the current page fault from the CR2 register.
+    ///
+    /// This must be called in the page fault handler, before another page fault can occur.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn read(error_code: PageFaultErrorCode) -> Self {
+        PageFault {
+            addr: Cr2::read(),
+            error_code,
+        }
+    }
+
+    /// Returns the page that contains the accessed address.
+    #[inline]
+    pub fn page(&self) -> Page {
+        Page::containing_address(self.addr)
+    }
+
+    /// Returns whether the given flags allow the access that caused the fault.
+    #[inline]
+    pub fn is_allowed_by(&self, flags: PageTableFlags) -> bool {
+        let error_code = self.error_code;
+        !(error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !flags.contains(PageTableFlags::WRITABLE)
+            || error_code.contains(PageFaultErrorCode::USER_MODE)
+                && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            || error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                && flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+}
+
+/// The metadata of a region of a [`VmaSet`] that is used by [`handle_page_fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultRegion {
+    /// The flags with which the pages of the region are mapped.
+    ///
+    /// The `PRESENT` flag is added automatically.
+    pub flags: PageTableFlags,
+    /// Decides how the page faults in the region are resolved.
+    pub resolver: FaultResolver,
+}
+
+/// Decides how a page fault in a [`FaultRegion`] is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolver {
+    /// Maps a new zeroed frame.
+    ZeroFill,
+    /// Like `ZeroFill`, but a write to a page that is mapped without the `WRITABLE` flag
+    /// copies its frame and maps the copy writable, see [`FaultMemory::is_exclusive`].
+    CopyOnWrite,
+    /// Maps a new frame that is filled through [`FaultMemory::load`].
+    File {
+        /// The ID of the file, which is passed to [`FaultMemory::load`].
+        file: u64,
+        /// The virtual address that corresponds to offset 0 in the file.
+        ///
+        /// The offset of a page in the file is its address minus `origin`, computed with
+        /// wrapping arithmetic. This keeps the offsets correct when the region is split or
+        /// merged.
+        origin: u64,
+    },
+    /// A guard region, every access is fatal.
+    Guard,
+    /// A guard region below a stack that grows down.
+    ///
+    /// An access to the region grows the stack region that directly follows it, so that it
+    /// starts at the accessed page. The first page of the region is never added to the stack,
+    /// so an access to it is fatal.
+    GrowDown,
+}
+
+/// Provides the accesses to physical memory and to backing storage that
+/// [`handle_page_fault`] needs.
+///
+/// # Safety
+///
+/// The implementer must ensure that [`frame_to_pointer`](Self::frame_to_pointer) returns a
+/// valid pointer to the 4KiB of the given frame.
+pub unsafe trait FaultMemory {
+    /// Returns a pointer through which the given frame can be accessed.
+    fn frame_to_pointer(&self, frame: PhysFrame) -> *mut u8;
+
+    /// Fills the given frame with the content of `file` at `offset`, for a page of a
+    /// [`FaultResolver::File`] region.
+    ///
+    /// Returns `false` if the content can't be loaded.
+    fn load(&mut self, file: u64, offset: u64, frame: PhysFrame) -> bool;
+
+    /// Returns whether the given frame of a copy-on-write page isn't shared with any other
+    /// mapping.
+    ///
+    /// An exclusive frame is made writable without copying it. The default implementation
+    /// always copies.
+    #[inline]
+    fn is_exclusive(&mut self, frame: PhysFrame) -> bool {
+        let _ = frame;
+        false
+    }
+
+    /// Called after a copy-on-write page was remapped from the given shared frame to a copy,
+    /// e.g. for decreasing the reference count of the frame.
+    #[inline]
+    fn release_shared(&mut self, frame: PhysFrame) {
+        let _ = frame;
+    }
+}
+
+/// The result of [`handle_page_fault`].
+#[derive(Debug)]
+#[must_use = "Resolved page faults must be flushed."]
+pub enum FaultOutcome {
+    /// The fault was resolved, so the faulting instruction can be retried after the page
+    /// was flushed.
+    Resolved(MapperFlush<Size4KiB>),
+    /// The fault can't be resolved, e.g. because it was caused by an invalid access.
+    Fatal(FatalFault),
+}
+
+/// The reason of a page fault that can't be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatalFault {
+    /// The accessed address is not part of any region.
+    NoRegion,
+    /// The flags of the region don't allow the access, or a protection key violation
+    /// occurred.
+    AccessViolation,
+    /// The accessed address is part of a guard region.
+    GuardPage,
+    /// A page table entry has a reserved bit set.
+    MalformedTable,
+    /// The page of the accessed address is mapped in an unexpected way, e.g. as a huge page.
+    UnexpectedMapping,
+    /// A frame was needed for resolving the fault, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+    /// [`FaultMemory::load`] failed.
+    LoadFailed,
+}
+
+/// Resolves a page fault through the resolver of the region that contains the accessed
+/// address.
+///
+/// This is meant to be called from the page fault handler:
+///
+/// ```ignore
+/// extern "x86-interrupt" fn page_fault_handler(
+///     stack_frame: InterruptStackFrame,
+///     error_code: PageFaultErrorCode,
+/// ) {
+///     let fault = PageFault::read(error_code);
+///     match unsafe { handle_page_fault(fault, &mut regions, &mut mapper, &mut frames, &mut memory) } {
+///         FaultOutcome::Resolved(flush) => flush.flush(),
+///         FaultOutcome::Fatal(reason) => kill_current_process(fault, reason),
+///     }
+/// }
+/// ```
+///
+/// New frames are allocated from `frame_allocator` and mapped through `mapper` with the flags
+/// of the region, using 4KiB pages. The `WRITABLE`, `USER_ACCESSIBLE` and `NO_EXECUTE` rights
+/// of the parent entries of the page are upgraded to the rights of the region, since the
+/// access would fault again otherwise. A fault that was resolved concurrently, e.g. by another
+/// CPU, is resolved too. [`FaultResolver::GrowDown`] regions are shrunk in `regions`.
+///
+/// A protection violation of a supervisor-mode access to a user accessible page is fatal, since
+/// the error code doesn't distinguish it from a SMAP or SMEP violation, which the flags of the
+/// page can't resolve.
+///
+/// ## Safety
+///
+/// The regions must describe the address space of `mapper`, and the pages of the regions
+/// must not be mapped in any other way than through this function or as copy-on-write pages
+/// of [`FaultResolver::CopyOnWrite`] regions. The frames of `frame_allocator` must be unused.
+pub unsafe fn handle_page_fault<M, A, E>(
+    fault: PageFault,
+    regions: &mut VmaSet<'_, FaultRegion>,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    memory: &mut E,
+) -> FaultOutcome
+where
+    M: Mapper<Size4KiB> + WalkMut,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    E: FaultMemory + ?Sized,
+{
+    let error_code = fault.error_code;
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        return FaultOutcome::Fatal(FatalFault::MalformedTable);
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+        return FaultOutcome::Fatal(FatalFault::AccessViolation);
+    }
+
+    let region = match regions.get(fault.addr) {
+        Some(region) => *region,
+        None => return FaultOutcome::Fatal(FatalFault::NoRegion),
+    };
+    let region = match region.data.resolver {
+        FaultResolver::Guard => return FaultOutcome::Fatal(FatalFault::GuardPage),
+        FaultResolver::GrowDown => match grow_down(fault, regions, region) {
+            Ok(region) => region,
+            Err(reason) => return FaultOutcome::Fatal(reason),
+        },
+        _ => region.data,
+    };
+    if !fault.is_allowed_by(region.flags) {
+        return FaultOutcome::Fatal(FatalFault::AccessViolation);
+    }
+    let flags = region.flags | PageTableFlags::PRESENT;
+    let page = fault.page();
+
+    match mapper.walk(fault.addr) {
+        WalkResult::NotMapped { .. } => {}
+        WalkResult::Mapped {
+            entry,
+            level,
+            flags: effective_flags,
+            ..
+        } => {
+            if level != PageTableLevel::One {
+                return FaultOutcome::Fatal(FatalFault::UnexpectedMapping);
+            }
+            let protection_violation =
+                error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+            if protection_violation
+                && !error_code.contains(PageFaultErrorCode::USER_MODE)
+                && effective_flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            {
+                return FaultOutcome::Fatal(FatalFault::AccessViolation);
+            }
+            // without a protection violation, the page was not present when the fault occurred,
+            // and with one, its flags didn't allow the access, since the other causes are
+            // excluded above. So if the flags allow the access now, the fault was resolved
+            // concurrently or the flags were changed after the TLB entry was cached.
+            if fault.is_allowed_by(effective_flags) {
+                return FaultOutcome::Resolved(MapperFlush::new(page));
+            }
+            if !fault.is_allowed_by(entry.flags()) {
+                let frame = PhysFrame::containing_address(entry.addr());
+                if let Err(reason) =
+                    copy_on_write(fault, region, flags, frame, mapper, frame_allocator, memory)
+                {
+                    return FaultOutcome::Fatal(reason);
+                }
+            }
+            // the entry allows the access now, but its parent entries might not
+            upgrade_parents(fault.addr, flags, mapper);
+            return FaultOutcome::Resolved(MapperFlush::new(page));
+        }
+    }
+
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return FaultOutcome::Fatal(FatalFault::FrameAllocationFailed),
+    };
+    match region.resolver {
+        FaultResolver::File { file, origin } => {
+            let offset = page.start_address().as_u64().wrapping_sub(origin);
+            if !memory.load(file, offset, frame) {
+                frame_allocator.deallocate_frame(frame);
+                return FaultOutcome::Fatal(FatalFault::LoadFailed);
+            }
+        }
+        _ => core::ptr::write_bytes(memory.frame_to_pointer(frame), 0, Size4KiB::SIZE as usize),
+    }
+
+    match mapper.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            // `map_to` adds the `WRITABLE` and `USER_ACCESSIBLE` flags to the parent entries,
+            // but it doesn't remove their `NO_EXECUTE` flag
+            upgrade_parents(fault.addr, flags, mapper);
+            FaultOutcome::Resolved(flush)
+        }
+        Err(err) => {
+            frame_allocator.deallocate_frame(frame);
+            match err {
+                // the fault was resolved concurrently
+                MapToError::PageAlreadyMapped(_) => FaultOutcome::Resolved(MapperFlush::new(page)),
+                MapToError::FrameAllocationFailed => {
+                    FaultOutcome::Fatal(FatalFault::FrameAllocationFailed)
+                }
+                MapToError::ParentEntryHugePage => {
+                    FaultOutcome::Fatal(FatalFault::UnexpectedMapping)
+                }
+            }
+        }
+    }
+}
+
+/// Adds the pages from the faulting page to the end of the given `GrowDown` region to the stack
+/// region that follows it and returns the metadata of the stack region.
+fn grow_down(
+    fault: PageFault,
+    regions: &mut VmaSet<'_, FaultRegion>,
+    guard: Vma<FaultRegion>,
+) -> Result<FaultRegion, FatalFault> {
+    let page = fault.page();
+    if page == guard.pages.start {
+        return Err(FatalFault::GuardPage);
+    }
+    let stack = match regions.get(guard.pages.end.start_address()) {
+        Some(stack) if stack.pages.start == guard.pages.end => stack.data,
+        _ => return Err(FatalFault::GuardPage),
+    };
+    match stack.resolver {
+        FaultResolver::Guard | FaultResolver::GrowDown => return Err(FatalFault::GuardPage),
+        _ => {}
+    }
+    if !fault.is_allowed_by(stack.flags) {
+        return Err(FatalFault::AccessViolation);
+    }
+
+    let grown = Page::range(page, guard.pages.end);
+    // shrinking the guard region never splits it and the grown pages are merged into the
+    // stack region, so no additional storage is needed
+    regions
+        .remove(grown, |_| {})
+        .and_then(|()| regions.insert(grown, stack))
+        .expect("failed to grow stack region");
+    Ok(stack)
+}
+
+/// Resolves a write to a present page that is mapped without the `WRITABLE` flag by mapping
+/// it to a writable copy of its frame.
+///
+/// Only the entry of the page is changed, the parent entries are upgraded by the caller.
+unsafe fn copy_on_write<M, A, E>(
+    fault: PageFault,
+    region: FaultRegion,
+    flags: PageTableFlags,
+    frame: PhysFrame,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    memory: &mut E,
+) -> Result<(), FatalFault>
+where
+    M: Mapper<Size4KiB> + WalkMut,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+    E: FaultMemory + ?Sized,
+{
+    if region.resolver != FaultResolver::CopyOnWrite
+        || !fault
+            .error_code
+            .contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+    {
+        return Err(FatalFault::AccessViolation);
+    }
+
+    let copy = if memory.is_exclusive(frame) {
+        frame
+    } else {
+        let copy = match frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return Err(FatalFault::FrameAllocationFailed),
+        };
+        core::ptr::copy_nonoverlapping(
+            memory.frame_to_pointer(frame),
+            memory.frame_to_pointer(copy),
+            Size4KiB::SIZE as usize,
+        );
+        copy
+    };
+    // `set_addr` replaces all flags, so the memory type and the protection key are copied from
+    // the old entry, and the entry is replaced in a single write, so that other CPUs never see
+    // the page unmapped
+    mapper.walk_mut(fault.addr, |entry, level| {
+        let mut new_entry = PageTableEntry::new();
+        new_entry.set_addr(copy.start_address(), flags);
+        new_entry.set_pat_index(level, entry.pat_index(level));
+        new_entry.set_protection_key(entry.protection_key());
+        *entry = new_entry;
+    });
+    if copy != frame {
+        memory.release_shared(frame);
+    }
+    Ok(())
+}
+
+/// Adds the `WRITABLE` and `USER_ACCESSIBLE` flags of `flags` to the parent entries of the
+/// 4KiB page that contains `addr` and removes their `NO_EXECUTE` flag if `flags` doesn't
+/// contain it.
+///
+/// The effective rights of the other pages below the parent entries don't change, since their
+/// own entries still restrict them.
+unsafe fn upgrade_parents<M>(addr: VirtAddr, flags: PageTableFlags, mapper: &mut M)
+where
+    M: WalkMut,
+{
+    let rights = flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    let mut level = mapper.paging_mode().root_level();
+    while let Some(lower_level) = level.next_lower_level() {
+        let entry_level = mapper.walk_mut_to_level(addr, level, |entry, entry_level| {
+            if entry_level == level {
+                let mut parent_flags = entry.flags() | rights;
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    parent_flags.remove(PageTableFlags::NO_EXECUTE);
+                }
+                if parent_flags != entry.flags() {
+                    entry.set_flags(parent_flags);
+                }
+            }
+            entry_level
+        });
+        // the walk stopped at a non-present entry or a huge page above `level`
+        if entry_level > level {
+            break;
+        }
+        level = lower_level;
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{
+        mapper::{MappedFrame, SimulatedMemory, TranslateResult, Walk},
+        ProtectionKey,
+    };
+    use alloc::vec::Vec;
+
+    const USER: PageTableFlags = PageTableFlags::WRITABLE
+        .union(PageTableFlags::USER_ACCESSIBLE)
+        .union(PageTableFlags::NO_EXECUTE);
+    const READ: PageFaultErrorCode = PageFaultErrorCode::USER_MODE;
+    const WRITE: PageFaultErrorCode =
+        PageFaultErrorCode::USER_MODE.union(PageFaultErrorCode::CAUSED_BY_WRITE);
+    const FETCH: PageFaultErrorCode =
+        PageFaultErrorCode::USER_MODE.union(PageFaultErrorCode::INSTRUCTION_FETCH);
+
+    /// Accesses the frames of a `SimulatedMemory` and loads a file whose content at each
+    /// offset is the offset itself.
+    struct Memory {
+        phys_offset: VirtAddr,
+        exclusive: bool,
+        released: Vec<PhysFrame>,
+    }
+
+    const FILE: u64 = 7;
+
+    impl Memory {
+        fn read(&self, frame: PhysFrame) -> u64 {
+            unsafe { *(self.frame_to_pointer(frame) as *const u64) }
+        }
+
+        fn fill(&self, frame: PhysFrame, value: u8) {
+            unsafe {
+                core::ptr::write_bytes(self.frame_to_pointer(frame), value, Size4KiB::SIZE as usize)
+            };
+        }
+
+        fn contains_only(&self, frame: PhysFrame, value: u8) -> bool {
+            let ptr = self.frame_to_pointer(frame);
+            (0..Size4KiB::SIZE as usize).all(|offset| unsafe { *ptr.add(offset) } == value)
+        }
+    }
+
+    unsafe impl FaultMemory for Memory {
+        fn frame_to_pointer(&self, frame: PhysFrame) -> *mut u8 {
+            (self.phys_offset + frame.start_address().as_u64()).as_mut_ptr()
+        }
+
+        fn load(&mut self, file: u64, offset: u64, frame: PhysFrame) -> bool {
+            if file != FILE {
+                return false;
+            }
+            unsafe { *(self.frame_to_pointer(frame) as *mut u64) = offset };
+            true
+        }
+
+        fn is_exclusive(&mut self, _frame: PhysFrame) -> bool {
+            self.exclusive
+        }
+
+        fn release_shared(&mut self, frame: PhysFrame) {
+            self.released.push(frame);
+        }
+    }
+
+    struct Fixture {
+        memory: SimulatedMemory,
+        fault_memory: Memory,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let memory = SimulatedMemory::new(16);
+            let fault_memory = Memory {
+                phys_offset: memory.phys_offset(),
+                exclusive: false,
+                released: Vec::new(),
+            };
+            Fixture {
+                memory,
+                fault_memory,
+            }
+        }
+
+        fn resolve(
+            &mut self,
+            addr: u64,
+            error_code: PageFaultErrorCode,
+            regions: &mut VmaSet<'_, FaultRegion>,
+        ) -> Result<(), FatalFault> {
+            let fault = PageFault {
+                addr: VirtAddr::new(addr),
+                error_code,
+            };
+            let (mut mapper, mut frames) = self.memory.mapped_page_table();
+            let outcome = unsafe {
+                handle_page_fault(
+                    fault,
+                    regions,
+                    &mut mapper,
+                    &mut frames,
+                    &mut self.fault_memory,
+                )
+            };
+            match outcome {
+                FaultOutcome::Resolved(flush) => {
+                    flush.ignore();
+                    Ok(())
+                }
+                FaultOutcome::Fatal(reason) => Err(reason),
+            }
+        }
+
+        /// Returns the frame and the flags of the entry that maps `addr`.
+        fn mapping(&mut self, addr: u64) -> (PhysFrame, PageTableFlags) {
+            match self.memory.translate(VirtAddr::new(addr)) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    flags,
+                    ..
+                } => (frame, flags),
+                other => panic!("{:#x} is not mapped to a 4KiB frame: {:?}", addr, other),
+            }
+        }
+
+        fn effective_flags(&mut self, addr: u64) -> PageTableFlags {
+            let (mapper, _) = self.memory.mapped_page_table();
+            match mapper.walk(VirtAddr::new(addr)) {
+                WalkResult::Mapped { flags, .. } => flags,
+                WalkResult::NotMapped { .. } => panic!("{:#x} is not mapped", addr),
+            }
+        }
+
+        /// Changes the flags of the entry of the table of the given level for `addr`.
+        fn update_parent<F>(&mut self, addr: u64, level: PageTableLevel, f: F)
+        where
+            F: FnOnce(PageTableFlags) -> PageTableFlags,
+        {
+            let (mut mapper, _) = self.memory.mapped_page_table();
+            unsafe {
+                mapper.walk_mut_to_level(VirtAddr::new(addr), level, |entry, _| {
+                    entry.set_flags(f(entry.flags()))
+                })
+            };
+        }
+    }
+
+    fn region(flags: PageTableFlags, resolver: FaultResolver) -> FaultRegion {
+        FaultRegion { flags, resolver }
+    }
+
+    #[test]
+    fn zero_fill() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 2];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let zero_fill = region(USER, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), zero_fill)
+            .unwrap();
+        let read_only = region(PageTableFlags::USER_ACCESSIBLE, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x20_0000, 0x30_0000), read_only)
+            .unwrap();
+
+        // a freed frame with old content is reused
+        let mut frames = fixture.memory.frame_allocator();
+        let dirty = frames.allocate_frame().unwrap();
+        fixture.fault_memory.fill(dirty, 0xff);
+        unsafe { frames.deallocate_frame(dirty) };
+
+        assert!(fixture.resolve(0x10_1234, WRITE, &mut regions).is_ok());
+        assert_eq!(
+            fixture.mapping(0x10_1234),
+            (dirty, USER | PageTableFlags::PRESENT)
+        );
+        assert!(fixture.fault_memory.contains_only(dirty, 0));
+
+        assert!(matches!(
+            fixture.resolve(0x30_0000, READ, &mut regions),
+            Err(FatalFault::NoRegion)
+        ));
+        assert!(matches!(
+            fixture.resolve(0x20_0000, WRITE, &mut regions),
+            Err(FatalFault::AccessViolation)
+        ));
+        fixture.memory.assert_not_mapped(VirtAddr::new(0x20_0000));
+    }
+
+    #[test]
+    fn resolved_concurrently() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 1];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let zero_fill = region(USER, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), zero_fill)
+            .unwrap();
+
+        assert!(fixture.resolve(0x10_0000, WRITE, &mut regions).is_ok());
+        let allocated = fixture.memory.allocated_frames();
+        let mapping = fixture.mapping(0x10_0000);
+
+        // e.g. another CPU faulted on the page before the mapping was visible to it
+        assert!(fixture.resolve(0x10_0008, WRITE, &mut regions).is_ok());
+        assert_eq!(fixture.memory.allocated_frames(), allocated);
+        assert_eq!(fixture.mapping(0x10_0000), mapping);
+    }
+
+    #[test]
+    fn supervisor_protection_violation() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 1];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let flags = USER - PageTableFlags::NO_EXECUTE;
+        let zero_fill = region(flags, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), zero_fill)
+            .unwrap();
+
+        assert!(fixture.resolve(0x10_0000, WRITE, &mut regions).is_ok());
+        let mapping = fixture.mapping(0x10_0000);
+        let protection = PageFaultErrorCode::PROTECTION_VIOLATION;
+
+        // a SMAP violation
+        assert!(matches!(
+            fixture.resolve(0x10_0000, protection, &mut regions),
+            Err(FatalFault::AccessViolation)
+        ));
+        // a SMEP violation
+        assert!(matches!(
+            fixture.resolve(
+                0x10_0000,
+                protection | PageFaultErrorCode::INSTRUCTION_FETCH,
+                &mut regions
+            ),
+            Err(FatalFault::AccessViolation)
+        ));
+        assert_eq!(fixture.mapping(0x10_0000), mapping);
+
+        // a stale TLB entry of a user access
+        assert!(fixture
+            .resolve(0x10_0000, READ | protection, &mut regions)
+            .is_ok());
+    }
+
+    #[test]
+    fn parent_rights_are_upgraded() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 1];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let flags = USER - PageTableFlags::NO_EXECUTE;
+        let zero_fill = region(flags, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), zero_fill)
+            .unwrap();
+
+        assert!(fixture.resolve(0x10_0000, WRITE, &mut regions).is_ok());
+        let mapping = fixture.mapping(0x10_0000);
+        fixture.update_parent(0x10_0000, PageTableLevel::Four, |flags| {
+            flags - PageTableFlags::WRITABLE - PageTableFlags::USER_ACCESSIBLE
+        });
+        fixture.update_parent(0x10_0000, PageTableLevel::Two, |flags| {
+            flags | PageTableFlags::NO_EXECUTE
+        });
+        assert_eq!(
+            fixture.effective_flags(0x10_0000),
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE
+        );
+
+        // the entry of the page allows the access, so only the parent entries are changed
+        assert!(fixture.resolve(0x10_0000, WRITE, &mut regions).is_ok());
+        assert_eq!(fixture.mapping(0x10_0000), mapping);
+        assert_eq!(
+            fixture.effective_flags(0x10_0000),
+            flags | PageTableFlags::PRESENT
+        );
+
+        // `map_to` doesn't remove the `NO_EXECUTE` flag from existing parent entries
+        fixture.update_parent(0x10_1000, PageTableLevel::Three, |flags| {
+            flags | PageTableFlags::NO_EXECUTE
+        });
+        assert!(fixture.resolve(0x10_1000, FETCH, &mut regions).is_ok());
+        assert_eq!(
+            fixture.effective_flags(0x10_1000),
+            flags | PageTableFlags::PRESENT
+        );
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 2];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let copy_on_write = region(USER, FaultResolver::CopyOnWrite);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), copy_on_write)
+            .unwrap();
+        let zero_fill = region(USER, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x20_0000, 0x30_0000), zero_fill)
+            .unwrap();
+
+        let shared = fixture.memory.frame_allocator().allocate_frame().unwrap();
+        fixture.fault_memory.fill(shared, 0x42);
+        let read_only = (USER - PageTableFlags::WRITABLE) | PageTableFlags::PRESENT;
+        for &addr in &[0x10_0000, 0x10_1000, 0x20_0000] {
+            let page = Page::containing_address(VirtAddr::new(addr));
+            fixture.memory.map(page, shared, read_only);
+        }
+        // e.g. all user mappings were made read-only for a fork
+        fixture.update_parent(0x10_0000, PageTableLevel::Three, |flags| {
+            flags - PageTableFlags::WRITABLE
+        });
+        let protection = PageFaultErrorCode::PROTECTION_VIOLATION;
+
+        // reading doesn't copy
+        assert!(fixture
+            .resolve(0x10_0000, READ | protection, &mut regions)
+            .is_ok());
+        assert_eq!(fixture.mapping(0x10_0000), (shared, read_only));
+
+        assert!(fixture
+            .resolve(0x10_0000, WRITE | protection, &mut regions)
+            .is_ok());
+        let (copy, flags) = fixture.mapping(0x10_0000);
+        assert_ne!(copy, shared);
+        assert_eq!(flags, USER | PageTableFlags::PRESENT);
+        assert!(fixture.fault_memory.contains_only(copy, 0x42));
+        assert_eq!(fixture.fault_memory.released, [shared]);
+        assert_eq!(
+            fixture.effective_flags(0x10_0000),
+            USER | PageTableFlags::PRESENT
+        );
+
+        // the last mapping of the frame is made writable without copying
+        fixture.fault_memory.exclusive = true;
+        assert!(fixture
+            .resolve(0x10_1000, WRITE | protection, &mut regions)
+            .is_ok());
+        assert_eq!(
+            fixture.mapping(0x10_1000),
+            (shared, USER | PageTableFlags::PRESENT)
+        );
+        assert_eq!(fixture.fault_memory.released, [shared]);
+
+        // other regions don't copy
+        assert!(matches!(
+            fixture.resolve(0x20_0000, WRITE | protection, &mut regions),
+            Err(FatalFault::AccessViolation)
+        ));
+        assert_eq!(fixture.mapping(0x20_0000), (shared, read_only));
+    }
+
+    #[test]
+    fn copy_on_write_keeps_memory_type_and_key() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 1];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let copy_on_write = region(USER, FaultResolver::CopyOnWrite);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), copy_on_write)
+            .unwrap();
+
+        let shared = fixture.memory.frame_allocator().allocate_frame().unwrap();
+        let read_only = (USER - PageTableFlags::WRITABLE) | PageTableFlags::PRESENT;
+        fixture
+            .memory
+            .map(SimulatedMemory::page(0x10_0000), shared, read_only);
+        let key = ProtectionKey::new(3);
+        {
+            let (mut mapper, _) = fixture.memory.mapped_page_table();
+            unsafe {
+                mapper.walk_mut(VirtAddr::new(0x10_0000), |entry, level| {
+                    entry.set_pat_index(level, 7);
+                    entry.set_protection_key(key);
+                })
+            };
+        }
+
+        let error_code = WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+        assert!(fixture.resolve(0x10_0000, error_code, &mut regions).is_ok());
+        let (mapper, _) = fixture.memory.mapped_page_table();
+        match mapper.walk(VirtAddr::new(0x10_0000)) {
+            WalkResult::Mapped { entry, level, .. } => {
+                assert_ne!(entry.addr(), shared.start_address());
+                assert!(entry.flags().contains(USER | PageTableFlags::PRESENT));
+                assert_eq!(entry.pat_index(level), 7);
+                assert_eq!(entry.protection_key(), key);
+            }
+            WalkResult::NotMapped { .. } => panic!("the page is not mapped"),
+        }
+    }
+
+    #[test]
+    fn file() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 2];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let file = FaultResolver::File {
+            file: FILE,
+            origin: 0xf_e000,
+        };
+        regions
+            .insert(
+                SimulatedMemory::pages(0x10_0000, 0x20_0000),
+                region(USER, file),
+            )
+            .unwrap();
+        let missing = FaultResolver::File {
+            file: FILE + 1,
+            origin: 0,
+        };
+        regions
+            .insert(
+                SimulatedMemory::pages(0x20_0000, 0x30_0000),
+                region(USER, missing),
+            )
+            .unwrap();
+
+        assert!(fixture.resolve(0x10_1234, READ, &mut regions).is_ok());
+        let (frame, _) = fixture.mapping(0x10_1234);
+        assert_eq!(fixture.fault_memory.read(frame), 0x3000);
+
+        // the frame is freed again
+        let allocated = fixture.memory.allocated_frames();
+        assert!(matches!(
+            fixture.resolve(0x20_0000, READ, &mut regions),
+            Err(FatalFault::LoadFailed)
+        ));
+        assert_eq!(fixture.memory.allocated_frames(), allocated);
+        fixture.memory.assert_not_mapped(VirtAddr::new(0x20_0000));
+    }
+
+    #[test]
+    fn guard() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 1];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let guard = region(USER, FaultResolver::Guard);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), guard)
+            .unwrap();
+
+        assert!(matches!(
+            fixture.resolve(0x10_0000, READ, &mut regions),
+            Err(FatalFault::GuardPage)
+        ));
+        fixture.memory.assert_not_mapped(VirtAddr::new(0x10_0000));
+    }
+
+    #[test]
+    fn grow_down() {
+        let mut fixture = Fixture::new();
+        let mut storage = [None; 3];
+        let mut regions = VmaSet::new(SimulatedMemory::pages(0x1000, 0x1000_0000), &mut storage);
+        let grow_down = region(PageTableFlags::empty(), FaultResolver::GrowDown);
+        let stack = region(USER, FaultResolver::ZeroFill);
+        regions
+            .insert(SimulatedMemory::pages(0x10_0000, 0x20_0000), grow_down)
+            .unwrap();
+        regions
+            .insert(SimulatedMemory::pages(0x20_0000, 0x30_0000), stack)
+            .unwrap();
+        // not followed by a stack
+        regions
+            .insert(SimulatedMemory::pages(0x40_0000, 0x50_0000), grow_down)
+            .unwrap();
+
+        assert!(fixture.resolve(0x1f_0008, WRITE, &mut regions).is_ok());
+        fixture.mapping(0x1f_0008);
+        let grown = regions.get(VirtAddr::new(0x1f_0000)).unwrap();
+        assert_eq!(grown.pages, SimulatedMemory::pages(0x1f_0000, 0x30_0000));
+        assert_eq!(grown.data, stack);
+        let guard = regions.get(VirtAddr::new(0x1e_f000)).unwrap();
+        assert_eq!(guard.pages, SimulatedMemory::pages(0x10_0000, 0x1f_0000));
+
+        // the first page is never added to the stack
+        assert!(matches!(
+            fixture.resolve(0x10_0000, WRITE, &mut regions),
+            Err(FatalFault::GuardPage)
+        ));
+        assert!(matches!(
+            fixture.resolve(0x4f_0000, WRITE, &mut regions),
+            Err(FatalFault::GuardPage)
+        ));
+        fixture.memory.assert_not_mapped(VirtAddr::new(0x10_0000));
+        fixture.memory.assert_not_mapped(VirtAddr::new(0x4f_0000));
+    }
+}
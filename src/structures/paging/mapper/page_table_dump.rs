@@ -0,0 +1,246 @@
+use core::fmt;
+
+use crate::structures::paging::{
+    mapper::{Mappings, Walk},
+    page_table::{PageTableEntry, PageTableFlags, PageTableLevel},
+    PageSize, Size1GiB, Size2MiB, Size4KiB,
+};
+use crate::VirtAddr;
+
+/// Formats the mappings of a page table as a list of merged regions, similar to the `ptdump`
+/// output of Linux.
+///
+/// Each line shows the virtual address range, the physical address range, the page size, and
+/// the attributes of a region:
+///
+/// ```text
+/// 0x0000000000400000-0x0000000000403000 -> 0x0000000012000-0x0000000015000 4K R - U -- - --- --- ---
+/// 0xffff800000000000-0xffff800080000000 -> 0x0000000000000-0x0000080000000 1G R W - NX G --- --- ---
+/// ```
+///
+/// The end addresses are exclusive. The attribute columns are `R` (present), `W` (writable),
+/// `U` (user accessible), `NX` (no execute), `G` (global), `PWT` (write through), `PCD` (cache
+/// disabled), and `PAT`. The effective flags of the mappings are used, see
+/// [`WalkResult::Mapped`](super::WalkResult::Mapped).
+///
+/// Consecutive pages are merged into a single region if they have the same size and the same
+/// attributes and if both their virtual and their physical addresses are contiguous.
+#[derive(Debug)]
+pub struct PageTableDump<'a, W: Walk> {
+    mappings: Mappings<'a, W>,
+}
+
+impl<'a, W: Walk> PageTableDump<'a, W> {
+    /// Creates a new dump of the given mappings.
+    ///
+    /// Use [`Walk::mappings_in_range`] to only dump a part of the address space.
+    #[inline]
+    pub fn new(mappings: Mappings<'a, W>) -> Self {
+        PageTableDump { mappings }
+    }
+}
+
+impl<'a, W: Walk> fmt::Display for PageTableDump<'a, W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut mappings = self.mappings.clone();
+        let mut current: Option<Region> = None;
+
+        while let Some((start, entry, level, flags)) = mappings.next_entry() {
+            let region = Region::new(start, &entry, level, flags);
+            match current {
+                Some(ref mut current) if current.is_continued_by(&region) => {
+                    current.len += region.len;
+                }
+                _ => {
+                    if let Some(current) = current {
+                        writeln!(f, "{}", current)?;
+                    }
+                    current = Some(region);
+                }
+            }
+        }
+
+        if let Some(current) = current {
+            writeln!(f, "{}", current)?;
+        }
+        Ok(())
+    }
+}
+
+/// A contiguous region of pages with the same size and attributes.
+#[derive(Clone, Copy)]
+struct Region {
+    virt_start: u64,
+    phys_start: u64,
+    len: u64,
+    page_size: u64,
+    /// The displayed flags, without the `HUGE_PAGE` flag.
+    flags: PageTableFlags,
+    pat: bool,
+}
+
+impl Region {
+    fn new(
+        start: VirtAddr,
+        entry: &PageTableEntry,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Self {
+        let page_size = level.entry_address_space_alignment();
+        let addr = entry.addr().as_u64();
+        // the PAT bit of huge page entries is part of the address
+        let phys_start = addr & !(page_size - 1);
+        let pat = entry.pat_index(level) & 4 != 0;
+        let displayed_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_CACHE;
+
+        Region {
+            virt_start: start.as_u64(),
+            phys_start,
+            len: page_size,
+            page_size,
+            flags: flags & displayed_flags,
+            pat,
+        }
+    }
+
+    /// Returns whether `next` directly follows this region and has the same attributes.
+    fn is_continued_by(&self, next: &Region) -> bool {
+        self.page_size == next.page_size
+            && self.flags == next.flags
+            && self.pat == next.pat
+            && self.virt_start.wrapping_add(self.len) == next.virt_start
+            && self.phys_start.wrapping_add(self.len) == next.phys_start
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the end of the last page of the address space doesn't fit into an `u64`
+        let virt_end = u128::from(self.virt_start) + u128::from(self.len);
+        let phys_end = self.phys_start + self.len;
+        let page_size = match self.page_size {
+            Size4KiB::SIZE => "4K",
+            Size2MiB::SIZE => "2M",
+            Size1GiB::SIZE => "1G",
+            _ => "??",
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#015x}-{:#015x} {}",
+            self.virt_start, virt_end, self.phys_start, phys_end, page_size
+        )?;
+
+        let columns = [
+            (PageTableFlags::PRESENT, "R"),
+            (PageTableFlags::WRITABLE, "W"),
+            (PageTableFlags::USER_ACCESSIBLE, "U"),
+            (PageTableFlags::NO_EXECUTE, "NX"),
+            (PageTableFlags::GLOBAL, "G"),
+            (PageTableFlags::WRITE_THROUGH, "PWT"),
+            (PageTableFlags::NO_CACHE, "PCD"),
+        ];
+        for &(flag, name) in columns.iter() {
+            if self.flags.contains(flag) {
+                write!(f, " {}", name)?;
+            } else {
+                write!(f, " {:-<1$}", "", name.len())?;
+            }
+        }
+        if self.pat {
+            write!(f, " PAT")
+        } else {
+            write!(f, " ---")
+        }
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use crate::structures::paging::{
+        mapper::{SimulatedMemory, Walk, WalkMut},
+        Page, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    };
+    use crate::{PhysAddr, VirtAddr};
+
+    fn map_4kib(memory: &mut SimulatedMemory, virt: u64, phys: u64, flags: PageTableFlags) {
+        memory.map(
+            Page::<Size4KiB>::containing_address(VirtAddr::new(virt)),
+            PhysFrame::containing_address(PhysAddr::new(phys)),
+            flags,
+        );
+    }
+
+    #[test]
+    fn merges_contiguous_pages() {
+        let read_only = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let mut memory = SimulatedMemory::new(16);
+        map_4kib(&mut memory, 0x40_0000, 0x1_2000, read_only);
+        map_4kib(&mut memory, 0x40_1000, 0x1_3000, read_only);
+        map_4kib(&mut memory, 0x40_2000, 0x1_4000, read_only);
+        // different flags
+        map_4kib(&mut memory, 0x40_3000, 0x1_5000, writable);
+        // physically not contiguous
+        map_4kib(&mut memory, 0x40_4000, 0x2_0000, writable);
+        // virtually not contiguous
+        map_4kib(&mut memory, 0x40_6000, 0x2_1000, writable);
+
+        let (mapper, _) = memory.mapped_page_table();
+        assert_eq!(
+            format!("{}", mapper.dump()),
+            "\
+0x0000000000400000-0x0000000000403000 -> 0x0000000012000-0x0000000015000 4K R - U -- - --- --- ---
+0x0000000000403000-0x0000000000404000 -> 0x0000000015000-0x0000000016000 4K R W - -- - --- --- ---
+0x0000000000404000-0x0000000000405000 -> 0x0000000020000-0x0000000021000 4K R W - -- - --- --- ---
+0x0000000000406000-0x0000000000407000 -> 0x0000000021000-0x0000000022000 4K R W - -- - --- --- ---
+"
+        );
+    }
+
+    #[test]
+    fn separates_page_sizes_and_memory_types() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let mut memory = SimulatedMemory::new(16);
+        for i in 0..2 {
+            memory.map(
+                Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000 + i * 0x20_0000)),
+                PhysFrame::containing_address(PhysAddr::new(0x4000_0000 + i * 0x20_0000)),
+                flags,
+            );
+        }
+        // directly follows the second 2MiB page, but is a 4KiB page
+        map_4kib(&mut memory, 0x60_0000, 0x4040_0000, flags);
+        // the PAT bit of the third 2MiB page is part of the entry address
+        memory.map(
+            Page::<Size2MiB>::containing_address(VirtAddr::new(0x80_0000)),
+            PhysFrame::containing_address(PhysAddr::new(0x4060_0000)),
+            flags,
+        );
+        {
+            let (mut mapper, _) = memory.mapped_page_table();
+            unsafe {
+                mapper.walk_mut(VirtAddr::new(0x80_0000), |entry, level| {
+                    entry.set_pat_index(level, 4)
+                })
+            };
+        }
+
+        let (mapper, _) = memory.mapped_page_table();
+        assert_eq!(
+            format!("{}", mapper.dump()),
+            "\
+0x0000000000200000-0x0000000000600000 -> 0x0000040000000-0x0000040400000 2M R W - -- - --- --- ---
+0x0000000000600000-0x0000000000601000 -> 0x0000040400000-0x0000040401000 4K R W - -- - --- --- ---
+0x0000000000800000-0x0000000000a00000 -> 0x0000040600000-0x0000040800000 2M R W - -- - --- --- PAT
+"
+        );
+    }
+}
@@ -0,0 +1,541 @@
+use crate::structures::paging::{
+    frame_alloc::FrameAllocator,
+    mapper::{
+        advance, HugePageMapper, MapRangeError, MapToError, Mapper, MapperAllSizes,
+        MapperFlushRange, RangeUpdateError, SplitError, TranslateError, Walk, WalkMut, WalkResult,
+    },
+    page::PageRange,
+    Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use crate::{PhysAddr, VirtAddr};
+
+/// Provides methods for mapping, unmapping and updating the flags of whole page ranges.
+///
+/// The methods of this trait work on pages of all sizes. New mappings are split into the
+/// largest `Size1GiB`, `Size2MiB`, and `Size4KiB` pieces that are aligned both in virtual and
+/// physical memory. Existing mappings are updated as a whole, except for huge pages that only
+/// partially overlap with the range, which are split first.
+///
+/// Instead of a flush token for each page, all methods return a single [`MapperFlushRange`].
+///
+/// This trait is automatically implemented for all types that implement [`MapperAllSizes`],
+/// [`HugePageMapper`] for both huge page sizes, and [`WalkMut`].
+pub trait RangeMapper:
+    MapperAllSizes + HugePageMapper<Size1GiB> + HugePageMapper<Size2MiB> + WalkMut
+{
+    /// Maps the given page range to the physical memory starting at `phys_start`.
+    ///
+    /// The range is mapped using the largest possible page sizes. `Size1GiB` pages are used
+    /// too, so the caller has to make sure that the CPU supports them if the range is large
+    /// enough.
+    ///
+    /// If any page of the range is already mapped, [`MapRangeError::PageAlreadyMapped`] is
+    /// returned and no mappings are created.
+    ///
+    /// If a frame for a page table can't be allocated, [`MapRangeError::FrameAllocationFailed`]
+    /// is returned and the pages before the failed page stay mapped. Since the range was
+    /// unmapped before, they can be removed again through [`unmap_range`](Self::unmap_range).
+    /// Use a [`MapTransaction`](super::MapTransaction) instead if the flags of the parent
+    /// entries must be restored too.
+    ///
+    /// ## Safety
+    ///
+    /// This is a convencience function that invokes [`Mapper::map_to`] internally, so
+    /// all safety requirements of it also apply for this function.
+    unsafe fn map_range<A>(
+        &mut self,
+        pages: PageRange,
+        phys_start: PhysFrame,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, MapRangeError>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let start = pages.start.start_address();
+        let end = pages.end.start_address();
+        let mut flush = MapperFlushRange::new();
+        if pages.is_empty() {
+            return Ok(flush);
+        }
+
+        if let Some((addr, _)) = next_mapping(self, start, end) {
+            return Err(MapRangeError::PageAlreadyMapped(addr));
+        }
+
+        let mut addr = start;
+        let mut frame_addr = phys_start.start_address();
+        loop {
+            let remaining = end - addr;
+            let aligned =
+                |size| addr.is_aligned(size) && frame_addr.is_aligned(size) && remaining >= size;
+
+            let result = if aligned(Size1GiB::SIZE) {
+                map_page::<Self, Size1GiB, A>(self, addr, frame_addr, flags, frame_allocator)
+            } else if aligned(Size2MiB::SIZE) {
+                map_page::<Self, Size2MiB, A>(self, addr, frame_addr, flags, frame_allocator)
+            } else {
+                map_page::<Self, Size4KiB, A>(self, addr, frame_addr, flags, frame_allocator)
+            };
+            let size = result?;
+            flush.add(addr, size);
+
+            if remaining == size {
+                break;
+            }
+            addr = advance(self.paging_mode(), addr, size).unwrap();
+            frame_addr += size;
+        }
+
+        Ok(flush)
+    }
+
+    /// Removes all mappings in the given page range.
+    ///
+    /// Unmapped ranges are skipped. Huge pages are removed as a whole if they are completely
+    /// contained in the range. Huge pages that only partially overlap with the range are split
+    /// first, using `frame_allocator` for the new page tables.
+    ///
+    /// Note that no page tables or frames are deallocated.
+    ///
+    /// ## Safety
+    ///
+    /// This function might invoke [`HugePageMapper::split_huge_page`] internally, so all
+    /// safety requirements of it also apply for this function.
+    unsafe fn unmap_range<A>(
+        &mut self,
+        pages: PageRange,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, RangeUpdateError>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let start = pages.start.start_address();
+        let end = pages.end.start_address();
+        let mut flush = MapperFlushRange::new();
+        if pages.is_empty() {
+            return Ok(flush);
+        }
+        check_range(self, start, end)?;
+        split_at(self, start, frame_allocator, &mut flush)?;
+        split_at(self, end, frame_allocator, &mut flush)?;
+
+        let mut next = Some(start);
+        while let Some((addr, size)) = next.and_then(|addr| next_mapping(self, addr, end)) {
+            match size {
+                Size1GiB::SIZE => unmap_page::<Self, Size1GiB>(self, addr),
+                Size2MiB::SIZE => unmap_page::<Self, Size2MiB>(self, addr),
+                _ => unmap_page::<Self, Size4KiB>(self, addr),
+            }
+            flush.add(addr, size);
+            next = advance(self.paging_mode(), addr, size);
+        }
+
+        Ok(flush)
+    }
+
+    /// Updates the flags of all mappings in the given page range.
+    ///
+    /// Unmapped ranges are skipped. Huge pages are updated as a whole if they are completely
+    /// contained in the range. Huge pages that only partially overlap with the range are split
+    /// first, using `frame_allocator` for the new page tables. The `HUGE_PAGE` flag is set
+    /// automatically for huge pages. The memory type of the mappings is kept, see
+    /// [`Mapper::update_protection_flags`].
+    ///
+    /// ## Safety
+    ///
+    /// This is a convencience function that invokes [`Mapper::update_protection_flags`] and
+    /// [`HugePageMapper::split_huge_page`] internally, so all safety requirements of them
+    /// also apply for this function.
+    unsafe fn protect_range<A>(
+        &mut self,
+        pages: PageRange,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, RangeUpdateError>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let start = pages.start.start_address();
+        let end = pages.end.start_address();
+        let mut flush = MapperFlushRange::new();
+        if pages.is_empty() {
+            return Ok(flush);
+        }
+        check_range(self, start, end)?;
+        split_at(self, start, frame_allocator, &mut flush)?;
+        split_at(self, end, frame_allocator, &mut flush)?;
+
+        let mut next = Some(start);
+        while let Some((addr, size)) = next.and_then(|addr| next_mapping(self, addr, end)) {
+            match size {
+                Size1GiB::SIZE => update_page::<Self, Size1GiB>(self, addr, flags),
+                Size2MiB::SIZE => update_page::<Self, Size2MiB>(self, addr, flags),
+                _ => update_page::<Self, Size4KiB>(self, addr, flags),
+            }
+            flush.add(addr, size);
+            next = advance(self.paging_mode(), addr, size);
+        }
+
+        Ok(flush)
+    }
+}
+
+impl<T> RangeMapper for T where
+    T: MapperAllSizes + HugePageMapper<Size1GiB> + HugePageMapper<Size2MiB> + WalkMut + ?Sized
+{
+}
+
+/// Returns the first mapped address in `addr..end` together with the size of its mapping.
+///
+/// Unmapped regions are skipped as a whole, based on the level of the first non-present entry.
+fn next_mapping<M>(mapper: &M, mut addr: VirtAddr, end: VirtAddr) -> Option<(VirtAddr, u64)>
+where
+    M: Walk + ?Sized,
+{
+    while addr < end {
+        match mapper.walk(addr) {
+            WalkResult::Mapped { level, .. } => {
+                return Some((addr, level.entry_address_space_alignment()))
+            }
+            WalkResult::NotMapped { level, .. } => {
+                let size = level.entry_address_space_alignment();
+                addr = advance(mapper.paging_mode(), addr, size)?;
+            }
+        }
+    }
+    None
+}
+
+/// Checks that all mappings in `start..end` point to valid physical addresses.
+fn check_range<M>(mapper: &M, start: VirtAddr, end: VirtAddr) -> Result<(), RangeUpdateError>
+where
+    M: MapperAllSizes + Walk + ?Sized,
+{
+    let mut next = Some(start);
+    while let Some((addr, size)) = next.and_then(|addr| next_mapping(mapper, addr, end)) {
+        // `unmap` requires the address of huge page entries to be aligned, the walk doesn't
+        let result = match size {
+            Size1GiB::SIZE => check_page::<M, Size1GiB>(mapper, addr),
+            Size2MiB::SIZE => check_page::<M, Size2MiB>(mapper, addr),
+            _ => check_page::<M, Size4KiB>(mapper, addr),
+        };
+        if let Err(TranslateError::InvalidFrameAddress(frame_addr)) = result {
+            return Err(RangeUpdateError::InvalidFrameAddress(frame_addr));
+        }
+
+        next = advance(mapper.paging_mode(), addr, size);
+    }
+    Ok(())
+}
+
+/// Splits the huge pages that contain `addr` without starting at it, until `addr` is the start
+/// of a mapping or unmapped.
+///
+/// The translation of all addresses stays the same, but the split huge pages are added to
+/// `flush`.
+unsafe fn split_at<M, A>(
+    mapper: &mut M,
+    addr: VirtAddr,
+    frame_allocator: &mut A,
+    flush: &mut MapperFlushRange,
+) -> Result<(), RangeUpdateError>
+where
+    M: HugePageMapper<Size1GiB> + HugePageMapper<Size2MiB> + Walk,
+    A: FrameAllocator<Size4KiB> + ?Sized,
+{
+    loop {
+        let size = match mapper.walk(addr) {
+            WalkResult::Mapped { level, .. } => level.entry_address_space_alignment(),
+            WalkResult::NotMapped { .. } => return Ok(()),
+        };
+        if addr.is_aligned(size) {
+            return Ok(());
+        }
+        let result =
+            match size {
+                Size1GiB::SIZE => mapper
+                    .split_huge_page(Page::<Size1GiB>::containing_address(addr), frame_allocator),
+                _ => mapper
+                    .split_huge_page(Page::<Size2MiB>::containing_address(addr), frame_allocator),
+            };
+        match result {
+            Ok(split_flush) => split_flush.ignore(),
+            Err(SplitError::FrameAllocationFailed) => {
+                return Err(RangeUpdateError::FrameAllocationFailed)
+            }
+            Err(err) => panic!("failed to split checked huge page: {:?}", err),
+        }
+        flush.add(addr.align_down(size), size);
+    }
+}
+
+fn check_page<M, S>(mapper: &M, addr: VirtAddr) -> Result<(), TranslateError>
+where
+    M: Mapper<S> + ?Sized,
+    S: PageSize,
+{
+    mapper.translate_page(Page::<S>::containing_address(addr))?;
+    Ok(())
+}
+
+unsafe fn map_page<M, S, A>(
+    mapper: &mut M,
+    addr: VirtAddr,
+    frame_addr: PhysAddr,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<u64, MapRangeError>
+where
+    M: Mapper<S>,
+    S: PageSize,
+    A: FrameAllocator<Size4KiB> + ?Sized,
+{
+    let page = Page::<S>::containing_address(addr);
+    let frame = PhysFrame::<S>::containing_address(frame_addr);
+    match mapper.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.ignore();
+            Ok(S::SIZE)
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapRangeError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) | Err(MapToError::PageAlreadyMapped(_)) => {
+            Err(MapRangeError::PageAlreadyMapped(addr))
+        }
+    }
+}
+
+fn unmap_page<M, S>(mapper: &mut M, addr: VirtAddr)
+where
+    M: Mapper<S> + ?Sized,
+    S: PageSize,
+{
+    match mapper.unmap(Page::containing_address(addr)) {
+        Ok((_, flush)) => flush.ignore(),
+        Err(err) => panic!("failed to unmap checked page: {:?}", err),
+    }
+}
+
+unsafe fn update_page<M, S>(mapper: &mut M, addr: VirtAddr, flags: PageTableFlags)
+where
+    M: Mapper<S> + WalkMut,
+    S: PageSize,
+{
+    match mapper.update_protection_flags(Page::containing_address(addr), flags) {
+        Ok(flush) => flush.ignore(),
+        Err(err) => panic!("failed to update flags of checked page: {:?}", err),
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{
+        mapper::{SimulatedMemory, Translate},
+        PageTableLevel,
+    };
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+    /// Returns the level of the entry that maps `addr`, the physical address and the flags.
+    fn leaf(memory: &mut SimulatedMemory, addr: u64) -> Option<(PageTableLevel, u64, u64)> {
+        let (mapper, _) = memory.mapped_page_table();
+        match mapper.walk(VirtAddr::new(addr)) {
+            WalkResult::Mapped {
+                entry,
+                level,
+                flags,
+                ..
+            } => {
+                let offset = addr % level.entry_address_space_alignment();
+                let phys = entry.addr().as_u64() + offset;
+                Some((level, phys, flags.bits()))
+            }
+            WalkResult::NotMapped { .. } => None,
+        }
+    }
+
+    #[test]
+    fn map_range_uses_largest_pages() {
+        let mut memory = SimulatedMemory::new(16);
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let pages = SimulatedMemory::pages(0x1f_f000, 0x40_1000);
+            let flush = unsafe {
+                mapper.map_range(
+                    pages,
+                    SimulatedMemory::frame(0x101f_f000),
+                    FLAGS,
+                    &mut frame_allocator,
+                )
+            }
+            .unwrap();
+            assert_eq!(flush.pages(), 3);
+            flush.ignore();
+
+            let pages = SimulatedMemory::pages(0x4000_0000, 0x8000_0000);
+            let frame = SimulatedMemory::frame(0xc000_0000);
+            unsafe { mapper.map_range(pages, frame, FLAGS, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+
+        let huge = FLAGS.union(PageTableFlags::HUGE_PAGE).bits();
+        assert_eq!(
+            leaf(&mut memory, 0x1f_f123),
+            Some((PageTableLevel::One, 0x101f_f123, FLAGS.bits()))
+        );
+        assert_eq!(
+            leaf(&mut memory, 0x3f_ffff),
+            Some((PageTableLevel::Two, 0x103f_ffff, huge))
+        );
+        assert_eq!(
+            leaf(&mut memory, 0x40_0000),
+            Some((PageTableLevel::One, 0x1040_0000, FLAGS.bits()))
+        );
+        assert_eq!(leaf(&mut memory, 0x40_1000), None);
+        assert_eq!(
+            leaf(&mut memory, 0x7fff_ffff),
+            Some((PageTableLevel::Three, 0xffff_ffff, huge))
+        );
+    }
+
+    #[test]
+    fn map_range_rejects_mapped_pages() {
+        let mut memory = SimulatedMemory::new(16);
+        memory.map(
+            SimulatedMemory::page(0x3000),
+            SimulatedMemory::frame(0x3000),
+            FLAGS,
+        );
+        let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+        let pages = SimulatedMemory::pages(0x1000, 0x5000);
+        let frame = SimulatedMemory::frame(0x10_0000);
+        assert!(matches!(
+            unsafe { mapper.map_range(pages, frame, FLAGS, &mut frame_allocator) },
+            Err(MapRangeError::PageAlreadyMapped(addr)) if addr.as_u64() == 0x3000
+        ));
+        assert!(mapper.translate_addr(VirtAddr::new(0x1000)).is_none());
+    }
+
+    #[test]
+    fn map_range_out_of_frames() {
+        // the root table and one table of each lower level
+        let mut memory = SimulatedMemory::new(4);
+        let pages = SimulatedMemory::pages(0x1f_f000, 0x20_1000);
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let frame = SimulatedMemory::frame(0x1000);
+            assert!(matches!(
+                unsafe { mapper.map_range(pages, frame, FLAGS, &mut frame_allocator) },
+                Err(MapRangeError::FrameAllocationFailed)
+            ));
+        }
+        // the pages before the failed page stay mapped until they are unmapped again
+        assert!(leaf(&mut memory, 0x1f_f000).is_some());
+        assert!(leaf(&mut memory, 0x20_0000).is_none());
+        let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+        unsafe { mapper.unmap_range(pages, &mut frame_allocator) }
+            .unwrap()
+            .ignore();
+        assert!(mapper.translate_addr(VirtAddr::new(0x1f_f000)).is_none());
+    }
+
+    #[test]
+    fn unmap_range_splits_partial_huge_page() {
+        let mut memory = SimulatedMemory::new(16);
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+        memory.map(page, frame, FLAGS);
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let pages = SimulatedMemory::pages(0x20_1000, 0x20_3000);
+            let flush = unsafe { mapper.unmap_range(pages, &mut frame_allocator) }.unwrap();
+            // the split huge page and the two unmapped pages
+            assert_eq!(flush.pages(), 3);
+            flush.ignore();
+        }
+
+        assert_eq!(
+            leaf(&mut memory, 0x20_0fff),
+            Some((PageTableLevel::One, 0x4000_0fff, FLAGS.bits()))
+        );
+        assert_eq!(leaf(&mut memory, 0x20_1000), None);
+        assert_eq!(leaf(&mut memory, 0x20_2fff), None);
+        assert_eq!(
+            leaf(&mut memory, 0x3f_f000),
+            Some((PageTableLevel::One, 0x401f_f000, FLAGS.bits()))
+        );
+    }
+
+    #[test]
+    fn protect_range_splits_partial_huge_pages() {
+        let mut memory = SimulatedMemory::new(16);
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        memory.map(page, frame, FLAGS);
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let pages = SimulatedMemory::pages(0x4020_1000, 0x4060_0000);
+            let read_only = PageTableFlags::PRESENT;
+            unsafe { mapper.protect_range(pages, read_only, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+
+        let huge = FLAGS.union(PageTableFlags::HUGE_PAGE).bits();
+        let read_only_huge = PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE;
+        assert_eq!(
+            leaf(&mut memory, 0x4000_0000),
+            Some((PageTableLevel::Two, 0x8000_0000, huge))
+        );
+        assert_eq!(
+            leaf(&mut memory, 0x4020_0000),
+            Some((PageTableLevel::One, 0x8020_0000, FLAGS.bits()))
+        );
+        assert_eq!(
+            leaf(&mut memory, 0x4020_1000),
+            Some((
+                PageTableLevel::One,
+                0x8020_1000,
+                PageTableFlags::PRESENT.bits()
+            ))
+        );
+        assert_eq!(
+            leaf(&mut memory, 0x4040_0000),
+            Some((PageTableLevel::Two, 0x8040_0000, read_only_huge.bits()))
+        );
+        assert_eq!(
+            leaf(&mut memory, 0x4060_0000),
+            Some((PageTableLevel::Two, 0x8060_0000, huge))
+        );
+    }
+
+    #[test]
+    fn split_out_of_frames() {
+        // the root table and the level 3 table of the 1GiB page
+        let mut memory = SimulatedMemory::new(2);
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        memory.map(page, frame, FLAGS);
+        {
+            let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+            let pages = SimulatedMemory::pages(0x4000_0000, 0x4000_1000);
+            assert!(matches!(
+                unsafe { mapper.unmap_range(pages, &mut frame_allocator) },
+                Err(RangeUpdateError::FrameAllocationFailed)
+            ));
+        }
+        assert_eq!(
+            leaf(&mut memory, 0x4000_0000),
+            Some((
+                PageTableLevel::Three,
+                0x8000_0000,
+                FLAGS.union(PageTableFlags::HUGE_PAGE).bits()
+            ))
+        );
+    }
+}
@@ -6,9 +6,9 @@ use super::*;
 use crate::registers::control::Cr3;
 use crate::structures::paging::PageTableIndex;
 use crate::structures::paging::{
-    frame_alloc::FrameAllocator,
-    page::{AddressNotAligned, NotGiantPageSize},
-    page_table::{FrameError, PageTable, PageTableEntry, PageTableFlags},
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    page::{AddressNotAligned, NotGiantPageSize, PageRangeInclusive},
+    page_table::{FrameError, PageTable, PageTableEntry, PageTableFlags, PageTableLevel},
     Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
 };
 use crate::VirtAddr;
@@ -27,8 +27,16 @@ use crate::VirtAddr;
 ///
 /// This struct implements the `Mapper` trait.
 ///
+/// Only 4-level paging is supported. For 5-level paging, use [`MappedLevel5PageTable`] or
+/// [`OffsetLevel5PageTable`] instead.
+///
 /// The page table flags `PRESENT` and `WRITABLE` are always set for higher level page table
 /// entries, even if not specified, because the design of the recursive page table requires it.
+///
+/// When a huge page is split through the `HugePageMapper` trait, the new page table can only
+/// be filled after it was linked into the hierarchy. Thus, the huge page must not be accessed
+/// while it is split, which also means that the code and stack of the caller must not be
+/// located in it.
 #[derive(Debug)]
 pub struct RecursivePageTable<'a> {
     p4: &'a mut PageTable,
@@ -327,7 +335,7 @@ impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p3_entry.addr())
+        let frame = PhysFrame::from_start_address(p3_entry.leaf_addr(PageTableLevel::Three))
             .map_err(|AddressNotAligned| UnmapError::InvalidFrameAddress(p3_entry.addr()))?;
 
         p3_entry.set_unused();
@@ -405,7 +413,7 @@ impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
             return Err(TranslateError::PageNotMapped);
         }
 
-        PhysFrame::from_start_address(p3_entry.addr())
+        PhysFrame::from_start_address(p3_entry.leaf_addr(PageTableLevel::Three))
             .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p3_entry.addr()))
     }
 }
@@ -455,7 +463,7 @@ impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p2_entry.addr())
+        let frame = PhysFrame::from_start_address(p2_entry.leaf_addr(PageTableLevel::Two))
             .map_err(|AddressNotAligned| UnmapError::InvalidFrameAddress(p2_entry.addr()))?;
 
         p2_entry.set_unused();
@@ -562,7 +570,7 @@ impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
             return Err(TranslateError::PageNotMapped);
         }
 
-        PhysFrame::from_start_address(p2_entry.addr())
+        PhysFrame::from_start_address(p2_entry.leaf_addr(PageTableLevel::Two))
             .map_err(|AddressNotAligned| TranslateError::InvalidFrameAddress(p2_entry.addr()))
     }
 }
@@ -611,10 +619,11 @@ impl<'a> Mapper<Size4KiB> for RecursivePageTable<'a> {
         let p1 = unsafe { &mut *(p1_ptr(page, self.recursive_index)) };
         let p1_entry = &mut p1[page.p1_index()];
 
-        let frame = p1_entry.frame().map_err(|err| match err {
-            FrameError::FrameNotPresent => UnmapError::PageNotMapped,
-            FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
-        })?;
+        if !p1_entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        // the `HUGE_PAGE` bit is the PAT bit in level 1 entries
+        let frame = PhysFrame::containing_address(p1_entry.addr());
 
         p1_entry.set_unused();
         Ok((frame, MapperFlush::new(page)))
@@ -811,10 +820,6 @@ impl<'a> Translate for RecursivePageTable<'a> {
         if p1_entry.is_unused() {
             return TranslateResult::NotMapped;
         }
-        if p1_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-            panic!("level 1 entry has huge page bit set")
-        }
-
         let frame = match PhysFrame::from_start_address(p1_entry.addr()) {
             Ok(frame) => frame,
             Err(AddressNotAligned) => return TranslateResult::InvalidFrameAddress(p1_entry.addr()),
@@ -829,6 +834,322 @@ impl<'a> Translate for RecursivePageTable<'a> {
     }
 }
 
+impl<'a> Walk for RecursivePageTable<'a> {
+    fn walk(&self, addr: VirtAddr) -> WalkResult {
+        let root = self.p4[self.recursive_index].addr();
+        walk_tables(
+            self.p4,
+            PageTableLevel::Four,
+            addr,
+            Some(root),
+            |page_table, index| {
+                // see `clean_up_addr_range` for the recursive address of the next table
+                let table_addr = VirtAddr::from_ptr(page_table as *const PageTable);
+                let next_table_addr =
+                    VirtAddr::new_truncate((table_addr.as_u64() << 9) | (u64::from(index) << 12));
+                unsafe { &*next_table_addr.as_ptr::<PageTable>() }
+            },
+        )
+    }
+}
+
+impl<'a> WalkMut for RecursivePageTable<'a> {
+    #[inline]
+    unsafe fn walk_mut_to_level<F, R>(&mut self, addr: VirtAddr, level: PageTableLevel, f: F) -> R
+    where
+        F: FnOnce(&mut PageTableEntry, PageTableLevel) -> R,
+    {
+        walk_tables_mut(
+            self.p4,
+            PageTableLevel::Four,
+            addr,
+            level,
+            |page_table, index| {
+                // see `clean_up_addr_range` for the recursive address of the next table
+                let table_addr = VirtAddr::from_ptr(page_table as *const PageTable);
+                let next_table_addr =
+                    VirtAddr::new_truncate((table_addr.as_u64() << 9) | (u64::from(index) << 12));
+                &mut *next_table_addr.as_mut_ptr::<PageTable>()
+            },
+            f,
+        )
+    }
+}
+
+impl<'a> CleanUp for RecursivePageTable<'a> {
+    #[inline]
+    unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) -> MapperFlushAll
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        /// Frees the empty page tables below `page_table`, see
+        /// `PageTableWalker::clean_up` of the `MappedPageTable` for details.
+        unsafe fn clean_up<D>(
+            recursive_index: PageTableIndex,
+            page_table: &mut PageTable,
+            level: PageTableLevel,
+            start: Option<VirtAddr>,
+            end: Option<VirtAddr>,
+            frame_deallocator: &mut D,
+        ) -> bool
+        where
+            D: FrameDeallocator<Size4KiB> + ?Sized,
+        {
+            if page_table.is_empty() {
+                return true;
+            }
+
+            let next_level = match level.next_lower_level() {
+                Some(next_level) => next_level,
+                None => return false,
+            };
+
+            let table_addr = VirtAddr::from_ptr(page_table as *const PageTable);
+            let start_index = start.map_or(0, |addr| usize::from(addr.page_table_index(level)));
+            let end_index = end.map_or(511, |addr| usize::from(addr.page_table_index(level)));
+
+            for index in start_index..=end_index {
+                // never follow the recursive entry
+                if level == PageTableLevel::Four && index == usize::from(recursive_index) {
+                    continue;
+                }
+
+                let entry = &mut page_table[index];
+                let frame = match entry.frame() {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+
+                // The recursive address of the next table is the address of this table with
+                // all indices shifted up by one level and the entry index appended.
+                let next_table_addr =
+                    VirtAddr::new_truncate((table_addr.as_u64() << 9) | ((index as u64) << 12));
+                let next_table = &mut *next_table_addr.as_mut_ptr::<PageTable>();
+
+                let next_start = if index == start_index { start } else { None };
+                let next_end = if index == end_index { end } else { None };
+                if clean_up(
+                    recursive_index,
+                    next_table,
+                    next_level,
+                    next_start,
+                    next_end,
+                    frame_deallocator,
+                ) {
+                    entry.set_unused();
+                    // the recursive mapping of the freed table must not be used anymore
+                    crate::instructions::tlb::flush(next_table_addr);
+                    frame_deallocator.deallocate_frame(frame);
+                }
+            }
+
+            page_table.is_empty()
+        }
+
+        clean_up(
+            self.recursive_index,
+            self.p4,
+            PageTableLevel::Four,
+            Some(range.start.start_address()),
+            Some(range.end.start_address()),
+            frame_deallocator,
+        );
+        MapperFlushAll::new()
+    }
+}
+
+impl<'a> HugePageMapper<Size1GiB> for RecursivePageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.p4;
+        p4[page.p4_index()].frame().map_err(split_error)?;
+
+        let p3 = &mut *(p3_ptr(page, self.recursive_index));
+        let table_page = p2_page(
+            Page::<Size2MiB>::containing_address(page.start_address()),
+            self.recursive_index,
+        );
+        split_huge_entry(
+            &mut p3[page.p3_index()],
+            PageTableLevel::Three,
+            table_page,
+            frame_allocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size2MiB::SIZE))
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.p4;
+        p4[page.p4_index()].frame().map_err(promote_error)?;
+
+        let p3 = &mut *(p3_ptr(page, self.recursive_index));
+        let table_page = p2_page(
+            Page::<Size2MiB>::containing_address(page.start_address()),
+            self.recursive_index,
+        );
+        promote_table(
+            &mut p3[page.p3_index()],
+            PageTableLevel::Three,
+            table_page,
+            frame_deallocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size2MiB::SIZE))
+    }
+}
+
+impl<'a> HugePageMapper<Size2MiB> for RecursivePageTable<'a> {
+    #[inline]
+    unsafe fn split_huge_page<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange, SplitError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.p4;
+        p4[page.p4_index()].frame().map_err(split_error)?;
+
+        let p3 = &mut *(p3_ptr(page, self.recursive_index));
+        p3[page.p3_index()].frame().map_err(split_error)?;
+
+        let p2 = &mut *(p2_ptr(page, self.recursive_index));
+        let table_page = p1_page(
+            Page::<Size4KiB>::containing_address(page.start_address()),
+            self.recursive_index,
+        );
+        split_huge_entry(
+            &mut p2[page.p2_index()],
+            PageTableLevel::Two,
+            table_page,
+            frame_allocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size4KiB::SIZE))
+    }
+
+    #[inline]
+    unsafe fn promote_to_huge_page<D>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange, PromoteError>
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        let p4 = &mut self.p4;
+        p4[page.p4_index()].frame().map_err(promote_error)?;
+
+        let p3 = &mut *(p3_ptr(page, self.recursive_index));
+        p3[page.p3_index()].frame().map_err(promote_error)?;
+
+        let p2 = &mut *(p2_ptr(page, self.recursive_index));
+        let table_page = p1_page(
+            Page::<Size4KiB>::containing_address(page.start_address()),
+            self.recursive_index,
+        );
+        promote_table(
+            &mut p2[page.p2_index()],
+            PageTableLevel::Two,
+            table_page,
+            frame_deallocator,
+        )?;
+        Ok(MapperFlushRange::for_page(page, Size4KiB::SIZE))
+    }
+}
+
+/// Splits the huge page `entry` of a table of the given level into a new page table, which is
+/// accessible at `table_page` through the recursive mapping.
+///
+/// The new table can only be written through the recursive mapping after `entry` points to it,
+/// so the huge page is not mapped correctly until the table is filled.
+unsafe fn split_huge_entry<A>(
+    entry: &mut PageTableEntry,
+    level: PageTableLevel,
+    table_page: Page,
+    allocator: &mut A,
+) -> Result<(), SplitError>
+where
+    A: FrameAllocator<Size4KiB> + ?Sized,
+{
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Err(SplitError::PageNotMapped);
+    }
+    if !flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(SplitError::NotHugePage);
+    }
+
+    let frame = allocator
+        .allocate_frame()
+        .ok_or(SplitError::FrameAllocationFailed)?;
+    let huge_entry = entry.clone();
+    entry.set_frame(frame, split_table_entry_flags(flags));
+
+    // the recursive address of the new table might still map the old huge page
+    crate::instructions::tlb::flush(table_page.start_address());
+    let page_table = &mut *table_page.start_address().as_mut_ptr::<PageTable>();
+    fill_split_table(&huge_entry, level, page_table);
+    Ok(())
+}
+
+/// Replaces the table referenced by `entry` of a table of the given level with a huge page. The
+/// table must be accessible at `table_page` through the recursive mapping.
+unsafe fn promote_table<D>(
+    entry: &mut PageTableEntry,
+    level: PageTableLevel,
+    table_page: Page,
+    frame_deallocator: &mut D,
+) -> Result<(), PromoteError>
+where
+    D: FrameDeallocator<Size4KiB> + ?Sized,
+{
+    let frame = entry.frame().map_err(|err| match err {
+        FrameError::FrameNotPresent => PromoteError::PageNotMapped,
+        FrameError::HugeFrame => PromoteError::AlreadyHugePage,
+    })?;
+    let page_table = &*table_page.start_address().as_ptr::<PageTable>();
+    let (addr, flags) = promoted_entry(entry, level, page_table)?;
+
+    entry.set_addr(addr, flags);
+    // the recursive mapping of the freed table must not be used anymore
+    crate::instructions::tlb::flush(table_page.start_address());
+    frame_deallocator.deallocate_frame(frame);
+    Ok(())
+}
+
+fn split_error(err: FrameError) -> SplitError {
+    match err {
+        FrameError::FrameNotPresent => SplitError::PageNotMapped,
+        FrameError::HugeFrame => SplitError::ParentEntryHugePage,
+    }
+}
+
+fn promote_error(err: FrameError) -> PromoteError {
+    match err {
+        FrameError::FrameNotPresent => PromoteError::PageNotMapped,
+        FrameError::HugeFrame => PromoteError::ParentEntryHugePage,
+    }
+}
+
 /// The given page table was not suitable to create a `RecursivePageTable`.
 #[derive(Debug)]
 pub enum InvalidPageTable {
@@ -0,0 +1,390 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::fmt;
+
+#[cfg(target_pointer_width = "64")]
+use crate::structures::paging::mapper::{OffsetLevel5PageTable, OffsetPageTable};
+use crate::structures::paging::{
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::{
+        MapToError, MappedLevel5PageTable, MappedPageTable, Mapper, PageTableFrameMapping,
+        Translate, TranslateResult,
+    },
+    page::PageRange,
+    Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use crate::{PhysAddr, VirtAddr};
+
+/// Simulated physical memory for testing page table code on the host, e.g. in `cargo test`.
+///
+/// The memory consists of a heap allocated buffer of 4KiB frames, starting at physical address
+/// zero. The first frame contains the root table, which is the level 4 table for 4-level paging
+/// and the level 5 table for 5-level paging. All other frames are handed out by the
+/// [`SimulatedFrameAllocator`] of the memory. Page tables can only be stored in the simulated
+/// frames, but pages can be mapped to arbitrary frames since the mapped memory is never
+/// accessed.
+///
+/// ```
+/// use x86_64::structures::paging::{
+///     mapper::SimulatedMemory, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+/// };
+/// use x86_64::{PhysAddr, VirtAddr};
+///
+/// let mut memory = SimulatedMemory::new(16);
+/// let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+/// let frame = PhysFrame::containing_address(PhysAddr::new(0x5000_0000));
+/// let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+///
+/// let (mut mapper, mut frame_allocator) = memory.mapped_page_table();
+/// unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) }.unwrap().ignore();
+///
+/// memory.assert_mapped(VirtAddr::new(0x1234), PhysAddr::new(0x5000_0234), flags);
+/// memory.assert_not_mapped(VirtAddr::new(0x2000));
+/// assert_eq!(memory.allocated_frames(), 4);
+/// ```
+pub struct SimulatedMemory {
+    frames: Box<[UnsafeCell<PageTable>]>,
+    /// The index of the next frame that was never allocated.
+    next_frame: Cell<usize>,
+    free_frames: RefCell<Vec<PhysFrame>>,
+}
+
+impl SimulatedMemory {
+    /// Creates a new simulated memory with the given number of 4KiB frames.
+    ///
+    /// The first frame is used as the root table. Panics if `frame_count` is zero.
+    pub fn new(frame_count: usize) -> Self {
+        assert!(
+            frame_count > 0,
+            "the memory needs a frame for the root table"
+        );
+        let frames = (0..frame_count)
+            .map(|_| UnsafeCell::new(PageTable::new()))
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        SimulatedMemory {
+            frames,
+            next_frame: Cell::new(1),
+            free_frames: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns the total number of frames of the memory.
+    #[inline]
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the number of frames that are currently allocated, including the frame of the
+    /// root table.
+    ///
+    /// This is useful for checking that page tables are freed correctly.
+    #[inline]
+    pub fn allocated_frames(&self) -> usize {
+        self.next_frame.get() - self.free_frames.borrow().len()
+    }
+
+    /// Returns the frame that contains the root table.
+    #[inline]
+    pub fn root_frame(&self) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(0))
+    }
+
+    /// Returns the virtual address at which the simulated physical memory is mapped.
+    ///
+    /// This is the `phys_offset` of the [`OffsetPageTable`] that is returned by
+    /// [`offset_page_table`](Self::offset_page_table).
+    #[cfg(target_pointer_width = "64")]
+    #[inline]
+    pub fn phys_offset(&self) -> VirtAddr {
+        VirtAddr::new(self.frames.as_ptr() as u64)
+    }
+
+    /// Returns a frame allocator for the memory.
+    ///
+    /// This is useful for page table types that only need a [`PageTableFrameMapping`], since
+    /// the memory can still be borrowed immutably.
+    #[inline]
+    pub fn frame_allocator(&self) -> SimulatedFrameAllocator<'_> {
+        SimulatedFrameAllocator { memory: self }
+    }
+
+    /// Returns a [`MappedPageTable`] for the level 4 table together with a frame allocator for
+    /// the memory.
+    pub fn mapped_page_table(
+        &mut self,
+    ) -> (
+        MappedPageTable<'_, &SimulatedMemory>,
+        SimulatedFrameAllocator<'_>,
+    ) {
+        let memory = &*self;
+        // Safety: the frames are only accessed through the returned mapper while `self` is
+        // mutably borrowed
+        let level_4_table = unsafe { &mut *memory.frames[0].get() };
+        let mapper = unsafe { MappedPageTable::new(level_4_table, memory) };
+        (mapper, SimulatedFrameAllocator { memory })
+    }
+
+    /// Returns an [`OffsetPageTable`] for the level 4 table together with a frame allocator for
+    /// the memory.
+    #[cfg(target_pointer_width = "64")]
+    pub fn offset_page_table(&mut self) -> (OffsetPageTable<'_>, SimulatedFrameAllocator<'_>) {
+        let memory = &*self;
+        // Safety: the frames are only accessed through the returned mapper while `self` is
+        // mutably borrowed
+        let level_4_table = unsafe { &mut *memory.frames[0].get() };
+        let mapper = unsafe { OffsetPageTable::new(level_4_table, memory.phys_offset()) };
+        (mapper, SimulatedFrameAllocator { memory })
+    }
+
+    /// Returns a [`MappedLevel5PageTable`] for the root table together with a frame allocator
+    /// for the memory.
+    ///
+    /// The root table is used as level 5 table, so the memory must not be used with the level 4
+    /// mappers and the helper methods of this type that use them, such as [`map`](Self::map).
+    pub fn mapped_level_5_page_table(
+        &mut self,
+    ) -> (
+        MappedLevel5PageTable<'_, &SimulatedMemory>,
+        SimulatedFrameAllocator<'_>,
+    ) {
+        let memory = &*self;
+        // Safety: the frames are only accessed through the returned mapper while `self` is
+        // mutably borrowed
+        let level_5_table = unsafe { &mut *memory.frames[0].get() };
+        let mapper = unsafe { MappedLevel5PageTable::new(level_5_table, memory) };
+        (mapper, SimulatedFrameAllocator { memory })
+    }
+
+    /// Returns an [`OffsetLevel5PageTable`] for the root table together with a frame allocator
+    /// for the memory.
+    ///
+    /// Like for [`mapped_level_5_page_table`](Self::mapped_level_5_page_table), the memory
+    /// must not be used with the level 4 mappers.
+    #[cfg(target_pointer_width = "64")]
+    pub fn offset_level_5_page_table(
+        &mut self,
+    ) -> (OffsetLevel5PageTable<'_>, SimulatedFrameAllocator<'_>) {
+        let memory = &*self;
+        // Safety: the frames are only accessed through the returned mapper while `self` is
+        // mutably borrowed
+        let level_5_table = unsafe { &mut *memory.frames[0].get() };
+        let mapper = unsafe { OffsetLevel5PageTable::new(level_5_table, memory.phys_offset()) };
+        (mapper, SimulatedFrameAllocator { memory })
+    }
+
+    /// Maps the given page to the given frame, creating all required page tables.
+    ///
+    /// The flags of the page tables are derived from `flags` like for [`Mapper::map_to`].
+    /// Panics if the mapping can't be created.
+    pub fn map<'a, S>(&'a mut self, page: Page<S>, frame: PhysFrame<S>, flags: PageTableFlags)
+    where
+        S: PageSize,
+        MappedPageTable<'a, &'a SimulatedMemory>: Mapper<S>,
+    {
+        let (mut mapper, mut frame_allocator) = self.mapped_page_table();
+        // Safety: the page table is only used in the simulated memory
+        match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+            Ok(flush) => flush.ignore(),
+            Err(err) => {
+                let reason = match err {
+                    MapToError::FrameAllocationFailed => "frame allocation failed",
+                    MapToError::ParentEntryHugePage => "parent entry is a huge page",
+                    MapToError::PageAlreadyMapped(_) => "page is already mapped",
+                };
+                panic!("failed to map {:?} to {:?}: {}", page, frame, reason)
+            }
+        }
+    }
+
+    /// Translates the given virtual address through the level 4 table.
+    pub fn translate(&mut self, addr: VirtAddr) -> TranslateResult {
+        self.mapped_page_table().0.translate(addr)
+    }
+
+    /// Asserts that the given virtual address is mapped to the given physical address and that
+    /// the flags of the mapping contain `flags`.
+    #[track_caller]
+    pub fn assert_mapped(&mut self, addr: VirtAddr, phys: PhysAddr, flags: PageTableFlags) {
+        match self.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags: actual_flags,
+            } => {
+                let actual = frame.start_address() + offset;
+                assert_eq!(actual, phys, "{:?} is mapped to a different address", addr);
+                assert!(
+                    actual_flags.contains(flags),
+                    "{:?} is mapped with {:?} instead of {:?}",
+                    addr,
+                    actual_flags,
+                    flags
+                );
+            }
+            result => panic!("{:?} is not mapped: {:?}", addr, result),
+        }
+    }
+
+    /// Asserts that the given virtual address is not mapped.
+    #[track_caller]
+    pub fn assert_not_mapped(&mut self, addr: VirtAddr) {
+        if let TranslateResult::Mapped { frame, offset, .. } = self.translate(addr) {
+            panic!(
+                "{:?} is mapped to {:?}",
+                addr,
+                frame.start_address() + offset
+            );
+        }
+    }
+
+    /// Returns the 4KiB page that contains the given virtual address.
+    ///
+    /// Together with [`frame`](Self::frame) and [`pages`](Self::pages), this is a shorthand for
+    /// the arguments of mapper calls in tests.
+    pub fn page(addr: u64) -> Page {
+        Page::containing_address(VirtAddr::new(addr))
+    }
+
+    /// Returns the 4KiB frame that contains the given physical address.
+    pub fn frame(addr: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(addr))
+    }
+
+    /// Returns the range of the 4KiB pages from the page that contains `start` up to, but not
+    /// including, the page that contains `end`.
+    pub fn pages(start: u64, end: u64) -> PageRange {
+        Page::range(Self::page(start), Self::page(end))
+    }
+
+    /// Returns the index of the given frame in `frames`, panicking if it is out of bounds.
+    fn frame_index(&self, frame: PhysFrame) -> usize {
+        let index = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        assert!(
+            index < self.frames.len(),
+            "{:?} is outside of the simulated memory",
+            frame
+        );
+        index
+    }
+}
+
+unsafe impl PageTableFrameMapping for SimulatedMemory {
+    #[inline]
+    fn frame_to_pointer(&self, frame: PhysFrame) -> *mut PageTable {
+        self.frames[self.frame_index(frame)].get()
+    }
+
+    fn pointer_to_frame(&self, page_table: *const PageTable) -> Option<PhysFrame> {
+        let offset = (page_table as usize).checked_sub(self.frames.as_ptr() as usize)?;
+        let index = offset / Size4KiB::SIZE as usize;
+        if offset % Size4KiB::SIZE as usize != 0 || index >= self.frames.len() {
+            return None;
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index as u64 * Size4KiB::SIZE,
+        )))
+    }
+}
+
+impl fmt::Debug for SimulatedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimulatedMemory")
+            .field("frame_count", &self.frame_count())
+            .field("allocated_frames", &self.allocated_frames())
+            .finish()
+    }
+}
+
+/// Allocates the frames of a [`SimulatedMemory`].
+///
+/// Deallocated frames are reused, they are zeroed by the mapper when a new page table is
+/// created in them.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedFrameAllocator<'a> {
+    memory: &'a SimulatedMemory,
+}
+
+unsafe impl<'a> FrameAllocator<Size4KiB> for SimulatedFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.memory.free_frames.borrow_mut().pop() {
+            return Some(frame);
+        }
+        let index = self.memory.next_frame.get();
+        if index >= self.memory.frames.len() {
+            return None;
+        }
+        self.memory.next_frame.set(index + 1);
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index as u64 * Size4KiB::SIZE,
+        )))
+    }
+}
+
+impl<'a> FrameDeallocator<Size4KiB> for SimulatedFrameAllocator<'a> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = self.memory.frame_index(frame);
+        let mut free_frames = self.memory.free_frames.borrow_mut();
+        assert!(
+            index > 0 && index < self.memory.next_frame.get() && !free_frames.contains(&frame),
+            "{:?} is not allocated",
+            frame
+        );
+        free_frames.push(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{
+        mapper::{Walk, WalkResult},
+        PageTableLevel,
+    };
+
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+    #[test]
+    fn offset_page_table() {
+        let mut memory = SimulatedMemory::new(8);
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0xdead_b000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x1234_5000));
+        {
+            let (mut mapper, mut frame_allocator) = memory.offset_page_table();
+            unsafe { mapper.map_to(page, frame, FLAGS, &mut frame_allocator) }
+                .unwrap()
+                .ignore();
+        }
+        memory.assert_mapped(
+            VirtAddr::new(0xdead_beef),
+            PhysAddr::new(0x1234_5eef),
+            FLAGS,
+        );
+        assert_eq!(memory.allocated_frames(), 4);
+    }
+
+    #[test]
+    fn visited_tables_contain_root() {
+        let mut memory = SimulatedMemory::new(8);
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        memory.map(page, PhysFrame::containing_address(PhysAddr::new(0)), FLAGS);
+        let root = Some(memory.root_frame().start_address());
+
+        let tables = match memory.mapped_page_table().0.walk(page.start_address()) {
+            WalkResult::Mapped { tables, .. } => tables,
+            result => panic!("not mapped: {:?}", result),
+        };
+        assert_eq!(tables.get(PageTableLevel::Four), root);
+        assert_eq!(tables.iter().count(), 4);
+
+        let tables = match memory.offset_page_table().0.walk(VirtAddr::new(0x20_0000)) {
+            WalkResult::NotMapped { level, tables } => {
+                assert_eq!(level, PageTableLevel::Two);
+                tables
+            }
+            result => panic!("mapped: {:?}", result),
+        };
+        assert_eq!(tables.get(PageTableLevel::Four), root);
+        assert_eq!(tables.iter().count(), 3);
+    }
+}
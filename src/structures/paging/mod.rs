@@ -13,11 +13,18 @@ pub use self::mapper::OffsetPageTable;
 #[doc(no_inline)]
 pub use self::mapper::RecursivePageTable;
 pub use self::mapper::{Mapper, Translate};
+pub use self::mode::PagingMode;
 pub use self::page::{Page, PageSize, Size1GiB, Size2MiB, Size4KiB};
-pub use self::page_table::{PageOffset, PageTable, PageTableFlags, PageTableIndex};
+pub use self::page_table::{
+    PageOffset, PageTable, PageTableFlags, PageTableIndex, PageTableLevel, ProtectionKey,
+};
+pub use self::typed_table::{Level1Table, Level2Table, Level3Table, Level4Table, Level5Table};
 
 pub mod frame;
-mod frame_alloc;
+pub mod frame_alloc;
 pub mod mapper;
+mod mode;
 pub mod page;
 pub mod page_table;
+pub mod typed_table;
+pub mod vma;
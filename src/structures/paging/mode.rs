@@ -0,0 +1,142 @@
+//! Abstractions for the paging modes of the CPU.
+
+use crate::{addr::VirtAddrNotValid, structures::paging::PageTableLevel, VirtAddr};
+
+/// The paging mode of the CPU, i.e. the number of levels of the page table hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PagingMode {
+    /// 4-level paging with 48-bit virtual addresses.
+    Level4,
+    /// 5-level paging (LA57) with 57-bit virtual addresses.
+    ///
+    /// This mode is active when the `L5_PAGING` flag of the CR4 register is set.
+    Level5,
+}
+
+impl PagingMode {
+    /// Returns the paging mode that is currently active, based on the `L5_PAGING` flag of
+    /// the CR4 register.
+    #[cfg(feature = "instructions")]
+    #[inline]
+    pub fn current() -> Self {
+        use crate::registers::control::{Cr4, Cr4Flags};
+
+        if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+            PagingMode::Level5
+        } else {
+            PagingMode::Level4
+        }
+    }
+
+    /// Returns the number of page table levels used in this mode.
+    #[inline]
+    pub const fn page_table_levels(self) -> u8 {
+        match self {
+            PagingMode::Level4 => 4,
+            PagingMode::Level5 => 5,
+        }
+    }
+
+    /// Returns the level of the root table of the page table hierarchy in this mode.
+    #[inline]
+    pub const fn root_level(self) -> PageTableLevel {
+        match self {
+            PagingMode::Level4 => PageTableLevel::Four,
+            PagingMode::Level5 => PageTableLevel::Five,
+        }
+    }
+
+    /// Returns the number of usable virtual address bits in this mode.
+    #[inline]
+    pub const fn virt_addr_bits(self) -> u8 {
+        match self {
+            PagingMode::Level4 => 48,
+            PagingMode::Level5 => 57,
+        }
+    }
+
+    /// Returns the first address of the higher half of the virtual address space, i.e. the
+    /// first canonical address after the non-canonical hole.
+    #[inline]
+    pub const fn higher_half_start(self) -> VirtAddr {
+        match self {
+            PagingMode::Level4 => VirtAddr::new_truncate(1 << 47),
+            PagingMode::Level5 => VirtAddr::new_truncate_la57(1 << 56),
+        }
+    }
+
+    /// Tries to create a new virtual address that is canonical in this mode.
+    ///
+    /// This is equivalent to [`VirtAddr::try_new`] for 4-level paging and to
+    /// [`VirtAddr::try_new_la57`] for 5-level paging.
+    #[inline]
+    pub fn try_new_virt_addr(self, addr: u64) -> Result<VirtAddr, VirtAddrNotValid> {
+        match self {
+            PagingMode::Level4 => VirtAddr::try_new(addr),
+            PagingMode::Level5 => VirtAddr::try_new_la57(addr),
+        }
+    }
+
+    /// Returns whether the given virtual address is canonical in this mode.
+    ///
+    /// All addresses are canonical for 5-level paging, but addresses created through the
+    /// `_la57` constructors of `VirtAddr` might not be canonical for 4-level paging.
+    #[inline]
+    pub fn is_canonical(self, addr: VirtAddr) -> bool {
+        matches!(self.try_new_virt_addr(addr.as_u64()), Ok(a) if a == addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_and_bits() {
+        assert_eq!(PagingMode::Level4.page_table_levels(), 4);
+        assert_eq!(PagingMode::Level5.page_table_levels(), 5);
+        assert_eq!(PagingMode::Level4.root_level(), PageTableLevel::Four);
+        assert_eq!(PagingMode::Level5.root_level(), PageTableLevel::Five);
+        assert_eq!(
+            PagingMode::Level4.higher_half_start().as_u64(),
+            0xffff_8000_0000_0000
+        );
+        assert_eq!(
+            PagingMode::Level5.higher_half_start().as_u64(),
+            0xff00_0000_0000_0000
+        );
+    }
+
+    #[test]
+    fn canonical_addresses() {
+        let level_4 = PagingMode::Level4;
+        let level_5 = PagingMode::Level5;
+
+        // bit 47 is sign extended for 4-level paging, but part of the lower half for 5-level
+        // paging
+        assert_eq!(
+            level_4
+                .try_new_virt_addr(0x8000_0000_0000)
+                .unwrap()
+                .as_u64(),
+            0xffff_8000_0000_0000
+        );
+        assert_eq!(
+            level_5
+                .try_new_virt_addr(0x8000_0000_0000)
+                .unwrap()
+                .as_u64(),
+            0x8000_0000_0000
+        );
+        assert!(level_4.try_new_virt_addr(0x00ab_cdef_0000_0000).is_err());
+        assert!(level_5.try_new_virt_addr(0x00ab_cdef_0000_0000).is_ok());
+        assert!(level_5.try_new_virt_addr(0x8000_0000_0000_0000).is_err());
+
+        let la57 = VirtAddr::new_la57(0x8000_0000_0000);
+        assert!(!level_4.is_canonical(la57));
+        assert!(level_5.is_canonical(la57));
+        let upper = VirtAddr::new(0xffff_8000_0000_0000);
+        assert!(level_4.is_canonical(upper));
+        assert!(level_5.is_canonical(upper));
+    }
+}
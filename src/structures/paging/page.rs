@@ -114,6 +114,16 @@ impl<S: PageSize> Page<S> {
         }
     }
 
+    const_fn! {
+        /// Returns the level 5 page table index of this page.
+        ///
+        /// This index is only used with 5-level paging.
+        #[inline]
+        pub fn p5_index(self) -> PageTableIndex {
+            self.start_address().p5_index()
+        }
+    }
+
     const_fn! {
         /// Returns the level 4 page table index of this page.
         #[inline]
@@ -93,6 +93,129 @@ impl PageTableEntry {
     pub fn set_flags(&mut self, flags: PageTableFlags) {
         self.entry = self.addr().as_u64() | flags.bits();
     }
+
+    /// Clears the given flags and returns the flags that the entry had before.
+    ///
+    /// In contrast to [`set_flags`](Self::set_flags), the flags are cleared with an atomic
+    /// operation on 64-bit targets. This way, an `ACCESSED` or `DIRTY` flag that the CPU sets
+    /// concurrently on a different core is not overwritten.
+    #[inline]
+    pub fn clear_flags(&mut self, flags: PageTableFlags) -> PageTableFlags {
+        #[cfg(target_pointer_width = "64")]
+        let old = {
+            use core::sync::atomic::{AtomicU64, Ordering};
+            // SAFETY: `AtomicU64` has the same size and alignment as `u64` on 64-bit targets
+            let entry = unsafe { &*(&mut self.entry as *mut u64 as *const AtomicU64) };
+            entry.fetch_and(!flags.bits(), Ordering::SeqCst)
+        };
+        #[cfg(not(target_pointer_width = "64"))]
+        let old = core::mem::replace(&mut self.entry, self.entry & !flags.bits());
+
+        PageTableFlags::from_bits_truncate(old)
+    }
+
+    /// Returns the index of the PAT entry that selects the memory type of the page mapped by
+    /// this entry.
+    ///
+    /// The index consists of the `WRITE_THROUGH` flag (bit 0), the `NO_CACHE` flag (bit 1), and
+    /// the PAT bit (bit 2). The PAT bit is bit 7 for entries of level 1 tables, where it has the
+    /// same position as the `HUGE_PAGE` flag, and bit 12 for huge page entries. The `level` is
+    /// the level of the page table that contains this entry.
+    ///
+    /// See [`Pat`](crate::registers::model_specific::Pat) for the memory types of the PAT
+    /// entries.
+    #[inline]
+    pub fn pat_index(&self, level: PageTableLevel) -> u8 {
+        let mut index = 0;
+        if self.entry & PageTableFlags::WRITE_THROUGH.bits() != 0 {
+            index |= 1;
+        }
+        if self.entry & PageTableFlags::NO_CACHE.bits() != 0 {
+            index |= 2;
+        }
+        if self.entry & Self::pat_bit(level) != 0 {
+            index |= 4;
+        }
+        index
+    }
+
+    /// Sets the index of the PAT entry that selects the memory type of the page mapped by this
+    /// entry.
+    ///
+    /// See [`pat_index`](Self::pat_index) for details. Panics if the index is >=8.
+    #[inline]
+    pub fn set_pat_index(&mut self, level: PageTableLevel, index: u8) {
+        assert!(index < 8);
+        let pat_bit = Self::pat_bit(level);
+        let mask = PageTableFlags::WRITE_THROUGH.bits() | PageTableFlags::NO_CACHE.bits() | pat_bit;
+
+        let mut bits = 0;
+        if index & 1 != 0 {
+            bits |= PageTableFlags::WRITE_THROUGH.bits();
+        }
+        if index & 2 != 0 {
+            bits |= PageTableFlags::NO_CACHE.bits();
+        }
+        if index & 4 != 0 {
+            bits |= pat_bit;
+        }
+        self.entry = (self.entry & !mask) | bits;
+    }
+
+    /// Returns the physical address of the page that is mapped by this leaf entry of a page
+    /// table of the given level.
+    ///
+    /// Unlike [`addr`](Self::addr), this ignores the PAT bit of huge page entries, which is
+    /// bit 12 and thus part of the address bits.
+    #[inline]
+    pub(crate) fn leaf_addr(&self, level: PageTableLevel) -> PhysAddr {
+        if level == PageTableLevel::One {
+            self.addr()
+        } else {
+            PhysAddr::new(self.addr().as_u64() & !Self::pat_bit(level))
+        }
+    }
+
+    /// Sets the flags of this leaf entry of a page table of the given level, but keeps the
+    /// memory type of the entry.
+    ///
+    /// The `WRITE_THROUGH`, `NO_CACHE`, and PAT bits of `flags` are ignored, see
+    /// [`pat_index`](Self::pat_index).
+    #[inline]
+    pub(crate) fn set_leaf_flags(&mut self, level: PageTableLevel, flags: PageTableFlags) {
+        let mut memory_type = PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+        if level == PageTableLevel::One {
+            memory_type |= PageTableFlags::HUGE_PAGE;
+        }
+        self.set_flags((flags - memory_type) | (self.flags() & memory_type));
+    }
+
+    /// Returns the PAT bit for entries of page tables of the given level.
+    #[inline]
+    fn pat_bit(level: PageTableLevel) -> u64 {
+        if level == PageTableLevel::One {
+            PageTableFlags::HUGE_PAGE.bits()
+        } else {
+            1 << 12
+        }
+    }
+
+    /// Returns the protection key of this entry.
+    ///
+    /// The protection key is only used for entries that map a page, i.e. level 1 entries and
+    /// huge page entries.
+    #[inline]
+    pub const fn protection_key(&self) -> ProtectionKey {
+        self.flags().protection_key()
+    }
+
+    /// Sets the protection key of this entry.
+    #[inline]
+    pub fn set_protection_key(&mut self, key: ProtectionKey) {
+        let mut flags = self.flags();
+        flags.set_protection_key(key);
+        self.set_flags(flags);
+    }
 }
 
 impl fmt::Debug for PageTableEntry {
@@ -128,6 +251,8 @@ bitflags! {
         const DIRTY =           1 << 6;
         /// Specifies that the entry maps a huge frame instead of a page table. Only allowed in
         /// P2 or P3 tables.
+        ///
+        /// In P1 tables, this bit is the PAT bit instead, see [`PageTableEntry::pat_index`].
         const HUGE_PAGE =       1 << 7;
         /// Indicates that the mapping is present in all address spaces, so it isn't flushed from
         /// the TLB on an address space switch.
@@ -153,12 +278,24 @@ bitflags! {
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
         const BIT_58 =          1 << 58;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Used as part of the protection key of a page if protection keys are enabled, see
+        /// [`PageTableFlags::protection_key`].
         const BIT_59 =          1 << 59;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Used as part of the protection key of a page if protection keys are enabled, see
+        /// [`PageTableFlags::protection_key`].
         const BIT_60 =          1 << 60;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Used as part of the protection key of a page if protection keys are enabled, see
+        /// [`PageTableFlags::protection_key`].
         const BIT_61 =          1 << 61;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Used as part of the protection key of a page if protection keys are enabled, see
+        /// [`PageTableFlags::protection_key`].
         const BIT_62 =          1 << 62;
         /// Forbid code execution from the mapped frames.
         ///
@@ -168,6 +305,77 @@ bitflags! {
     }
 }
 
+impl PageTableFlags {
+    /// The bits that contain the protection key of a page (bits 59–62).
+    const PROTECTION_KEY_MASK: u64 = 0xf << PROTECTION_KEY_SHIFT;
+
+    /// Returns the protection key that is stored in the bits 59–62.
+    ///
+    /// Protection keys are only used if they are enabled in the CR4 register, through the
+    /// `PROTECTION_KEY` flag for user pages and the `PROTECTION_KEY_SUPERVISOR` flag for
+    /// supervisor pages. Otherwise, these bits are available to the OS.
+    #[inline]
+    pub const fn protection_key(self) -> ProtectionKey {
+        ProtectionKey::new_truncate(
+            ((self.bits() & Self::PROTECTION_KEY_MASK) >> PROTECTION_KEY_SHIFT) as u8,
+        )
+    }
+
+    /// Stores the given protection key in the bits 59–62.
+    #[inline]
+    pub fn set_protection_key(&mut self, key: ProtectionKey) {
+        let bits =
+            (self.bits() & !Self::PROTECTION_KEY_MASK) | (u64::from(key.0) << PROTECTION_KEY_SHIFT);
+        *self = Self::from_bits_truncate(bits);
+    }
+}
+
+/// The position of the protection key in a page table entry.
+const PROTECTION_KEY_SHIFT: u64 = 59;
+
+/// A 4-bit protection key of a page.
+///
+/// The protection key selects the access rights in the PKRU register (for user pages) or in
+/// the PKRS register (for supervisor pages) that apply to a page, in addition to the access
+/// rights of the page table entries. See the
+/// [`protection_key`](crate::registers::protection_key) module for these registers.
+///
+/// Guaranteed to only ever contain 0..16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtectionKey(u8);
+
+impl ProtectionKey {
+    /// The number of protection keys.
+    pub const COUNT: u8 = 16;
+
+    /// Creates a new protection key from the given `u8`. Panics if the given value is >=16.
+    #[inline]
+    pub fn new(key: u8) -> Self {
+        assert!(key < Self::COUNT);
+        Self(key)
+    }
+
+    /// Creates a new protection key from the given `u8`. Throws away bits if the value is >=16.
+    #[inline]
+    pub const fn new_truncate(key: u8) -> Self {
+        Self(key % Self::COUNT)
+    }
+}
+
+impl From<ProtectionKey> for u8 {
+    #[inline]
+    fn from(key: ProtectionKey) -> Self {
+        key.0
+    }
+}
+
+impl From<ProtectionKey> for u32 {
+    #[inline]
+    fn from(key: ProtectionKey) -> Self {
+        u32::from(key.0)
+    }
+}
+
 /// The number of entries in a page table.
 const ENTRY_COUNT: usize = 512;
 
@@ -216,6 +424,12 @@ impl PageTable {
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut PageTableEntry> {
         self.entries.iter_mut()
     }
+
+    /// Checks if the page table is empty (all entries are zero).
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl Index<usize> for PageTable {
@@ -314,6 +528,61 @@ impl From<PageTableIndex> for usize {
     }
 }
 
+/// A value between 1 and 5, identifying the level of a page table.
+///
+/// Level 5 tables are only used with 5-level paging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PageTableLevel {
+    /// Represents the level for a page table.
+    One = 1,
+    /// Represents the level for a page directory.
+    Two,
+    /// Represents the level for a page-directory pointer.
+    Three,
+    /// Represents the level for a page-map level-4.
+    Four,
+    /// Represents the level for a page-map level-5.
+    Five,
+}
+
+impl PageTableLevel {
+    /// Returns the next lower level or `None` for level 1
+    #[inline]
+    pub const fn next_lower_level(self) -> Option<Self> {
+        match self {
+            PageTableLevel::Five => Some(PageTableLevel::Four),
+            PageTableLevel::Four => Some(PageTableLevel::Three),
+            PageTableLevel::Three => Some(PageTableLevel::Two),
+            PageTableLevel::Two => Some(PageTableLevel::One),
+            PageTableLevel::One => None,
+        }
+    }
+
+    /// Returns the next higher level or `None` for level 5
+    #[inline]
+    pub const fn next_higher_level(self) -> Option<Self> {
+        match self {
+            PageTableLevel::Five => None,
+            PageTableLevel::Four => Some(PageTableLevel::Five),
+            PageTableLevel::Three => Some(PageTableLevel::Four),
+            PageTableLevel::Two => Some(PageTableLevel::Three),
+            PageTableLevel::One => Some(PageTableLevel::Two),
+        }
+    }
+
+    /// Returns the alignment for the address space described by a table of this level.
+    #[inline]
+    pub const fn table_address_space_alignment(self) -> u64 {
+        1u64 << (self as u8 * 9 + 12)
+    }
+
+    /// Returns the alignment for the address space described by an entry in a table of this level.
+    #[inline]
+    pub const fn entry_address_space_alignment(self) -> u64 {
+        1u64 << (((self as u8 - 1) * 9) + 12)
+    }
+}
+
 /// A 12-bit offset into a 4KiB Page.
 ///
 /// This type is returned by the `VirtAddr::page_offset` method.
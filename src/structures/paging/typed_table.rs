@@ -0,0 +1,584 @@
+//! Page table views that know the level of the table.
+//!
+//! A [`PageTable`] and its [`PageTableEntry`]s don't know their level, so nothing prevents
+//! setting the `HUGE_PAGE` flag in a level 4 entry or reading a 2MiB frame as a 4KiB frame.
+//! The types of this module wrap a `PageTable` of a specific level and only provide the
+//! operations that are valid for the entries of that level:
+//!
+//! - [`Level5Entry`] and [`Level4Entry`] always point to the next lower page table.
+//! - [`Level3Entry`] and [`Level2Entry`] point either to the next lower page table or to a
+//!   1GiB or 2MiB frame, see [`EntryTarget`].
+//! - [`Level1Entry`] always points to a 4KiB frame.
+//!
+//! The setters never create an entry that is invalid for its level: the `HUGE_PAGE` flag is
+//! removed from the flags of entries that point to a page table and only set through
+//! `set_frame`. The entries of a table that was created through `from_table` can still contain
+//! an invalid `HUGE_PAGE` flag, e.g. in a level 4 table, which is checked at runtime and
+//! reported as `FrameError::HugeFrame`.
+//!
+//! The views have the same memory layout as a `PageTable`, so they can be created from an
+//! existing table through `from_table` and `from_table_mut` without copying:
+//!
+//! ```
+//! use x86_64::structures::paging::{
+//!     typed_table::{EntryTarget, Level2Table},
+//!     PageTable, PageTableFlags, PhysFrame, Size2MiB,
+//! };
+//! use x86_64::PhysAddr;
+//!
+//! let mut table = PageTable::new();
+//! let level_2_table = Level2Table::from_table_mut(&mut table);
+//! let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0x20_0000));
+//! level_2_table[3].set_frame(frame, PageTableFlags::PRESENT);
+//!
+//! assert_eq!(level_2_table[3].target(), Ok(EntryTarget::Frame(frame)));
+//! assert!(table[3].flags().contains(PageTableFlags::HUGE_PAGE));
+//! ```
+
+use core::fmt;
+use core::ops::{Index, IndexMut};
+
+use super::{
+    mapper::PageTableFrameMapping,
+    page_table::{FrameError, PageTableEntry},
+    PageSize, PageTable, PageTableFlags, PageTableIndex, PageTableLevel, PhysFrame, Size1GiB,
+    Size2MiB, Size4KiB,
+};
+use crate::PhysAddr;
+
+/// The target of a level 3 or level 2 entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryTarget<S: PageSize> {
+    /// The entry points to the page table of the next lower level.
+    Table(PhysFrame<Size4KiB>),
+    /// The entry maps a huge page to the given frame.
+    Frame(PhysFrame<S>),
+}
+
+macro_rules! typed_table {
+    ($(#[$doc:meta])* $table:ident, $(#[$entry_doc:meta])* $entry:ident, $level:expr) => {
+        $(#[$doc])*
+        #[repr(transparent)]
+        pub struct $table {
+            table: PageTable,
+        }
+
+        impl $table {
+            /// The level of the page table.
+            pub const LEVEL: PageTableLevel = $level;
+
+            /// Creates an empty page table.
+            #[inline]
+            pub const fn new() -> Self {
+                $table {
+                    table: PageTable::new(),
+                }
+            }
+
+            /// Interprets the given page table as a table of this level.
+            #[inline]
+            pub fn from_table(table: &PageTable) -> &Self {
+                // SAFETY: the type is a `repr(transparent)` wrapper around `PageTable`
+                unsafe { &*(table as *const PageTable as *const Self) }
+            }
+
+            /// Interprets the given page table as a table of this level.
+            #[inline]
+            pub fn from_table_mut(table: &mut PageTable) -> &mut Self {
+                // SAFETY: the type is a `repr(transparent)` wrapper around `PageTable`
+                unsafe { &mut *(table as *mut PageTable as *mut Self) }
+            }
+
+            /// Returns the untyped page table.
+            #[inline]
+            pub fn as_table(&self) -> &PageTable {
+                &self.table
+            }
+
+            /// Clears all entries.
+            #[inline]
+            pub fn zero(&mut self) {
+                self.table.zero();
+            }
+
+            /// Returns an iterator over the entries of the page table.
+            #[inline]
+            pub fn iter(&self) -> impl Iterator<Item = &$entry> {
+                self.table.iter().map($entry::from_entry)
+            }
+
+            /// Returns an iterator that allows modifying the entries of the page table.
+            #[inline]
+            pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut $entry> {
+                self.table.iter_mut().map($entry::from_entry_mut)
+            }
+
+            /// Checks if the page table is empty (all entries are zero).
+            #[inline]
+            pub fn is_empty(&self) -> bool {
+                self.table.is_empty()
+            }
+        }
+
+        impl Index<usize> for $table {
+            type Output = $entry;
+
+            #[inline]
+            fn index(&self, index: usize) -> &Self::Output {
+                $entry::from_entry(&self.table[index])
+            }
+        }
+
+        impl IndexMut<usize> for $table {
+            #[inline]
+            fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+                $entry::from_entry_mut(&mut self.table[index])
+            }
+        }
+
+        impl Index<PageTableIndex> for $table {
+            type Output = $entry;
+
+            #[inline]
+            fn index(&self, index: PageTableIndex) -> &Self::Output {
+                &self[usize::from(index)]
+            }
+        }
+
+        impl IndexMut<PageTableIndex> for $table {
+            #[inline]
+            fn index_mut(&mut self, index: PageTableIndex) -> &mut Self::Output {
+                &mut self[usize::from(index)]
+            }
+        }
+
+        impl Default for $table {
+            #[inline]
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl fmt::Debug for $table {
+            #[inline]
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.table.fmt(f)
+            }
+        }
+
+        $(#[$entry_doc])*
+        #[derive(Clone)]
+        #[repr(transparent)]
+        pub struct $entry {
+            entry: PageTableEntry,
+        }
+
+        impl $entry {
+            /// Creates an unused page table entry.
+            #[inline]
+            pub const fn new() -> Self {
+                $entry {
+                    entry: PageTableEntry::new(),
+                }
+            }
+
+            /// Returns whether this entry is zero.
+            #[inline]
+            pub const fn is_unused(&self) -> bool {
+                self.entry.is_unused()
+            }
+
+            /// Sets this entry to zero.
+            #[inline]
+            pub fn set_unused(&mut self) {
+                self.entry.set_unused();
+            }
+
+            /// Returns the flags of this entry.
+            #[inline]
+            pub const fn flags(&self) -> PageTableFlags {
+                self.entry.flags()
+            }
+
+            /// Returns the physical address mapped by this entry, might be zero.
+            #[inline]
+            pub fn addr(&self) -> PhysAddr {
+                self.entry.addr()
+            }
+
+            /// Returns the untyped page table entry.
+            #[inline]
+            pub fn as_entry(&self) -> &PageTableEntry {
+                &self.entry
+            }
+
+            #[inline]
+            fn from_entry(entry: &PageTableEntry) -> &Self {
+                // SAFETY: the type is a `repr(transparent)` wrapper around `PageTableEntry`
+                unsafe { &*(entry as *const PageTableEntry as *const Self) }
+            }
+
+            #[inline]
+            fn from_entry_mut(entry: &mut PageTableEntry) -> &mut Self {
+                // SAFETY: the type is a `repr(transparent)` wrapper around `PageTableEntry`
+                unsafe { &mut *(entry as *mut PageTableEntry as *mut Self) }
+            }
+        }
+
+        impl Default for $entry {
+            #[inline]
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl fmt::Debug for $entry {
+            #[inline]
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.entry.fmt(f)
+            }
+        }
+    };
+}
+
+/// Implements the methods of entries that always point to the next lower page table.
+macro_rules! table_entry {
+    ($entry:ident, $next_table:ident) => {
+        impl $entry {
+            /// Returns the frame of the next lower page table.
+            ///
+            /// Returns `FrameError::FrameNotPresent` if the entry doesn't have the `PRESENT`
+            /// flag set and `FrameError::HugeFrame` if the entry has the invalid `HUGE_PAGE`
+            /// flag set.
+            #[inline]
+            pub fn frame(&self) -> Result<PhysFrame, FrameError> {
+                self.entry.frame()
+            }
+
+            /// Points the entry to the given frame of the next lower page table.
+            ///
+            /// The `HUGE_PAGE` flag is removed from `flags`, since entries of this level can't
+            /// map huge pages.
+            #[inline]
+            pub fn set_table(&mut self, frame: PhysFrame, flags: PageTableFlags) {
+                self.entry
+                    .set_frame(frame, flags - PageTableFlags::HUGE_PAGE);
+            }
+
+            /// Sets the flags of this entry.
+            ///
+            /// The `HUGE_PAGE` flag is removed from `flags`, like for
+            /// [`set_table`](Self::set_table).
+            #[inline]
+            pub fn set_flags(&mut self, flags: PageTableFlags) {
+                self.entry.set_flags(flags - PageTableFlags::HUGE_PAGE);
+            }
+
+            /// Returns the next lower page table.
+            ///
+            /// Returns the errors of [`frame`](Self::frame).
+            ///
+            /// ## Safety
+            ///
+            /// The caller must ensure that `mapping` returns a valid pointer to the table and
+            /// that the table is not mutably borrowed elsewhere.
+            #[inline]
+            pub unsafe fn next_table<M>(&self, mapping: &M) -> Result<&$next_table, FrameError>
+            where
+                M: PageTableFrameMapping + ?Sized,
+            {
+                let frame = self.frame()?;
+                Ok($next_table::from_table(&*mapping.frame_to_pointer(frame)))
+            }
+
+            /// Returns the next lower page table mutably.
+            ///
+            /// Returns the errors of [`frame`](Self::frame).
+            ///
+            /// ## Safety
+            ///
+            /// The caller must ensure that `mapping` returns a valid pointer to the table and
+            /// that the table is not borrowed elsewhere.
+            #[inline]
+            pub unsafe fn next_table_mut<M>(
+                &mut self,
+                mapping: &M,
+            ) -> Result<&mut $next_table, FrameError>
+            where
+                M: PageTableFrameMapping + ?Sized,
+            {
+                let frame = self.frame()?;
+                Ok($next_table::from_table_mut(
+                    &mut *mapping.frame_to_pointer(frame),
+                ))
+            }
+        }
+    };
+}
+
+/// Implements the methods of entries that point to the next lower page table or a huge page.
+macro_rules! huge_entry {
+    ($entry:ident, $size:ident, $next_table:ident) => {
+        impl $entry {
+            /// Returns the page table or the huge frame that this entry points to.
+            ///
+            /// Returns `FrameError::FrameNotPresent` if the entry doesn't have the `PRESENT`
+            /// flag set.
+            #[inline]
+            pub fn target(&self) -> Result<EntryTarget<$size>, FrameError> {
+                match self.entry.frame() {
+                    Ok(frame) => Ok(EntryTarget::Table(frame)),
+                    Err(FrameError::HugeFrame) => Ok(EntryTarget::Frame(
+                        PhysFrame::containing_address(self.addr()),
+                    )),
+                    Err(err) => Err(err),
+                }
+            }
+
+            /// Returns whether the entry maps a huge page.
+            #[inline]
+            pub fn is_huge(&self) -> bool {
+                self.flags().contains(PageTableFlags::HUGE_PAGE)
+            }
+
+            /// Points the entry to the given frame of the next lower page table.
+            ///
+            /// The `HUGE_PAGE` flag is removed from `flags`, use [`set_frame`](Self::set_frame)
+            /// for mapping a huge page.
+            #[inline]
+            pub fn set_table(&mut self, frame: PhysFrame, flags: PageTableFlags) {
+                self.entry
+                    .set_frame(frame, flags - PageTableFlags::HUGE_PAGE);
+            }
+
+            /// Maps the entry to the given huge frame.
+            ///
+            /// The `HUGE_PAGE` flag is added to `flags` automatically.
+            #[inline]
+            pub fn set_frame(&mut self, frame: PhysFrame<$size>, flags: PageTableFlags) {
+                self.entry
+                    .set_addr(frame.start_address(), flags | PageTableFlags::HUGE_PAGE);
+            }
+
+            /// Sets the flags of this entry.
+            ///
+            /// The `HUGE_PAGE` flag of `flags` is ignored and the entry keeps pointing to a page
+            /// table or mapping a huge page, use [`set_table`](Self::set_table) or
+            /// [`set_frame`](Self::set_frame) for changing the target.
+            #[inline]
+            pub fn set_flags(&mut self, flags: PageTableFlags) {
+                let huge_page = self.flags() & PageTableFlags::HUGE_PAGE;
+                self.entry
+                    .set_flags((flags - PageTableFlags::HUGE_PAGE) | huge_page);
+            }
+
+            /// Returns the next lower page table.
+            ///
+            /// Returns `FrameError::FrameNotPresent` if the entry doesn't have the `PRESENT`
+            /// flag set and `FrameError::HugeFrame` if the entry maps a huge page.
+            ///
+            /// ## Safety
+            ///
+            /// The caller must ensure that `mapping` returns a valid pointer to the table and
+            /// that the table is not mutably borrowed elsewhere.
+            #[inline]
+            pub unsafe fn next_table<M>(&self, mapping: &M) -> Result<&$next_table, FrameError>
+            where
+                M: PageTableFrameMapping + ?Sized,
+            {
+                let frame = self.entry.frame()?;
+                Ok($next_table::from_table(&*mapping.frame_to_pointer(frame)))
+            }
+
+            /// Returns the next lower page table mutably.
+            ///
+            /// Returns the errors of [`next_table`](Self::next_table).
+            ///
+            /// ## Safety
+            ///
+            /// The caller must ensure that `mapping` returns a valid pointer to the table and
+            /// that the table is not borrowed elsewhere.
+            #[inline]
+            pub unsafe fn next_table_mut<M>(
+                &mut self,
+                mapping: &M,
+            ) -> Result<&mut $next_table, FrameError>
+            where
+                M: PageTableFrameMapping + ?Sized,
+            {
+                let frame = self.entry.frame()?;
+                Ok($next_table::from_table_mut(
+                    &mut *mapping.frame_to_pointer(frame),
+                ))
+            }
+        }
+    };
+}
+
+typed_table!(
+    /// A level 5 page table, which is only used with 5-level paging.
+    Level5Table,
+    /// An entry of a [`Level5Table`], which points to a [`Level4Table`].
+    Level5Entry,
+    PageTableLevel::Five
+);
+typed_table!(
+    /// A level 4 page table.
+    Level4Table,
+    /// An entry of a [`Level4Table`], which points to a [`Level3Table`].
+    Level4Entry,
+    PageTableLevel::Four
+);
+typed_table!(
+    /// A level 3 page table.
+    Level3Table,
+    /// An entry of a [`Level3Table`], which points to a [`Level2Table`] or maps a 1GiB page.
+    Level3Entry,
+    PageTableLevel::Three
+);
+typed_table!(
+    /// A level 2 page table.
+    Level2Table,
+    /// An entry of a [`Level2Table`], which points to a [`Level1Table`] or maps a 2MiB page.
+    Level2Entry,
+    PageTableLevel::Two
+);
+typed_table!(
+    /// A level 1 page table.
+    Level1Table,
+    /// An entry of a [`Level1Table`], which maps a 4KiB page.
+    Level1Entry,
+    PageTableLevel::One
+);
+
+table_entry!(Level5Entry, Level4Table);
+table_entry!(Level4Entry, Level3Table);
+huge_entry!(Level3Entry, Size1GiB, Level2Table);
+huge_entry!(Level2Entry, Size2MiB, Level1Table);
+
+impl Level1Entry {
+    /// Returns the frame mapped by this entry.
+    ///
+    /// Returns `FrameError::FrameNotPresent` if the entry doesn't have the `PRESENT` flag set.
+    /// Bit 7, which is the `HUGE_PAGE` flag in higher levels, is the PAT bit in level 1
+    /// entries, so it is ignored.
+    #[inline]
+    pub fn frame(&self) -> Result<PhysFrame, FrameError> {
+        if self.flags().contains(PageTableFlags::PRESENT) {
+            Ok(PhysFrame::containing_address(self.addr()))
+        } else {
+            Err(FrameError::FrameNotPresent)
+        }
+    }
+
+    /// Maps the entry to the given frame.
+    ///
+    /// In contrast to [`PageTableEntry::set_frame`], `flags` may contain bit 7, which selects
+    /// the memory type through the PAT.
+    #[inline]
+    pub fn set_frame(&mut self, frame: PhysFrame, flags: PageTableFlags) {
+        self.entry.set_addr(frame.start_address(), flags);
+    }
+
+    /// Sets the flags of this entry.
+    #[inline]
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.entry.set_flags(flags);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(index: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index * Size4KiB::SIZE))
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn walk_typed_hierarchy() {
+        use crate::structures::paging::{mapper::SimulatedMemory, FrameAllocator};
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let memory = SimulatedMemory::new(3);
+        let mut frame_allocator = memory.frame_allocator();
+        let level_3_frame = frame_allocator.allocate_frame().unwrap();
+        let level_2_frame = frame_allocator.allocate_frame().unwrap();
+        let huge_frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+        unsafe {
+            let level_4_table =
+                Level4Table::from_table_mut(&mut *memory.frame_to_pointer(memory.root_frame()));
+            level_4_table[1].set_table(level_3_frame, flags);
+            let level_3_table = level_4_table[1].next_table_mut(&memory).unwrap();
+            level_3_table[2].set_frame(huge_frame, flags);
+            level_3_table[3].set_table(level_2_frame, flags);
+        }
+
+        let level_4_table =
+            Level4Table::from_table(unsafe { &*memory.frame_to_pointer(memory.root_frame()) });
+        assert_eq!(level_4_table[1].frame(), Ok(level_3_frame));
+        assert_eq!(
+            unsafe { level_4_table[0].next_table(&memory) }.unwrap_err(),
+            FrameError::FrameNotPresent
+        );
+
+        let level_3_table = unsafe { level_4_table[1].next_table(&memory) }.unwrap();
+        assert_eq!(
+            level_3_table[2].target(),
+            Ok(EntryTarget::Frame(huge_frame))
+        );
+        assert_eq!(
+            level_3_table[3].target(),
+            Ok(EntryTarget::Table(level_2_frame))
+        );
+        assert_eq!(
+            unsafe { level_3_table[2].next_table(&memory) }.unwrap_err(),
+            FrameError::HugeFrame
+        );
+        let level_2_table = unsafe { level_3_table[3].next_table(&memory) }.unwrap();
+        assert!(level_2_table.is_empty());
+    }
+
+    #[test]
+    fn level_1_pat_bit() {
+        let mut table = Level1Table::new();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE;
+        table[0].set_frame(frame(5), flags);
+        assert_eq!(table[0].frame(), Ok(frame(5)));
+        assert_eq!(table[0].flags(), flags);
+    }
+
+    #[test]
+    fn huge_flag_in_level_4() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mut table = Level4Table::new();
+        table[0].set_table(frame(1), flags | PageTableFlags::HUGE_PAGE);
+        assert_eq!(table[0].flags(), flags);
+        table[0].set_flags(flags | PageTableFlags::NO_EXECUTE | PageTableFlags::HUGE_PAGE);
+        assert_eq!(table[0].flags(), flags | PageTableFlags::NO_EXECUTE);
+        assert_eq!(table[0].frame(), Ok(frame(1)));
+
+        // an invalid entry of an untyped table is detected at runtime
+        let mut untyped = PageTable::new();
+        untyped[0].set_addr(frame(1).start_address(), flags | PageTableFlags::HUGE_PAGE);
+        let table = Level4Table::from_table(&untyped);
+        assert_eq!(table[0].frame(), Err(FrameError::HugeFrame));
+    }
+
+    #[test]
+    fn huge_flag_in_level_2() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let huge_frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+        let mut table = Level2Table::new();
+        table[0].set_table(frame(1), flags | PageTableFlags::HUGE_PAGE);
+        table[1].set_frame(huge_frame, flags);
+
+        // `set_flags` doesn't change the target of the entries
+        table[0].set_flags(flags | PageTableFlags::HUGE_PAGE);
+        table[1].set_flags(flags);
+        assert_eq!(table[0].target(), Ok(EntryTarget::Table(frame(1))));
+        assert_eq!(table[1].target(), Ok(EntryTarget::Frame(huge_frame)));
+        assert_eq!(table[1].flags(), flags | PageTableFlags::HUGE_PAGE);
+    }
+}
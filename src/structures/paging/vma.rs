@@ -0,0 +1,538 @@
+//! Tracking the allocated regions of a virtual address space.
+
+use crate::structures::paging::{page::PageRange, Page, PageSize, Size4KiB};
+use crate::VirtAddr;
+
+/// A region of virtual memory with attached metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma<T> {
+    /// The pages of the region.
+    pub pages: PageRange,
+    /// The metadata of the region, e.g. its flags and the kind of its backing memory.
+    pub data: T,
+}
+
+/// A set of allocated virtual memory regions, as used for implementing `mmap`.
+///
+/// The regions are stored sorted and without overlaps in a buffer that is passed by the caller,
+/// so each region takes one element of the buffer. Adjacent regions with equal metadata are
+/// merged into a single region when they are inserted, and removing a part of a region splits
+/// it.
+///
+/// All regions are contained in the bounds of the set, which must not contain the canonical
+/// hole, so a set only manages a part of either the lower or the upper half of the address
+/// space. Since the end of a [`PageRange`] is exclusive, the last page of a half can't be part
+/// of a region. The last page of the lower half should not be mapped anyway, since a `sysret`
+/// to the first non-canonical address raises a general protection fault in kernel mode on some
+/// CPUs.
+///
+/// ```
+/// use x86_64::structures::paging::{vma::VmaSet, Page, PageSize, Size2MiB};
+/// use x86_64::VirtAddr;
+///
+/// let bounds = Page::range(
+///     Page::containing_address(VirtAddr::new(0x1000)),
+///     Page::containing_address(VirtAddr::new(0x7fff_ffff_f000)),
+/// );
+/// let mut storage = [None; 16];
+/// let mut set = VmaSet::new(bounds, &mut storage);
+///
+/// let pages = set.allocate(1024, Size2MiB::SIZE, "heap").unwrap();
+/// assert!(pages.start.start_address().is_aligned(Size2MiB::SIZE));
+/// assert_eq!(set.get(pages.start.start_address()).unwrap().data, "heap");
+/// ```
+#[derive(Debug)]
+pub struct VmaSet<'a, T> {
+    bounds: PageRange,
+    /// The regions, sorted by their start address. Only the first `len` elements are `Some`.
+    regions: &'a mut [Option<Vma<T>>],
+    len: usize,
+}
+
+impl<'a, T> VmaSet<'a, T>
+where
+    T: Clone + PartialEq,
+{
+    /// Creates an empty set whose regions are stored in `storage`.
+    ///
+    /// The previous content of `storage` is dropped. Panics if `bounds` contains the canonical
+    /// hole.
+    pub fn new(bounds: PageRange, storage: &'a mut [Option<Vma<T>>]) -> Self {
+        if !bounds.is_empty() {
+            let last = bounds.end - 1;
+            assert!(
+                is_upper_half(bounds.start) == is_upper_half(last),
+                "bounds must not contain the canonical hole"
+            );
+        }
+        for region in storage.iter_mut() {
+            *region = None;
+        }
+        VmaSet {
+            bounds,
+            regions: storage,
+            len: 0,
+        }
+    }
+
+    /// Returns the pages in which the regions are allocated.
+    #[inline]
+    pub fn bounds(&self) -> PageRange {
+        self.bounds
+    }
+
+    /// Returns the number of regions.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the set contains no regions.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum number of regions, i.e. the length of the storage.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Returns an iterator over the regions, sorted by their start address.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &Vma<T>> + '_ {
+        self.regions[..self.len].iter().flatten()
+    }
+
+    /// Returns the region that contains the given address.
+    pub fn get(&self, addr: VirtAddr) -> Option<&Vma<T>> {
+        let page = Page::containing_address(addr);
+        let index = self.position(page);
+        if index < self.len && self.region(index).pages.start <= page {
+            Some(self.region(index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the first free range of `count` pages whose start address is aligned to
+    /// `align`.
+    ///
+    /// Panics if `count` is 0 or if `align` is not a power of two.
+    pub fn find_free(&self, count: u64, align: u64) -> Option<PageRange> {
+        assert!(count > 0, "count must be greater than 0");
+        assert!(align.is_power_of_two(), "`align` must be a power of two");
+        let align = align.max(Size4KiB::SIZE);
+        let size = count.checked_mul(Size4KiB::SIZE)?;
+
+        let mut gap_start = self.bounds.start;
+        let gap_ends = self
+            .iter()
+            .map(|region| (region.pages.start, region.pages.end))
+            .chain(Some((self.bounds.end, self.bounds.end)));
+        for (gap_end, next_start) in gap_ends {
+            if gap_start < gap_end {
+                let start = checked_align_up(gap_start.start_address().as_u64(), align);
+                let end = start.and_then(|start| start.checked_add(size));
+                if let (Some(start), Some(end)) = (start, end) {
+                    if end <= gap_end.start_address().as_u64() {
+                        // the range is part of the gap, so both addresses are canonical (for
+                        // 5-level paging at least) and truncating doesn't change them, even if
+                        // the range contains bit 47
+                        let start = Page::containing_address(VirtAddr::new_truncate_la57(start));
+                        let end = Page::containing_address(VirtAddr::new_truncate_la57(end));
+                        return Some(Page::range(start, end));
+                    }
+                }
+            }
+            gap_start = next_start;
+        }
+        None
+    }
+
+    /// Inserts a region with the given pages and metadata.
+    ///
+    /// The region is merged with the adjacent regions if their metadata is equal.
+    pub fn insert(&mut self, pages: PageRange, data: T) -> Result<(), VmaError> {
+        if pages.is_empty() || pages.start < self.bounds.start || pages.end > self.bounds.end {
+            return Err(VmaError::InvalidRange);
+        }
+        let index = self.position(pages.start);
+        if index < self.len && self.region(index).pages.start < pages.end {
+            return Err(VmaError::Overlap(self.region(index).pages));
+        }
+
+        let merge_left = index > 0 && {
+            let left = self.region(index - 1);
+            left.pages.end == pages.start && left.data == data
+        };
+        let merge_right = index < self.len && {
+            let right = self.region(index);
+            right.pages.start == pages.end && right.data == data
+        };
+        match (merge_left, merge_right) {
+            (true, true) => {
+                let end = self.region(index).pages.end;
+                self.region_mut(index - 1).pages.end = end;
+                self.remove_index(index);
+            }
+            (true, false) => self.region_mut(index - 1).pages.end = pages.end,
+            (false, true) => self.region_mut(index).pages.start = pages.start,
+            (false, false) => {
+                if self.len == self.capacity() {
+                    return Err(VmaError::StorageFull);
+                }
+                self.insert_index(index, Vma { pages, data });
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts a region of `count` pages at the first free range whose start address is
+    /// aligned to `align` and returns its pages.
+    ///
+    /// Panics if `count` is 0 or if `align` is not a power of two.
+    pub fn allocate(&mut self, count: u64, align: u64, data: T) -> Result<PageRange, VmaError> {
+        let pages = self.find_free(count, align).ok_or(VmaError::OutOfSpace)?;
+        self.insert(pages, data)?;
+        Ok(pages)
+    }
+
+    /// Removes the given pages from the regions of the set.
+    ///
+    /// Regions that are only partially contained in `pages` are shrunk, and a region that
+    /// contains `pages` in its middle is split into two regions. The pages do not have to be
+    /// allocated. The function `f` is called with each part of a region that was removed, e.g.
+    /// for unmapping it.
+    ///
+    /// Returns [`VmaError::StorageFull`] without removing any pages if a region must be split,
+    /// but the storage has no space for the second part.
+    pub fn remove<F>(&mut self, pages: PageRange, mut f: F) -> Result<(), VmaError>
+    where
+        F: FnMut(&Vma<T>),
+    {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let mut index = self.position(pages.start);
+
+        if index < self.len {
+            let region = self.region(index);
+            if region.pages.start < pages.start && region.pages.end > pages.end {
+                if self.len == self.capacity() {
+                    return Err(VmaError::StorageFull);
+                }
+                let (middle, right) = (
+                    Vma {
+                        pages,
+                        data: region.data.clone(),
+                    },
+                    Vma {
+                        pages: Page::range(pages.end, region.pages.end),
+                        data: region.data.clone(),
+                    },
+                );
+                f(&middle);
+                self.region_mut(index).pages.end = pages.start;
+                self.insert_index(index + 1, right);
+                return Ok(());
+            }
+        }
+
+        while index < self.len && self.region(index).pages.start < pages.end {
+            let region = self.region_mut(index);
+            let removed = Vma {
+                pages: Page::range(
+                    region.pages.start.max(pages.start),
+                    region.pages.end.min(pages.end),
+                ),
+                data: region.data.clone(),
+            };
+            if region.pages.start < pages.start {
+                region.pages.end = pages.start;
+                index += 1;
+            } else if region.pages.end > pages.end {
+                region.pages.start = pages.end;
+                index = self.len;
+            } else {
+                self.remove_index(index);
+            }
+            f(&removed);
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the first region that ends after the given page.
+    fn position(&self, page: Page) -> usize {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.region(middle).pages.end <= page {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    }
+
+    fn region(&self, index: usize) -> &Vma<T> {
+        self.regions[index]
+            .as_ref()
+            .expect("region index out of bounds")
+    }
+
+    fn region_mut(&mut self, index: usize) -> &mut Vma<T> {
+        self.regions[index]
+            .as_mut()
+            .expect("region index out of bounds")
+    }
+
+    fn insert_index(&mut self, index: usize, region: Vma<T>) {
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+    }
+
+    fn remove_index(&mut self, index: usize) {
+        self.regions[index] = None;
+        self.regions[index..self.len].rotate_left(1);
+        self.len -= 1;
+    }
+}
+
+/// An error indicating that an operation of a [`VmaSet`] failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The given range is empty or not contained in the bounds of the set.
+    InvalidRange,
+    /// The given range overlaps with the given region.
+    Overlap(PageRange),
+    /// The storage of the set has no space for another region.
+    StorageFull,
+    /// The bounds of the set contain no free range that is large enough.
+    OutOfSpace,
+}
+
+/// Bit 63 is set for all upper half addresses, both for 4-level and 5-level paging.
+fn is_upper_half(page: Page) -> bool {
+    page.start_address().as_u64() & (1 << 63) != 0
+}
+
+fn checked_align_up(addr: u64, align: u64) -> Option<u64> {
+    let mask = align - 1;
+    if addr & mask == 0 {
+        Some(addr)
+    } else {
+        (addr | mask).checked_add(1)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{mapper::SimulatedMemory, Size2MiB};
+
+    fn regions<T: Clone + PartialEq>(set: &VmaSet<'_, T>) -> Vec<(u64, u64, T)> {
+        set.iter()
+            .map(|region| {
+                (
+                    region.pages.start.start_address().as_u64(),
+                    region.pages.end.start_address().as_u64(),
+                    region.data.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn insert_merge_and_lookup() {
+        let mut storage = [None; 4];
+        let mut set = VmaSet::new(SimulatedMemory::pages(0x1000, 0x10_0000), &mut storage);
+        set.insert(SimulatedMemory::pages(0x4000, 0x6000), 1)
+            .unwrap();
+        set.insert(SimulatedMemory::pages(0x8000, 0x9000), 1)
+            .unwrap();
+        set.insert(SimulatedMemory::pages(0x2000, 0x3000), 2)
+            .unwrap();
+        assert_eq!(
+            set.insert(SimulatedMemory::pages(0x5000, 0x7000), 1),
+            Err(VmaError::Overlap(SimulatedMemory::pages(0x4000, 0x6000)))
+        );
+        assert_eq!(
+            set.insert(SimulatedMemory::pages(0x0, 0x1000), 1),
+            Err(VmaError::InvalidRange)
+        );
+
+        // merges with both neighbours
+        set.insert(SimulatedMemory::pages(0x6000, 0x8000), 1)
+            .unwrap();
+        assert_eq!(
+            regions(&set),
+            vec![(0x2000, 0x3000, 2), (0x4000, 0x9000, 1)]
+        );
+        set.insert(SimulatedMemory::pages(0x3000, 0x4000), 3)
+            .unwrap();
+        assert_eq!(set.len(), 3);
+
+        assert_eq!(set.get(VirtAddr::new(0x8fff)).unwrap().data, 1);
+        assert_eq!(set.get(VirtAddr::new(0x3123)).unwrap().data, 3);
+        assert!(set.get(VirtAddr::new(0x9000)).is_none());
+        assert!(set.get(VirtAddr::new(0x1000)).is_none());
+    }
+
+    #[test]
+    fn remove_splits() {
+        let mut storage = [None; 3];
+        let mut set = VmaSet::new(SimulatedMemory::pages(0x1000, 0x10_0000), &mut storage);
+        set.insert(SimulatedMemory::pages(0x2000, 0x8000), 'a')
+            .unwrap();
+        set.insert(SimulatedMemory::pages(0x9000, 0xc000), 'b')
+            .unwrap();
+
+        let mut removed = Vec::new();
+        set.remove(SimulatedMemory::pages(0x4000, 0x5000), |region| {
+            removed.push(region.pages)
+        })
+        .unwrap();
+        assert_eq!(removed, vec![SimulatedMemory::pages(0x4000, 0x5000)]);
+        assert_eq!(
+            regions(&set),
+            vec![
+                (0x2000, 0x4000, 'a'),
+                (0x5000, 0x8000, 'a'),
+                (0x9000, 0xc000, 'b')
+            ]
+        );
+
+        // no space for another split
+        assert_eq!(
+            set.remove(SimulatedMemory::pages(0xa000, 0xb000), |_| panic!()),
+            Err(VmaError::StorageFull)
+        );
+
+        removed.clear();
+        set.remove(SimulatedMemory::pages(0x3000, 0xa000), |region| {
+            removed.push(region.pages)
+        })
+        .unwrap();
+        assert_eq!(
+            removed,
+            vec![
+                SimulatedMemory::pages(0x3000, 0x4000),
+                SimulatedMemory::pages(0x5000, 0x8000),
+                SimulatedMemory::pages(0x9000, 0xa000)
+            ]
+        );
+        assert_eq!(
+            regions(&set),
+            vec![(0x2000, 0x3000, 'a'), (0xa000, 0xc000, 'b')]
+        );
+    }
+
+    #[test]
+    fn find_free_with_alignment() {
+        let mut storage = [None; 4];
+        let mut set = VmaSet::new(SimulatedMemory::pages(0x1000, 0x80_0000), &mut storage);
+        set.insert(SimulatedMemory::pages(0x1000, 0x20_1000), ())
+            .unwrap();
+
+        let free = set.find_free(2, Size2MiB::SIZE).unwrap();
+        assert_eq!(free, SimulatedMemory::pages(0x40_0000, 0x40_2000));
+        assert_eq!(
+            set.find_free(2, 0x1000).unwrap(),
+            SimulatedMemory::pages(0x20_1000, 0x20_3000)
+        );
+
+        set.allocate(0x200, Size2MiB::SIZE, ()).unwrap();
+        assert_eq!(
+            set.find_free(0x200, Size2MiB::SIZE).unwrap(),
+            SimulatedMemory::pages(0x60_0000, 0x80_0000)
+        );
+        assert_eq!(
+            set.allocate(0x201, Size2MiB::SIZE, ()),
+            Err(VmaError::OutOfSpace)
+        );
+    }
+
+    #[test]
+    fn upper_half() {
+        let bounds = Page::range(
+            Page::containing_address(VirtAddr::new(0xffff_ffff_ffe0_0000)),
+            Page::containing_address(VirtAddr::new(0xffff_ffff_ffff_f000)),
+        );
+        let mut storage = [None; 2];
+        let mut set = VmaSet::new(bounds, &mut storage);
+        assert_eq!(set.allocate(0x1ff, 0x1000, ()).unwrap(), bounds);
+        assert!(set.find_free(1, 0x1000).is_none());
+        assert!(set.find_free(1, Size2MiB::SIZE).is_none());
+    }
+
+    #[test]
+    fn la57_lower_half() {
+        let bounds = Page::range(
+            Page::containing_address(VirtAddr::new_la57(0x7fff_ffff_e000)),
+            Page::containing_address(VirtAddr::new_la57(0x8000_0001_0000)),
+        );
+        let mut storage = [None; 2];
+        let mut set = VmaSet::new(bounds, &mut storage);
+
+        let pages = set.allocate(4, 0x1000, 'a').unwrap();
+        assert_eq!(pages.start.start_address().as_u64(), 0x7fff_ffff_e000);
+        assert_eq!(pages.end.start_address().as_u64(), 0x8000_0000_2000);
+        assert_eq!(
+            set.get(VirtAddr::new_la57(0x8000_0000_1000)).unwrap().pages,
+            pages
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn canonical_hole() {
+        let mut storage: [Option<Vma<()>>; 1] = [None];
+        VmaSet::new(
+            SimulatedMemory::pages(0x7fff_ffff_f000, 0xffff_8000_0000_1000),
+            &mut storage,
+        );
+    }
+
+    fn la57_pages(start: u64, end: u64) -> PageRange {
+        Page::range(
+            Page::containing_address(VirtAddr::new_la57(start)),
+            Page::containing_address(VirtAddr::new_la57(end)),
+        )
+    }
+
+    #[test]
+    fn five_level_lower_half() {
+        // bit 47 is set, but the bounds are part of the 5-level lower half
+        let mut storage = [None; 2];
+        let mut set = VmaSet::new(
+            la57_pages(0x7fff_0000_0000, 0x00ff_ffff_ffff_f000),
+            &mut storage,
+        );
+        set.insert(la57_pages(0x7fff_0000_0000, 0x8000_0000_1000), 1)
+            .unwrap();
+        let free = set.find_free(2, Size4KiB::SIZE).unwrap();
+        assert_eq!(free, la57_pages(0x8000_0000_1000, 0x8000_0000_3000));
+        set.insert(free, 2).unwrap();
+        assert_eq!(
+            regions(&set),
+            vec![
+                (0x7fff_0000_0000, 0x8000_0000_1000, 1),
+                (0x8000_0000_1000, 0x8000_0000_3000, 2)
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn five_level_canonical_hole() {
+        let mut storage: [Option<Vma<()>>; 1] = [None];
+        VmaSet::new(
+            la57_pages(0x00ff_ffff_ffff_f000, 0xff00_0000_0000_1000),
+            &mut storage,
+        );
+    }
+}